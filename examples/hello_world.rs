@@ -7,7 +7,7 @@ fn main() {
 
     {
         // create main method
-        let mut method = class.define_method(ACC_PUBLIC | ACC_STATIC, "main", &[Java::Array(Box::new(Java::Class("java/lang/String")))], &Java::Void);
+        let mut method = class.define_main();
 
         // push PrintStream object and string to print onto the stack, and then call println function
         method.get_static("java/lang/System", "out", &Java::Class("java/io/PrintStream"));
@@ -18,9 +18,9 @@ fn main() {
         method.do_return();
 
         // fini!
-        method.done();
+        method.done().unwrap();
     }
 
-    let classfile = class.done();
+    let classfile = class.done().unwrap();
     write_classfile(classfile, "hello_world.class");
 }
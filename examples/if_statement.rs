@@ -16,7 +16,7 @@ fn main() {
         // } else {
         //     System.out.println("Hello without args!");
         // }
-        method.change_env();
+        method.set_new_env();
         method.aload0();
         method.array_length();
         method.ifle("false");
@@ -38,9 +38,9 @@ fn main() {
         method.do_return();
 
         // fini!
-        method.done();
+        method.done().unwrap();
     }
 
-    let classfile = class.done();
+    let classfile = class.done().unwrap();
     write_classfile(classfile, "if_statement.class");
 }
@@ -26,9 +26,9 @@ fn main() {
         method.do_return();
 
         // fini!
-        method.done();
+        method.done().unwrap();
     }
 
-    let classfile = class.done();
+    let classfile = class.done().unwrap();
     write_classfile(classfile, "simple_addition.class");
 }
@@ -18,7 +18,7 @@ fn main() {
                                Java::Class("java/lang/String")],
                              &Java::Void);
         method.do_return();
-        method.done();
+        method.done().unwrap();
     }
 
     {
@@ -47,9 +47,9 @@ fn main() {
                               &[Java::Class("java/lang/String")], &Java::Void);
 
         method.do_return();
-        method.done();
+        method.done().unwrap();
     }
 
-    let classfile = class.done();
+    let classfile = class.done().unwrap();
     write_classfile(classfile, "static_methods.class");
 }
@@ -0,0 +1,29 @@
+// Access and property flags for classes, fields and methods. Some bits mean
+// different things depending on what they're applied to, e.g. 0x0040 is
+// ACC_VOLATILE on a field but ACC_BRIDGE on a method.
+
+pub const ACC_PUBLIC: u16 = 0x0001;
+pub const ACC_PRIVATE: u16 = 0x0002;
+pub const ACC_PROTECTED: u16 = 0x0004;
+pub const ACC_STATIC: u16 = 0x0008;
+pub const ACC_FINAL: u16 = 0x0010;
+
+// classes
+pub const ACC_SUPER: u16 = 0x0020;
+pub const ACC_INTERFACE: u16 = 0x0200;
+pub const ACC_ABSTRACT: u16 = 0x0400;
+pub const ACC_ANNOTATION: u16 = 0x2000;
+pub const ACC_ENUM: u16 = 0x4000;
+
+// fields
+pub const ACC_VOLATILE: u16 = 0x0040;
+pub const ACC_TRANSIENT: u16 = 0x0080;
+
+// methods
+pub const ACC_SYNCHRONIZED: u16 = 0x0020;
+pub const ACC_BRIDGE: u16 = 0x0040;
+pub const ACC_VARARGS: u16 = 0x0080;
+pub const ACC_NATIVE: u16 = 0x0100;
+pub const ACC_STRICT: u16 = 0x0800;
+
+pub const ACC_SYNTHETIC: u16 = 0x1000;
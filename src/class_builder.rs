@@ -1,51 +1,342 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
 
+use access_flags::*;
 use classfile::*;
+use error::*;
 use java_type_signatures::*;
+use serialization::encode_modified_utf8;
 
-pub const ACC_PUBLIC: u16 = 0x1;
-pub const ACC_STATIC: u16 = 0x8;
+// method handle kinds, for define_method_handle and BootstrapArgument::MethodHandle
+pub const REF_GET_FIELD: u8 = 1;
+pub const REF_GET_STATIC: u8 = 2;
+pub const REF_PUT_FIELD: u8 = 3;
+pub const REF_PUT_STATIC: u8 = 4;
+pub const REF_INVOKE_VIRTUAL: u8 = 5;
+pub const REF_INVOKE_STATIC: u8 = 6;
+pub const REF_INVOKE_SPECIAL: u8 = 7;
+pub const REF_NEW_INVOKE_SPECIAL: u8 = 8;
+pub const REF_INVOKE_INTERFACE: u8 = 9;
+
+// constant_pool_count is a u16 and counts one more than the number of entries
+const MAX_CONSTANT_POOL_ENTRIES: usize = 65534;
+
+// A static argument passed to a bootstrap method. Method types and handles are
+// given as descriptors, e.g. "(Ljava/lang/Object;)Z".
+#[derive(Clone, Debug, PartialEq)]
+pub enum BootstrapArgument<'a> {
+    Integer(i32),
+    Float(f32),
+    Long(i64),
+    Double(f64),
+    String(&'a str),
+    Class(&'a str),
+    MethodType(&'a str),
+    MethodHandle(u8, &'a str, &'a str, &'a str), // reference kind, class, name, descriptor
+}
+
+// The value of an annotation element. Enums and nested annotations are named by
+// their class, e.g. Enum("java/lang/annotation/RetentionPolicy", "RUNTIME").
+#[derive(Clone, Debug, PartialEq)]
+pub enum AnnotationValue<'a> {
+    Byte(i8),
+    Char(char),
+    Double(f64),
+    Float(f32),
+    Int(i32),
+    Long(i64),
+    Short(i16),
+    Boolean(bool),
+    String(&'a str),
+    Enum(&'a str, &'a str),
+    Class(Java<'a>),
+    Annotation(&'a str, Vec<(&'a str, AnnotationValue<'a>)>),
+    Array(Vec<AnnotationValue<'a>>),
+}
 
 pub struct ClassBuilder {
+    minor_version: u16,
+    major_version: u16,
     access_flags: u16,
     this_class_index: u16,
     super_class_index: u16,
     constants: Vec<Constant>,
+    interfaces: Vec<Interface>,
+    fields: Vec<Field>,
     methods: Vec<Method>,
+    attributes: Vec<Attribute>,
+    bootstrap_methods: Vec<BootstrapMethod>,
+    inner_classes: Vec<InnerClass>,
+    annotations: Vec<Annotation>,
+    // entries that didn't fit in the constant pool; reported by done()
+    overflowed_constants: usize,
+    // the first bad argument passed to one of the methods below; reported by done()
+    misuse: Option<AssemblerError>,
 }
 
 impl ClassBuilder {
+    // ACC_SUPER is added for classes, since every JVM since 1.0.2 expects it for
+    // invokespecial to work properly; use set_super_flag to leave it out. It's
+    // never set on interfaces, which mustn't have it.
     pub fn new(access_flags: u16, this_class: &str, super_class: &str) -> ClassBuilder {
+        let access_flags = if access_flags & ACC_INTERFACE != 0 {
+            access_flags & !ACC_SUPER
+        } else {
+            access_flags | ACC_SUPER
+        };
         let mut builder = ClassBuilder {
+            minor_version: MINOR_VERSION,
+            major_version: MAJOR_VERSION,
             access_flags: access_flags,
             this_class_index: 0,
             super_class_index: 0,
             constants: vec![],
+            interfaces: vec![],
+            fields: vec![],
             methods: vec![],
+            attributes: vec![],
+            bootstrap_methods: vec![],
+            inner_classes: vec![],
+            annotations: vec![],
+            overflowed_constants: 0,
+            misuse: None,
         };
 
+        // class names can be given with dots or slashes ("com.example.Foo" or
+        // "com/example/Foo"); they're stored with slashes either way
+        for class in &[this_class, super_class] {
+            if !is_valid_class_name(class) {
+                builder.record_misuse(AssemblerError::InvalidClassName(class.to_string()));
+            }
+        }
+
         builder.this_class_index = builder.define_class(this_class);
         builder.super_class_index = builder.define_class(super_class);
         builder
     }
 
+    // An interface: abstract, with java/lang/Object as its super class as the JVM
+    // requires. Its methods should all be defined with define_abstract_method.
+    pub fn new_interface(access_flags: u16, this_class: &str) -> ClassBuilder {
+        ClassBuilder::new(access_flags | ACC_INTERFACE | ACC_ABSTRACT, this_class, "java/lang/Object")
+    }
+
+    // the package the class is in, e.g. "com/example" for com/example/Foo, or ""
+    // if it's in the unnamed package
+    pub fn package(&self) -> &str {
+        // this_class_index is set by new() and always points at a Class
+        let name = match *self.lookup_constant(self.this_class_index) {
+            Constant::Class(name_index) => self.lookup_utf8(name_index),
+            ref constant => panic!("Wanted Class, found {:?}", constant)
+        };
+        match name.rfind('/') {
+            Some(i) => &name[..i],
+            None => "",
+        }
+    }
+
+    // Defaults to Java 8 (52.0). Class files from before Java 7 (51.0) don't
+    // need stack map frames, so none are generated for them.
+    pub fn set_version(&mut self, major: u16, minor: u16) {
+        self.major_version = major;
+        self.minor_version = minor;
+    }
+
+    pub fn set_super_flag(&mut self, set: bool) {
+        if set && self.access_flags & ACC_INTERFACE == 0 {
+            self.access_flags |= ACC_SUPER;
+        } else {
+            self.access_flags &= !ACC_SUPER;
+        }
+    }
+
+    pub fn add_interface(&mut self, interface_name: &str) {
+        let class_index = self.define_class(interface_name);
+        self.interfaces.push(Interface { class_index: class_index });
+    }
+
+    pub fn mark_deprecated(&mut self) {
+        let already_marked = self.attributes.iter().any(|attribute| match *attribute {
+            Attribute::Deprecated(_) => true,
+            _ => false,
+        });
+        if !already_marked {
+            let name_index = self.define_utf8("Deprecated");
+            self.attributes.push(Attribute::Deprecated(name_index));
+        }
+    }
+
+    // adds a runtime-visible annotation, e.g. add_annotation("java/lang/Deprecated", &[])
+    pub fn add_annotation(&mut self, annotation_type: &str, elements: &[(&str, AnnotationValue)]) {
+        let annotation = self.define_annotation(annotation_type, elements);
+        self.annotations.push(annotation);
+    }
+
+    // the generic signature of the class, e.g. "<T:Ljava/lang/Object;>Ljava/lang/Object;"
+    pub fn set_class_signature(&mut self, signature: &str) {
+        let name_index = self.define_utf8("Signature");
+        let signature_index = self.define_utf8(signature);
+        self.attributes.retain(|attribute| match *attribute {
+            Attribute::Signature(..) => false,
+            _ => true,
+        });
+        self.attributes.push(Attribute::Signature(name_index, signature_index));
+    }
+
+    pub fn set_source_file(&mut self, filename: &str) {
+        let name_index = self.define_utf8("SourceFile");
+        let sourcefile_index = self.define_utf8(filename);
+        self.attributes.retain(|attribute| match *attribute {
+            Attribute::SourceFile(..) => false,
+            _ => true,
+        });
+        self.attributes.push(Attribute::SourceFile(name_index, sourcefile_index));
+    }
+
+    // Adds a static method to the BootstrapMethods table and returns its index,
+    // for use with MethodBuilder::invoke_dynamic.
+    pub fn add_bootstrap_method(&mut self, class: &str, name: &str, argument_types: &[Java],
+                                return_type: &Java, arguments: &[BootstrapArgument]) -> u16 {
+        let descriptor = method_signature(argument_types, return_type);
+        let method_ref = self.define_method_handle(REF_INVOKE_STATIC, class, name, &descriptor);
+        let arguments = arguments.iter().map(|argument| {
+            match *argument {
+                BootstrapArgument::Integer(n) => self.define_integer(n),
+                BootstrapArgument::Float(n) => self.define_float(n),
+                BootstrapArgument::Long(n) => self.define_long(n),
+                BootstrapArgument::Double(n) => self.define_double(n),
+                BootstrapArgument::String(s) => self.define_string(s),
+                BootstrapArgument::Class(c) => self.define_class(c),
+                BootstrapArgument::MethodType(d) => self.define_method_type(d),
+                BootstrapArgument::MethodHandle(kind, c, n, d) => self.define_method_handle(kind, c, n, d),
+            }
+        }).collect();
+
+        let bootstrap_method = BootstrapMethod { method_ref: method_ref, arguments: arguments };
+        match self.bootstrap_methods.iter().position(|m| *m == bootstrap_method) {
+            Some(i) => i as u16,
+            None => {
+                self.bootstrap_methods.push(bootstrap_method);
+                self.bootstrap_methods.len() as u16 - 1
+            },
+        }
+    }
+
+    // Records a nested class for the InnerClasses attribute. Both the outer and the
+    // nested class should list it. `outer` is None for local and anonymous classes,
+    // and `inner_name` is None for anonymous ones.
+    pub fn add_inner_class(&mut self, inner: &str, outer: Option<&str>, inner_name: Option<&str>,
+                           access_flags: u16) {
+        let inner_class_info_index = self.define_class(inner);
+        let outer_class_info_index = match outer {
+            Some(outer) => self.define_class(outer),
+            None => 0,
+        };
+        let inner_name_index = match inner_name {
+            Some(name) => self.define_utf8(name),
+            None => 0,
+        };
+        let inner_class = InnerClass {
+            inner_class_info_index: inner_class_info_index,
+            outer_class_info_index: outer_class_info_index,
+            inner_name_index: inner_name_index,
+            inner_class_access_flags: access_flags,
+        };
+        if !self.inner_classes.contains(&inner_class) {
+            self.inner_classes.push(inner_class);
+        }
+    }
+
+    // For local and anonymous classes: the class they're declared in, and the
+    // method if there is one (there isn't for e.g. instance initializers).
+    pub fn set_enclosing_method(&mut self, class: &str, method: Option<(&str, &[Java], &Java)>) {
+        let name_index = self.define_utf8("EnclosingMethod");
+        let class_index = self.define_class(class);
+        let method_index = match method {
+            Some((name, argument_types, return_type)) => {
+                let descriptor = method_signature(argument_types, return_type);
+                self.define_name_and_type(name, &descriptor)
+            },
+            None => 0,
+        };
+        self.attributes.retain(|attribute| match *attribute {
+            Attribute::EnclosingMethod(..) => false,
+            _ => true,
+        });
+        self.attributes.push(Attribute::EnclosingMethod(name_index, class_index, method_index));
+    }
+
+    pub fn define_field(&mut self, access_flags: u16, name: &str, field_type: &Java) -> FieldBuilder<'_> {
+        FieldBuilder::new(self, access_flags, name, field_type)
+    }
+
     pub fn define_method(&mut self, access_flags: u16, name: &str, argument_types: &[Java],
                          return_type: &Java) -> MethodBuilder {
         MethodBuilder::new(self, access_flags, name, argument_types, return_type)
     }
+
+    // defines a field the compiler made up, e.g. to hold a captured variable
+    pub fn mark_synthetic_field(&mut self, access_flags: u16, name: &str,
+                                field_type: &Java) -> FieldBuilder<'_> {
+        FieldBuilder::new(self, access_flags, name, field_type).mark_synthetic()
+    }
+
+    // public static void main(String[] args)
+    pub fn define_main(&mut self) -> MethodBuilder<'_> {
+        let args = Java::array(Java::Class("java/lang/String"), 1);
+        MethodBuilder::new(self, ACC_PUBLIC | ACC_STATIC, "main", &[args], &Java::Void)
+    }
+
+    pub fn define_constructor(&mut self, access_flags: u16,
+                              argument_types: &[Java]) -> MethodBuilder<'_> {
+        MethodBuilder::new(self, access_flags, "<init>", argument_types, &Java::Void)
+    }
+
+    // declares a method without a body; ACC_ABSTRACT is added to the given flags
+    pub fn define_abstract_method(&mut self, access_flags: u16, name: &str,
+                                  argument_types: &[Java], return_type: &Java) {
+        MethodBuilder::new(self, access_flags | ACC_ABSTRACT, name, argument_types, return_type)
+            .done_without_code();
+    }
+
+    // Throws away the methods defined so far, e.g. to generate them again
+    // differently. The constant pool is kept, so regenerating the same methods
+    // reuses its entries instead of adding more.
+    pub fn clear_methods(&mut self) {
+        self.methods.clear();
+    }
     
+    // the number of entries in the constant pool so far; longs and doubles count twice
+    pub fn constant_count(&self) -> u16 {
+        self.constants.len() as u16
+    }
+
+    // the index of `constant` if it's already in the pool, without adding it
+    pub fn find_constant(&self, constant: &Constant) -> Option<u16> {
+        self.constants.iter()
+            .position(|c| same_constant(constant, c))
+            .map(|i| i as u16 + 1)
+    }
+
     fn push_constant(&mut self, constant: Constant) -> u16 {
-        let mut i: u16 = 1;
-        for c in &self.constants {
-            if constant == *c {
-                return i;
-            }
+        if let Some(index) = self.find_constant(&constant) {
+            return index;
+        }
 
-            i += 1;
+        let two_slots = constant.takes_two_slots();
+        // Once the pool is full, hand out a dummy index so building can carry on;
+        // done() then fails rather than producing a corrupt class.
+        let slots = if two_slots { 2 } else { 1 };
+        if self.overflowed_constants > 0 || self.constants.len() + slots > MAX_CONSTANT_POOL_ENTRIES {
+            self.overflowed_constants += slots;
+            return 0;
         }
-        
         self.constants.push(constant);
-        self.constants.len() as u16
+        let index = self.constants.len() as u16;
+        if two_slots {
+            self.constants.push(Constant::Unusable);
+        }
+        index
     }
 
     fn define_integer(&mut self, n: i32) -> u16 {
@@ -55,13 +346,27 @@ impl ClassBuilder {
     fn define_float(&mut self, n: f32) -> u16 {
         self.push_constant(Constant::Float(n))
     }
+
+    fn define_long(&mut self, n: i64) -> u16 {
+        self.push_constant(Constant::Long(n))
+    }
+
+    fn define_double(&mut self, n: f64) -> u16 {
+        self.push_constant(Constant::Double(n))
+    }
     
+    // A Utf8 constant's length is a u16 count of modified UTF-8 bytes, so
+    // anything longer is reported by done().
     fn define_utf8(&mut self, string: &str) -> u16 {
+        let length = encode_modified_utf8(string).len();
+        if length > u16::MAX as usize {
+            self.record_misuse(AssemblerError::StringTooLong(length));
+        }
         self.push_constant(Constant::Utf8(string.to_owned()))
     }
 
     fn define_class(&mut self, class: &str) -> u16 {
-        let name_index = self.define_utf8(class);
+        let name_index = self.define_utf8(&internal_name(class));
         self.push_constant(Constant::Class(name_index))
     }
 
@@ -79,10 +384,171 @@ impl ClassBuilder {
 
     fn define_methodref(&mut self, class: &str, name: &str, argument_types: &[Java],
                         return_type: &Java) -> u16 {
+        let descriptor = method_signature(argument_types, return_type);
+        self.define_methodref_from_descriptor(class, name, &descriptor)
+    }
+
+    fn define_methodref_from_descriptor(&mut self, class: &str, name: &str,
+                                        descriptor: &str) -> u16 {
+        let class_index = self.define_class(class);
+        let name_and_type_index = self.define_name_and_type(name, descriptor);
+        self.push_constant(Constant::Methodref(class_index, name_and_type_index))
+    }
+
+    fn define_interface_methodref(&mut self, class: &str, name: &str, argument_types: &[Java],
+                                  return_type: &Java) -> u16 {
         let class_index = self.define_class(class);
         let descriptor = method_signature(argument_types, return_type);
         let name_and_type_index = self.define_name_and_type(name, &descriptor);
-        self.push_constant(Constant::Methodref(class_index, name_and_type_index))
+        self.push_constant(Constant::InterfaceMethodref(class_index, name_and_type_index))
+    }
+
+    // the type the verifier sees for a value of type `t`, or None for void
+    fn define_verification_type(&mut self, t: &Java) -> Option<VerificationType> {
+        match *t {
+            Java::Boolean | Java::Byte | Java::Char | Java::Short | Java::Int =>
+                Some(VerificationType::Integer),
+            Java::Long => Some(VerificationType::Long),
+            Java::Float => Some(VerificationType::Float),
+            Java::Double => Some(VerificationType::Double),
+            Java::Void => None,
+            Java::Class(class) => Some(VerificationType::Object(self.define_class(class))),
+            Java::Array(_) => {
+                let descriptor = format!("{}", t);
+                Some(VerificationType::Object(self.define_class(&descriptor)))
+            },
+        }
+    }
+
+    fn record_misuse(&mut self, error: AssemblerError) {
+        if self.misuse.is_none() {
+            self.misuse = Some(error);
+        }
+    }
+
+    // The lookup_* helpers and member_descriptor are only given indices the
+    // builder made itself, so a panic in them is a bug here rather than bad input.
+    fn lookup_constant(&self, index: u16) -> &Constant {
+        &self.constants[index as usize - 1]
+    }
+
+    // the descriptor of the field a Fieldref points at, or None if `index`
+    // (which can come from the caller) isn't a Fieldref
+    fn fieldref_descriptor(&self, index: u16) -> Option<String> {
+        match self.constants.get((index as usize).wrapping_sub(1)) {
+            Some(&Constant::Fieldref(..)) => Some(self.member_descriptor(index).to_owned()),
+            _ => None,
+        }
+    }
+
+    fn lookup_utf8(&self, index: u16) -> &str {
+        match *self.lookup_constant(index) {
+            Constant::Utf8(ref string) => string,
+            ref constant => panic!("Wanted Utf8, found {:?}", constant)
+        }
+    }
+
+    // the descriptor of the field or method a Fieldref/Methodref/InterfaceMethodref/InvokeDynamic points at
+    fn member_descriptor(&self, index: u16) -> &str {
+        let name_and_type_index = match *self.lookup_constant(index) {
+            Constant::Fieldref(_, i) | Constant::Methodref(_, i) |
+            Constant::InterfaceMethodref(_, i) | Constant::InvokeDynamic(_, i) => i,
+            ref constant => panic!("Wanted a member reference, found {:?}", constant)
+        };
+        match *self.lookup_constant(name_and_type_index) {
+            Constant::NameAndType(_, descriptor_index) => self.lookup_utf8(descriptor_index),
+            ref constant => panic!("Wanted NameAndType, found {:?}", constant)
+        }
+    }
+
+    // Kinds 1 to 4 refer to a field and take a field descriptor; the rest refer
+    // to a method. Only REF_NEW_INVOKE_SPECIAL may refer to a constructor. An
+    // invalid handle is reported by done(), and 0 is returned in its place.
+    pub fn define_method_handle(&mut self, reference_kind: u8, class: &str, name: &str,
+                                descriptor: &str) -> u16 {
+        let problem = match reference_kind {
+            REF_GET_FIELD..=REF_PUT_STATIC => match parse_type_signature(descriptor) {
+                Some(Java::Void) | None => Some(format!("{:?} isn't a field descriptor", descriptor)),
+                Some(_) => None,
+            },
+            REF_INVOKE_VIRTUAL..=REF_INVOKE_INTERFACE if parse_method_signature(descriptor).is_none() =>
+                Some(format!("{:?} isn't a method descriptor", descriptor)),
+            REF_NEW_INVOKE_SPECIAL if name != "<init>" =>
+                Some(format!("it must refer to <init>, not {:?}", name)),
+            REF_NEW_INVOKE_SPECIAL => None,
+            REF_INVOKE_VIRTUAL..=REF_INVOKE_INTERFACE if name == "<init>" || name == "<clinit>" =>
+                Some(format!("it can't refer to {:?}", name)),
+            REF_INVOKE_VIRTUAL..=REF_INVOKE_INTERFACE => None,
+            _ => Some("there is no such kind".to_owned()),
+        };
+        if let Some(problem) = problem {
+            self.record_misuse(AssemblerError::InvalidMethodHandle(reference_kind, problem));
+            return 0;
+        }
+        let class_index = self.define_class(class);
+        let name_and_type_index = self.define_name_and_type(name, descriptor);
+        let reference = match reference_kind {
+            REF_GET_FIELD..=REF_PUT_STATIC => Constant::Fieldref(class_index, name_and_type_index),
+            REF_INVOKE_INTERFACE => Constant::InterfaceMethodref(class_index, name_and_type_index),
+            _ => Constant::Methodref(class_index, name_and_type_index),
+        };
+        let reference_index = self.push_constant(reference);
+        self.push_constant(Constant::MethodHandle(reference_kind, reference_index))
+    }
+
+    // Like define_method_handle, an invalid descriptor is reported by done().
+    pub fn define_method_type(&mut self, descriptor: &str) -> u16 {
+        if parse_method_signature(descriptor).is_none() {
+            self.record_misuse(AssemblerError::InvalidDescriptor(descriptor.to_owned()));
+            return 0;
+        }
+        let descriptor_index = self.define_utf8(descriptor);
+        self.push_constant(Constant::MethodType(descriptor_index))
+    }
+
+    fn define_invoke_dynamic(&mut self, bootstrap_method: u16, name: &str,
+                             argument_types: &[Java], return_type: &Java) -> u16 {
+        let descriptor = method_signature(argument_types, return_type);
+        let name_and_type_index = self.define_name_and_type(name, &descriptor);
+        self.push_constant(Constant::InvokeDynamic(bootstrap_method, name_and_type_index))
+    }
+
+    fn define_annotation(&mut self, annotation_type: &str,
+                         elements: &[(&str, AnnotationValue)]) -> Annotation {
+        let type_index = self.define_utf8(&format!("{}", Java::Class(annotation_type)));
+        let element_value_pairs = elements.iter().map(|&(name, ref value)| {
+            ElementValuePair {
+                element_name_index: self.define_utf8(name),
+                value: self.define_element_value(value),
+            }
+        }).collect();
+        Annotation { type_index: type_index, element_value_pairs: element_value_pairs }
+    }
+
+    // bytes, chars, shorts and booleans are all stored as Integer constants
+    fn define_element_value(&mut self, value: &AnnotationValue) -> ElementValue {
+        match *value {
+            AnnotationValue::Byte(n) => ElementValue::Byte(self.define_integer(n as i32)),
+            AnnotationValue::Char(c) => ElementValue::Char(self.define_integer(c as i32)),
+            AnnotationValue::Double(n) => ElementValue::Double(self.define_double(n)),
+            AnnotationValue::Float(n) => ElementValue::Float(self.define_float(n)),
+            AnnotationValue::Int(n) => ElementValue::Int(self.define_integer(n)),
+            AnnotationValue::Long(n) => ElementValue::Long(self.define_long(n)),
+            AnnotationValue::Short(n) => ElementValue::Short(self.define_integer(n as i32)),
+            AnnotationValue::Boolean(b) => ElementValue::Boolean(self.define_integer(b as i32)),
+            AnnotationValue::String(s) => ElementValue::String(self.define_utf8(s)),
+            AnnotationValue::Enum(enum_type, name) => {
+                let type_name_index = self.define_utf8(&format!("{}", Java::Class(enum_type)));
+                ElementValue::Enum(type_name_index, self.define_utf8(name))
+            },
+            AnnotationValue::Class(ref class) => ElementValue::Class(self.define_utf8(&format!("{}", class))),
+            AnnotationValue::Annotation(annotation_type, ref elements) => {
+                ElementValue::Annotation(self.define_annotation(annotation_type, elements))
+            },
+            AnnotationValue::Array(ref values) => {
+                ElementValue::Array(values.iter().map(|v| self.define_element_value(v)).collect())
+            },
+        }
     }
 
     fn define_name_and_type(&mut self, name: &str, descriptor: &str) -> u16 {
@@ -91,34 +557,297 @@ impl ClassBuilder {
         self.push_constant(Constant::NameAndType(name_index, descriptor_index))
     }
 
-    pub fn done(self) -> Classfile {
-        Classfile::new(self.constants, self.access_flags, self.this_class_index,
-                       self.super_class_index, self.methods)
+    pub fn done(mut self) -> Result<Classfile, AssemblerError> {
+        if let Some(error) = self.misuse.clone() {
+            return Err(error);
+        }
+        if !self.bootstrap_methods.is_empty() {
+            let name_index = self.define_utf8("BootstrapMethods");
+            let bootstrap_methods = self.bootstrap_methods.clone();
+            self.attributes.push(Attribute::BootstrapMethods(name_index, bootstrap_methods));
+        }
+        if !self.inner_classes.is_empty() {
+            let name_index = self.define_utf8("InnerClasses");
+            let inner_classes = self.inner_classes.clone();
+            self.attributes.push(Attribute::InnerClasses(name_index, inner_classes));
+        }
+        if !self.annotations.is_empty() {
+            let name_index = self.define_utf8("RuntimeVisibleAnnotations");
+            let annotations = self.annotations.clone();
+            self.attributes.push(Attribute::RuntimeVisibleAnnotations(name_index, annotations));
+        }
+        if self.overflowed_constants > 0 {
+            let entries = self.constants.len() + self.overflowed_constants;
+            return Err(AssemblerError::ConstantPoolFull(entries));
+        }
+        // the JVM rejects a class with two methods of the same name and descriptor
+        let mut signatures = HashSet::new();
+        for method in &self.methods {
+            if !signatures.insert((method.name_index, method.descriptor_index)) {
+                let name = self.lookup_utf8(method.name_index).to_owned();
+                let descriptor = self.lookup_utf8(method.descriptor_index).to_owned();
+                return Err(AssemblerError::DuplicateMethod(name, descriptor));
+            }
+        }
+        let mut classfile = Classfile::new(self.constants, self.access_flags, self.this_class_index,
+                                           self.super_class_index, self.methods);
+        classfile.minor_version = self.minor_version;
+        classfile.major_version = self.major_version;
+        classfile.interfaces = self.interfaces;
+        classfile.fields = self.fields;
+        classfile.attributes = self.attributes;
+        Ok(classfile)
+    }
+}
+
+// Floats and doubles are compared by their bits, so NaNs get merged into one
+// entry while 0.0 and -0.0 stay separate.
+fn same_constant(a: &Constant, b: &Constant) -> bool {
+    match (a, b) {
+        (&Constant::Float(x), &Constant::Float(y)) => x.to_bits() == y.to_bits(),
+        (&Constant::Double(x), &Constant::Double(y)) => x.to_bits() == y.to_bits(),
+        _ => a == b,
+    }
+}
+
+// Each part of a class name has to be non-empty, and can't contain the
+// characters descriptors use as delimiters. Catches things like "com/example/"
+// or a descriptor ("Lcom/example/Foo;") passed where a name was wanted.
+fn is_valid_class_name(class: &str) -> bool {
+    internal_name(class).split('/').all(|part| {
+        !part.is_empty() && !part.contains(&[';', '['][..])
+    })
+}
+
+// Every Fieldref in the pool was made from a Java type or a descriptor that
+// define_method_handle checked, so its descriptor always parses.
+fn parse_field_type(descriptor: &str) -> Java<'_> {
+    match parse_type_signature(descriptor) {
+        Some(t) => t,
+        None => panic!("Invalid field descriptor in the constant pool: {:?}", descriptor),
+    }
+}
+
+pub struct FieldBuilder<'a> {
+    classfile: &'a mut ClassBuilder,
+    access_flags: u16,
+    name_index: u16,
+    descriptor_index: u16,
+    attributes: Vec<Attribute>,
+}
+
+impl<'a> FieldBuilder<'a> {
+    fn new(classfile: &'a mut ClassBuilder, access_flags: u16, name: &str,
+           field_type: &Java) -> FieldBuilder<'a> {
+        let name_index = classfile.define_utf8(name);
+        let descriptor = format!("{}", field_type);
+        let descriptor_index = classfile.define_utf8(&descriptor);
+        FieldBuilder {
+            classfile: classfile,
+            access_flags: access_flags,
+            name_index: name_index,
+            descriptor_index: descriptor_index,
+            attributes: vec![],
+        }
+    }
+
+    pub fn constant_value_int(self, value: i32) -> FieldBuilder<'a> {
+        let index = self.classfile.define_integer(value);
+        self.constant_value(index)
+    }
+
+    pub fn constant_value_float(self, value: f32) -> FieldBuilder<'a> {
+        let index = self.classfile.define_float(value);
+        self.constant_value(index)
+    }
+
+    pub fn constant_value_string(self, value: &str) -> FieldBuilder<'a> {
+        let index = self.classfile.define_string(value);
+        self.constant_value(index)
+    }
+
+    // sets both ACC_SYNTHETIC and the Synthetic attribute, which older tools look for
+    pub fn mark_synthetic(mut self) -> FieldBuilder<'a> {
+        self.access_flags |= ACC_SYNTHETIC;
+        let name_index = self.classfile.define_utf8("Synthetic");
+        self.attributes.push(Attribute::Synthetic(name_index));
+        self
+    }
+
+    // the generic type of the field, e.g. "Ljava/util/List<Ljava/lang/String;>;"
+    pub fn set_signature(mut self, signature: &str) -> FieldBuilder<'a> {
+        let name_index = self.classfile.define_utf8("Signature");
+        let signature_index = self.classfile.define_utf8(signature);
+        self.attributes.push(Attribute::Signature(name_index, signature_index));
+        self
+    }
+
+    fn constant_value(mut self, index: u16) -> FieldBuilder<'a> {
+        let name_index = self.classfile.define_utf8("ConstantValue");
+        self.attributes.push(Attribute::ConstantValue(name_index, index));
+        self
+    }
+
+    pub fn done(self) {
+        let field = Field::new(self.access_flags, self.name_index, self.descriptor_index,
+                               self.attributes);
+        self.classfile.fields.push(field);
     }
 }
 
+// Emits the code of one method. A bad argument to one of the emitting methods,
+// such as an invalid descriptor or an empty tableswitch, doesn't panic: it's
+// passed to record_misuse, the method usually emits nothing, and it returns
+// self as normal so the chain carries on. Only the first misuse is kept, as
+// later ones are often knock-on effects of it, and done() returns it before
+// checking anything else.
 pub struct MethodBuilder<'a> {
     classfile: &'a mut ClassBuilder,
     access_flags: u16,
     name_index: u16,
     descriptor_index: u16,
     instructions: Vec<(u16, IntermediateInstruction<'a>)>,
-    labels: HashMap<(String, u16), u16>,
+    labels: HashMap<(LabelName<'a>, u16), u16>,
+    label_count: u32,
     stack_index: u16,
     curr_stack_depth: u16,
     max_stack_depth: u16,
-    stack_frames: Vec<StackMapFrame>,
-    last_stack_frame_index: Option<u16>,
+    stack_frames: Vec<(u16, Vec<VerificationType>, Vec<VerificationType>)>,
+    branch_states: HashMap<(LabelName<'a>, u16), (Vec<VerificationType>, Vec<VerificationType>)>,
+    initial_locals: Vec<VerificationType>,
     num_locals: u16,
     stack_types: Vec<VerificationType>,
+    locals: Vec<VerificationType>,
     env_num: u16,
     env_count: u16,
+    exception_handlers: Vec<ExceptionHandler<'a>>,
+    line_numbers: Vec<(u16, u16)>,
+    local_variables: Vec<LocalVariable<'a>>,
+    strict: bool,
+    exceptions: Vec<u16>,
+    deprecated: bool,
+    synthetic: bool,
+    annotations: Vec<Annotation>,
+    signature: Option<u16>,
+    stack_split: Option<AssemblerError>,
+    // the first bad argument passed to one of the methods below; reported by done()
+    misuse: Option<AssemblerError>,
+    code_too_large: bool,
+}
+
+// What if_then_else and while_loop test, against the operands the condition
+// code left on the stack: Eq through Le compare an int with zero, the Icmp ones
+// two ints, the Acmp ones two references, and Null/NonNull a single reference.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Condition {
+    Eq,
+    Ne,
+    Lt,
+    Ge,
+    Gt,
+    Le,
+    IcmpEq,
+    IcmpNe,
+    IcmpLt,
+    IcmpGe,
+    IcmpGt,
+    IcmpLe,
+    AcmpEq,
+    AcmpNe,
+    Null,
+    NonNull,
+}
+
+impl Condition {
+    pub fn negate(self) -> Condition {
+        match self {
+            Condition::Eq => Condition::Ne,
+            Condition::Ne => Condition::Eq,
+            Condition::Lt => Condition::Ge,
+            Condition::Ge => Condition::Lt,
+            Condition::Gt => Condition::Le,
+            Condition::Le => Condition::Gt,
+            Condition::IcmpEq => Condition::IcmpNe,
+            Condition::IcmpNe => Condition::IcmpEq,
+            Condition::IcmpLt => Condition::IcmpGe,
+            Condition::IcmpGe => Condition::IcmpLt,
+            Condition::IcmpGt => Condition::IcmpLe,
+            Condition::IcmpLe => Condition::IcmpGt,
+            Condition::AcmpEq => Condition::AcmpNe,
+            Condition::AcmpNe => Condition::AcmpEq,
+            Condition::Null => Condition::NonNull,
+            Condition::NonNull => Condition::Null,
+        }
+    }
+}
+
+// A label made by MethodBuilder::new_label. Unlike a named label it can't be
+// misspelled, and it's unique within its method.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct Label {
+    id: u32,
+}
+
+// Branches, switches and label definitions take either a name or a Label.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum LabelName<'a> {
+    Named(&'a str),
+    Generated(u32),
+}
+
+impl<'a> From<&'a str> for LabelName<'a> {
+    fn from(name: &'a str) -> LabelName<'a> {
+        LabelName::Named(name)
+    }
+}
+
+impl<'a> From<Label> for LabelName<'a> {
+    fn from(label: Label) -> LabelName<'a> {
+        LabelName::Generated(label.id)
+    }
+}
+
+impl<'a, 'b> From<&'b Label> for LabelName<'a> {
+    fn from(label: &'b Label) -> LabelName<'a> {
+        LabelName::Generated(label.id)
+    }
+}
+
+impl<'a> fmt::Display for LabelName<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            LabelName::Named(name) => write!(f, "{}", name),
+            LabelName::Generated(id) => write!(f, "#{}", id),
+        }
+    }
 }
 
 #[derive(Debug)]
 pub enum IntermediateInstruction<'a> {
     Ready(Instruction),
-    Waiting(&'a str, u16, Instruction),
+    Waiting(LabelName<'a>, u16, Instruction),
+    WaitingSwitch(LabelName<'a>, Vec<LabelName<'a>>, u16, Instruction),
+    // dropped by optimize(); takes up no space in the final code
+    Removed(Instruction),
+}
+
+// an exception table entry whose labels get resolved to PCs in done()
+struct ExceptionHandler<'a> {
+    start: LabelName<'a>,
+    end: LabelName<'a>,
+    handler: LabelName<'a>,
+    env: u16,
+    catch_type: u16,
+    exception_class: u16,
+}
+
+struct LocalVariable<'a> {
+    name_index: u16,
+    descriptor_index: u16,
+    slot: u16,
+    start: LabelName<'a>,
+    end: LabelName<'a>,
+    env: u16,
 }
 
 impl<'a> MethodBuilder<'a> {
@@ -127,6 +856,25 @@ impl<'a> MethodBuilder<'a> {
         let name_index = classfile.define_utf8(name);
         let descriptor = method_signature(argument_types, return_type);
         let descriptor_index = classfile.define_utf8(&descriptor);
+
+        // instance methods get `this` in local 0, ahead of the arguments
+        let mut locals = vec![];
+        if access_flags & ACC_STATIC == 0 {
+            if name == "<init>" {
+                locals.push(VerificationType::UninitializedThis);
+            } else {
+                locals.push(VerificationType::Object(classfile.this_class_index));
+            }
+        }
+        for t in argument_types {
+            if let Some(verification_type) = classfile.define_verification_type(t) {
+                locals.push(verification_type);
+                if t.slot_size() == 2 {
+                    locals.push(VerificationType::Top);
+                }
+            }
+        }
+
         MethodBuilder {
             classfile: classfile,
             access_flags: access_flags,
@@ -134,15 +882,30 @@ impl<'a> MethodBuilder<'a> {
             descriptor_index: descriptor_index,
             instructions: vec![],
             labels: HashMap::new(),
+            label_count: 0,
             stack_index: 0,
             curr_stack_depth: 0,
             max_stack_depth: 0,
             stack_frames: vec![],
-            last_stack_frame_index: None,
-            num_locals: argument_types.len() as u16,
+            branch_states: HashMap::new(),
+            initial_locals: locals.clone(),
+            num_locals: locals.len() as u16,
             stack_types: Vec::new(),
+            locals: locals,
             env_num: 0,
             env_count: 0,
+            exception_handlers: vec![],
+            line_numbers: vec![],
+            local_variables: vec![],
+            strict: false,
+            exceptions: vec![],
+            deprecated: false,
+            synthetic: false,
+            annotations: vec![],
+            signature: None,
+            stack_split: None,
+            misuse: None,
+            code_too_large: false,
         }
     }
 
@@ -151,8 +914,9 @@ impl<'a> MethodBuilder<'a> {
         self.env_count
     }
 
-    pub fn set_env(&mut self, n: u16) {
+    pub fn set_env(&mut self, n: u16) -> &mut Self {
         self.env_num = n;
+        self
     }
 
     pub fn set_new_env(&mut self) -> u16 {
@@ -160,616 +924,4897 @@ impl<'a> MethodBuilder<'a> {
         self.env_num
     }
     
-    pub fn nyew(&mut self, class_name: &str) {
+    pub fn new_object(&mut self, class_name: &str) -> &mut Self {
         let idx: u16 = self.classfile.define_class(class_name);
         // the index needs to be split into two u8s (idx1 is the bigger half)
         let idx1 = (idx >> 8) as u8;
-        let idx2 = (idx | 0xff) as u8;
+        let idx2 = (idx & 0xff) as u8;
+        let pc = self.stack_index;
         self.push_instruction(Instruction::New(idx1, idx2));
         self.increase_stack_depth();
+        self.stack_types.push(VerificationType::Uninitialized(pc));
+        self
     }
 
-    pub fn dup(&mut self) {
-        self.push_instruction(Instruction::Dup);
-        self.increase_stack_depth();
+    #[deprecated(note = "use `new_object` instead")]
+    pub fn nyew(&mut self, class_name: &str) -> &mut Self {
+        self.new_object(class_name);
+        self
     }
 
-    pub fn i2c(&mut self) {
-        self.push_instruction(Instruction::I2C);
+    pub fn nop(&mut self) -> &mut Self {
+        self.push_instruction(Instruction::Nop);
+        self
     }
 
-    pub fn i2f(&mut self) {
-        self.push_instruction(Instruction::I2F);
+    pub fn dup(&mut self) -> &mut Self {
+        self.check_stack_split(Instruction::Dup, 1, 0);
+        self.push_instruction(Instruction::Dup);
+        self.shuffle_stack_types(1, 0, true);
+        self.increase_stack_depth();
+        self
     }
 
-    pub fn f2i(&mut self) {
-        self.push_instruction(Instruction::F2I);
-    }
-    
-    pub fn irem(&mut self) {
-        self.push_instruction(Instruction::Irem);
+    pub fn pop(&mut self) -> &mut Self {
+        self.check_stack_split(Instruction::Pop, 1, 0);
+        self.push_instruction(Instruction::Pop);
         self.decrease_stack_depth();
+        self
     }
 
-    pub fn frem(&mut self) {
-        self.push_instruction(Instruction::Frem);
-        self.decrease_stack_depth();
+    pub fn pop2(&mut self) -> &mut Self {
+        self.check_stack_split(Instruction::Pop2, 2, 0);
+        self.push_instruction(Instruction::Pop2);
+        self.decrease_stack_depth_by(2);
+        self
     }
-    
-    pub fn iconstm1(&mut self) {
-        self.push_instruction(Instruction::IconstM1);
-        self.increase_stack_depth();
-        self.stack_types.push(VerificationType::Integer);
+
+    pub fn swap(&mut self) -> &mut Self {
+        self.check_stack_split(Instruction::Swap, 1, 1);
+        self.push_instruction(Instruction::Swap);
+        self.shuffle_stack_types(1, 1, false);
+        self
     }
 
-    pub fn iconst0(&mut self) {
-        self.push_instruction(Instruction::Iconst0);
+    pub fn dup_x1(&mut self) -> &mut Self {
+        self.check_stack_split(Instruction::DupX1, 1, 1);
+        self.push_instruction(Instruction::DupX1);
+        self.shuffle_stack_types(1, 1, true);
         self.increase_stack_depth();
-        self.stack_types.push(VerificationType::Integer);
+        self
     }
 
-    pub fn iconst1(&mut self) {
-        self.push_instruction(Instruction::Iconst1);
+    pub fn dup_x2(&mut self) -> &mut Self {
+        self.check_stack_split(Instruction::DupX2, 1, 2);
+        self.push_instruction(Instruction::DupX2);
+        self.shuffle_stack_types(1, 2, true);
         self.increase_stack_depth();
-        self.stack_types.push(VerificationType::Integer);
+        self
     }
 
-    pub fn iconst2(&mut self) {
-        self.push_instruction(Instruction::Iconst2);
-        self.increase_stack_depth();
-        self.stack_types.push(VerificationType::Integer);
+    pub fn dup2(&mut self) -> &mut Self {
+        self.check_stack_split(Instruction::Dup2, 2, 0);
+        self.push_instruction(Instruction::Dup2);
+        self.shuffle_stack_types(2, 0, true);
+        self.increase_stack_depth_by(2);
+        self
     }
 
-    pub fn iconst3(&mut self) {
-        self.push_instruction(Instruction::Iconst3);
-        self.increase_stack_depth();
-        self.stack_types.push(VerificationType::Integer);
+    pub fn dup2_x1(&mut self) -> &mut Self {
+        self.check_stack_split(Instruction::Dup2X1, 2, 1);
+        self.push_instruction(Instruction::Dup2X1);
+        self.shuffle_stack_types(2, 1, true);
+        self.increase_stack_depth_by(2);
+        self
     }
 
-    pub fn iconst4(&mut self) {
-        self.push_instruction(Instruction::Iconst4);
-        self.increase_stack_depth();
-        self.stack_types.push(VerificationType::Integer);
+    pub fn dup2_x2(&mut self) -> &mut Self {
+        self.check_stack_split(Instruction::Dup2X2, 2, 2);
+        self.push_instruction(Instruction::Dup2X2);
+        self.shuffle_stack_types(2, 2, true);
+        self.increase_stack_depth_by(2);
+        self
     }
 
-    pub fn iconst5(&mut self) {
-        self.push_instruction(Instruction::Iconst5);
-        self.increase_stack_depth();
-        self.stack_types.push(VerificationType::Integer);
+    pub fn i2c(&mut self) -> &mut Self {
+        self.push_instruction(Instruction::I2C);
+        self
     }
 
-    pub fn istore0(&mut self) {
-        self.push_instruction(Instruction::Istore0);
+    pub fn i2f(&mut self) -> &mut Self {
+        self.push_instruction(Instruction::I2F);
         self.decrease_stack_depth();
-        self.increase_locals();
+        self.increase_stack_depth();
+        self.stack_types.push(VerificationType::Float);
+        self
     }
 
-    pub fn istore1(&mut self) {
-        self.push_instruction(Instruction::Istore1);
+    pub fn f2i(&mut self) -> &mut Self {
+        self.push_instruction(Instruction::F2I);
         self.decrease_stack_depth();
-        self.increase_locals();
+        self.increase_stack_depth();
+        self.stack_types.push(VerificationType::Integer);
+        self
     }
-    
-    pub fn istore2(&mut self) {
-        self.push_instruction(Instruction::Istore2);
+    pub fn i2l(&mut self) -> &mut Self {
+        self.push_instruction(Instruction::I2L);
         self.decrease_stack_depth();
-        self.increase_locals();
+        self.increase_stack_depth_by(2);
+        self.stack_types.push(VerificationType::Long);
+        self.stack_types.push(VerificationType::Top);
+        self
     }
-
-    pub fn istore3(&mut self) {
-        self.push_instruction(Instruction::Istore3);
+    pub fn i2d(&mut self) -> &mut Self {
+        self.push_instruction(Instruction::I2D);
         self.decrease_stack_depth();
-        self.increase_locals();
+        self.increase_stack_depth_by(2);
+        self.stack_types.push(VerificationType::Double);
+        self.stack_types.push(VerificationType::Top);
+        self
     }
-
-    pub fn istore(&mut self, idx: u8) {
-        self.push_instruction(Instruction::Istore(idx));
-        self.decrease_stack_depth();
-        self.increase_locals();
+    pub fn i2b(&mut self) -> &mut Self {
+        self.push_instruction(Instruction::I2B);
+        self
+    }
+    pub fn i2s(&mut self) -> &mut Self {
+        self.push_instruction(Instruction::I2S);
+        self
+    }
+    pub fn l2i(&mut self) -> &mut Self {
+        self.push_instruction(Instruction::L2I);
+        self.decrease_stack_depth_by(2);
+        self.increase_stack_depth();
+        self.stack_types.push(VerificationType::Integer);
+        self
+    }
+    pub fn l2f(&mut self) -> &mut Self {
+        self.push_instruction(Instruction::L2F);
+        self.decrease_stack_depth_by(2);
+        self.increase_stack_depth();
+        self.stack_types.push(VerificationType::Float);
+        self
+    }
+    pub fn l2d(&mut self) -> &mut Self {
+        self.push_instruction(Instruction::L2D);
+        self.decrease_stack_depth_by(2);
+        self.increase_stack_depth_by(2);
+        self.stack_types.push(VerificationType::Double);
+        self.stack_types.push(VerificationType::Top);
+        self
+    }
+    pub fn f2l(&mut self) -> &mut Self {
+        self.push_instruction(Instruction::F2L);
+        self.decrease_stack_depth();
+        self.increase_stack_depth_by(2);
+        self.stack_types.push(VerificationType::Long);
+        self.stack_types.push(VerificationType::Top);
+        self
+    }
+    pub fn f2d(&mut self) -> &mut Self {
+        self.push_instruction(Instruction::F2D);
+        self.decrease_stack_depth();
+        self.increase_stack_depth_by(2);
+        self.stack_types.push(VerificationType::Double);
+        self.stack_types.push(VerificationType::Top);
+        self
+    }
+    pub fn d2i(&mut self) -> &mut Self {
+        self.push_instruction(Instruction::D2I);
+        self.decrease_stack_depth_by(2);
+        self.increase_stack_depth();
+        self.stack_types.push(VerificationType::Integer);
+        self
+    }
+    pub fn d2l(&mut self) -> &mut Self {
+        self.push_instruction(Instruction::D2L);
+        self.decrease_stack_depth_by(2);
+        self.increase_stack_depth_by(2);
+        self.stack_types.push(VerificationType::Long);
+        self.stack_types.push(VerificationType::Top);
+        self
+    }
+    pub fn d2f(&mut self) -> &mut Self {
+        self.push_instruction(Instruction::D2F);
+        self.decrease_stack_depth_by(2);
+        self.increase_stack_depth();
+        self.stack_types.push(VerificationType::Float);
+        self
+    }
+    
+    pub fn irem(&mut self) -> &mut Self {
+        self.push_instruction(Instruction::Irem);
+        self.decrease_stack_depth();
+        self
+    }
+
+    pub fn frem(&mut self) -> &mut Self {
+        self.push_instruction(Instruction::Frem);
+        self.decrease_stack_depth();
+        self
+    }
+
+    pub fn fneg(&mut self) -> &mut Self {
+        self.push_instruction(Instruction::Fneg);
+        self
+    }
+    
+    pub fn iconstm1(&mut self) -> &mut Self {
+        self.push_instruction(Instruction::IconstM1);
+        self.increase_stack_depth();
+        self.stack_types.push(VerificationType::Integer);
+        self
+    }
+
+    pub fn iconst0(&mut self) -> &mut Self {
+        self.push_instruction(Instruction::Iconst0);
+        self.increase_stack_depth();
+        self.stack_types.push(VerificationType::Integer);
+        self
+    }
+
+    pub fn iconst1(&mut self) -> &mut Self {
+        self.push_instruction(Instruction::Iconst1);
+        self.increase_stack_depth();
+        self.stack_types.push(VerificationType::Integer);
+        self
+    }
+
+    pub fn iconst2(&mut self) -> &mut Self {
+        self.push_instruction(Instruction::Iconst2);
+        self.increase_stack_depth();
+        self.stack_types.push(VerificationType::Integer);
+        self
+    }
+
+    pub fn iconst3(&mut self) -> &mut Self {
+        self.push_instruction(Instruction::Iconst3);
+        self.increase_stack_depth();
+        self.stack_types.push(VerificationType::Integer);
+        self
+    }
+
+    pub fn iconst4(&mut self) -> &mut Self {
+        self.push_instruction(Instruction::Iconst4);
+        self.increase_stack_depth();
+        self.stack_types.push(VerificationType::Integer);
+        self
+    }
+
+    pub fn iconst5(&mut self) -> &mut Self {
+        self.push_instruction(Instruction::Iconst5);
+        self.increase_stack_depth();
+        self.stack_types.push(VerificationType::Integer);
+        self
+    }
+
+    pub fn istore0(&mut self) -> &mut Self {
+        self.push_instruction(Instruction::Istore0);
+        self.set_local(0, VerificationType::Integer);
+        self.decrease_stack_depth();
+        self
+    }
+
+    pub fn istore1(&mut self) -> &mut Self {
+        self.push_instruction(Instruction::Istore1);
+        self.set_local(1, VerificationType::Integer);
+        self.decrease_stack_depth();
+        self
+    }
+    
+    pub fn istore2(&mut self) -> &mut Self {
+        self.push_instruction(Instruction::Istore2);
+        self.set_local(2, VerificationType::Integer);
+        self.decrease_stack_depth();
+        self
     }
-    
 
-    pub fn fconst0(&mut self) {
+    pub fn istore3(&mut self) -> &mut Self {
+        self.push_instruction(Instruction::Istore3);
+        self.set_local(3, VerificationType::Integer);
+        self.decrease_stack_depth();
+        self
+    }
+
+    pub fn istore(&mut self, idx: u16) -> &mut Self {
+        self.push_local_instruction(idx, Instruction::Istore, Instruction::WideIstore);
+        self.set_local(idx, VerificationType::Integer);
+        self.decrease_stack_depth();
+        self
+    }
+    
+
+    pub fn iinc(&mut self, local: u16, delta: i16) -> &mut Self {
+        if local > ::std::u8::MAX as u16 || delta != delta as i8 as i16 {
+            self.push_instruction(Instruction::WideIinc(local, delta));
+        } else {
+            self.push_instruction(Instruction::Iinc(local as u8, delta as i8));
+        }
+        self
+    }
+
+    pub fn fconst0(&mut self) -> &mut Self {
         self.push_instruction(Instruction::Fconst0);
         self.increase_stack_depth();
-        //self.stack_types.push(VerificationType::Float);
+        self.stack_types.push(VerificationType::Float);
+        self
     }
 
-    pub fn fconst1(&mut self) {
+    pub fn fconst1(&mut self) -> &mut Self {
         self.push_instruction(Instruction::Fconst1);
         self.increase_stack_depth();
-        //self.stack_types.push(VerificationType::Integer);
+        self.stack_types.push(VerificationType::Float);
+        self
     }
 
-    pub fn fconst2(&mut self) {
+    pub fn fconst2(&mut self) -> &mut Self {
         self.push_instruction(Instruction::Fconst2);
         self.increase_stack_depth();
-        //self.stack_types.push(VerificationType::Integer);
+        self.stack_types.push(VerificationType::Float);
+        self
     }
 
-    pub fn fstore0(&mut self) {
+    pub fn fstore0(&mut self) -> &mut Self {
         self.push_instruction(Instruction::Fstore0);
+        self.set_local(0, VerificationType::Float);
         self.decrease_stack_depth();
-        self.increase_locals();
+        self
     }
 
-    pub fn fstore1(&mut self) {
+    pub fn fstore1(&mut self) -> &mut Self {
         self.push_instruction(Instruction::Fstore1);
+        self.set_local(1, VerificationType::Float);
         self.decrease_stack_depth();
-        self.increase_locals();
+        self
     }
     
-    pub fn fstore2(&mut self) {
+    pub fn fstore2(&mut self) -> &mut Self {
         self.push_instruction(Instruction::Fstore2);
+        self.set_local(2, VerificationType::Float);
         self.decrease_stack_depth();
-        self.increase_locals();
+        self
     }
 
-    pub fn fstore3(&mut self) {
+    pub fn fstore3(&mut self) -> &mut Self {
         self.push_instruction(Instruction::Fstore3);
+        self.set_local(3, VerificationType::Float);
         self.decrease_stack_depth();
-        self.increase_locals();
+        self
     }
 
-    pub fn fstore(&mut self, idx: u8) {
-        self.push_instruction(Instruction::Fstore(idx));
+    pub fn fstore(&mut self, idx: u16) -> &mut Self {
+        self.push_local_instruction(idx, Instruction::Fstore, Instruction::WideFstore);
+        self.set_local(idx, VerificationType::Float);
         self.decrease_stack_depth();
-        self.increase_locals();
+        self
     }
     
-    pub fn fload0(&mut self) {
+    pub fn fload0(&mut self) -> &mut Self {
         self.push_instruction(Instruction::Fload0);
         self.increase_stack_depth();
-        //self.stack_types.push(VerificationType::Integer);
+        self.stack_types.push(VerificationType::Float);
+        self
     }
     
-    pub fn fload1(&mut self) {
+    pub fn fload1(&mut self) -> &mut Self {
         self.push_instruction(Instruction::Fload1);
         self.increase_stack_depth();
-        //self.stack_types.push(VerificationType::Integer);
+        self.stack_types.push(VerificationType::Float);
+        self
     }
 
-    pub fn fload2(&mut self) {
+    pub fn fload2(&mut self) -> &mut Self {
         self.push_instruction(Instruction::Fload2);
         self.increase_stack_depth();
-        //self.stack_types.push(VerificationType::Integer);
+        self.stack_types.push(VerificationType::Float);
+        self
     }
 
-    pub fn fload3(&mut self) {
+    pub fn fload3(&mut self) -> &mut Self {
         self.push_instruction(Instruction::Fload3);
         self.increase_stack_depth();
-        //self.stack_types.push(VerificationType::Integer);
+        self.stack_types.push(VerificationType::Float);
+        self
     }
 
-    pub fn fload(&mut self, reg: u8) {
-        self.push_instruction(Instruction::Fload(reg));
+    pub fn fload(&mut self, reg: u16) -> &mut Self {
+        self.push_local_instruction(reg, Instruction::Fload, Instruction::WideFload);
         self.increase_stack_depth();
-        //self.stack_types.push(VerificationType::Integer);
-    }    
+        self.stack_types.push(VerificationType::Float);
+        self
+    }
 
-    pub fn fadd(&mut self) {
+    pub fn fadd(&mut self) -> &mut Self {
         self.push_instruction(Instruction::Fadd);
         self.decrease_stack_depth();
+        self
     }
 
-    pub fn fsub(&mut self) {
+    pub fn fsub(&mut self) -> &mut Self {
         self.push_instruction(Instruction::Fsub);
         self.decrease_stack_depth();
+        self
     }
 
-    pub fn fmul(&mut self) {
+    pub fn fmul(&mut self) -> &mut Self {
         self.push_instruction(Instruction::Fmul);
         self.decrease_stack_depth();
+        self
     }
 
-    pub fn fdiv(&mut self) {
+    pub fn fdiv(&mut self) -> &mut Self {
         self.push_instruction(Instruction::Fdiv);
         self.decrease_stack_depth();
+        self
     }
     
-    pub fn bipush(&mut self, value: i8) {
+    pub fn lconst0(&mut self) -> &mut Self {
+        self.push_instruction(Instruction::Lconst0);
+        self.increase_stack_depth_by(2);
+        self.stack_types.push(VerificationType::Long);
+        self.stack_types.push(VerificationType::Top);
+        self
+    }
+
+    pub fn lconst1(&mut self) -> &mut Self {
+        self.push_instruction(Instruction::Lconst1);
+        self.increase_stack_depth_by(2);
+        self.stack_types.push(VerificationType::Long);
+        self.stack_types.push(VerificationType::Top);
+        self
+    }
+
+    pub fn lload0(&mut self) -> &mut Self {
+        self.push_instruction(Instruction::Lload0);
+        self.increase_stack_depth_by(2);
+        self.stack_types.push(VerificationType::Long);
+        self.stack_types.push(VerificationType::Top);
+        self
+    }
+
+    pub fn lload1(&mut self) -> &mut Self {
+        self.push_instruction(Instruction::Lload1);
+        self.increase_stack_depth_by(2);
+        self.stack_types.push(VerificationType::Long);
+        self.stack_types.push(VerificationType::Top);
+        self
+    }
+
+    pub fn lload2(&mut self) -> &mut Self {
+        self.push_instruction(Instruction::Lload2);
+        self.increase_stack_depth_by(2);
+        self.stack_types.push(VerificationType::Long);
+        self.stack_types.push(VerificationType::Top);
+        self
+    }
+
+    pub fn lload3(&mut self) -> &mut Self {
+        self.push_instruction(Instruction::Lload3);
+        self.increase_stack_depth_by(2);
+        self.stack_types.push(VerificationType::Long);
+        self.stack_types.push(VerificationType::Top);
+        self
+    }
+
+    pub fn lload(&mut self, reg: u16) -> &mut Self {
+        self.push_local_instruction(reg, Instruction::Lload, Instruction::WideLload);
+        self.increase_stack_depth_by(2);
+        self.stack_types.push(VerificationType::Long);
+        self.stack_types.push(VerificationType::Top);
+        self
+    }
+
+    pub fn lstore0(&mut self) -> &mut Self {
+        self.push_instruction(Instruction::Lstore0);
+        self.set_local(0, VerificationType::Long);
+        self.decrease_stack_depth_by(2);
+        self
+    }
+
+    pub fn lstore1(&mut self) -> &mut Self {
+        self.push_instruction(Instruction::Lstore1);
+        self.set_local(1, VerificationType::Long);
+        self.decrease_stack_depth_by(2);
+        self
+    }
+
+    pub fn lstore2(&mut self) -> &mut Self {
+        self.push_instruction(Instruction::Lstore2);
+        self.set_local(2, VerificationType::Long);
+        self.decrease_stack_depth_by(2);
+        self
+    }
+
+    pub fn lstore3(&mut self) -> &mut Self {
+        self.push_instruction(Instruction::Lstore3);
+        self.set_local(3, VerificationType::Long);
+        self.decrease_stack_depth_by(2);
+        self
+    }
+
+    pub fn lstore(&mut self, idx: u16) -> &mut Self {
+        self.push_local_instruction(idx, Instruction::Lstore, Instruction::WideLstore);
+        self.set_local(idx, VerificationType::Long);
+        self.decrease_stack_depth_by(2);
+        self
+    }
+
+    pub fn ladd(&mut self) -> &mut Self {
+        self.push_instruction(Instruction::Ladd);
+        self.decrease_stack_depth_by(2);
+        self
+    }
+
+    pub fn lsub(&mut self) -> &mut Self {
+        self.push_instruction(Instruction::Lsub);
+        self.decrease_stack_depth_by(2);
+        self
+    }
+
+    pub fn lmul(&mut self) -> &mut Self {
+        self.push_instruction(Instruction::Lmul);
+        self.decrease_stack_depth_by(2);
+        self
+    }
+
+    pub fn ldiv(&mut self) -> &mut Self {
+        self.push_instruction(Instruction::Ldiv);
+        self.decrease_stack_depth_by(2);
+        self
+    }
+
+    pub fn lrem(&mut self) -> &mut Self {
+        self.push_instruction(Instruction::Lrem);
+        self.decrease_stack_depth_by(2);
+        self
+    }
+
+    pub fn dconst0(&mut self) -> &mut Self {
+        self.push_instruction(Instruction::Dconst0);
+        self.increase_stack_depth_by(2);
+        self.stack_types.push(VerificationType::Double);
+        self.stack_types.push(VerificationType::Top);
+        self
+    }
+
+    pub fn dconst1(&mut self) -> &mut Self {
+        self.push_instruction(Instruction::Dconst1);
+        self.increase_stack_depth_by(2);
+        self.stack_types.push(VerificationType::Double);
+        self.stack_types.push(VerificationType::Top);
+        self
+    }
+
+    pub fn dload0(&mut self) -> &mut Self {
+        self.push_instruction(Instruction::Dload0);
+        self.increase_stack_depth_by(2);
+        self.stack_types.push(VerificationType::Double);
+        self.stack_types.push(VerificationType::Top);
+        self
+    }
+
+    pub fn dload1(&mut self) -> &mut Self {
+        self.push_instruction(Instruction::Dload1);
+        self.increase_stack_depth_by(2);
+        self.stack_types.push(VerificationType::Double);
+        self.stack_types.push(VerificationType::Top);
+        self
+    }
+
+    pub fn dload2(&mut self) -> &mut Self {
+        self.push_instruction(Instruction::Dload2);
+        self.increase_stack_depth_by(2);
+        self.stack_types.push(VerificationType::Double);
+        self.stack_types.push(VerificationType::Top);
+        self
+    }
+
+    pub fn dload3(&mut self) -> &mut Self {
+        self.push_instruction(Instruction::Dload3);
+        self.increase_stack_depth_by(2);
+        self.stack_types.push(VerificationType::Double);
+        self.stack_types.push(VerificationType::Top);
+        self
+    }
+
+    pub fn dload(&mut self, reg: u16) -> &mut Self {
+        self.push_local_instruction(reg, Instruction::Dload, Instruction::WideDload);
+        self.increase_stack_depth_by(2);
+        self.stack_types.push(VerificationType::Double);
+        self.stack_types.push(VerificationType::Top);
+        self
+    }
+
+    pub fn dstore0(&mut self) -> &mut Self {
+        self.push_instruction(Instruction::Dstore0);
+        self.set_local(0, VerificationType::Double);
+        self.decrease_stack_depth_by(2);
+        self
+    }
+
+    pub fn dstore1(&mut self) -> &mut Self {
+        self.push_instruction(Instruction::Dstore1);
+        self.set_local(1, VerificationType::Double);
+        self.decrease_stack_depth_by(2);
+        self
+    }
+
+    pub fn dstore2(&mut self) -> &mut Self {
+        self.push_instruction(Instruction::Dstore2);
+        self.set_local(2, VerificationType::Double);
+        self.decrease_stack_depth_by(2);
+        self
+    }
+
+    pub fn dstore3(&mut self) -> &mut Self {
+        self.push_instruction(Instruction::Dstore3);
+        self.set_local(3, VerificationType::Double);
+        self.decrease_stack_depth_by(2);
+        self
+    }
+
+    pub fn dstore(&mut self, idx: u16) -> &mut Self {
+        self.push_local_instruction(idx, Instruction::Dstore, Instruction::WideDstore);
+        self.set_local(idx, VerificationType::Double);
+        self.decrease_stack_depth_by(2);
+        self
+    }
+
+    pub fn dadd(&mut self) -> &mut Self {
+        self.push_instruction(Instruction::Dadd);
+        self.decrease_stack_depth_by(2);
+        self
+    }
+
+    pub fn dsub(&mut self) -> &mut Self {
+        self.push_instruction(Instruction::Dsub);
+        self.decrease_stack_depth_by(2);
+        self
+    }
+
+    pub fn dmul(&mut self) -> &mut Self {
+        self.push_instruction(Instruction::Dmul);
+        self.decrease_stack_depth_by(2);
+        self
+    }
+
+    pub fn ddiv(&mut self) -> &mut Self {
+        self.push_instruction(Instruction::Ddiv);
+        self.decrease_stack_depth_by(2);
+        self
+    }
+
+    pub fn drem(&mut self) -> &mut Self {
+        self.push_instruction(Instruction::Drem);
+        self.decrease_stack_depth_by(2);
+        self
+    }
+
+    pub fn dneg(&mut self) -> &mut Self {
+        self.push_instruction(Instruction::Dneg);
+        self
+    }
+
+    pub fn bipush(&mut self, value: i8) -> &mut Self {
         self.push_instruction(Instruction::Bipush(value as u8));
         self.increase_stack_depth();
         self.stack_types.push(VerificationType::Integer);
+        self
     }
 
-    pub fn sipush(&mut self, val0: i8, val1: i8) {
-        self.push_instruction(Instruction::Sipush(val0 as u8, val1 as u8));
+    pub fn sipush(&mut self, value: i16) -> &mut Self {
+        self.push_instruction(Instruction::Sipush((value >> 8) as u8, value as u8));
         self.increase_stack_depth();
         self.stack_types.push(VerificationType::Integer);
+        self
     }
     
-    pub fn iload0(&mut self) {
+    pub fn iload0(&mut self) -> &mut Self {
         self.push_instruction(Instruction::Iload0);
         self.increase_stack_depth();
         self.stack_types.push(VerificationType::Integer);
+        self
     }
     
-    pub fn iload1(&mut self) {
+    pub fn iload1(&mut self) -> &mut Self {
         self.push_instruction(Instruction::Iload1);
         self.increase_stack_depth();
         self.stack_types.push(VerificationType::Integer);
+        self
     }
 
-    pub fn iload2(&mut self) {
+    pub fn iload2(&mut self) -> &mut Self {
         self.push_instruction(Instruction::Iload2);
         self.increase_stack_depth();
         self.stack_types.push(VerificationType::Integer);
+        self
     }
 
-    pub fn iload3(&mut self) {
+    pub fn iload3(&mut self) -> &mut Self {
         self.push_instruction(Instruction::Iload3);
         self.increase_stack_depth();
         self.stack_types.push(VerificationType::Integer);
+        self
     }
 
-    pub fn iload(&mut self, reg: u8) {
-        self.push_instruction(Instruction::Iload(reg));
+    pub fn iload(&mut self, reg: u16) -> &mut Self {
+        self.push_local_instruction(reg, Instruction::Iload, Instruction::WideIload);
         self.increase_stack_depth();
         self.stack_types.push(VerificationType::Integer);
-    }    
+        self
+    }
     
-    pub fn load_constant(&mut self, value: &str) {
+    pub fn load_constant(&mut self, value: &str) -> &mut Self {
         let string_index = self.classfile.define_string(value);
-        if string_index > ::std::u8::MAX as u16 {
-            panic!("Placed a constant in too high of an index: {}", string_index)
-        }
-        self.push_instruction(Instruction::LoadConstant(string_index as u8));
+        self.push_load_constant(string_index);
+        let string_class = self.classfile.define_class("java/lang/String");
+        self.increase_stack_depth();
+        self.stack_types.push(VerificationType::Object(string_class));
+        self
+    }
+
+    // pushes the java/lang/Class for `class`, like Foo.class in Java; arrays are
+    // named by their descriptor, e.g. "[I"
+    pub fn load_class_constant(&mut self, class: &str) -> &mut Self {
+        let class_index = self.classfile.define_class(class);
+        self.push_load_constant(class_index);
+        let class_class = self.classfile.define_class("java/lang/Class");
+        self.increase_stack_depth();
+        self.stack_types.push(VerificationType::Object(class_class));
+        self
+    }
+
+    // pushes a java/lang/invoke/MethodHandle; see ClassBuilder::define_method_handle
+    pub fn load_method_handle(&mut self, reference_kind: u8, class: &str, name: &str,
+                              descriptor: &str) -> &mut Self {
+        let handle_index = self.classfile.define_method_handle(reference_kind, class, name, descriptor);
+        self.push_load_constant(handle_index);
+        let handle_class = self.classfile.define_class("java/lang/invoke/MethodHandle");
+        self.increase_stack_depth();
+        self.stack_types.push(VerificationType::Object(handle_class));
+        self
+    }
+
+    // pushes a java/lang/invoke/MethodType for a descriptor like "(I)V"
+    pub fn load_method_type(&mut self, descriptor: &str) -> &mut Self {
+        let type_index = self.classfile.define_method_type(descriptor);
+        self.push_load_constant(type_index);
+        let type_class = self.classfile.define_class("java/lang/invoke/MethodType");
         self.increase_stack_depth();
-        // TODO: push to stack_types
+        self.stack_types.push(VerificationType::Object(type_class));
+        self
     }
 
-    pub fn load_constant_integer(&mut self, value: i32) {
+    pub fn load_constant_integer(&mut self, value: i32) -> &mut Self {
         let i32_index = self.classfile.define_integer(value);
-        if i32_index > ::std::u8::MAX as u16 {
-            panic!("Placed a constant in too high of an index: {}", i32_index)
-        }
-        self.push_instruction(Instruction::LoadConstant(i32_index as u8));
+        self.push_load_constant(i32_index);
         self.increase_stack_depth();
         self.stack_types.push(VerificationType::Integer);
+        self
     }
 
-    pub fn load_constant_float(&mut self, value: f32) {
+    pub fn load_constant_float(&mut self, value: f32) -> &mut Self {
         let f32_index = self.classfile.define_float(value);
-        if f32_index > ::std::u8::MAX as u16 {
-            panic!("Placed a constant in too high of an index: {}", f32_index)
-        }
-        self.push_instruction(Instruction::LoadConstant(f32_index as u8));
+        self.push_load_constant(f32_index);
         self.increase_stack_depth();
-        //self.stack_types.push(VerificationType::Integer);
+        self.stack_types.push(VerificationType::Float);
+        self
+    }
+
+    pub fn load_constant_long(&mut self, value: i64) -> &mut Self {
+        let i64_index = self.classfile.define_long(value);
+        self.push_instruction(Instruction::Ldc2W(i64_index));
+        self.increase_stack_depth_by(2);
+        self.stack_types.push(VerificationType::Long);
+        self.stack_types.push(VerificationType::Top);
+        self
+    }
+
+    pub fn load_constant_double(&mut self, value: f64) -> &mut Self {
+        let f64_index = self.classfile.define_double(value);
+        self.push_instruction(Instruction::Ldc2W(f64_index));
+        self.increase_stack_depth_by(2);
+        self.stack_types.push(VerificationType::Double);
+        self.stack_types.push(VerificationType::Top);
+        self
+    }
+
+    // The push_* methods pick the shortest instruction that loads the value,
+    // falling back to the constant pool.
+    pub fn push_int(&mut self, value: i32) -> &mut Self {
+        match value {
+            -1 => self.iconstm1(),
+            0 => self.iconst0(),
+            1 => self.iconst1(),
+            2 => self.iconst2(),
+            3 => self.iconst3(),
+            4 => self.iconst4(),
+            5 => self.iconst5(),
+            -128..=127 => self.bipush(value as i8),
+            -32768..=32767 => self.sipush(value as i16),
+            _ => self.load_constant_integer(value),
+        }
+    }
+
+    pub fn push_long(&mut self, value: i64) -> &mut Self {
+        match value {
+            0 => self.lconst0(),
+            1 => self.lconst1(),
+            _ => self.load_constant_long(value),
+        }
+    }
+
+    // compared by bits, since -0.0 has no const instruction
+    pub fn push_float(&mut self, value: f32) -> &mut Self {
+        let bits = value.to_bits();
+        if bits == 0.0f32.to_bits() {
+            self.fconst0()
+        } else if bits == 1.0f32.to_bits() {
+            self.fconst1()
+        } else if bits == 2.0f32.to_bits() {
+            self.fconst2()
+        } else {
+            self.load_constant_float(value)
+        }
+    }
+
+    pub fn push_double(&mut self, value: f64) -> &mut Self {
+        let bits = value.to_bits();
+        if bits == 0.0f64.to_bits() {
+            self.dconst0()
+        } else if bits == 1.0f64.to_bits() {
+            self.dconst1()
+        } else {
+            self.load_constant_double(value)
+        }
     }
 
-    pub fn aconst_null(&mut self) {
+    pub fn aconst_null(&mut self) -> &mut Self {
         self.push_instruction(Instruction::AConstNull);
         self.increase_stack_depth();
+        self.stack_types.push(VerificationType::Null);
+        self
     }
 
-    pub fn astore0(&mut self) {
+    pub fn astore0(&mut self) -> &mut Self {
         self.push_instruction(Instruction::Astore0);
-        self.increase_stack_depth();
-        // TODO: push to stack_types
+        let reference = self.top_reference();
+        self.set_local(0, reference);
+        self.decrease_stack_depth();
+        self
     }
 
-    pub fn astore1(&mut self) {
+    pub fn astore1(&mut self) -> &mut Self {
         self.push_instruction(Instruction::Astore1);
-        self.increase_stack_depth();
-        // TODO: push to stack_types
+        let reference = self.top_reference();
+        self.set_local(1, reference);
+        self.decrease_stack_depth();
+        self
     }
 
-    pub fn astore2(&mut self) {
+    pub fn astore2(&mut self) -> &mut Self {
         self.push_instruction(Instruction::Astore2);
-        self.increase_stack_depth();
-        // TODO: push to stack_types
+        let reference = self.top_reference();
+        self.set_local(2, reference);
+        self.decrease_stack_depth();
+        self
     }
 
-    pub fn astore3(&mut self) {
+    pub fn astore3(&mut self) -> &mut Self {
         self.push_instruction(Instruction::Astore3);
-        self.increase_stack_depth();
-        // TODO: push to stack_types
+        let reference = self.top_reference();
+        self.set_local(3, reference);
+        self.decrease_stack_depth();
+        self
     }
 
-    pub fn astore(&mut self, reg: u8) {
-        self.push_instruction(Instruction::Astore(reg));
-        self.increase_stack_depth();
+    pub fn astore(&mut self, reg: u16) -> &mut Self {
+        self.push_local_instruction(reg, Instruction::Astore, Instruction::WideAstore);
+        let reference = self.top_reference();
+        self.set_local(reg, reference);
+        self.decrease_stack_depth();
+        self
     }
     
-    pub fn aload0(&mut self) {
+    pub fn aload0(&mut self) -> &mut Self {
         self.push_instruction(Instruction::Aload0);
+        let reference = self.local_reference(0);
         self.increase_stack_depth();
-        // TODO: push to stack_types
+        self.stack_types.push(reference);
+        self
     }
 
-    pub fn aload1(&mut self) {
+    pub fn aload1(&mut self) -> &mut Self {
         self.push_instruction(Instruction::Aload1);
+        let reference = self.local_reference(1);
         self.increase_stack_depth();
-        // TODO: push to stack_types
+        self.stack_types.push(reference);
+        self
     }
 
-    pub fn aload2(&mut self) {
+    pub fn aload2(&mut self) -> &mut Self {
         self.push_instruction(Instruction::Aload2);
+        let reference = self.local_reference(2);
         self.increase_stack_depth();
-        // TODO: push to stack_types
+        self.stack_types.push(reference);
+        self
     }
 
-    pub fn aload3(&mut self) {
+    pub fn aload3(&mut self) -> &mut Self {
         self.push_instruction(Instruction::Aload3);
+        let reference = self.local_reference(3);
         self.increase_stack_depth();
-        // TODO: push to stack_types
+        self.stack_types.push(reference);
+        self
     }
 
-    pub fn aload(&mut self, reg: u8) {
-        self.push_instruction(Instruction::Aload(reg));
+    pub fn aload(&mut self, reg: u16) -> &mut Self {
+        self.push_local_instruction(reg, Instruction::Aload, Instruction::WideAload);
+        let reference = self.local_reference(reg);
         self.increase_stack_depth();
+        self.stack_types.push(reference);
+        self
     }
     
-    pub fn aaload(&mut self) {
+    pub fn aaload(&mut self) -> &mut Self {
         self.push_instruction(Instruction::Aaload);
         self.decrease_stack_depth();
-        // TODO: push to stack_types
+        let array = self.top_reference();
+        let element = self.array_element(array);
+        self.decrease_stack_depth();
+        self.increase_stack_depth();
+        self.stack_types.push(element);
+        self
     }
 
-    pub fn iadd(&mut self) {
-        self.push_instruction(Instruction::Iadd);
-        self.decrease_stack_depth();
+    pub fn iaload(&mut self) -> &mut Self {
+        self.push_array_load(Instruction::Iaload, VerificationType::Integer)
     }
 
-    pub fn isub(&mut self) {
-        self.push_instruction(Instruction::Isub);
-        self.decrease_stack_depth();
+    pub fn laload(&mut self) -> &mut Self {
+        self.push_array_load(Instruction::Laload, VerificationType::Long)
     }
 
-    pub fn imul(&mut self) {
-        self.push_instruction(Instruction::Imul);
-        self.decrease_stack_depth();
+    pub fn faload(&mut self) -> &mut Self {
+        self.push_array_load(Instruction::Faload, VerificationType::Float)
     }
 
-    pub fn idiv(&mut self) {
-        self.push_instruction(Instruction::Idiv);
-        self.decrease_stack_depth();
+    pub fn daload(&mut self) -> &mut Self {
+        self.push_array_load(Instruction::Daload, VerificationType::Double)
     }
-    
-    pub fn ifeq(&mut self, label: &'a str) {
-        self.delay_instruction(label, Instruction::IfEq(0));
-        self.decrease_stack_depth();
+
+    // also used for boolean arrays
+    pub fn baload(&mut self) -> &mut Self {
+        self.push_array_load(Instruction::Baload, VerificationType::Integer)
     }
 
-    pub fn ifne(&mut self, label: &'a str) {
-        self.delay_instruction(label, Instruction::IfNe(0));
-        self.decrease_stack_depth();
+    pub fn caload(&mut self) -> &mut Self {
+        self.push_array_load(Instruction::Caload, VerificationType::Integer)
     }
 
-    pub fn iflt(&mut self, label: &'a str) {
-        self.delay_instruction(label, Instruction::IfLt(0));
-        self.decrease_stack_depth();
+    pub fn saload(&mut self) -> &mut Self {
+        self.push_array_load(Instruction::Saload, VerificationType::Integer)
     }
 
-    pub fn ifge(&mut self, label: &'a str) {
-        self.delay_instruction(label, Instruction::IfGe(0));
-        self.decrease_stack_depth();
+    pub fn iastore(&mut self) -> &mut Self {
+        self.push_array_store(Instruction::Iastore, 1)
     }
 
-    pub fn ifgt(&mut self, label: &'a str) {
-        self.delay_instruction(label, Instruction::IfGt(0));
-        self.decrease_stack_depth();
+    pub fn lastore(&mut self) -> &mut Self {
+        self.push_array_store(Instruction::Lastore, 2)
     }
 
-    pub fn ifle(&mut self, label: &'a str) {
-        self.delay_instruction(label, Instruction::IfLe(0));
-        self.decrease_stack_depth();
+    pub fn fastore(&mut self) -> &mut Self {
+        self.push_array_store(Instruction::Fastore, 1)
     }
 
-    pub fn if_icmp_eq(&mut self, label: &'a str) {
-        self.delay_instruction(label, Instruction::IfIcmpEq(0));
-        self.decrease_stack_depth_by(2);
-        // TODO: push to stack_types?
+    pub fn dastore(&mut self) -> &mut Self {
+        self.push_array_store(Instruction::Dastore, 2)
     }
 
-    pub fn if_icmp_ne(&mut self, label: &'a str) {
-        self.delay_instruction(label, Instruction::IfIcmpNe(0));
-        self.decrease_stack_depth_by(2);
-        // TODO: push to stack_types?
+    pub fn aastore(&mut self) -> &mut Self {
+        self.push_array_store(Instruction::Aastore, 1)
     }
 
-    pub fn if_icmp_lt(&mut self, label: &'a str) {
-        self.delay_instruction(label, Instruction::IfIcmpLt(0));
-        self.decrease_stack_depth_by(2);
-        // TODO: push to stack_types?
+    // also used for boolean arrays
+    pub fn bastore(&mut self) -> &mut Self {
+        self.push_array_store(Instruction::Bastore, 1)
     }
 
-    pub fn if_icmp_ge(&mut self, label: &'a str) {
-        self.delay_instruction(label, Instruction::IfIcmpGe(0));
-        self.decrease_stack_depth_by(2);
-        // TODO: push to stack_types?
+    pub fn castore(&mut self) -> &mut Self {
+        self.push_array_store(Instruction::Castore, 1)
     }
 
-    pub fn if_icmp_gt(&mut self, label: &'a str) {
-        self.delay_instruction(label, Instruction::IfIcmpGt(0));
-        self.decrease_stack_depth_by(2);
-        // TODO: push to stack_types?
+    pub fn sastore(&mut self) -> &mut Self {
+        self.push_array_store(Instruction::Sastore, 1)
     }
 
-    pub fn if_icmp_le(&mut self, label: &'a str) {
-        self.delay_instruction(label, Instruction::IfIcmpLe(0));
-        self.decrease_stack_depth_by(2);
-        // TODO: push to stack_types?
+    // Loads an element of an array of `element_type`, picking the instruction
+    // that matches it: baload for booleans and bytes, caload for chars and so on.
+    pub fn array_load(&mut self, element_type: &Java) -> &mut Self {
+        match *element_type {
+            Java::Boolean | Java::Byte => self.baload(),
+            Java::Char => self.caload(),
+            Java::Short => self.saload(),
+            Java::Int => self.iaload(),
+            Java::Long => self.laload(),
+            Java::Float => self.faload(),
+            Java::Double => self.daload(),
+            Java::Class(_) | Java::Array(_) => self.aaload(),
+            Java::Void => {
+                self.record_misuse(AssemblerError::VoidNotAllowed("an array element".to_owned()));
+                self
+            },
+        }
     }
 
-    pub fn goto(&mut self, label: &'a str) {
-        self.delay_instruction(label, Instruction::Goto(0));
+    pub fn array_store(&mut self, element_type: &Java) -> &mut Self {
+        match *element_type {
+            Java::Boolean | Java::Byte => self.bastore(),
+            Java::Char => self.castore(),
+            Java::Short => self.sastore(),
+            Java::Int => self.iastore(),
+            Java::Long => self.lastore(),
+            Java::Float => self.fastore(),
+            Java::Double => self.dastore(),
+            Java::Class(_) | Java::Array(_) => self.aastore(),
+            Java::Void => {
+                self.record_misuse(AssemblerError::VoidNotAllowed("an array element".to_owned()));
+                self
+            },
+        }
     }
-    
-    pub fn ireturn(&mut self) {
-        self.push_instruction(Instruction::IReturn);
+
+    pub fn iadd(&mut self) -> &mut Self {
+        self.push_instruction(Instruction::Iadd);
         self.decrease_stack_depth();
+        self
     }
 
-    pub fn freturn(&mut self) {
-        self.push_instruction(Instruction::FReturn);
+    pub fn isub(&mut self) -> &mut Self {
+        self.push_instruction(Instruction::Isub);
         self.decrease_stack_depth();
+        self
+    }
+
+    pub fn imul(&mut self) -> &mut Self {
+        self.push_instruction(Instruction::Imul);
+        self.decrease_stack_depth();
+        self
+    }
+
+    pub fn idiv(&mut self) -> &mut Self {
+        self.push_instruction(Instruction::Idiv);
+        self.decrease_stack_depth();
+        self
+    }
+    
+    pub fn ineg(&mut self) -> &mut Self {
+        self.push_instruction(Instruction::Ineg);
+        self
+    }
+
+    pub fn iand(&mut self) -> &mut Self {
+        self.push_instruction(Instruction::Iand);
+        self.decrease_stack_depth();
+        self
+    }
+
+    pub fn ior(&mut self) -> &mut Self {
+        self.push_instruction(Instruction::Ior);
+        self.decrease_stack_depth();
+        self
+    }
+
+    pub fn ixor(&mut self) -> &mut Self {
+        self.push_instruction(Instruction::Ixor);
+        self.decrease_stack_depth();
+        self
+    }
+
+    pub fn ishl(&mut self) -> &mut Self {
+        self.push_instruction(Instruction::Ishl);
+        self.decrease_stack_depth();
+        self
+    }
+
+    pub fn ishr(&mut self) -> &mut Self {
+        self.push_instruction(Instruction::Ishr);
+        self.decrease_stack_depth();
+        self
+    }
+
+    pub fn iushr(&mut self) -> &mut Self {
+        self.push_instruction(Instruction::Iushr);
+        self.decrease_stack_depth();
+        self
+    }
+
+    pub fn lneg(&mut self) -> &mut Self {
+        self.push_instruction(Instruction::Lneg);
+        self
+    }
+
+    pub fn land(&mut self) -> &mut Self {
+        self.push_instruction(Instruction::Land);
+        self.decrease_stack_depth_by(2);
+        self
+    }
+
+    pub fn lor(&mut self) -> &mut Self {
+        self.push_instruction(Instruction::Lor);
+        self.decrease_stack_depth_by(2);
+        self
+    }
+
+    pub fn lxor(&mut self) -> &mut Self {
+        self.push_instruction(Instruction::Lxor);
+        self.decrease_stack_depth_by(2);
+        self
+    }
+
+    pub fn lshl(&mut self) -> &mut Self {
+        self.push_instruction(Instruction::Lshl);
+        self.decrease_stack_depth();
+        self
+    }
+
+    pub fn lshr(&mut self) -> &mut Self {
+        self.push_instruction(Instruction::Lshr);
+        self.decrease_stack_depth();
+        self
+    }
+
+    pub fn lushr(&mut self) -> &mut Self {
+        self.push_instruction(Instruction::Lushr);
+        self.decrease_stack_depth();
+        self
+    }
+
+    pub fn lcmp(&mut self) -> &mut Self {
+        self.push_instruction(Instruction::Lcmp);
+        self.decrease_stack_depth_by(4);
+        self.increase_stack_depth();
+        self.stack_types.push(VerificationType::Integer);
+        self
+    }
+
+    pub fn fcmpl(&mut self) -> &mut Self {
+        self.push_instruction(Instruction::Fcmpl);
+        self.decrease_stack_depth_by(2);
+        self.increase_stack_depth();
+        self.stack_types.push(VerificationType::Integer);
+        self
+    }
+
+    pub fn fcmpg(&mut self) -> &mut Self {
+        self.push_instruction(Instruction::Fcmpg);
+        self.decrease_stack_depth_by(2);
+        self.increase_stack_depth();
+        self.stack_types.push(VerificationType::Integer);
+        self
+    }
+
+    pub fn dcmpl(&mut self) -> &mut Self {
+        self.push_instruction(Instruction::Dcmpl);
+        self.decrease_stack_depth_by(4);
+        self.increase_stack_depth();
+        self.stack_types.push(VerificationType::Integer);
+        self
+    }
+
+    pub fn dcmpg(&mut self) -> &mut Self {
+        self.push_instruction(Instruction::Dcmpg);
+        self.decrease_stack_depth_by(4);
+        self.increase_stack_depth();
+        self.stack_types.push(VerificationType::Integer);
+        self
+    }
+
+    pub fn ifeq<L: Into<LabelName<'a>>>(&mut self, label: L) -> &mut Self {
+        self.decrease_stack_depth();
+        self.delay_instruction(label.into(), Instruction::IfEq(0));
+        self
+    }
+
+    pub fn ifne<L: Into<LabelName<'a>>>(&mut self, label: L) -> &mut Self {
+        self.decrease_stack_depth();
+        self.delay_instruction(label.into(), Instruction::IfNe(0));
+        self
+    }
+
+    pub fn iflt<L: Into<LabelName<'a>>>(&mut self, label: L) -> &mut Self {
+        self.decrease_stack_depth();
+        self.delay_instruction(label.into(), Instruction::IfLt(0));
+        self
+    }
+
+    pub fn ifge<L: Into<LabelName<'a>>>(&mut self, label: L) -> &mut Self {
+        self.decrease_stack_depth();
+        self.delay_instruction(label.into(), Instruction::IfGe(0));
+        self
+    }
+
+    pub fn ifgt<L: Into<LabelName<'a>>>(&mut self, label: L) -> &mut Self {
+        self.decrease_stack_depth();
+        self.delay_instruction(label.into(), Instruction::IfGt(0));
+        self
+    }
+
+    pub fn ifle<L: Into<LabelName<'a>>>(&mut self, label: L) -> &mut Self {
+        self.decrease_stack_depth();
+        self.delay_instruction(label.into(), Instruction::IfLe(0));
+        self
+    }
+
+    pub fn if_icmp_eq<L: Into<LabelName<'a>>>(&mut self, label: L) -> &mut Self {
+        self.decrease_stack_depth_by(2);
+        self.delay_instruction(label.into(), Instruction::IfIcmpEq(0));
+        self
+    }
+
+    pub fn if_icmp_ne<L: Into<LabelName<'a>>>(&mut self, label: L) -> &mut Self {
+        self.decrease_stack_depth_by(2);
+        self.delay_instruction(label.into(), Instruction::IfIcmpNe(0));
+        self
+    }
+
+    pub fn if_icmp_lt<L: Into<LabelName<'a>>>(&mut self, label: L) -> &mut Self {
+        self.decrease_stack_depth_by(2);
+        self.delay_instruction(label.into(), Instruction::IfIcmpLt(0));
+        self
+    }
+
+    pub fn if_icmp_ge<L: Into<LabelName<'a>>>(&mut self, label: L) -> &mut Self {
+        self.decrease_stack_depth_by(2);
+        self.delay_instruction(label.into(), Instruction::IfIcmpGe(0));
+        self
+    }
+
+    pub fn if_icmp_gt<L: Into<LabelName<'a>>>(&mut self, label: L) -> &mut Self {
+        self.decrease_stack_depth_by(2);
+        self.delay_instruction(label.into(), Instruction::IfIcmpGt(0));
+        self
+    }
+
+    pub fn if_icmp_le<L: Into<LabelName<'a>>>(&mut self, label: L) -> &mut Self {
+        self.decrease_stack_depth_by(2);
+        self.delay_instruction(label.into(), Instruction::IfIcmpLe(0));
+        self
+    }
+
+    pub fn if_acmp_eq<L: Into<LabelName<'a>>>(&mut self, label: L) -> &mut Self {
+        self.decrease_stack_depth_by(2);
+        self.delay_instruction(label.into(), Instruction::IfAcmpEq(0));
+        self
+    }
+
+    pub fn if_acmp_ne<L: Into<LabelName<'a>>>(&mut self, label: L) -> &mut Self {
+        self.decrease_stack_depth_by(2);
+        self.delay_instruction(label.into(), Instruction::IfAcmpNe(0));
+        self
+    }
+
+    pub fn ifnull<L: Into<LabelName<'a>>>(&mut self, label: L) -> &mut Self {
+        self.decrease_stack_depth();
+        self.delay_instruction(label.into(), Instruction::IfNull(0));
+        self
+    }
+
+    pub fn ifnonnull<L: Into<LabelName<'a>>>(&mut self, label: L) -> &mut Self {
+        self.decrease_stack_depth();
+        self.delay_instruction(label.into(), Instruction::IfNonNull(0));
+        self
+    }
+
+    // Jumps to `labels[key - low]`, or to `default` if the key is out of range.
+    // done() fails if there are no labels, or if the last key is past i32::MAX.
+    pub fn tableswitch<D, L>(&mut self, default: D, low: i32, labels: &[L]) -> &mut Self
+        where D: Into<LabelName<'a>>, L: Into<LabelName<'a>> + Copy {
+        if labels.is_empty() {
+            self.record_misuse(AssemblerError::EmptyTableSwitch(low));
+            return self;
+        }
+        let high = low as i64 + labels.len() as i64 - 1;
+        if high > i32::MAX as i64 {
+            self.record_misuse(AssemblerError::TableSwitchOutOfRange(low, labels.len()));
+            return self;
+        }
+        let high = high as i32;
+        let offsets = vec![0; labels.len()];
+        let labels = labels.iter().map(|&label| label.into()).collect();
+        self.decrease_stack_depth();
+        self.delay_switch(default.into(), labels, Instruction::TableSwitch(0, low, high, offsets));
+        self
+    }
+
+    // Cases may be given in any order; they get sorted by key as the JVM requires.
+    // done() fails if two cases have the same key.
+    pub fn lookupswitch<D, L>(&mut self, default: D, cases: &[(i32, L)]) -> &mut Self
+        where D: Into<LabelName<'a>>, L: Into<LabelName<'a>> + Copy {
+        let mut cases = cases.to_vec();
+        cases.sort_by_key(|&(key, _)| key);
+        if let Some(pair) = cases.windows(2).find(|pair| pair[0].0 == pair[1].0) {
+            self.record_misuse(AssemblerError::DuplicateSwitchKey(pair[0].0));
+            return self;
+        }
+        let pairs = cases.iter().map(|&(key, _)| (key, 0)).collect();
+        let labels = cases.iter().map(|&(_, label)| label.into()).collect();
+        self.decrease_stack_depth();
+        self.delay_switch(default.into(), labels, Instruction::LookupSwitch(0, pairs));
+        self
+    }
+
+    pub fn goto<L: Into<LabelName<'a>>>(&mut self, label: L) -> &mut Self {
+        self.delay_instruction(label.into(), Instruction::Goto(0));
+        self
+    }
+
+    // Calls the subroutine at `label`, which starts with the return address on
+    // the stack. Subroutines were dropped from the verifier in class version 51,
+    // so set an older version to use them; there's no stack map type for a
+    // return address either, so it's only tracked as far as the stack depth.
+    pub fn jsr<L: Into<LabelName<'a>>>(&mut self, label: L) -> &mut Self {
+        self.increase_stack_depth();
+        self.stack_types.push(VerificationType::Top);
+        self.delay_instruction(label.into(), Instruction::Jsr(0));
+        // by the time execution gets back here the subroutine has used it up
+        self.decrease_stack_depth();
+        self
+    }
+
+    // returns from a subroutine to the address stored in `local`
+    pub fn ret(&mut self, local: u8) -> &mut Self {
+        self.push_instruction(Instruction::Ret(local));
+        self
+    }
+
+    // Branches to `label` if `condition` holds for the operands on the stack.
+    pub fn branch_if<L: Into<LabelName<'a>>>(&mut self, condition: Condition, label: L) -> &mut Self {
+        match condition {
+            Condition::Eq => self.ifeq(label),
+            Condition::Ne => self.ifne(label),
+            Condition::Lt => self.iflt(label),
+            Condition::Ge => self.ifge(label),
+            Condition::Gt => self.ifgt(label),
+            Condition::Le => self.ifle(label),
+            Condition::IcmpEq => self.if_icmp_eq(label),
+            Condition::IcmpNe => self.if_icmp_ne(label),
+            Condition::IcmpLt => self.if_icmp_lt(label),
+            Condition::IcmpGe => self.if_icmp_ge(label),
+            Condition::IcmpGt => self.if_icmp_gt(label),
+            Condition::IcmpLe => self.if_icmp_le(label),
+            Condition::AcmpEq => self.if_acmp_eq(label),
+            Condition::AcmpNe => self.if_acmp_ne(label),
+            Condition::Null => self.ifnull(label),
+            Condition::NonNull => self.ifnonnull(label),
+        }
+    }
+
+    // Structured if without an else, for operands already on the stack: `body`
+    // only runs if `condition` holds.
+    pub fn if_then<B>(&mut self, condition: Condition, body: B) -> &mut Self
+        where B: FnOnce(&mut MethodBuilder<'a>) {
+        let end = self.new_label();
+        self.branch_if(condition.negate(), end);
+        body(self);
+        self.label(end);
+        self
+    }
+
+    // Structured if/else: `condition` pushes the operands and says how to test
+    // them, then one of the two branches runs. Either branch may return or
+    // throw instead of falling through to the code after the if.
+    pub fn if_then_else<C, T, E>(&mut self, condition: C, then_branch: T, else_branch: E) -> &mut Self
+        where C: FnOnce(&mut MethodBuilder<'a>) -> Condition,
+              T: FnOnce(&mut MethodBuilder<'a>),
+              E: FnOnce(&mut MethodBuilder<'a>) {
+        let otherwise = self.new_label();
+        let end = self.new_label();
+        let test = condition(self);
+        self.branch_if(test.negate(), otherwise);
+        then_branch(self);
+        let then_falls_through = self.falls_through();
+        if then_falls_through {
+            self.goto(end);
+        }
+        self.label(otherwise);
+        else_branch(self);
+        if then_falls_through || self.falls_through() {
+            self.label(end);
+        }
+        self
+    }
+
+    // Structured loop: runs `body` for as long as `condition` holds, testing it
+    // before each iteration.
+    pub fn while_loop<C, B>(&mut self, condition: C, body: B) -> &mut Self
+        where C: FnOnce(&mut MethodBuilder<'a>) -> Condition,
+              B: FnOnce(&mut MethodBuilder<'a>) {
+        let top = self.new_label();
+        let end = self.new_label();
+        self.label(top);
+        let test = condition(self);
+        self.branch_if(test.negate(), end);
+        body(self);
+        if self.falls_through() {
+            self.goto(top);
+        }
+        self.label(end);
+        self
+    }
+
+    // Splices in instructions that are already encoded, e.g. copied from another
+    // class file. `size` must match the number of bytes, and `stack_delta` is
+    // their net effect on the stack, which is also all max_stack accounts for.
+    // Anything they push is typed as Top, so branching with it still on the
+    // stack won't pass the verifier. Strict methods can't use raw bytes at all,
+    // since they can't be checked. A wrong size, or a delta outside -255..=255,
+    // is reported by done() and nothing is emitted.
+    pub fn emit_raw(&mut self, bytes: &[u8], size: u16, stack_delta: i32) -> &mut Self {
+        if bytes.len() != size as usize {
+            self.record_misuse(AssemblerError::RawSizeMismatch(size, bytes.len()));
+            return self;
+        }
+        if !(-255..=255).contains(&stack_delta) {
+            self.record_misuse(AssemblerError::RawStackDeltaOutOfRange(stack_delta));
+            return self;
+        }
+        self.push_instruction(Instruction::Raw(bytes.to_vec()));
+        if stack_delta < 0 {
+            self.decrease_stack_depth_by(-stack_delta as u8);
+        } else {
+            self.increase_stack_depth_by(stack_delta as u8);
+            for _ in 0..stack_delta {
+                self.stack_types.push(VerificationType::Top);
+            }
+        }
+        self
+    }
+
+    // The offset the next instruction will be written at. Like labels, it may
+    // still move if done() has to widen a goto before it.
+    pub fn current_pc(&self) -> u16 {
+        self.stack_index
+    }
+
+    // Branches to an absolute PC, as returned by current_pc(), instead of a
+    // label, e.g. when translating existing bytecode. The PC gets an anonymous
+    // label of its own, so it moves along with the code around it; the stack map
+    // frame still comes from a label placed there, if the class version needs
+    // one. done() fails if the PC isn't the start of an instruction or the end
+    // of the code, or if `opcode` isn't a branch.
+    pub fn branch_to_pc(&mut self, opcode: u8, target_pc: u16) -> &mut Self {
+        match opcode {
+            0x99..=0xa7 | 0xc6..=0xc8 => {},
+            _ => {
+                self.record_misuse(AssemblerError::NotABranch(opcode));
+                return self;
+            },
+        }
+        let label = self.new_label();
+        self.labels.insert((label.into(), self.env_num), target_pc);
+        match opcode {
+            0x99 => self.ifeq(label),
+            0x9a => self.ifne(label),
+            0x9b => self.iflt(label),
+            0x9c => self.ifge(label),
+            0x9d => self.ifgt(label),
+            0x9e => self.ifle(label),
+            0x9f => self.if_icmp_eq(label),
+            0xa0 => self.if_icmp_ne(label),
+            0xa1 => self.if_icmp_lt(label),
+            0xa2 => self.if_icmp_ge(label),
+            0xa3 => self.if_icmp_gt(label),
+            0xa4 => self.if_icmp_le(label),
+            0xa5 => self.if_acmp_eq(label),
+            0xa6 => self.if_acmp_ne(label),
+            0xa7 | 0xc8 => self.goto(label),
+            0xc6 => self.ifnull(label),
+            0xc7 => self.ifnonnull(label),
+            _ => unreachable!(),
+        }
     }
     
-    pub fn do_return(&mut self) {
+    pub fn ireturn(&mut self) -> &mut Self {
+        self.push_instruction(Instruction::IReturn);
+        self.decrease_stack_depth();
+        self
+    }
+
+    pub fn freturn(&mut self) -> &mut Self {
+        self.push_instruction(Instruction::FReturn);
+        self.decrease_stack_depth();
+        self
+    }
+
+    pub fn lreturn(&mut self) -> &mut Self {
+        self.push_instruction(Instruction::Lreturn);
+        self.decrease_stack_depth_by(2);
+        self
+    }
+
+    pub fn dreturn(&mut self) -> &mut Self {
+        self.push_instruction(Instruction::Dreturn);
+        self.decrease_stack_depth_by(2);
+        self
+    }
+    
+    pub fn do_return(&mut self) -> &mut Self {
         self.push_instruction(Instruction::Return);
+        self
     }
 
-    pub fn areturn(&mut self) {
+    pub fn areturn(&mut self) -> &mut Self {
         self.push_instruction(Instruction::Areturn);
+        self.decrease_stack_depth();
+        self
     }
     
-    pub fn get_static(&mut self, class: &str, name: &str, argument_type: &Java) {
+    pub fn get_static(&mut self, class: &str, name: &str, argument_type: &Java) -> &mut Self {
         let fieldref_index = self.classfile.define_fieldref(class, name, argument_type);
         self.push_instruction(Instruction::GetStatic(fieldref_index));
-        self.increase_stack_depth();
-        // TODO: push to stack_types
+        self.push_java_type(argument_type);
+        self
+    }
+
+    pub fn put_static(&mut self, class: &str, name: &str, field_type: &Java) -> &mut Self {
+        let fieldref_index = self.classfile.define_fieldref(class, name, field_type);
+        self.push_instruction(Instruction::PutStatic(fieldref_index));
+        self.decrease_stack_depth_by(field_type.slot_size());
+        self
+    }
+
+    // Like get_static and put_static, but for a fieldref that's already in the
+    // constant pool, e.g. one found with ClassBuilder::find_constant. The field's
+    // type is read from the fieldref's descriptor.
+    pub fn get_static_index(&mut self, fieldref_index: u16) -> &mut Self {
+        let descriptor = match self.fieldref_descriptor(fieldref_index) {
+            Some(descriptor) => descriptor,
+            None => return self,
+        };
+        self.push_instruction(Instruction::GetStatic(fieldref_index));
+        self.push_java_type(&parse_field_type(&descriptor));
+        self
+    }
+
+    pub fn put_static_index(&mut self, fieldref_index: u16) -> &mut Self {
+        let descriptor = match self.fieldref_descriptor(fieldref_index) {
+            Some(descriptor) => descriptor,
+            None => return self,
+        };
+        self.push_instruction(Instruction::PutStatic(fieldref_index));
+        self.decrease_stack_depth_by(parse_field_type(&descriptor).slot_size());
+        self
+    }
+
+    pub fn get_field(&mut self, class: &str, name: &str, field_type: &Java) -> &mut Self {
+        let fieldref_index = self.classfile.define_fieldref(class, name, field_type);
+        self.push_instruction(Instruction::GetField(fieldref_index));
+        self.decrease_stack_depth();
+        self.push_java_type(field_type);
+        self
+    }
+
+    pub fn put_field(&mut self, class: &str, name: &str, field_type: &Java) -> &mut Self {
+        let fieldref_index = self.classfile.define_fieldref(class, name, field_type);
+        self.push_instruction(Instruction::PutField(fieldref_index));
+        self.decrease_stack_depth_by(1 + field_type.slot_size());
+        self
+    }
+
+    // the instance field versions of get_static_index and put_static_index
+    pub fn get_field_index(&mut self, fieldref_index: u16) -> &mut Self {
+        let descriptor = match self.fieldref_descriptor(fieldref_index) {
+            Some(descriptor) => descriptor,
+            None => return self,
+        };
+        self.push_instruction(Instruction::GetField(fieldref_index));
+        self.decrease_stack_depth();
+        self.push_java_type(&parse_field_type(&descriptor));
+        self
+    }
+
+    pub fn put_field_index(&mut self, fieldref_index: u16) -> &mut Self {
+        let descriptor = match self.fieldref_descriptor(fieldref_index) {
+            Some(descriptor) => descriptor,
+            None => return self,
+        };
+        self.push_instruction(Instruction::PutField(fieldref_index));
+        self.decrease_stack_depth_by(1 + parse_field_type(&descriptor).slot_size());
+        self
     }
 
     pub fn invoke_virtual(&mut self, class: &str, name: &str,
-                          argument_types: &[Java], return_type: &Java) {
+                          argument_types: &[Java], return_type: &Java) -> &mut Self {
+        self.invoke_virtual_typed(class, name, argument_types, return_type);
+        self
+    }
+
+    pub fn invoke_special(&mut self, class: &str, name: &str,
+                          argument_types: &[Java], return_type: &Java) -> &mut Self {
+        self.invoke_special_typed(class, name, argument_types, return_type);
+        self
+    }
+
+    pub fn invoke_static(&mut self, class: &str, name: &str,
+                         argument_types: &[Java], return_type: &Java) -> &mut Self {
+        self.invoke_static_typed(class, name, argument_types, return_type);
+        self
+    }
+
+    // The *_typed variants return the type the call leaves on the stack, or None
+    // for a void method, for generators that keep track of frames themselves.
+    pub fn invoke_virtual_typed(&mut self, class: &str, name: &str, argument_types: &[Java],
+                                return_type: &Java) -> Option<VerificationType> {
         let methodref_index =
             self.classfile.define_methodref(class, name, argument_types, return_type);
-        self.push_instruction(Instruction::InvokeVirtual(methodref_index));
-        self.decrease_stack_depth_by(argument_types.len() as u8 + 1);
-        if *return_type != Java::Void { self.increase_stack_depth(); }
-        // TODO: push to stack_types
+        self.invoke(Instruction::InvokeVirtual(methodref_index), class, name,
+                    argument_types, return_type)
     }
 
-    pub fn invoke_special(&mut self, class: &str, name: &str,
-                          argument_types: &[Java], return_type: &Java) {
+    pub fn invoke_special_typed(&mut self, class: &str, name: &str, argument_types: &[Java],
+                                return_type: &Java) -> Option<VerificationType> {
         let methodref_index =
             self.classfile.define_methodref(class, name, argument_types, return_type);
-        self.push_instruction(Instruction::InvokeSpecial(methodref_index));
-        self.decrease_stack_depth_by(argument_types.len() as u8 + 1);
-        if *return_type != Java::Void { self.increase_stack_depth(); }
-        // TODO: push to stack_types
+        self.invoke(Instruction::InvokeSpecial(methodref_index), class, name,
+                    argument_types, return_type)
     }
 
-    pub fn invoke_static(&mut self, class: &str, name: &str,
-                         argument_types: &[Java], return_type: &Java) {
+    pub fn invoke_static_typed(&mut self, class: &str, name: &str, argument_types: &[Java],
+                               return_type: &Java) -> Option<VerificationType> {
         let methodref_index =
             self.classfile.define_methodref(class, name, argument_types, return_type);
-        self.push_instruction(Instruction::InvokeStatic(methodref_index));
-        self.decrease_stack_depth_by(argument_types.len() as u8);
-        if *return_type != Java::Void { self.increase_stack_depth(); }
-        // TODO: push to stack_types
+        self.invoke(Instruction::InvokeStatic(methodref_index), class, name,
+                    argument_types, return_type)
     }
 
-    pub fn array_length(&mut self) {
-        self.push_instruction(Instruction::ArrayLength);
-        // TODO: push to stack_types?
+    // System.out.println(text)
+    pub fn println_string(&mut self, text: &str) -> &mut Self {
+        let string = Java::Class("java/lang/String");
+        self.get_static("java/lang/System", "out", &Java::Class("java/io/PrintStream"))
+            .load_constant(text)
+            .invoke_virtual("java/io/PrintStream", "println", &[string], &Java::Void)
     }
 
-    pub fn label(&mut self, name: &str) {
-        let env = self.env_num;
-        self.labels.insert((name.to_owned(), env), self.stack_index);
-        
-        // create a stack map table entry
-        let offset = match self.last_stack_frame_index {
-            Some(i) => self.stack_index - i - 1,
-            None => self.stack_index
-        };
-        
-        let frame = if self.stack_types.is_empty() {
-            if offset > ::std::u8::MAX as u16 {
-                StackMapFrame::SameFrameExtended(offset)
-            } else {
-                StackMapFrame::SameFrame(offset as u8)
-            }
-        } else {
-            let last_type = self.stack_types[self.stack_types.len()-1].clone();
-            if offset > ::std::u8::MAX as u16 {
-                StackMapFrame::SameLocals1StackItemFrameExtended(offset, last_type)
-            } else {
-                StackMapFrame::SameLocals1StackItemFrame(offset as u8, last_type)
-            }
+    // Calls super(...) from a constructor, passing along this constructor's leading
+    // arguments, e.g. the first int and String for (I, Ljava/lang/String;).
+    pub fn call_super_constructor(&mut self, super_class: &str, argument_types: &[Java]) -> &mut Self {
+        self.aload0();
+        let mut slot = 1;
+        for t in argument_types {
+            self.load_argument(t, slot);
+            slot += t.slot_size() as u16;
+        }
+        self.invoke_special(super_class, "<init>", argument_types, &Java::Void);
+        self
+    }
+
+    fn load_argument(&mut self, argument_type: &Java, slot: u16) {
+        match *argument_type {
+            Java::Boolean | Java::Byte | Java::Char | Java::Short | Java::Int => self.iload(slot),
+            Java::Long => self.lload(slot),
+            Java::Float => self.fload(slot),
+            Java::Double => self.dload(slot),
+            Java::Class(_) | Java::Array(_) => self.aload(slot),
+            Java::Void => {
+                self.record_misuse(AssemblerError::VoidNotAllowed("a constructor argument".to_owned()));
+                self
+            },
         };
-        
-        self.stack_frames.push(frame);
-        self.last_stack_frame_index = Some(self.stack_index);
     }
 
-    fn push_instruction(&mut self, instruction: Instruction) {
-        let index = self.stack_index;
-        self.stack_index += instruction.size() as u16;
-        self.instructions.push((index, IntermediateInstruction::Ready(instruction)));
+    // The *_desc variants take a method descriptor such as "(Ljava/lang/String;J)I"
+    // instead of a list of Java types. done() reports an invalid descriptor.
+    pub fn invoke_virtual_desc(&mut self, class: &str, name: &str, descriptor: &str) -> &mut Self {
+        let (argument_types, return_type) = match parse_method_signature(descriptor) {
+            Some(signature) => signature,
+            None => {
+                self.record_misuse(AssemblerError::InvalidDescriptor(descriptor.to_owned()));
+                return self;
+            },
+        };
+        self.invoke_virtual(class, name, &argument_types, &return_type);
+        self
     }
 
-    fn delay_instruction(&mut self, label: &'a str, instruction: Instruction) {
-        let index = self.stack_index;
-        let env = self.env_num;
-        self.stack_index += instruction.size() as u16;
-        self.instructions.push((index, IntermediateInstruction::Waiting(label, env,
-                                                                        instruction)));
+    pub fn invoke_special_desc(&mut self, class: &str, name: &str, descriptor: &str) -> &mut Self {
+        let (argument_types, return_type) = match parse_method_signature(descriptor) {
+            Some(signature) => signature,
+            None => {
+                self.record_misuse(AssemblerError::InvalidDescriptor(descriptor.to_owned()));
+                return self;
+            },
+        };
+        self.invoke_special(class, name, &argument_types, &return_type);
+        self
     }
 
-    fn increase_locals(&mut self) {
-        self.num_locals += 1;
+    pub fn invoke_static_desc(&mut self, class: &str, name: &str, descriptor: &str) -> &mut Self {
+        let (argument_types, return_type) = match parse_method_signature(descriptor) {
+            Some(signature) => signature,
+            None => {
+                self.record_misuse(AssemblerError::InvalidDescriptor(descriptor.to_owned()));
+                return self;
+            },
+        };
+        self.invoke_static(class, name, &argument_types, &return_type);
+        self
     }
 
-    fn increase_stack_depth(&mut self) {
-        // self.curr_stack_depth += 1;
-        // if self.curr_stack_depth > self.max_stack_depth {
-        //     self.max_stack_depth = self.curr_stack_depth;
-        // }
+    // emits a call and updates the stack for its receiver, arguments and result;
+    // longs and doubles take up two slots each
+    fn invoke(&mut self, instruction: Instruction, class: &str, name: &str,
+              argument_types: &[Java], return_type: &Java) -> Option<VerificationType> {
+        let has_receiver = match instruction {
+            Instruction::InvokeStatic(_) | Instruction::InvokeDynamic(_) => false,
+            _ => true,
+        };
+        let is_constructor_call = match instruction {
+            Instruction::InvokeSpecial(_) => name == "<init>",
+            _ => false,
+        };
+        self.push_instruction(instruction);
+
+        let argument_slots = argument_types.iter().fold(0, |sum, t| sum + t.slot_size());
+        let receiver = self.stack_types.len().checked_sub(argument_slots as usize + 1)
+            .map(|i| self.stack_types[i].clone());
+        self.decrease_stack_depth_by(argument_slots + has_receiver as u8);
+        if is_constructor_call {
+            if let Some(receiver) = receiver {
+                self.initialize(receiver, class);
+            }
+        }
+        self.push_java_type(return_type)
     }
 
-    fn decrease_stack_depth(&mut self) {
-        // if self.curr_stack_depth > 0 {
-        //     self.curr_stack_depth -= 1;
-        //     self.stack_types.pop();
-        // }
+    pub fn invoke_interface(&mut self, class: &str, name: &str,
+                            argument_types: &[Java], return_type: &Java) -> &mut Self {
+        self.invoke_interface_typed(class, name, argument_types, return_type);
+        self
     }
 
-    fn decrease_stack_depth_by(&mut self, n: u8) {
-        // self.curr_stack_depth -= n as u16;
-        // TODO: pop from stack_types
+    pub fn invoke_interface_typed(&mut self, class: &str, name: &str, argument_types: &[Java],
+                                  return_type: &Java) -> Option<VerificationType> {
+        let methodref_index =
+            self.classfile.define_interface_methodref(class, name, argument_types, return_type);
+        // the count operand includes the object reference, and longs/doubles count twice
+        let count = argument_types.iter().fold(1, |sum, t| sum + t.slot_size());
+        self.push_instruction(Instruction::InvokeInterface(methodref_index, count));
+        self.decrease_stack_depth_by(count);
+        self.push_java_type(return_type)
     }
-    
-    pub fn done(self) {
-        // if self.curr_stack_depth != 0 {
-        //     println!("Warning: stack depth at the end of a method should be 0, but is {} instead", self.curr_stack_depth);
-        // }
 
-        let classfile = self.classfile;
-        let labels = self.labels;
-        let real_instructions = self.instructions.into_iter().map(|(pos, ir)| match ir {
-            IntermediateInstruction::Ready(i) => i,
-            IntermediateInstruction::Waiting(l, e, i) => {
-                let tup = (l.to_string(), e);
-                let label_pos = labels.get(&tup).unwrap();
-                let offset = label_pos - pos;
-                fill_offset(i, offset)
-            }
-        }).collect();
-        
-        let stack_map_table_index = classfile.define_utf8("StackMapTable");
-        let stack_map_table = Attribute::StackMapTable(stack_map_table_index,
-                                                       self.stack_frames);
-        
-        let code_index = classfile.define_utf8("Code");
-        let code = Attribute::Code(code_index, self.max_stack_depth, self.num_locals,
-                                   real_instructions, vec![], vec![stack_map_table]);
+    // calls the site's bootstrap method (see ClassBuilder::add_bootstrap_method) the
+    // first time it runs, then whatever method it linked the call site to
+    pub fn invoke_dynamic(&mut self, bootstrap_method: u16, name: &str,
+                          argument_types: &[Java], return_type: &Java) -> &mut Self {
+        self.invoke_dynamic_typed(bootstrap_method, name, argument_types, return_type);
+        self
+    }
 
-        let method = Method::new(self.access_flags, self.name_index, self.descriptor_index,
-                                 vec![code]);
-        classfile.methods.push(method);
+    pub fn invoke_dynamic_typed(&mut self, bootstrap_method: u16, name: &str, argument_types: &[Java],
+                                return_type: &Java) -> Option<VerificationType> {
+        let invoke_dynamic_index =
+            self.classfile.define_invoke_dynamic(bootstrap_method, name, argument_types, return_type);
+        self.push_instruction(Instruction::InvokeDynamic(invoke_dynamic_index));
+        let argument_slots = argument_types.iter().fold(0, |sum, t| sum + t.slot_size());
+        self.decrease_stack_depth_by(argument_slots);
+        self.push_java_type(return_type)
+    }
+
+    pub fn check_cast(&mut self, class: &str) -> &mut Self {
+        let class_index = self.classfile.define_class(class);
+        self.push_instruction(Instruction::CheckCast(class_index));
+        self.decrease_stack_depth();
+        self.increase_stack_depth();
+        self.stack_types.push(VerificationType::Object(class_index));
+        self
+    }
+
+    pub fn instance_of(&mut self, class: &str) -> &mut Self {
+        let class_index = self.classfile.define_class(class);
+        self.push_instruction(Instruction::InstanceOf(class_index));
+        self.decrease_stack_depth();
+        self.increase_stack_depth();
+        self.stack_types.push(VerificationType::Integer);
+        self
+    }
+
+    pub fn new_array(&mut self, element: PrimitiveType) -> &mut Self {
+        let array_class = self.classfile.define_class(element.array_descriptor());
+        self.push_instruction(Instruction::NewArray(element.atype()));
+        self.decrease_stack_depth();
+        self.increase_stack_depth();
+        self.stack_types.push(VerificationType::Object(array_class));
+        self
+    }
+
+    pub fn new_object_array(&mut self, class: &str) -> &mut Self {
+        let class_index = self.classfile.define_class(class);
+        let array_class = if class.starts_with('[') {
+            self.classfile.define_class(&format!("[{}", class))
+        } else {
+            self.classfile.define_class(&format!("[L{};", class))
+        };
+        self.push_instruction(Instruction::ANewArray(class_index));
+        self.decrease_stack_depth();
+        self.increase_stack_depth();
+        self.stack_types.push(VerificationType::Object(array_class));
+        self
+    }
+
+    // `class` is the descriptor of the array type itself, e.g. "[[I"
+    pub fn multi_new_array(&mut self, class: &str, dimensions: u8) -> &mut Self {
+        let class_index = self.classfile.define_class(class);
+        self.push_instruction(Instruction::MultiANewArray(class_index, dimensions));
+        self.decrease_stack_depth_by(dimensions);
+        self.increase_stack_depth();
+        self.stack_types.push(VerificationType::Object(class_index));
+        self
+    }
+
+    pub fn array_length(&mut self) -> &mut Self {
+        self.push_instruction(Instruction::ArrayLength);
+        self.decrease_stack_depth();
+        self.increase_stack_depth();
+        self.stack_types.push(VerificationType::Integer);
+        self
+    }
+
+    // Code between the `start` and `end` labels is protected by the code at the
+    // `handler` label; a missing `exception_class` catches everything. Call this
+    // before placing the handler label so it starts with the exception on the stack.
+    pub fn try_catch<L: Into<LabelName<'a>>>(&mut self, start: L, end: L, handler: L,
+                                             exception_class: Option<&str>) -> &mut Self {
+        let catch_type = match exception_class {
+            Some(class) => self.classfile.define_class(class),
+            None => 0,
+        };
+        let exception_class = match exception_class {
+            Some(_) => catch_type,
+            None => self.classfile.define_class("java/lang/Throwable"),
+        };
+        let env = self.env_num;
+        self.exception_handlers.push(ExceptionHandler {
+            start: start.into(),
+            end: end.into(),
+            handler: handler.into(),
+            env: env,
+            catch_type: catch_type,
+            exception_class: exception_class,
+        });
+        self
+    }
+
+    pub fn monitor_enter(&mut self) -> &mut Self {
+        self.push_instruction(Instruction::MonitorEnter);
+        self.decrease_stack_depth();
+        self
+    }
+
+    pub fn monitor_exit(&mut self) -> &mut Self {
+        self.push_instruction(Instruction::MonitorExit);
+        self.decrease_stack_depth();
+        self
+    }
+
+    pub fn athrow(&mut self) -> &mut Self {
+        self.push_instruction(Instruction::Athrow);
+        self.decrease_stack_depth();
+        self
+    }
+
+    // a fresh label, distinct from every other label in this method; place it
+    // with `label` and branch to it like a named one
+    pub fn new_label(&mut self) -> Label {
+        self.label_count += 1;
+        Label { id: self.label_count }
+    }
+
+    pub fn label<L: Into<LabelName<'a>>>(&mut self, label: L) -> &mut Self {
+        let env = self.env_num;
+        let name = label.into();
+        let key = (name, env);
+        let reachable = self.falls_through();
+        self.labels.insert(key.clone(), self.stack_index);
+
+        // The state here is what falls through from the previous instruction merged
+        // with the state at every branch to this label seen so far. Code after an
+        // unconditional jump that nothing branches to (yet) just keeps the current state.
+        let fall_through = (self.locals.clone(), self.stack_types.clone());
+        let (mut locals, mut stack) = match self.branch_states.get(&key).cloned() {
+            Some(branch) => {
+                if reachable {
+                    self.merge_states(fall_through, branch)
+                } else {
+                    branch
+                }
+            },
+            None => fall_through,
+        };
+
+        // exception handlers are entered with just the exception on the stack, and
+        // only with the locals that were already set when the protected code started
+        let handler = self.exception_handlers.iter()
+            .find(|h| h.handler == name && h.env == env)
+            .map(|h| (h.start, h.exception_class));
+        if let Some((start, class_index)) = handler {
+            let start_pc = self.labels.get(&(start, env)).cloned();
+            let start_locals = self.stack_frames.iter().rev()
+                .find(|&&(pc, _, _)| Some(pc) == start_pc)
+                .map(|&(_, ref locals, _)| locals.clone());
+            if let Some(start_locals) = start_locals {
+                locals = self.merge_locals(start_locals, locals);
+            }
+            stack = vec![VerificationType::Object(class_index)];
+        }
+
+        self.curr_stack_depth = stack.len() as u16;
+        if self.curr_stack_depth > self.max_stack_depth {
+            self.max_stack_depth = self.curr_stack_depth;
+        }
+        self.locals = locals.clone();
+        self.stack_types = stack.clone();
+
+        // remember the state for a stack map table entry; the entries themselves
+        // are built in done(), once the final PCs are known
+        self.stack_frames.push((self.stack_index, locals, stack));
+        self
+    }
+
+    // whether the previous instruction can continue on to the next one
+    fn falls_through(&self) -> bool {
+        if self.labels.values().any(|&pc| pc == self.stack_index) {
+            return true;
+        }
+        !self.ends_with_jump()
+    }
+
+    // whether the last instruction always jumps, returns or throws
+    fn ends_with_jump(&self) -> bool {
+        match self.instructions.iter().rev().find(|&&(_, ref ir)| !ir.is_removed()) {
+            Some(&(_, ref ir)) => match *ir.instruction() {
+                Instruction::Goto(_) | Instruction::GotoW(_) |
+                Instruction::TableSwitch(..) | Instruction::LookupSwitch(..) |
+                Instruction::Return | Instruction::IReturn | Instruction::Lreturn |
+                Instruction::FReturn | Instruction::Dreturn | Instruction::Areturn |
+                Instruction::Athrow | Instruction::Ret(_) => true,
+                _ => false,
+            },
+            None => false,
+        }
+    }
+
+    // every return instruction has to match the return type in the descriptor
+    fn check_returns(&self) -> Result<(), AssemblerError> {
+        let descriptor = self.classfile.lookup_utf8(self.descriptor_index);
+        let (_, return_type) = parse_method_descriptor(descriptor);
+        let expected = return_instruction(return_type);
+        for &(_, ref ir) in self.instructions.iter().filter(|&&(_, ref ir)| !ir.is_removed()) {
+            let found = ir.instruction();
+            match *found {
+                Instruction::Return | Instruction::IReturn | Instruction::Lreturn |
+                Instruction::FReturn | Instruction::Dreturn | Instruction::Areturn if *found != expected => {
+                    let name = self.classfile.lookup_utf8(self.name_index).to_owned();
+                    return Err(AssemblerError::WrongReturn(name, expected.mnemonic().to_owned(),
+                                                           found.mnemonic().to_owned()));
+                },
+                _ => {},
+            }
+        }
+        Ok(())
+    }
+
+    // Execution mustn't run off the end of the code, either from the last
+    // instruction or from a branch to a label after it. Labels there are fine
+    // otherwise, e.g. to end an exception handler's range.
+    fn check_ending(&self) -> Result<(), AssemblerError> {
+        let branches_to_end = self.instructions.iter().any(|&(_, ref ir)| {
+            let targets = match *ir {
+                IntermediateInstruction::Waiting(label, env, _) => vec![(label, env)],
+                IntermediateInstruction::WaitingSwitch(default, ref labels, env, _) => {
+                    let mut targets = vec![(default, env)];
+                    targets.extend(labels.iter().map(|&label| (label, env)));
+                    targets
+                },
+                _ => vec![],
+            };
+            targets.into_iter().any(|(label, env)| {
+                self.labels.get(&(label, env)) == Some(&self.stack_index)
+            })
+        });
+        if branches_to_end || !self.ends_with_jump() {
+            let name = self.classfile.lookup_utf8(self.name_index).to_owned();
+            return Err(AssemblerError::MissingReturn(name));
+        }
+        Ok(())
+    }
+
+    // records the current state as one of the ways into `label`
+    fn record_branch(&mut self, label: LabelName<'a>, env: u16) {
+        let key = (label, env);
+        let state = (self.locals.clone(), self.stack_types.clone());
+        let state = match self.branch_states.remove(&key) {
+            Some(previous) => self.merge_states(previous, state),
+            None => state,
+        };
+        self.branch_states.insert(key, state);
+    }
+
+    fn merge_states(&mut self, a: (Vec<VerificationType>, Vec<VerificationType>),
+                    b: (Vec<VerificationType>, Vec<VerificationType>))
+                    -> (Vec<VerificationType>, Vec<VerificationType>) {
+        let (a_locals, a_stack) = a;
+        let (b_locals, b_stack) = b;
+        let locals = self.merge_locals(a_locals, b_locals);
+        // stacks of different heights can't be merged, so the bytecode is broken
+        // anyway; just keep the first one
+        let stack = if a_stack.len() == b_stack.len() {
+            a_stack.into_iter().zip(b_stack).map(|(x, y)| self.merge_type(x, y)).collect()
+        } else {
+            a_stack
+        };
+        (locals, stack)
+    }
+
+    // locals only set on some of the paths become unusable
+    fn merge_locals(&mut self, a: Vec<VerificationType>,
+                    b: Vec<VerificationType>) -> Vec<VerificationType> {
+        a.into_iter().zip(b).map(|(x, y)| self.merge_type(x, y)).collect()
+    }
+
+    fn merge_type(&mut self, a: VerificationType, b: VerificationType) -> VerificationType {
+        match (a, b) {
+            (a, b) if a == b => a,
+            (VerificationType::Null, t @ VerificationType::Object(_)) |
+            (t @ VerificationType::Object(_), VerificationType::Null) => t,
+            (VerificationType::Object(_), VerificationType::Object(_)) =>
+                VerificationType::Object(self.classfile.define_class("java/lang/Object")),
+            _ => VerificationType::Top,
+        }
+    }
+
+    // The JVM caps a method's code at 65535 bytes. Past that the PCs wrap around,
+    // which done() reports instead of writing out garbage.
+    fn advance_pc(&mut self, size: u32) {
+        let pc = self.stack_index as u32 + size;
+        self.stack_index = pc as u16;
+        self.code_too_large |= pc > u16::MAX as u32;
+    }
+
+    fn push_instruction(&mut self, instruction: Instruction) {
+        let index = self.stack_index;
+        self.advance_pc(instruction.size(index));
+        self.instructions.push((index, IntermediateInstruction::Ready(instruction)));
+    }
+
+    // locals past 255 need the wide form of the instruction
+    fn push_local_instruction(&mut self, index: u16, narrow: fn(u8) -> Instruction,
+                              wide: fn(u16) -> Instruction) {
+        if index > ::std::u8::MAX as u16 {
+            self.push_instruction(wide(index));
+        } else {
+            self.push_instruction(narrow(index as u8));
+        }
+    }
+
+    // ldc only has room for a one-byte index, so fall back to ldc_w beyond that
+    fn push_load_constant(&mut self, index: u16) {
+        if index > ::std::u8::MAX as u16 {
+            self.push_instruction(Instruction::LdcW(index));
+        } else {
+            self.push_instruction(Instruction::LoadConstant(index as u8));
+        }
+    }
+
+    // The PC recorded here is provisional: done() may promote a goto to goto_w,
+    // which grows it by two bytes and shifts everything after it. Labels, frames
+    // and exception handlers all record these provisional PCs, and get mapped
+    // onto the final layout in done().
+    fn delay_instruction(&mut self, label: LabelName<'a>, instruction: Instruction) {
+        let index = self.stack_index;
+        let env = self.env_num;
+        self.record_branch(label, env);
+        self.advance_pc(instruction.size(index));
+        self.instructions.push((index, IntermediateInstruction::Waiting(label, env,
+                                                                        instruction)));
+    }
+
+    fn delay_switch(&mut self, default: LabelName<'a>, targets: Vec<LabelName<'a>>,
+                    instruction: Instruction) {
+        let index = self.stack_index;
+        let env = self.env_num;
+        self.record_branch(default, env);
+        for &target in &targets {
+            self.record_branch(target, env);
+        }
+        self.advance_pc(instruction.size(index));
+        self.instructions.push((index, IntermediateInstruction::WaitingSwitch(default, targets, env,
+                                                                              instruction)));
+    }
+
+    fn push_java_type(&mut self, t: &Java) -> Option<VerificationType> {
+        let verification_type = self.classfile.define_verification_type(t);
+        if let Some(ref verification_type) = verification_type {
+            self.increase_stack_depth_by(t.slot_size());
+            self.stack_types.push(verification_type.clone());
+            if t.slot_size() == 2 {
+                self.stack_types.push(VerificationType::Top);
+            }
+        }
+        verification_type
+    }
+
+    // The stack shuffling instructions work on slots, and mustn't be used to pull
+    // a long or double apart: that's what pop2 and the dup2 family are for. This
+    // checks that the top `top` slots, and the `below` slots under them, each hold
+    // whole values. The first misuse is reported by done().
+    fn check_stack_split(&mut self, instruction: Instruction, top: usize, below: usize) {
+        let len = self.stack_types.len();
+        let splits = [top, top + below].iter().any(|&n| {
+            n <= len && self.stack_types[len - n] == VerificationType::Top
+        });
+        if splits && self.stack_split.is_none() {
+            let error = AssemblerError::SplitsLongOrDouble(instruction.mnemonic().to_owned(), self.stack_index);
+            self.stack_split = Some(error);
+        }
+    }
+
+    // Reorders the types on the stack like the dup and swap instructions do: the
+    // top `top` slots are moved below the `below` slots under them, and are also
+    // left on top if `duplicate` is set.
+    fn shuffle_stack_types(&mut self, top: usize, below: usize, duplicate: bool) {
+        if self.stack_types.len() < top + below {
+            return;
+        }
+        let top_len = self.stack_types.len() - top;
+        let top_types = self.stack_types.split_off(top_len);
+        let below_len = self.stack_types.len() - below;
+        let below_types = self.stack_types.split_off(below_len);
+        if duplicate {
+            self.stack_types.extend(top_types.iter().cloned());
+        }
+        self.stack_types.extend(below_types);
+        self.stack_types.extend(top_types);
+    }
+
+    fn set_local(&mut self, index: u16, verification_type: VerificationType) {
+        let index = index as usize;
+        let two_slots = verification_type == VerificationType::Long ||
+                        verification_type == VerificationType::Double;
+        let needed = if two_slots { index + 2 } else { index + 1 };
+        if self.locals.len() < needed {
+            self.locals.resize(needed, VerificationType::Top);
+        }
+        // max_locals covers the highest slot written to, not how many stores there were
+        if needed as u16 > self.num_locals {
+            self.num_locals = needed as u16;
+        }
+
+        // overwriting half of a long or double leaves the other half unusable
+        if index > 0 && self.locals[index] == VerificationType::Top {
+            match self.locals[index - 1] {
+                VerificationType::Long | VerificationType::Double =>
+                    self.locals[index - 1] = VerificationType::Top,
+                _ => {},
+            }
+        }
+        self.locals[index] = verification_type;
+        if two_slots {
+            self.locals[index + 1] = VerificationType::Top;
+        }
+    }
+
+    // the type of the reference in a local, falling back to Object if it isn't known
+    fn local_reference(&mut self, index: u16) -> VerificationType {
+        match self.locals.get(index as usize) {
+            Some(&VerificationType::Top) | None => {},
+            Some(t) => return t.clone(),
+        }
+        VerificationType::Object(self.classfile.define_class("java/lang/Object"))
+    }
+
+    // pops the array and index, and pushes the element
+    fn push_array_load(&mut self, instruction: Instruction, element: VerificationType) -> &mut Self {
+        self.push_instruction(instruction);
+        self.decrease_stack_depth_by(2);
+        let two_slots = element == VerificationType::Long || element == VerificationType::Double;
+        self.increase_stack_depth_by(if two_slots { 2 } else { 1 });
+        self.stack_types.push(element);
+        if two_slots {
+            self.stack_types.push(VerificationType::Top);
+        }
+        self
+    }
+
+    // pops the array, index and a value taking up `value_slots` slots
+    fn push_array_store(&mut self, instruction: Instruction, value_slots: u8) -> &mut Self {
+        self.push_instruction(instruction);
+        self.decrease_stack_depth_by(2 + value_slots);
+        self
+    }
+
+    // the type of the reference on top of the stack, falling back to Object if it isn't known
+    fn top_reference(&mut self) -> VerificationType {
+        match self.stack_types.last() {
+            Some(&VerificationType::Top) | None => {},
+            Some(t) => return t.clone(),
+        }
+        VerificationType::Object(self.classfile.define_class("java/lang/Object"))
+    }
+
+    // the type of the elements of an array of references
+    fn array_element(&mut self, array: VerificationType) -> VerificationType {
+        let array_class = match array {
+            VerificationType::Object(class_index) => {
+                match *self.classfile.lookup_constant(class_index) {
+                    Constant::Class(name_index) =>
+                        Some(self.classfile.lookup_utf8(name_index).to_owned()),
+                    _ => None,
+                }
+            },
+            _ => None,
+        };
+        let element_class = match array_class {
+            Some(ref name) if name.starts_with("[L") => name[2..name.len() - 1].to_owned(),
+            Some(ref name) if name.starts_with("[[") => name[1..].to_owned(),
+            _ => "java/lang/Object".to_owned(),
+        };
+        VerificationType::Object(self.classfile.define_class(&element_class))
+    }
+
+    // after a constructor call, every copy of the object it was called on is initialized
+    fn initialize(&mut self, uninitialized: VerificationType, class: &str) {
+        let initialized = match uninitialized {
+            VerificationType::UninitializedThis =>
+                VerificationType::Object(self.classfile.this_class_index),
+            VerificationType::Uninitialized(_) =>
+                VerificationType::Object(self.classfile.define_class(class)),
+            _ => return,
+        };
+        for t in self.stack_types.iter_mut().chain(self.locals.iter_mut()) {
+            if *t == uninitialized {
+                *t = initialized.clone();
+            }
+        }
+    }
+
+    fn record_misuse(&mut self, error: AssemblerError) {
+        if self.misuse.is_none() {
+            self.misuse = Some(error);
+        }
+    }
+
+    // the descriptor of the field `fieldref_index` refers to, or None if it
+    // isn't a Fieldref, which done() then reports
+    fn fieldref_descriptor(&mut self, fieldref_index: u16) -> Option<String> {
+        let descriptor = self.classfile.fieldref_descriptor(fieldref_index);
+        if descriptor.is_none() {
+            self.record_misuse(AssemblerError::NotAFieldref(fieldref_index));
+        }
+        descriptor
+    }
+
+    fn increase_stack_depth(&mut self) {
+        self.increase_stack_depth_by(1);
+    }
+
+    fn increase_stack_depth_by(&mut self, n: u8) {
+        self.curr_stack_depth += n as u16;
+        if self.curr_stack_depth > self.max_stack_depth {
+            self.max_stack_depth = self.curr_stack_depth;
+        }
+    }
+
+    fn decrease_stack_depth(&mut self) {
+        self.decrease_stack_depth_by(1);
+    }
+
+    fn decrease_stack_depth_by(&mut self, n: u8) {
+        self.curr_stack_depth = self.curr_stack_depth.saturating_sub(n as u16);
+        let new_len = self.stack_types.len().saturating_sub(n as usize);
+        self.stack_types.truncate(new_len);
+    }
+    
+    // attributes the instructions emitted from here on to the given source line
+    pub fn mark_line(&mut self, line: u16) -> &mut Self {
+        let pc = self.stack_index;
+        self.line_numbers.retain(|&(marked_pc, _)| marked_pc != pc);
+        self.line_numbers.push((pc, line));
+        self
+    }
+
+    // names the local in `slot` for debuggers, over the code between two labels
+    pub fn declare_local<L: Into<LabelName<'a>>>(&mut self, name: &str, ty: &Java, slot: u16,
+                                                 start: L, end: L) -> &mut Self {
+        let name_index = self.classfile.define_utf8(name);
+        let descriptor = format!("{}", ty);
+        let descriptor_index = self.classfile.define_utf8(&descriptor);
+        let env = self.env_num;
+        self.local_variables.push(LocalVariable {
+            name_index: name_index,
+            descriptor_index: descriptor_index,
+            slot: slot,
+            start: start.into(),
+            end: end.into(),
+            env: env,
+        });
+        let locals_needed = slot + ty.slot_size() as u16;
+        if locals_needed > self.num_locals {
+            self.num_locals = locals_needed;
+        }
+        self
+    }
+
+    // declares that the method may throw `class`, like a `throws` clause in Java
+    pub fn throws(&mut self, class: &str) -> &mut Self {
+        let class_index = self.classfile.define_class(class);
+        if !self.exceptions.contains(&class_index) {
+            self.exceptions.push(class_index);
+        }
+        self
+    }
+
+    pub fn mark_deprecated(&mut self) -> &mut Self {
+        self.deprecated = true;
+        self
+    }
+
+    // the generic signature of the method, e.g. "(Ljava/util/List<Ljava/lang/String;>;)V"
+    pub fn set_signature(&mut self, signature: &str) -> &mut Self {
+        self.signature = Some(self.classfile.define_utf8(signature));
+        self
+    }
+
+    pub fn add_annotation(&mut self, annotation_type: &str,
+                          elements: &[(&str, AnnotationValue)]) -> &mut Self {
+        let annotation = self.classfile.define_annotation(annotation_type, elements);
+        self.annotations.push(annotation);
+        self
+    }
+
+    // sets both ACC_SYNTHETIC and the Synthetic attribute, which older tools look for
+    pub fn mark_synthetic(&mut self) -> &mut Self {
+        self.access_flags |= ACC_SYNTHETIC;
+        self.synthetic = true;
+        self
+    }
+
+    // the number of operand stack slots in use after the last instruction
+    pub fn stack_depth(&self) -> u16 {
+        self.curr_stack_depth
+    }
+
+    // the types of those slots, bottom first, as the next stack map frame would
+    // record them; a long or double is followed by a Top for its second slot
+    pub fn stack_types(&self) -> &[VerificationType] {
+        &self.stack_types
+    }
+
+    // the number of local variable slots the method needs so far, including
+    // `this` and the arguments
+    pub fn locals_count(&self) -> u16 {
+        self.num_locals
+    }
+
+    // the stack map type for an instance of `class`, e.g. "java/lang/String"
+    pub fn object_verification_type(&mut self, class: &str) -> VerificationType {
+        VerificationType::Object(self.classfile.define_class(class))
+    }
+
+    // makes done() fail if verify() finds a problem with the method
+    pub fn set_strict(&mut self, strict: bool) -> &mut Self {
+        self.strict = strict;
+        self
+    }
+
+    // Runs the instructions against a simulated operand stack and reports the
+    // first one that finds the wrong type or too few values on the stack. Code
+    // that's only reachable by a backwards jump is assumed to start with an
+    // empty stack.
+    pub fn verify(&self) -> Result<(), AssemblerError> {
+        let descriptor = self.classfile.lookup_utf8(self.descriptor_index);
+        let (_, return_type) = parse_method_descriptor(descriptor);
+
+        let mut target_stacks: HashMap<u16, Vec<Slot>> = HashMap::new();
+        for h in &self.exception_handlers {
+            if let Some(&pc) = self.labels.get(&(h.handler, h.env)) {
+                target_stacks.insert(pc, vec![Slot::Reference]);
+            }
+        }
+
+        let mut stack = SimulatedStack { slots: vec![] };
+        let mut reachable = true;
+        for &(pc, ref ir) in self.instructions.iter().filter(|&&(_, ref ir)| !ir.is_removed()) {
+            let instruction = ir.instruction();
+            match target_stacks.get(&pc) {
+                Some(expected) if reachable && expected.len() != stack.slots.len() => {
+                    let problem = format!("stack height is {} here but {} when jumped to",
+                                          stack.slots.len(), expected.len());
+                    return Err(AssemblerError::TypeMismatch(instruction.mnemonic().to_owned(), pc, problem));
+                },
+                Some(expected) if !reachable => stack.slots = expected.clone(),
+                None if !reachable => stack.slots = vec![],
+                _ => {},
+            }
+
+            try!(self.simulate(instruction, &return_type, &mut stack).map_err(|e| {
+                let mnemonic = instruction.mnemonic().to_owned();
+                match e {
+                    StackError::Underflow => AssemblerError::StackUnderflow(mnemonic, pc),
+                    StackError::Mismatch(problem) => AssemblerError::TypeMismatch(mnemonic, pc, problem),
+                }
+            }));
+
+            let targets = match *ir {
+                IntermediateInstruction::Ready(_) | IntermediateInstruction::Removed(_) => vec![],
+                IntermediateInstruction::Waiting(l, e, _) => vec![(l, e)],
+                IntermediateInstruction::WaitingSwitch(default, ref labels, e, _) => {
+                    let mut targets = vec![(default, e)];
+                    targets.extend(labels.iter().map(|&l| (l, e)));
+                    targets
+                },
+            };
+            for (l, e) in targets {
+                let target = match self.labels.get(&(l, e)) {
+                    Some(&target) => target,
+                    None => return Err(AssemblerError::UndefinedLabel(l.to_string(), e)),
+                };
+                let previous = target_stacks.insert(target, stack.slots.clone());
+                if let Some(previous) = previous {
+                    if previous.len() != stack.slots.len() {
+                        let problem = format!("jumps to {} with stack height {}, but it's also reached with {}",
+                                              l, stack.slots.len(), previous.len());
+                        return Err(AssemblerError::TypeMismatch(instruction.mnemonic().to_owned(), pc, problem));
+                    }
+                }
+            }
+
+            reachable = match *instruction {
+                Instruction::Goto(_) | Instruction::GotoW(_) | Instruction::Athrow |
+                Instruction::IReturn | Instruction::Lreturn | Instruction::FReturn |
+                Instruction::Dreturn | Instruction::Areturn | Instruction::Return |
+                Instruction::TableSwitch(..) | Instruction::LookupSwitch(..) |
+                Instruction::Ret(_) => false,
+                _ => true,
+            };
+            // only the subroutine sees the return address a jsr pushes
+            match *instruction {
+                Instruction::Jsr(_) | Instruction::JsrW(_) => { stack.slots.pop(); },
+                _ => {},
+            }
+        }
+        Ok(())
+    }
+
+    fn simulate(&self, instruction: &Instruction, return_type: &Option<Slot>,
+                stack: &mut SimulatedStack) -> Result<(), StackError> {
+        let classfile = &*self.classfile;
+        match *instruction {
+            Instruction::Nop | Instruction::Iinc(..) | Instruction::WideIinc(..) |
+            Instruction::Goto(_) | Instruction::GotoW(_) | Instruction::Ret(_) => {},
+
+            Instruction::IconstM1 | Instruction::Iconst0 | Instruction::Iconst1 |
+            Instruction::Iconst2 | Instruction::Iconst3 | Instruction::Iconst4 |
+            Instruction::Iconst5 | Instruction::Bipush(_) | Instruction::Sipush(..) |
+            Instruction::Iload0 | Instruction::Iload1 | Instruction::Iload2 |
+            Instruction::Iload3 | Instruction::Iload(_) | Instruction::WideIload(_) =>
+                stack.push(Slot::Int),
+            Instruction::Fconst0 | Instruction::Fconst1 | Instruction::Fconst2 |
+            Instruction::Fload0 | Instruction::Fload1 | Instruction::Fload2 |
+            Instruction::Fload3 | Instruction::Fload(_) | Instruction::WideFload(_) =>
+                stack.push(Slot::Float),
+            Instruction::Lconst0 | Instruction::Lconst1 | Instruction::Lload0 |
+            Instruction::Lload1 | Instruction::Lload2 | Instruction::Lload3 |
+            Instruction::Lload(_) | Instruction::WideLload(_) => stack.push(Slot::Long),
+            Instruction::Dconst0 | Instruction::Dconst1 | Instruction::Dload0 |
+            Instruction::Dload1 | Instruction::Dload2 | Instruction::Dload3 |
+            Instruction::Dload(_) | Instruction::WideDload(_) => stack.push(Slot::Double),
+            Instruction::AConstNull | Instruction::Aload0 | Instruction::Aload1 |
+            Instruction::Aload2 | Instruction::Aload3 | Instruction::Aload(_) |
+            Instruction::WideAload(_) | Instruction::New(..) => stack.push(Slot::Reference),
+            Instruction::Jsr(_) | Instruction::JsrW(_) => stack.push(Slot::ReturnAddress),
+
+            Instruction::Istore0 | Instruction::Istore1 | Instruction::Istore2 |
+            Instruction::Istore3 | Instruction::Istore(_) | Instruction::WideIstore(_) =>
+                try!(stack.pop(Slot::Int)),
+            Instruction::Fstore0 | Instruction::Fstore1 | Instruction::Fstore2 |
+            Instruction::Fstore3 | Instruction::Fstore(_) | Instruction::WideFstore(_) =>
+                try!(stack.pop(Slot::Float)),
+            Instruction::Lstore0 | Instruction::Lstore1 | Instruction::Lstore2 |
+            Instruction::Lstore3 | Instruction::Lstore(_) | Instruction::WideLstore(_) =>
+                try!(stack.pop(Slot::Long)),
+            Instruction::Dstore0 | Instruction::Dstore1 | Instruction::Dstore2 |
+            Instruction::Dstore3 | Instruction::Dstore(_) | Instruction::WideDstore(_) =>
+                try!(stack.pop(Slot::Double)),
+            Instruction::Astore0 | Instruction::Astore1 | Instruction::Astore2 |
+            Instruction::Astore3 | Instruction::Astore(_) | Instruction::WideAstore(_) => {
+                // astore is also how a subroutine saves its return address
+                if stack.slots.last() == Some(&Slot::ReturnAddress) {
+                    try!(stack.pop(Slot::ReturnAddress));
+                } else {
+                    try!(stack.pop(Slot::Reference));
+                }
+            },
+
+            Instruction::Iadd | Instruction::Isub | Instruction::Imul | Instruction::Idiv |
+            Instruction::Irem | Instruction::Iand | Instruction::Ior | Instruction::Ixor |
+            Instruction::Ishl | Instruction::Ishr | Instruction::Iushr =>
+                try!(stack.apply(&[Slot::Int, Slot::Int], Some(Slot::Int))),
+            Instruction::Fadd | Instruction::Fsub | Instruction::Fmul | Instruction::Fdiv |
+            Instruction::Frem =>
+                try!(stack.apply(&[Slot::Float, Slot::Float], Some(Slot::Float))),
+            Instruction::Ladd | Instruction::Lsub | Instruction::Lmul | Instruction::Ldiv |
+            Instruction::Lrem | Instruction::Land | Instruction::Lor | Instruction::Lxor =>
+                try!(stack.apply(&[Slot::Long, Slot::Long], Some(Slot::Long))),
+            Instruction::Lshl | Instruction::Lshr | Instruction::Lushr =>
+                try!(stack.apply(&[Slot::Long, Slot::Int], Some(Slot::Long))),
+            Instruction::Dadd | Instruction::Dsub | Instruction::Dmul | Instruction::Ddiv |
+            Instruction::Drem =>
+                try!(stack.apply(&[Slot::Double, Slot::Double], Some(Slot::Double))),
+            Instruction::Ineg | Instruction::I2B | Instruction::I2C | Instruction::I2S =>
+                try!(stack.apply(&[Slot::Int], Some(Slot::Int))),
+            Instruction::Lneg => try!(stack.apply(&[Slot::Long], Some(Slot::Long))),
+            Instruction::Fneg => try!(stack.apply(&[Slot::Float], Some(Slot::Float))),
+            Instruction::Dneg => try!(stack.apply(&[Slot::Double], Some(Slot::Double))),
+
+            Instruction::I2L => try!(stack.apply(&[Slot::Int], Some(Slot::Long))),
+            Instruction::I2F => try!(stack.apply(&[Slot::Int], Some(Slot::Float))),
+            Instruction::I2D => try!(stack.apply(&[Slot::Int], Some(Slot::Double))),
+            Instruction::L2I => try!(stack.apply(&[Slot::Long], Some(Slot::Int))),
+            Instruction::L2F => try!(stack.apply(&[Slot::Long], Some(Slot::Float))),
+            Instruction::L2D => try!(stack.apply(&[Slot::Long], Some(Slot::Double))),
+            Instruction::F2I => try!(stack.apply(&[Slot::Float], Some(Slot::Int))),
+            Instruction::F2L => try!(stack.apply(&[Slot::Float], Some(Slot::Long))),
+            Instruction::F2D => try!(stack.apply(&[Slot::Float], Some(Slot::Double))),
+            Instruction::D2I => try!(stack.apply(&[Slot::Double], Some(Slot::Int))),
+            Instruction::D2L => try!(stack.apply(&[Slot::Double], Some(Slot::Long))),
+            Instruction::D2F => try!(stack.apply(&[Slot::Double], Some(Slot::Float))),
+
+            Instruction::Lcmp => try!(stack.apply(&[Slot::Long, Slot::Long], Some(Slot::Int))),
+            Instruction::Fcmpl | Instruction::Fcmpg =>
+                try!(stack.apply(&[Slot::Float, Slot::Float], Some(Slot::Int))),
+            Instruction::Dcmpl | Instruction::Dcmpg =>
+                try!(stack.apply(&[Slot::Double, Slot::Double], Some(Slot::Int))),
+
+            Instruction::IfEq(_) | Instruction::IfNe(_) | Instruction::IfLt(_) |
+            Instruction::IfGe(_) | Instruction::IfGt(_) | Instruction::IfLe(_) |
+            Instruction::TableSwitch(..) | Instruction::LookupSwitch(..) =>
+                try!(stack.pop(Slot::Int)),
+            Instruction::IfIcmpEq(_) | Instruction::IfIcmpNe(_) | Instruction::IfIcmpLt(_) |
+            Instruction::IfIcmpGe(_) | Instruction::IfIcmpGt(_) | Instruction::IfIcmpLe(_) =>
+                try!(stack.apply(&[Slot::Int, Slot::Int], None)),
+            Instruction::IfAcmpEq(_) | Instruction::IfAcmpNe(_) =>
+                try!(stack.apply(&[Slot::Reference, Slot::Reference], None)),
+            Instruction::IfNull(_) | Instruction::IfNonNull(_) | Instruction::Athrow |
+            Instruction::MonitorEnter | Instruction::MonitorExit =>
+                try!(stack.pop(Slot::Reference)),
+
+            Instruction::IReturn | Instruction::FReturn | Instruction::Lreturn |
+            Instruction::Dreturn | Instruction::Areturn | Instruction::Return => {
+                let returned = match *instruction {
+                    Instruction::IReturn => Some(Slot::Int),
+                    Instruction::FReturn => Some(Slot::Float),
+                    Instruction::Lreturn => Some(Slot::Long),
+                    Instruction::Dreturn => Some(Slot::Double),
+                    Instruction::Areturn => Some(Slot::Reference),
+                    _ => None,
+                };
+                if returned != *return_type {
+                    return Err(StackError::Mismatch(format!("the method's descriptor returns {:?}", return_type)));
+                }
+                if let Some(slot) = returned {
+                    try!(stack.pop(slot));
+                }
+            },
+
+            Instruction::Aaload =>
+                try!(stack.apply(&[Slot::Reference, Slot::Int], Some(Slot::Reference))),
+            Instruction::Iaload | Instruction::Baload | Instruction::Caload | Instruction::Saload =>
+                try!(stack.apply(&[Slot::Reference, Slot::Int], Some(Slot::Int))),
+            Instruction::Laload => try!(stack.apply(&[Slot::Reference, Slot::Int], Some(Slot::Long))),
+            Instruction::Faload => try!(stack.apply(&[Slot::Reference, Slot::Int], Some(Slot::Float))),
+            Instruction::Daload => try!(stack.apply(&[Slot::Reference, Slot::Int], Some(Slot::Double))),
+            Instruction::Iastore | Instruction::Bastore | Instruction::Castore | Instruction::Sastore =>
+                try!(stack.apply(&[Slot::Reference, Slot::Int, Slot::Int], None)),
+            Instruction::Lastore => try!(stack.apply(&[Slot::Reference, Slot::Int, Slot::Long], None)),
+            Instruction::Fastore => try!(stack.apply(&[Slot::Reference, Slot::Int, Slot::Float], None)),
+            Instruction::Dastore => try!(stack.apply(&[Slot::Reference, Slot::Int, Slot::Double], None)),
+            Instruction::Aastore =>
+                try!(stack.apply(&[Slot::Reference, Slot::Int, Slot::Reference], None)),
+            Instruction::ArrayLength => try!(stack.apply(&[Slot::Reference], Some(Slot::Int))),
+            Instruction::NewArray(_) | Instruction::ANewArray(_) =>
+                try!(stack.apply(&[Slot::Int], Some(Slot::Reference))),
+            Instruction::MultiANewArray(_, dimensions) => {
+                for _ in 0..dimensions {
+                    try!(stack.pop(Slot::Int));
+                }
+                stack.push(Slot::Reference);
+            },
+            Instruction::CheckCast(_) =>
+                try!(stack.apply(&[Slot::Reference], Some(Slot::Reference))),
+            Instruction::InstanceOf(_) => try!(stack.apply(&[Slot::Reference], Some(Slot::Int))),
+
+            Instruction::LoadConstant(index) => stack.push(loaded_slot(classfile, index as u16)),
+            Instruction::LdcW(index) | Instruction::Ldc2W(index) =>
+                stack.push(loaded_slot(classfile, index)),
+
+            Instruction::GetStatic(index) =>
+                stack.push(parse_field_descriptor(classfile.member_descriptor(index))),
+            Instruction::PutStatic(index) =>
+                try!(stack.pop(parse_field_descriptor(classfile.member_descriptor(index)))),
+            Instruction::GetField(index) => {
+                let field = parse_field_descriptor(classfile.member_descriptor(index));
+                try!(stack.apply(&[Slot::Reference], Some(field)));
+            },
+            Instruction::PutField(index) => {
+                let field = parse_field_descriptor(classfile.member_descriptor(index));
+                try!(stack.apply(&[Slot::Reference, field], None));
+            },
+            Instruction::InvokeVirtual(index) | Instruction::InvokeSpecial(index) |
+            Instruction::InvokeInterface(index, _) | Instruction::InvokeStatic(index) |
+            Instruction::InvokeDynamic(index) => {
+                let (mut arguments, returned) =
+                    parse_method_descriptor(classfile.member_descriptor(index));
+                match *instruction {
+                    Instruction::InvokeStatic(_) | Instruction::InvokeDynamic(_) => {},
+                    _ => arguments.insert(0, Slot::Reference),
+                }
+                try!(stack.apply(&arguments, returned));
+            },
+
+            Instruction::Pop => { try!(stack.take(1)); },
+            Instruction::Pop2 => { try!(stack.take(2)); },
+            Instruction::Dup | Instruction::Dup2 => {
+                let top = try!(stack.take(if *instruction == Instruction::Dup { 1 } else { 2 }));
+                stack.slots.extend(top.iter().cloned());
+                stack.slots.extend(top);
+            },
+            Instruction::DupX1 | Instruction::DupX2 | Instruction::Dup2X1 |
+            Instruction::Dup2X2 | Instruction::Swap => {
+                let (top_size, below_size) = match *instruction {
+                    Instruction::DupX1 | Instruction::Swap => (1, 1),
+                    Instruction::DupX2 => (1, 2),
+                    Instruction::Dup2X1 => (2, 1),
+                    _ => (2, 2),
+                };
+                let top = try!(stack.take(top_size));
+                let below = try!(stack.take(below_size));
+                if *instruction != Instruction::Swap {
+                    stack.slots.extend(top.iter().cloned());
+                }
+                stack.slots.extend(below);
+                stack.slots.extend(top);
+            },
+
+            Instruction::Raw(_) => return Err(StackError::Mismatch("can't check raw bytes".to_owned())),
+        }
+        Ok(())
+    }
+
+    // abstract and native methods have no body, so they mustn't get a Code attribute
+    fn done_without_code(self) {
+        let mut method_attributes = vec![];
+        push_method_attributes(self.classfile, &mut method_attributes, self.exceptions,
+                               self.deprecated, self.synthetic, self.annotations, self.signature);
+        let method = Method::new(self.access_flags, self.name_index, self.descriptor_index,
+                                 method_attributes);
+        self.classfile.methods.push(method);
+    }
+
+    // A peephole pass over the instructions so far: drops gotos that jump to the
+    // very next instruction, pushes that are immediately popped, and folds int
+    // arithmetic on two constants into a single push. Nothing that a label points
+    // into or that sits inside an exception handler's range is touched, so the
+    // method behaves exactly as before. Removed instructions take up no space, so
+    // labels and frames are mapped onto the shorter code by done().
+    pub fn optimize(&mut self) -> &mut Self {
+        let label_pcs: HashSet<u16> = self.labels.values().cloned().collect();
+        let mut protected = vec![];
+        for h in &self.exception_handlers {
+            let start = self.labels.get(&(h.start, h.env));
+            let end = self.labels.get(&(h.end, h.env));
+            if let (Some(&start), Some(&end)) = (start, end) {
+                protected.push((start, end));
+            }
+        }
+        let touchable = |pc: u16| !protected.iter().any(|&(start, end)| pc >= start && pc < end);
+
+        loop {
+            let kept: Vec<usize> = (0..self.instructions.len())
+                .filter(|&i| !self.instructions[i].1.is_removed())
+                .collect();
+            let pc_of = |n: usize| kept.get(n).map_or(self.stack_index, |&i| self.instructions[i].0);
+            let mut changed = false;
+
+            for n in 0..kept.len() {
+                let (a, b, c) = (kept[n], kept.get(n + 1).cloned(), kept.get(n + 2).cloned());
+                let pc = self.instructions[a].0;
+
+                // goto the next instruction
+                if let IntermediateInstruction::Waiting(l, e, Instruction::Goto(_)) = self.instructions[a].1 {
+                    if let Some(&target) = self.labels.get(&(l, e)) {
+                        if target > pc && target <= pc_of(n + 1) && touchable(pc) {
+                            self.instructions[a].1.remove();
+                            changed = true;
+                            break;
+                        }
+                    }
+                }
+
+                let b = match b {
+                    Some(b) if !label_pcs.contains(&self.instructions[b].0) => b,
+                    _ => continue,
+                };
+                if !touchable(pc) || !touchable(self.instructions[b].0) {
+                    continue;
+                }
+
+                // a push that's popped straight away
+                let width = match *self.instructions[b].1.instruction() {
+                    Instruction::Pop => 1,
+                    Instruction::Pop2 => 2,
+                    _ => 0,
+                };
+                if width != 0 && self.pushed_without_side_effects(self.instructions[a].1.instruction()) == Some(width) {
+                    self.instructions[a].1.remove();
+                    self.instructions[b].1.remove();
+                    changed = true;
+                    break;
+                }
+
+                // arithmetic on two int constants
+                let c = match c {
+                    Some(c) if !label_pcs.contains(&self.instructions[c].0) && touchable(self.instructions[c].0) => c,
+                    _ => continue,
+                };
+                let x = self.int_constant(self.instructions[a].1.instruction());
+                let y = self.int_constant(self.instructions[b].1.instruction());
+                if let (Some(x), Some(y)) = (x, y) {
+                    let folded = match *self.instructions[c].1.instruction() {
+                        Instruction::Iadd => Some(x.wrapping_add(y)),
+                        Instruction::Isub => Some(x.wrapping_sub(y)),
+                        Instruction::Imul => Some(x.wrapping_mul(y)),
+                        Instruction::Iand => Some(x & y),
+                        Instruction::Ior => Some(x | y),
+                        Instruction::Ixor => Some(x ^ y),
+                        _ => None,
+                    };
+                    if let Some(value) = folded {
+                        let instruction = int_push_instruction(self.classfile, value);
+                        self.instructions[a].1 = IntermediateInstruction::Ready(instruction);
+                        self.instructions[b].1.remove();
+                        self.instructions[c].1.remove();
+                        changed = true;
+                        break;
+                    }
+                }
+            }
+
+            if !changed {
+                return self;
+            }
+        }
+    }
+
+    // the number of slots `instruction` pushes, if it does nothing but push
+    fn pushed_without_side_effects(&self, instruction: &Instruction) -> Option<u8> {
+        match *instruction {
+            Instruction::IconstM1 | Instruction::Iconst0 | Instruction::Iconst1 |
+            Instruction::Iconst2 | Instruction::Iconst3 | Instruction::Iconst4 |
+            Instruction::Iconst5 | Instruction::Bipush(_) | Instruction::Sipush(_, _) |
+            Instruction::Fconst0 | Instruction::Fconst1 | Instruction::Fconst2 |
+            Instruction::AConstNull | Instruction::Dup |
+            Instruction::Iload0 | Instruction::Iload1 | Instruction::Iload2 |
+            Instruction::Iload3 | Instruction::Iload(_) | Instruction::WideIload(_) |
+            Instruction::Fload0 | Instruction::Fload1 | Instruction::Fload2 |
+            Instruction::Fload3 | Instruction::Fload(_) | Instruction::WideFload(_) |
+            Instruction::Aload0 | Instruction::Aload1 | Instruction::Aload2 |
+            Instruction::Aload3 | Instruction::Aload(_) | Instruction::WideAload(_) => Some(1),
+            Instruction::Lconst0 | Instruction::Lconst1 |
+            Instruction::Dconst0 | Instruction::Dconst1 |
+            Instruction::Lload0 | Instruction::Lload1 | Instruction::Lload2 |
+            Instruction::Lload3 | Instruction::Lload(_) | Instruction::WideLload(_) |
+            Instruction::Dload0 | Instruction::Dload1 | Instruction::Dload2 |
+            Instruction::Dload3 | Instruction::Dload(_) | Instruction::WideDload(_) |
+            Instruction::Ldc2W(_) => Some(2),
+            // loading a class, method type or method handle can fail, so only
+            // plain values count
+            Instruction::LoadConstant(index) => self.constant_width(index as u16),
+            Instruction::LdcW(index) => self.constant_width(index),
+            _ => None,
+        }
+    }
+
+    fn constant_width(&self, index: u16) -> Option<u8> {
+        match self.classfile.constants.get(index as usize - 1) {
+            Some(&Constant::Integer(_)) | Some(&Constant::Float(_)) |
+            Some(&Constant::String(_)) => Some(1),
+            _ => None,
+        }
+    }
+
+    // the value `instruction` pushes, if it's an int constant
+    fn int_constant(&self, instruction: &Instruction) -> Option<i32> {
+        let index = match *instruction {
+            Instruction::IconstM1 => return Some(-1),
+            Instruction::Iconst0 => return Some(0),
+            Instruction::Iconst1 => return Some(1),
+            Instruction::Iconst2 => return Some(2),
+            Instruction::Iconst3 => return Some(3),
+            Instruction::Iconst4 => return Some(4),
+            Instruction::Iconst5 => return Some(5),
+            Instruction::Bipush(b) => return Some(b as i8 as i32),
+            Instruction::Sipush(high, low) => return Some(((high as u16) << 8 | low as u16) as i16 as i32),
+            Instruction::LoadConstant(index) => index as u16,
+            Instruction::LdcW(index) => index,
+            _ => return None,
+        };
+        match self.classfile.constants.get(index as usize - 1) {
+            Some(&Constant::Integer(n)) => Some(n),
+            _ => None,
+        }
+    }
+
+    pub fn done(self) -> Result<(), AssemblerError> {
+        if self.access_flags & (ACC_ABSTRACT | ACC_NATIVE) != 0 {
+            self.done_without_code();
+            return Ok(());
+        }
+
+        if let Some(error) = self.misuse.clone() {
+            return Err(error);
+        }
+        if let Some(error) = self.stack_split.clone() {
+            return Err(error);
+        }
+        if self.code_too_large {
+            let name = self.classfile.lookup_utf8(self.name_index).to_owned();
+            return Err(AssemblerError::MethodTooLarge(name));
+        }
+
+        if self.strict {
+            try!(self.verify());
+        }
+
+        try!(self.check_labels());
+        try!(self.check_local_scopes());
+        try!(self.check_ending());
+        try!(self.check_returns());
+
+        let classfile = self.classfile;
+        let labels = self.labels;
+        let instructions = self.instructions;
+        // check_labels has made sure every label used is defined
+        let label_pc = |l: LabelName, e: u16| *labels.get(&(l, e)).unwrap();
+
+        // Lay out the code, promoting any goto or jsr whose offset doesn't fit in
+        // 16 bits to a goto_w or jsr_w. Promotion shifts every later instruction,
+        // which can push other branches out of range, so keep going until nothing
+        // else changes.
+        let mut promoted = vec![false; instructions.len()];
+        let mut layout = lay_out(&instructions, &promoted, self.stack_index);
+        loop {
+            let mut changed = false;
+            for (i, &(_, ref ir)) in instructions.iter().enumerate() {
+                if let IntermediateInstruction::Waiting(l, e, Instruction::Goto(_)) |
+                       IntermediateInstruction::Waiting(l, e, Instruction::Jsr(_)) = *ir {
+                    let offset = layout.pc_of(label_pc(l, e)) as i32 - layout.pcs[i] as i32;
+                    if !promoted[i] && !fits_in_i16(offset) {
+                        promoted[i] = true;
+                        changed = true;
+                    }
+                }
+            }
+
+            if !changed {
+                break;
+            }
+            layout = lay_out(&instructions, &promoted, self.stack_index);
+        }
+        // widening branches can push code that fit over the limit
+        if layout.length > ::std::u16::MAX as u32 {
+            let name = classfile.lookup_utf8(self.name_index).to_owned();
+            return Err(AssemblerError::MethodTooLarge(name));
+        }
+
+        // Offsets are relative to the branch itself, so they're negative for loops.
+        // Only goto and jsr can be widened, so a conditional branch that's too far
+        // away is an error.
+        let real_instructions = try!(instructions.into_iter().enumerate().filter(|&(_, (_, ref ir))| !ir.is_removed()).map(|(i, (_, ir))| {
+            let pos = layout.pcs[i] as i32;
+            Ok(match ir {
+                IntermediateInstruction::Ready(i) | IntermediateInstruction::Removed(i) => i,
+                IntermediateInstruction::Waiting(l, e, inst) => {
+                    let offset = layout.pc_of(label_pc(l, e)) as i32 - pos;
+                    if promoted[i] {
+                        match inst {
+                            Instruction::Jsr(_) => Instruction::JsrW(offset as u32),
+                            _ => Instruction::GotoW(offset as u32),
+                        }
+                    } else if !fits_in_i16(offset) {
+                        return Err(AssemblerError::BranchOutOfRange(l.to_string(), offset));
+                    } else {
+                        fill_offset(inst, offset)
+                    }
+                },
+                IntermediateInstruction::WaitingSwitch(default, targets, e, inst) => {
+                    let offset_of = |l: LabelName| layout.pc_of(label_pc(l, e)) as i32 - pos;
+                    let default_offset = offset_of(default);
+                    let offsets = targets.iter().map(|&l| offset_of(l)).collect();
+                    fill_switch_offsets(inst, default_offset, offsets)
+                },
+            })
+        }).collect::<Result<Vec<_>, _>>());
+
+        let exception_table = self.exception_handlers.iter().map(|h| {
+            ExceptionTableEntry {
+                start_pc: layout.pc_of(label_pc(h.start, h.env)),
+                end_pc: layout.pc_of(label_pc(h.end, h.env)),
+                handler_pc: layout.pc_of(label_pc(h.handler, h.env)),
+                catch_type: h.catch_type,
+            }
+        }).collect();
+
+        // uninitialized objects are identified by the PC of their `new` instruction
+        let relocate = |types: Vec<VerificationType>| -> Vec<VerificationType> {
+            types.into_iter().map(|t| match t {
+                VerificationType::Uninitialized(new_pc) =>
+                    VerificationType::Uninitialized(layout.pc_of(new_pc)),
+                t => t,
+            }).collect()
+        };
+        // a label after the last instruction doesn't start any code that needs a frame
+        let end_pc = self.stack_index;
+        let frames = self.stack_frames.into_iter()
+            .filter(|&(pc, _, _)| pc < end_pc)
+            .map(|(pc, locals, stack)| (layout.pc_of(pc), relocate(locals), relocate(stack)))
+            .collect();
+        
+        let mut code_attributes = vec![];
+        if classfile.major_version > 50 {
+            let stack_map_table_index = classfile.define_utf8("StackMapTable");
+            code_attributes.push(Attribute::StackMapTable(stack_map_table_index,
+                                                          build_stack_map_frames(&self.initial_locals, frames)));
+        }
+
+        // a line marked after the last instruction doesn't cover any code either
+        let line_numbers: Vec<LineNumberTableEntry> = self.line_numbers.into_iter()
+            .filter(|&(pc, _)| pc < end_pc)
+            .map(|(pc, line)| LineNumberTableEntry { start_pc: layout.pc_of(pc), line_number: line })
+            .collect();
+        if !line_numbers.is_empty() {
+            let line_number_table_index = classfile.define_utf8("LineNumberTable");
+            code_attributes.push(Attribute::LineNumberTable(line_number_table_index, line_numbers));
+        }
+
+        let local_variables: Vec<LocalVariableTableEntry> = self.local_variables.iter().map(|v| {
+            let start_pc = layout.pc_of(label_pc(v.start, v.env));
+            let end_pc = layout.pc_of(label_pc(v.end, v.env));
+            LocalVariableTableEntry {
+                start_pc: start_pc,
+                length: end_pc - start_pc,
+                name_index: v.name_index,
+                descriptor_index: v.descriptor_index,
+                index: v.slot,
+            }
+        }).collect();
+        if !local_variables.is_empty() {
+            let local_variable_table_index = classfile.define_utf8("LocalVariableTable");
+            code_attributes.push(Attribute::LocalVariableTable(local_variable_table_index,
+                                                               local_variables));
+        }
+
+        let code_index = classfile.define_utf8("Code");
+        let code = Attribute::Code(code_index, self.max_stack_depth, self.num_locals,
+                                   real_instructions, exception_table, code_attributes);
+
+        let mut method_attributes = vec![code];
+        push_method_attributes(classfile, &mut method_attributes, self.exceptions,
+                               self.deprecated, self.synthetic, self.annotations, self.signature);
+
+        let method = Method::new(self.access_flags, self.name_index, self.descriptor_index,
+                                 method_attributes);
+        classfile.methods.push(method);
+        Ok(())
+    }
+
+    // every label that's branched to or used by an exception handler or local
+    // variable has to be defined somewhere in the same environment
+    fn check_labels(&self) -> Result<(), AssemblerError> {
+        let mut used = vec![];
+        for &(_, ref ir) in &self.instructions {
+            match *ir {
+                IntermediateInstruction::Ready(_) | IntermediateInstruction::Removed(_) => {},
+                IntermediateInstruction::Waiting(label, env, _) => used.push((label, env)),
+                IntermediateInstruction::WaitingSwitch(default, ref targets, env, _) => {
+                    used.push((default, env));
+                    used.extend(targets.iter().map(|&label| (label, env)));
+                },
+            }
+        }
+        for h in &self.exception_handlers {
+            used.extend(vec![(h.start, h.env), (h.end, h.env), (h.handler, h.env)]);
+        }
+        for v in &self.local_variables {
+            used.extend(vec![(v.start, v.env), (v.end, v.env)]);
+        }
+
+        if let Some((label, env)) = used.into_iter().find(|&(label, env)| !self.labels.contains_key(&(label, env))) {
+            return Err(AssemblerError::UndefinedLabel(label.to_string(), env));
+        }
+
+        // labels made by branch_to_pc can point anywhere, but code can only
+        // jump to the start of an instruction or just past the last one
+        let boundaries: HashSet<u16> = self.instructions.iter().map(|&(pc, _)| pc)
+            .chain(Some(self.stack_index)).collect();
+        match self.labels.values().filter(|pc| !boundaries.contains(pc)).min() {
+            Some(&pc) => Err(AssemblerError::InvalidBranchTarget(pc)),
+            None => Ok(()),
+        }
+    }
+
+    // a local declared with declare_local must end at or after where it starts
+    fn check_local_scopes(&self) -> Result<(), AssemblerError> {
+        for v in &self.local_variables {
+            if self.labels[&(v.end, v.env)] < self.labels[&(v.start, v.env)] {
+                let name = self.classfile.lookup_utf8(v.name_index).to_owned();
+                return Err(AssemblerError::LocalEndsBeforeStart(name));
+            }
+        }
+        Ok(())
+    }
+}
+
+// the attributes a method gets whether or not it has code
+fn push_method_attributes(classfile: &mut ClassBuilder, attributes: &mut Vec<Attribute>,
+                          exceptions: Vec<u16>, deprecated: bool, synthetic: bool,
+                          annotations: Vec<Annotation>, signature: Option<u16>) {
+    if !exceptions.is_empty() {
+        let exceptions_index = classfile.define_utf8("Exceptions");
+        attributes.push(Attribute::Exceptions(exceptions_index, exceptions));
+    }
+    if deprecated {
+        let deprecated_index = classfile.define_utf8("Deprecated");
+        attributes.push(Attribute::Deprecated(deprecated_index));
+    }
+    if synthetic {
+        let synthetic_index = classfile.define_utf8("Synthetic");
+        attributes.push(Attribute::Synthetic(synthetic_index));
+    }
+    if !annotations.is_empty() {
+        let annotations_index = classfile.define_utf8("RuntimeVisibleAnnotations");
+        attributes.push(Attribute::RuntimeVisibleAnnotations(annotations_index, annotations));
+    }
+    if let Some(signature_index) = signature {
+        let name_index = classfile.define_utf8("Signature");
+        attributes.push(Attribute::Signature(name_index, signature_index));
+    }
+}
+
+impl<'a> IntermediateInstruction<'a> {
+    fn instruction(&self) -> &Instruction {
+        match *self {
+            IntermediateInstruction::Ready(ref i) => i,
+            IntermediateInstruction::Waiting(_, _, ref i) => i,
+            IntermediateInstruction::WaitingSwitch(_, _, _, ref i) => i,
+            IntermediateInstruction::Removed(ref i) => i,
+        }
+    }
+
+    fn is_removed(&self) -> bool {
+        match *self {
+            IntermediateInstruction::Removed(_) => true,
+            _ => false,
+        }
+    }
+
+    fn remove(&mut self) {
+        let instruction = match ::std::mem::replace(self, IntermediateInstruction::Removed(Instruction::Nop)) {
+            IntermediateInstruction::Ready(i) => i,
+            IntermediateInstruction::Waiting(_, _, i) => i,
+            IntermediateInstruction::WaitingSwitch(_, _, _, i) => i,
+            IntermediateInstruction::Removed(i) => i,
+        };
+        *self = IntermediateInstruction::Removed(instruction);
+    }
+}
+
+// the final PC of each instruction, after any gotos have been widened
+struct Layout {
+    pcs: Vec<u16>,
+    new_pcs: HashMap<u16, u16>,
+    length: u32,
+}
+
+impl Layout {
+    // maps a PC recorded while building the method onto the final layout; every
+    // such PC is an instruction boundary, which check_labels makes sure of
+    fn pc_of(&self, provisional_pc: u16) -> u16 {
+        *self.new_pcs.get(&provisional_pc).unwrap()
+    }
+}
+
+fn lay_out(instructions: &[(u16, IntermediateInstruction)], promoted: &[bool],
+           end_pc: u16) -> Layout {
+    let mut pcs = vec![];
+    let mut new_pcs = HashMap::new();
+    // counted in a u32 so that done() can tell when the code got too long
+    let mut length: u32 = 0;
+    for (i, &(provisional_pc, ref ir)) in instructions.iter().enumerate() {
+        let pc = length as u16;
+        pcs.push(pc);
+        new_pcs.insert(provisional_pc, pc);
+        length += if ir.is_removed() {
+            0
+        } else if promoted[i] {
+            Instruction::GotoW(0).size(pc) // the same size as jsr_w
+        } else {
+            ir.instruction().size(pc)
+        };
+    }
+    // labels can also point just past the last instruction
+    new_pcs.insert(end_pc, length as u16);
+    Layout { pcs: pcs, new_pcs: new_pcs, length: length }
+}
+
+// the shortest instruction that pushes `value`, as push_int would emit it
+fn int_push_instruction(classfile: &mut ClassBuilder, value: i32) -> Instruction {
+    match value {
+        -1 => Instruction::IconstM1,
+        0 => Instruction::Iconst0,
+        1 => Instruction::Iconst1,
+        2 => Instruction::Iconst2,
+        3 => Instruction::Iconst3,
+        4 => Instruction::Iconst4,
+        5 => Instruction::Iconst5,
+        -128..=127 => Instruction::Bipush(value as u8),
+        -32768..=32767 => Instruction::Sipush((value >> 8) as u8, value as u8),
+        _ => {
+            let index = classfile.define_integer(value);
+            if index > ::std::u8::MAX as u16 {
+                Instruction::LdcW(index)
+            } else {
+                Instruction::LoadConstant(index as u8)
+            }
+        },
+    }
+}
+
+fn fits_in_i16(n: i32) -> bool {
+    n >= ::std::i16::MIN as i32 && n <= ::std::i16::MAX as i32
+}
+
+// Turns (pc, locals, stack) snapshots into delta-encoded stack map frames. Each
+// frame is described relative to the previous one, starting from the locals the
+// method is entered with.
+fn build_stack_map_frames(initial_locals: &[VerificationType],
+                          mut frames: Vec<(u16, Vec<VerificationType>, Vec<VerificationType>)>)
+                          -> Vec<StackMapFrame> {
+    // only one frame is allowed per PC, and the last one recorded there wins
+    frames.reverse();
+    frames.sort_by_key(|&(pc, _, _)| pc);
+    frames.dedup_by_key(|&mut (pc, _, _)| pc);
+
+    let mut last_pc = None;
+    let mut last_locals = frame_locals(initial_locals);
+    frames.into_iter().map(|(pc, locals, stack)| {
+        let offset = match last_pc {
+            Some(i) => pc - i - 1,
+            None => pc
+        };
+        last_pc = Some(pc);
+
+        let locals = frame_locals(&locals);
+        let mut stack = frame_types(&stack);
+        let frame = if locals == last_locals && stack.is_empty() {
+            if offset > 63 {
+                StackMapFrame::SameFrameExtended(offset)
+            } else {
+                StackMapFrame::SameFrame(offset as u8)
+            }
+        } else if locals == last_locals && stack.len() == 1 {
+            let top = stack.pop().unwrap();
+            if offset > 63 {
+                StackMapFrame::SameLocals1StackItemFrameExtended(offset, top)
+            } else {
+                StackMapFrame::SameLocals1StackItemFrame(offset as u8, top)
+            }
+        } else if stack.is_empty() && locals.len() > last_locals.len() &&
+                  locals.len() - last_locals.len() <= 3 && locals.starts_with(&last_locals) {
+            let added = locals[last_locals.len()..].to_vec();
+            StackMapFrame::AppendFrame(added.len() as u8, offset, added)
+        } else if stack.is_empty() && last_locals.len() > locals.len() &&
+                  last_locals.len() - locals.len() <= 3 && last_locals.starts_with(&locals) {
+            StackMapFrame::ChopFrame((last_locals.len() - locals.len()) as u8, offset)
+        } else {
+            StackMapFrame::FullFrame(offset, locals.clone(), stack)
+        };
+        last_locals = locals;
+        frame
+    }).collect()
+}
+
+// Frames list a long or double once, where the builder tracks it as the type
+// followed by Top for its second slot.
+fn frame_types(slots: &[VerificationType]) -> Vec<VerificationType> {
+    let mut types = vec![];
+    let mut skip_next = false;
+    for t in slots {
+        if skip_next {
+            skip_next = false;
+            continue;
+        }
+        skip_next = *t == VerificationType::Long || *t == VerificationType::Double;
+        types.push(t.clone());
+    }
+    types
+}
+
+// unset locals at the end don't need to be listed
+fn frame_locals(slots: &[VerificationType]) -> Vec<VerificationType> {
+    let mut types = frame_types(slots);
+    while types.last() == Some(&VerificationType::Top) {
+        types.pop();
+    }
+    types
+}
+
+// done() has already checked the offset fits (promoting goto and jsr when it
+// doesn't), and only branches are left waiting for one, so neither panic can
+// be reached from the public API.
+fn fill_offset(instruction: Instruction, offset: i32) -> Instruction {
+    if !fits_in_i16(offset) {
+        panic!("Branch offset {} doesn't fit in 16 bits: {}", offset, instruction.mnemonic())
+    }
+    // branch offsets are signed, so store them in two's complement
+    let offset = offset as i16 as u16;
+    match instruction {
+        Instruction::IfEq(_) => Instruction::IfEq(offset),
+        Instruction::IfNe(_) => Instruction::IfNe(offset),
+        Instruction::IfLt(_) => Instruction::IfLt(offset),
+        Instruction::IfGe(_) => Instruction::IfGe(offset),
+        Instruction::IfGt(_) => Instruction::IfGt(offset),
+        Instruction::IfLe(_) => Instruction::IfLe(offset),
+        Instruction::IfIcmpEq(_) => Instruction::IfIcmpEq(offset),
+        Instruction::IfIcmpNe(_) => Instruction::IfIcmpNe(offset),
+        Instruction::IfIcmpLt(_) => Instruction::IfIcmpLt(offset),
+        Instruction::IfIcmpGe(_) => Instruction::IfIcmpGe(offset),
+        Instruction::IfIcmpGt(_) => Instruction::IfIcmpGt(offset),
+        Instruction::IfIcmpLe(_) => Instruction::IfIcmpLe(offset),
+        Instruction::IfAcmpEq(_) => Instruction::IfAcmpEq(offset),
+        Instruction::IfAcmpNe(_) => Instruction::IfAcmpNe(offset),
+        Instruction::IfNull(_) => Instruction::IfNull(offset),
+        Instruction::IfNonNull(_) => Instruction::IfNonNull(offset),
+        Instruction::Goto(_) => Instruction::Goto(offset),
+        Instruction::Jsr(_) => Instruction::Jsr(offset),
+        _ => panic!("Instruction type doesn't have an offset to fill: {}", instruction.mnemonic())
+    }
+}
+
+// only called for the switches tableswitch and lookupswitch left waiting
+fn fill_switch_offsets(instruction: Instruction, default: i32, offsets: Vec<i32>) -> Instruction {
+    match instruction {
+        Instruction::TableSwitch(_, low, high, _) => Instruction::TableSwitch(default, low, high, offsets),
+        Instruction::LookupSwitch(_, pairs) => {
+            let pairs = pairs.into_iter().zip(offsets).map(|((key, _), offset)| (key, offset)).collect();
+            Instruction::LookupSwitch(default, pairs)
+        },
+        _ => panic!("Instruction type isn't a switch: {}", instruction.mnemonic())
+    }
+}
+
+// what verify() knows about a value on the operand stack; longs and doubles
+// are followed by a Top, since they take up two slots
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Slot {
+    Int,
+    Float,
+    Long,
+    Double,
+    Reference,
+    ReturnAddress,
+    Top,
+}
+
+// why simulate() rejected an instruction; verify() adds where it happened
+enum StackError {
+    Underflow,
+    Mismatch(String),
+}
+
+struct SimulatedStack {
+    slots: Vec<Slot>,
+}
+
+impl SimulatedStack {
+    fn push(&mut self, slot: Slot) {
+        self.slots.push(slot);
+        if slot == Slot::Long || slot == Slot::Double {
+            self.slots.push(Slot::Top);
+        }
+    }
+
+    fn pop(&mut self, expected: Slot) -> Result<(), StackError> {
+        let size = if expected == Slot::Long || expected == Slot::Double { 2 } else { 1 };
+        if size == 1 && self.slots.last() == Some(&Slot::Top) {
+            let found = self.slots[self.slots.len() - 2];
+            return Err(StackError::Mismatch(format!("expected {:?} on the stack, found {:?}", expected, found)));
+        }
+        let found = try!(self.take(size));
+        if found[0] != expected {
+            return Err(StackError::Mismatch(format!("expected {:?} on the stack, found {:?}", expected, found[0])));
+        }
+        Ok(())
+    }
+
+    // pops the operands (given in the order they were pushed) and pushes the result
+    fn apply(&mut self, operands: &[Slot], result: Option<Slot>) -> Result<(), StackError> {
+        for &operand in operands.iter().rev() {
+            try!(self.pop(operand));
+        }
+        if let Some(slot) = result {
+            self.push(slot);
+        }
+        Ok(())
+    }
+
+    // removes the top n slots, refusing to split a long or double in half
+    fn take(&mut self, n: usize) -> Result<Vec<Slot>, StackError> {
+        if self.slots.len() < n {
+            return Err(StackError::Underflow);
+        }
+        let split_at = self.slots.len() - n;
+        if self.slots[split_at] == Slot::Top {
+            return Err(StackError::Mismatch("would split a long or double on the stack".to_owned()));
+        }
+        Ok(self.slots.split_off(split_at))
+    }
+}
+
+fn loaded_slot(classfile: &ClassBuilder, index: u16) -> Slot {
+    match *classfile.lookup_constant(index) {
+        Constant::Integer(_) => Slot::Int,
+        Constant::Float(_) => Slot::Float,
+        Constant::Long(_) => Slot::Long,
+        Constant::Double(_) => Slot::Double,
+        _ => Slot::Reference,
+    }
+}
+
+fn parse_field_descriptor(descriptor: &str) -> Slot {
+    match descriptor.chars().next() {
+        Some('J') => Slot::Long,
+        Some('F') => Slot::Float,
+        Some('D') => Slot::Double,
+        Some('L') | Some('[') => Slot::Reference,
+        _ => Slot::Int,
+    }
+}
+
+// the return instruction for a method returning `return_type`
+fn return_instruction(return_type: Option<Slot>) -> Instruction {
+    match return_type {
+        None => Instruction::Return,
+        Some(Slot::Long) => Instruction::Lreturn,
+        Some(Slot::Float) => Instruction::FReturn,
+        Some(Slot::Double) => Instruction::Dreturn,
+        Some(Slot::Reference) => Instruction::Areturn,
+        Some(_) => Instruction::IReturn,
     }
 }
 
-fn fill_offset(instruction: Instruction, offset: u16) -> Instruction {
-    match instruction {
-        Instruction::IfEq(_) => Instruction::IfEq(offset),
-        Instruction::IfNe(_) => Instruction::IfNe(offset),
-        Instruction::IfLt(_) => Instruction::IfLt(offset),
-        Instruction::IfGe(_) => Instruction::IfGe(offset),
-        Instruction::IfGt(_) => Instruction::IfGt(offset),
-        Instruction::IfLe(_) => Instruction::IfLe(offset),
-        Instruction::IfIcmpEq(_) => Instruction::IfIcmpEq(offset),
-        Instruction::IfIcmpNe(_) => Instruction::IfIcmpNe(offset),
-        Instruction::IfIcmpLt(_) => Instruction::IfIcmpLt(offset),
-        Instruction::IfIcmpGe(_) => Instruction::IfIcmpGe(offset),
-        Instruction::IfIcmpGt(_) => Instruction::IfIcmpGt(offset),
-        Instruction::IfIcmpLe(_) => Instruction::IfIcmpLe(offset),
-        Instruction::Goto(_) => Instruction::Goto(offset),
-        _ => panic!("Instruction type doesn't have an offset to fill: {:?}", instruction)
+// splits a method descriptor into the slots of its arguments and return value
+fn parse_method_descriptor(descriptor: &str) -> (Vec<Slot>, Option<Slot>) {
+    let mut arguments = vec![];
+    let mut chars = descriptor.chars().skip(1).peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            ')' => break,
+            'L' => {
+                chars.by_ref().take_while(|&c| c != ';').count();
+                arguments.push(Slot::Reference);
+            },
+            '[' => {
+                while chars.peek() == Some(&'[') {
+                    chars.next();
+                }
+                if chars.next() == Some('L') {
+                    chars.by_ref().take_while(|&c| c != ';').count();
+                }
+                arguments.push(Slot::Reference);
+            },
+            c => arguments.push(parse_field_descriptor(&c.to_string())),
+        }
+    }
+
+    let return_type: String = chars.collect();
+    if return_type == "V" {
+        (arguments, None)
+    } else {
+        (arguments, Some(parse_field_descriptor(&return_type)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // builds a class T with a single static method m, written by `body`
+    fn try_build<F>(argument_types: &[Java], return_type: &Java, body: F) -> Result<Classfile, AssemblerError>
+        where F: FnOnce(&mut MethodBuilder) {
+        let mut class = ClassBuilder::new(ACC_PUBLIC, "T", "java/lang/Object");
+        {
+            let mut method = class.define_method(ACC_PUBLIC | ACC_STATIC, "m", argument_types, return_type);
+            body(&mut method);
+            try!(method.done());
+        }
+        class.done()
+    }
+
+    fn build<F>(argument_types: &[Java], return_type: &Java, body: F) -> Classfile
+        where F: FnOnce(&mut MethodBuilder) {
+        try_build(argument_types, return_type, body).unwrap()
+    }
+
+    // the Code attribute of m
+    fn code_attribute(classfile: &Classfile) -> &Attribute {
+        classfile.methods[0].attributes.iter().find(|a| match **a {
+            Attribute::Code(..) => true,
+            _ => false,
+        }).unwrap()
+    }
+
+    fn code_of(classfile: &Classfile) -> Vec<Instruction> {
+        match *code_attribute(classfile) {
+            Attribute::Code(_, _, _, ref instructions, _, _) => instructions.clone(),
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    #[allow(deprecated)]
+    fn nyew_splits_the_index_into_high_and_low_bytes() {
+        let mut class = ClassBuilder::new(ACC_PUBLIC, "T", "java/lang/Object");
+        class.define_utf8("m");
+        class.define_utf8("()V");
+        // the Utf8 for the name lands at 0x0101 and the Class at 0x0102
+        while class.constant_count() < 0x0100 {
+            let filler = format!("filler{}", class.constant_count());
+            class.define_utf8(&filler);
+        }
+        {
+            let mut method = class.define_method(ACC_PUBLIC | ACC_STATIC, "m", &[], &Java::Void);
+            method.nyew("Foo").pop().do_return();
+            method.done().unwrap();
+        }
+        let classfile = class.done().unwrap();
+        assert_eq!(code_of(&classfile)[0], Instruction::New(0x01, 0x02));
+    }
+
+    fn max_stack(classfile: &Classfile) -> u16 {
+        match *code_attribute(classfile) {
+            Attribute::Code(_, max_stack, _, _, _, _) => max_stack,
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn max_stack_of_an_int_addition() {
+        let classfile = build(&[], &Java::Int, |m| {
+            m.iconst1().iconst2().iadd().ireturn();
+        });
+        assert_eq!(max_stack(&classfile), 2);
+    }
+
+    #[test]
+    fn adds_two_longs() {
+        let classfile = build(&[Java::Long, Java::Long], &Java::Long, |m| {
+            m.lload0().lload2().ladd().lreturn();
+        });
+        assert_eq!(code_of(&classfile),
+                   vec![Instruction::Lload0, Instruction::Lload2, Instruction::Ladd, Instruction::Lreturn]);
+        assert_eq!(max_stack(&classfile), 4);
+        assert_eq!(max_locals(&classfile), 4);
+    }
+
+    #[test]
+    fn doubles_take_two_stack_slots() {
+        let classfile = build(&[], &Java::Double, |m| {
+            m.dconst1().dconst1().dadd().dreturn();
+        });
+        assert_eq!(max_stack(&classfile), 4);
+    }
+
+    #[test]
+    fn a_long_takes_two_constant_pool_entries() {
+        let mut class = ClassBuilder::new(ACC_PUBLIC, "T", "java/lang/Object");
+        let long_index = class.define_long(1 << 40);
+        let double_index = class.define_double(0.5);
+        let next_index = class.define_integer(100000);
+        assert_eq!(double_index, long_index + 2);
+        assert_eq!(next_index, double_index + 2);
+        assert_eq!(class.constant_count(), next_index);
+    }
+
+    #[test]
+    fn large_longs_are_loaded_with_ldc2_w() {
+        let classfile = build(&[], &Java::Long, |m| {
+            m.push_long(1 << 40).lreturn();
+        });
+        let index = match code_of(&classfile)[0] {
+            Instruction::Ldc2W(index) => index,
+            ref other => panic!("expected ldc2_w, found {:?}", other),
+        };
+        assert_eq!(*classfile.lookup_constant(index), Constant::Long(1 << 40));
+    }
+
+    #[test]
+    fn constants_past_255_are_loaded_with_ldc_w() {
+        let classfile = build(&[], &Java::Void, |m| {
+            for i in 0..300 {
+                m.load_constant(&format!("string {}", i)).pop();
+            }
+            m.do_return();
+        });
+        let code = code_of(&classfile);
+        let index = match code[code.len() - 3] {
+            Instruction::LdcW(index) => index,
+            ref other => panic!("expected ldc_w, found {:?}", other),
+        };
+        match *classfile.lookup_constant(index) {
+            Constant::String(string_index) => assert_eq!(classfile.lookup_string(string_index), "string 299"),
+            ref other => panic!("expected a String, found {:?}", other),
+        }
+    }
+
+    #[test]
+    fn declares_a_static_field() {
+        let mut class = ClassBuilder::new(ACC_PUBLIC, "T", "java/lang/Object");
+        class.define_field(ACC_PUBLIC | ACC_STATIC, "count", &Java::Int).done();
+        let classfile = class.done().unwrap();
+        assert_eq!(classfile.fields.len(), 1);
+        let field = &classfile.fields[0];
+        assert_eq!(field.access_flags, ACC_PUBLIC | ACC_STATIC);
+        assert_eq!(classfile.lookup_string(field.name_index), "count");
+        assert_eq!(classfile.lookup_string(field.descriptor_index), "I");
+    }
+
+    #[test]
+    fn reads_and_writes_an_instance_field() {
+        let mut class = ClassBuilder::new(ACC_PUBLIC, "T", "java/lang/Object");
+        class.define_field(ACC_PRIVATE, "x", &Java::Int).done();
+        {
+            let mut method = class.define_method(ACC_PUBLIC, "increment", &[], &Java::Void);
+            method.aload0().aload0().get_field("T", "x", &Java::Int).iconst1().iadd();
+            assert_eq!(method.stack_depth(), 2);
+            method.put_field("T", "x", &Java::Int);
+            assert_eq!(method.stack_depth(), 0);
+            method.do_return();
+            method.done().unwrap();
+        }
+        let classfile = class.done().unwrap();
+        assert_eq!(max_stack(&classfile), 3);
+        let code = code_of(&classfile);
+        match (&code[2], &code[5]) {
+            (&Instruction::GetField(get), &Instruction::PutField(put)) => assert_eq!(get, put),
+            other => panic!("expected getfield and putfield, found {:?}", other),
+        }
+    }
+
+    #[test]
+    fn invoke_interface_counts_the_receiver() {
+        let classfile = build(&[Java::Class("java/util/List")], &Java::Int, |m| {
+            m.aload0().invoke_interface("java/util/List", "size", &[], &Java::Int).ireturn();
+        });
+        let instruction = code_of(&classfile)[1].clone();
+        let index = match instruction {
+            Instruction::InvokeInterface(index, 1) => index,
+            ref other => panic!("expected invokeinterface with a count of 1, found {:?}", other),
+        };
+        assert_eq!(instruction.size(1), 5);
+        match *classfile.lookup_constant(index) {
+            Constant::InterfaceMethodref(..) => {},
+            ref other => panic!("expected an InterfaceMethodref, found {:?}", other),
+        }
+    }
+
+    #[test]
+    fn new_int_array() {
+        let classfile = build(&[], &Java::Void, |m| {
+            m.iconst5().new_array(PrimitiveType::Int).pop().do_return();
+        });
+        assert_eq!(code_of(&classfile)[1], Instruction::NewArray(10));
+    }
+
+    #[test]
+    fn dup_x1_needs_a_third_slot() {
+        let classfile = build(&[], &Java::Void, |m| {
+            m.iconst1().iconst2().dup_x1();
+            assert_eq!(m.stack_depth(), 3);
+            m.pop().pop().pop().do_return();
+        });
+        assert_eq!(max_stack(&classfile), 3);
+    }
+
+    #[test]
+    fn ifnull_branches_forward() {
+        let classfile = build(&[Java::Class("java/lang/Object")], &Java::Int, |m| {
+            m.aload0().ifnull("null").iconst1().ireturn()
+                .label("null").iconst0().ireturn();
+        });
+        // ifnull is at pc 1 and the label at pc 6
+        assert_eq!(code_of(&classfile)[1], Instruction::IfNull(5));
+    }
+
+    fn exception_table(classfile: &Classfile) -> Vec<ExceptionTableEntry> {
+        match *code_attribute(classfile) {
+            Attribute::Code(_, _, _, _, ref exception_table, _) => exception_table.clone(),
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn catches_exception() {
+        let classfile = build(&[], &Java::Void, |m| {
+            m.label("start").iconst1().pop().label("end").do_return()
+                .try_catch("start", "end", "handler", Some("java/lang/Exception"))
+                .label("handler").athrow();
+        });
+        let table = exception_table(&classfile);
+        assert_eq!(table.len(), 1);
+        assert_eq!((table[0].start_pc, table[0].end_pc, table[0].handler_pc), (0, 2, 3));
+        match *classfile.lookup_constant(table[0].catch_type) {
+            Constant::Class(name) => assert_eq!(classfile.lookup_string(name), "java/lang/Exception"),
+            ref other => panic!("expected a Class, found {:?}", other),
+        }
+    }
+
+    #[test]
+    fn check_cast_and_instance_of() {
+        let mut string_index = 0;
+        let classfile = build(&[Java::Class("java/lang/Object")], &Java::Int, |m| {
+            string_index = match m.object_verification_type("java/lang/String") {
+                VerificationType::Object(index) => index,
+                _ => unreachable!(),
+            };
+            m.aload0().check_cast("java/lang/String");
+            assert_eq!(m.stack_types(), &[VerificationType::Object(string_index)]);
+            m.instance_of("java/lang/String");
+            assert_eq!(m.stack_types(), &[VerificationType::Integer]);
+            m.ireturn();
+        });
+        let code = code_of(&classfile);
+        assert_eq!(code[1], Instruction::CheckCast(string_index));
+        assert_eq!(code[2], Instruction::InstanceOf(string_index));
+    }
+
+    #[test]
+    fn synchronized_block() {
+        let classfile = build(&[Java::Class("java/lang/Object")], &Java::Void, |m| {
+            m.aload0().monitor_enter();
+            assert_eq!(m.stack_depth(), 0);
+            m.nop().aload0().monitor_exit();
+            assert_eq!(m.stack_depth(), 0);
+            m.do_return();
+        });
+        let code = code_of(&classfile);
+        assert_eq!(code[1], Instruction::MonitorEnter);
+        assert_eq!(code[4], Instruction::MonitorExit);
+        assert_eq!(max_stack(&classfile), 1);
+    }
+
+    #[test]
+    fn for_loop_with_iinc() {
+        let classfile = build(&[], &Java::Void, |m| {
+            m.iconst0().istore1()
+                .label("loop").iload1().bipush(10).if_icmp_ge("done")
+                .iinc(1, 1).goto("loop")
+                .label("done").do_return();
+        });
+        assert!(code_of(&classfile).contains(&Instruction::Iinc(0x01, 1)));
+    }
+
+    #[test]
+    fn lookupswitch_with_three_cases() {
+        let classfile = build(&[Java::Int], &Java::Int, |m| {
+            m.iload0().lookupswitch("default", &[(3, "c"), (1, "a"), (2, "b")])
+                .label("a").iconst1().ireturn()
+                .label("b").iconst2().ireturn()
+                .label("c").iconst3().ireturn()
+                .label("default").iconst0().ireturn();
+        });
+        let switch = code_of(&classfile)[1].clone();
+        // opcode, two bytes of padding to reach pc 4, then the default, the
+        // count and three pairs
+        assert_eq!(switch.size(1), 35);
+        assert_eq!(switch, Instruction::LookupSwitch(41, vec![(1, 35), (2, 37), (3, 39)]));
+    }
+
+    #[test]
+    fn tableswitch_without_labels() {
+        let result = try_build(&[Java::Int], &Java::Void, |m| {
+            m.iload0().tableswitch::<_, &str>("default", 5, &[])
+                .label("default").do_return();
+        });
+        assert_eq!(result.unwrap_err(), AssemblerError::EmptyTableSwitch(5));
+    }
+
+    #[test]
+    fn tableswitch_past_the_largest_key() {
+        let result = try_build(&[Java::Int], &Java::Void, |m| {
+            m.iload0().tableswitch("default", i32::MAX, &["a", "b"])
+                .label("a").label("b").label("default").do_return();
+        });
+        assert_eq!(result.unwrap_err(), AssemblerError::TableSwitchOutOfRange(i32::MAX, 2));
+    }
+
+    #[test]
+    fn lookupswitch_with_a_duplicate_key() {
+        let result = try_build(&[Java::Int], &Java::Void, |m| {
+            m.iload0().lookupswitch("default", &[(1, "a"), (2, "b"), (1, "b")])
+                .label("a").label("b").label("default").do_return();
+        });
+        assert_eq!(result.unwrap_err(), AssemblerError::DuplicateSwitchKey(1));
+    }
+
+    #[test]
+    fn far_goto_becomes_goto_w() {
+        let mut class = ClassBuilder::new(ACC_PUBLIC, "T", "java/lang/Object");
+        {
+            let mut method = class.define_method(ACC_PUBLIC | ACC_STATIC, "m", &[], &Java::Void);
+            method.set_strict(true).goto("end");
+            for _ in 0..40000 {
+                method.nop();
+            }
+            method.label("end").do_return();
+            method.done().unwrap();
+        }
+        let classfile = class.done().unwrap();
+        assert_eq!(code_of(&classfile)[0], Instruction::GotoW(40005));
+    }
+
+    #[test]
+    fn far_jsr_becomes_jsr_w() {
+        let mut class = ClassBuilder::new(ACC_PUBLIC, "T", "java/lang/Object");
+        class.set_version(49, 0);
+        {
+            let mut method = class.define_method(ACC_PUBLIC | ACC_STATIC, "m", &[], &Java::Void);
+            method.jsr("subroutine").do_return();
+            for _ in 0..40000 {
+                method.nop();
+            }
+            method.label("subroutine").astore0().ret(0);
+            method.done().unwrap();
+        }
+        let classfile = class.done().unwrap();
+        assert_eq!(code_of(&classfile)[0], Instruction::JsrW(40006));
+    }
+
+    #[test]
+    fn iand_of_two_arguments() {
+        let classfile = build(&[Java::Int, Java::Int], &Java::Int, |m| {
+            m.iload0().iload1().iand().ireturn();
+        });
+        assert_eq!(max_stack(&classfile), 2);
+    }
+
+    #[test]
+    fn i2d_widens_the_stack() {
+        let classfile = build(&[Java::Int], &Java::Double, |m| {
+            m.iload0().i2d();
+            assert_eq!(m.stack_types(), &[VerificationType::Double, VerificationType::Top]);
+            m.dreturn();
+        });
+        assert_eq!(max_stack(&classfile), 2);
+    }
+
+    #[test]
+    fn dcmpg_then_ifle() {
+        let classfile = build(&[Java::Double, Java::Double], &Java::Int, |m| {
+            m.dload0().dload2().dcmpg().ifle("less").iconst1().ireturn()
+                .label("less").iconst0().ireturn();
+        });
+        let code = code_of(&classfile);
+        assert_eq!(code[2], Instruction::Dcmpg);
+        assert_eq!(code[3], Instruction::IfLe(5));
+        assert_eq!(max_stack(&classfile), 4);
+    }
+
+    #[test]
+    fn static_final_field_with_a_constant_value() {
+        let mut class = ClassBuilder::new(ACC_PUBLIC, "T", "java/lang/Object");
+        class.define_field(ACC_PUBLIC | ACC_STATIC | ACC_FINAL, "MAX", &Java::Int)
+            .constant_value_int(100)
+            .done();
+        let classfile = class.done().unwrap();
+        match classfile.fields[0].attributes[..] {
+            [Attribute::ConstantValue(_, index)] =>
+                assert_eq!(*classfile.lookup_constant(index), Constant::Integer(100)),
+            ref other => panic!("expected a ConstantValue, found {:?}", other),
+        }
+    }
+
+    #[test]
+    fn source_file_attribute() {
+        let mut class = ClassBuilder::new(ACC_PUBLIC, "T", "java/lang/Object");
+        class.set_source_file("Main.java");
+        let classfile = class.done().unwrap();
+        match classfile.attributes[..] {
+            [Attribute::SourceFile(name_index, index)] => {
+                assert_eq!(classfile.lookup_string(name_index), "SourceFile");
+                assert_eq!(classfile.lookup_string(index), "Main.java");
+            },
+            ref other => panic!("expected a SourceFile, found {:?}", other),
+        }
+    }
+
+    // the attributes of m's Code attribute
+    fn code_attributes(classfile: &Classfile) -> Vec<Attribute> {
+        match *code_attribute(classfile) {
+            Attribute::Code(_, _, _, _, _, ref attributes) => attributes.clone(),
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn line_numbers() {
+        let classfile = build(&[], &Java::Void, |m| {
+            m.mark_line(10).iconst1().mark_line(11).pop().do_return();
+        });
+        let entries = code_attributes(&classfile).into_iter().filter_map(|a| match a {
+            Attribute::LineNumberTable(_, entries) => Some(entries),
+            _ => None,
+        }).next().unwrap();
+        assert_eq!(entries, vec![LineNumberTableEntry { start_pc: 0, line_number: 10 },
+                                 LineNumberTableEntry { start_pc: 1, line_number: 11 }]);
+    }
+
+    #[test]
+    fn local_variable_spans_its_labels() {
+        let classfile = build(&[], &Java::Void, |m| {
+            m.iconst0().istore0()
+                .label("start").iinc(0, 1).iinc(0, 2).label("end")
+                .do_return()
+                .declare_local("i", &Java::Int, 0, "start", "end");
+        });
+        let entries = code_attributes(&classfile).into_iter().filter_map(|a| match a {
+            Attribute::LocalVariableTable(_, entries) => Some(entries),
+            _ => None,
+        }).next().unwrap();
+        assert_eq!(entries.len(), 1);
+        assert_eq!((entries[0].start_pc, entries[0].length, entries[0].index), (2, 6, 0));
+        assert_eq!(classfile.lookup_string(entries[0].name_index), "i");
+        assert_eq!(classfile.lookup_string(entries[0].descriptor_index), "I");
+    }
+
+    #[test]
+    fn local_variable_ending_before_it_starts() {
+        let result = try_build(&[], &Java::Void, |m| {
+            m.iconst0().istore0().label("end").iinc(0, 1).label("start").do_return()
+                .declare_local("i", &Java::Int, 0, "start", "end");
+        });
+        assert_eq!(result.unwrap_err(), AssemblerError::LocalEndsBeforeStart("i".to_owned()));
+    }
+
+    #[test]
+    fn iadd_with_one_int_underflows() {
+        let result = try_build(&[], &Java::Int, |m| {
+            m.set_strict(true).iconst1().iadd().ireturn();
+        });
+        assert_eq!(result.unwrap_err(), AssemblerError::StackUnderflow("iadd".to_owned(), 1));
+    }
+
+    // the frames of m's StackMapTable
+    fn stack_map_frames(classfile: &Classfile) -> Vec<StackMapFrame> {
+        code_attributes(classfile).into_iter().filter_map(|a| match a {
+            Attribute::StackMapTable(_, frames) => Some(frames),
+            _ => None,
+        }).next().unwrap_or_default()
+    }
+
+    #[test]
+    fn frames_mixing_int_and_reference_locals() {
+        let mut string = VerificationType::Top;
+        let classfile = build(&[Java::Int, Java::Class("java/lang/String")], &Java::Void, |m| {
+            string = m.object_verification_type("java/lang/String");
+            m.set_strict(true)
+                .aload1().astore2().iload0().istore3()
+                .iload0().ifeq("skip")
+                .aconst_null().astore2()
+                .label("skip").do_return();
+        });
+        assert_eq!(stack_map_frames(&classfile),
+                   vec![StackMapFrame::AppendFrame(2, 10, vec![string, VerificationType::Integer])]);
+    }
+
+    #[test]
+    fn astore_pops_the_reference() {
+        build(&[], &Java::Void, |m| {
+            assert_eq!(m.locals_count(), 0);
+            m.aconst_null();
+            assert_eq!(m.stack_depth(), 1);
+            m.astore1();
+            assert_eq!(m.stack_depth(), 0);
+            assert_eq!(m.locals_count(), 2);
+            m.do_return();
+        });
+    }
+
+    #[test]
+    fn float_constants_are_compared_by_bits() {
+        let mut class = ClassBuilder::new(ACC_PUBLIC, "T", "java/lang/Object");
+        assert_eq!(class.define_float(::std::f32::NAN), class.define_float(::std::f32::NAN));
+        assert_ne!(class.define_float(0.0), class.define_float(-0.0));
+        assert_eq!(class.define_double(::std::f64::NAN), class.define_double(::std::f64::NAN));
+        assert_ne!(class.define_double(0.0), class.define_double(-0.0));
+    }
+
+    #[test]
+    fn invoke_dynamic_with_one_bootstrap_method() {
+        let mut class = ClassBuilder::new(ACC_PUBLIC, "T", "java/lang/Object");
+        let lookup = Java::Class("java/lang/invoke/MethodHandles$Lookup");
+        let string = Java::Class("java/lang/String");
+        let method_type = Java::Class("java/lang/invoke/MethodType");
+        let bootstrap_method = class.add_bootstrap_method(
+            "T", "bootstrap", &[lookup, string, method_type],
+            &Java::Class("java/lang/invoke/CallSite"), &[]);
+        {
+            let mut method = class.define_method(ACC_PUBLIC | ACC_STATIC, "m", &[], &Java::Void);
+            method.invoke_dynamic(bootstrap_method, "run", &[], &Java::Void).do_return();
+            method.done().unwrap();
+        }
+        let classfile = class.done().unwrap();
+        let instruction = code_of(&classfile)[0].clone();
+        match instruction {
+            Instruction::InvokeDynamic(index) => match *classfile.lookup_constant(index) {
+                Constant::InvokeDynamic(0, _) => {},
+                ref other => panic!("expected an InvokeDynamic, found {:?}", other),
+            },
+            ref other => panic!("expected invokedynamic, found {:?}", other),
+        }
+        assert_eq!(instruction.size(0), 5);
+        let bootstrap_methods = classfile.attributes.iter().filter_map(|a| match *a {
+            Attribute::BootstrapMethods(_, ref methods) => Some(methods.len()),
+            _ => None,
+        }).collect::<Vec<_>>();
+        assert_eq!(bootstrap_methods, vec![1]);
+    }
+
+    #[test]
+    fn throws_clauses_share_one_attribute() {
+        let classfile = build(&[], &Java::Void, |m| {
+            m.throws("java/io/IOException").throws("java/lang/InterruptedException").do_return();
+        });
+        let exceptions = classfile.methods[0].attributes.iter().filter_map(|a| match *a {
+            Attribute::Exceptions(_, ref classes) => Some(classes.clone()),
+            _ => None,
+        }).collect::<Vec<_>>();
+        assert_eq!(exceptions.len(), 1);
+        let names = exceptions[0].iter().map(|&index| match *classfile.lookup_constant(index) {
+            Constant::Class(name) => classfile.lookup_string(name),
+            ref other => panic!("expected a Class, found {:?}", other),
+        }).collect::<Vec<_>>();
+        assert_eq!(names, vec!["java/io/IOException", "java/lang/InterruptedException"]);
+    }
+
+    #[test]
+    fn invoke_with_a_descriptor_pops_long_arguments() {
+        build(&[], &Java::Void, |m| {
+            m.push_long(1).iconst1().invoke_static_desc("T", "f", "(JI)V");
+            assert_eq!(m.stack_depth(), 0);
+            m.push_long(1).invoke_static_desc("T", "g", "(J)Ljava/lang/String;");
+            assert_eq!(m.stack_depth(), 1);
+            m.pop().do_return();
+        });
+    }
+
+    #[test]
+    fn invoke_with_an_invalid_descriptor() {
+        let result = try_build(&[], &Java::Void, |m| {
+            m.invoke_static_desc("T", "f", "(J").do_return();
+        });
+        assert_eq!(result.unwrap_err(), AssemblerError::InvalidDescriptor("(J".to_owned()));
+    }
+
+    #[test]
+    fn invoke_with_long_and_double_arguments() {
+        let classfile = build(&[], &Java::Long, |m| {
+            m.lconst1().dconst1();
+            assert_eq!(m.stack_depth(), 4);
+            m.invoke_static("T", "f", &[Java::Long, Java::Double], &Java::Long);
+            assert_eq!(m.stack_depth(), 2);
+            assert_eq!(m.stack_types(), &[VerificationType::Long, VerificationType::Top]);
+            m.lreturn();
+        });
+        assert_eq!(max_stack(&classfile), 4);
+    }
+
+    #[test]
+    fn abstract_method_has_no_code() {
+        let mut class = ClassBuilder::new(ACC_PUBLIC | ACC_ABSTRACT, "T", "java/lang/Object");
+        class.define_abstract_method(ACC_PUBLIC, "run", &[], &Java::Void);
+        let classfile = class.done().unwrap();
+        assert_eq!(classfile.methods[0].access_flags, ACC_PUBLIC | ACC_ABSTRACT);
+        assert!(classfile.methods[0].attributes.is_empty());
+    }
+
+    #[test]
+    fn default_constructor_calls_super() {
+        let mut class = ClassBuilder::new(ACC_PUBLIC, "T", "java/lang/Object");
+        {
+            let mut constructor = class.define_constructor(ACC_PUBLIC, &[]);
+            constructor.call_super_constructor("java/lang/Object", &[]).do_return();
+            constructor.done().unwrap();
+        }
+        let classfile = class.done().unwrap();
+        assert_eq!(classfile.lookup_string(classfile.methods[0].name_index), "<init>");
+        assert_eq!(classfile.lookup_string(classfile.methods[0].descriptor_index), "()V");
+        let code = code_of(&classfile);
+        assert_eq!(code[0], Instruction::Aload0);
+        let methodref = match code[1] {
+            Instruction::InvokeSpecial(index) => index,
+            ref other => panic!("expected invokespecial, found {:?}", other),
+        };
+        let (class_index, name_and_type) = match *classfile.lookup_constant(methodref) {
+            Constant::Methodref(class_index, name_and_type) => (class_index, name_and_type),
+            ref other => panic!("expected a Methodref, found {:?}", other),
+        };
+        assert_eq!(class_index, classfile.super_class);
+        match *classfile.lookup_constant(name_and_type) {
+            Constant::NameAndType(name, _) => assert_eq!(classfile.lookup_string(name), "<init>"),
+            ref other => panic!("expected a NameAndType, found {:?}", other),
+        }
+    }
+
+    #[test]
+    fn frame_after_a_forward_branch_appends_locals() {
+        let classfile = build(&[], &Java::Void, |m| {
+            m.iconst1().istore0()
+                .iload0().ifeq("end")
+                .iconst2().istore1()
+                .label("end").do_return();
+        });
+        assert_eq!(stack_map_frames(&classfile),
+                   vec![StackMapFrame::AppendFrame(1, 8, vec![VerificationType::Integer])]);
+    }
+
+    #[test]
+    fn chained_and_statement_forms_agree() {
+        let chained = build(&[Java::Int], &Java::Int, |m| {
+            m.iload0().iconst2().imul().ireturn();
+        });
+        let statements = build(&[Java::Int], &Java::Int, |m| {
+            m.iload0();
+            m.iconst2();
+            m.imul();
+            m.ireturn();
+        });
+        assert_eq!(code_of(&chained), code_of(&statements));
+    }
+
+    #[test]
+    fn branch_to_an_undefined_label() {
+        let result = try_build(&[], &Java::Void, |m| {
+            m.goto("nonexistent").do_return();
+        });
+        match result {
+            Err(AssemblerError::UndefinedLabel(label, _)) => assert_eq!(label, "nonexistent"),
+            other => panic!("expected an undefined label, found {:?}", other),
+        }
+    }
+
+    #[test]
+    fn backward_goto_has_a_negative_offset() {
+        let classfile = build(&[Java::Int], &Java::Void, |m| {
+            m.label("top").iinc(0, 1).goto("top");
+        });
+        // the goto is at pc 3 and jumps back to pc 0
+        assert_eq!(code_of(&classfile)[1], Instruction::Goto(-3i16 as u16));
+    }
+
+    #[test]
+    fn array_argument_descriptor() {
+        let classfile = build(&[Java::array(Java::Class("java/lang/String"), 1)], &Java::Void, |m| {
+            m.do_return();
+        });
+        assert_eq!(classfile.lookup_string(classfile.methods[0].descriptor_index), "([Ljava/lang/String;)V");
+    }
+
+    #[test]
+    fn define_main_declares_main() {
+        let mut class = ClassBuilder::new(ACC_PUBLIC, "T", "java/lang/Object");
+        {
+            let mut main = class.define_main();
+            main.println_string("hello").do_return();
+            main.done().unwrap();
+        }
+        let classfile = class.done().unwrap();
+        let method = &classfile.methods[0];
+        assert_eq!(method.access_flags, ACC_PUBLIC | ACC_STATIC);
+        assert_eq!(classfile.lookup_string(method.name_index), "main");
+        assert_eq!(classfile.lookup_string(method.descriptor_index), "([Ljava/lang/String;)V");
+    }
+
+    // the name a Fieldref or Methodref refers to
+    fn member_name(classfile: &Classfile, index: u16) -> &str {
+        let name_and_type = match *classfile.lookup_constant(index) {
+            Constant::Fieldref(_, i) | Constant::Methodref(_, i) => i,
+            ref other => panic!("expected a member reference, found {:?}", other),
+        };
+        match *classfile.lookup_constant(name_and_type) {
+            Constant::NameAndType(name, _) => classfile.lookup_string(name),
+            ref other => panic!("expected a NameAndType, found {:?}", other),
+        }
+    }
+
+    #[test]
+    fn println_string_emits_three_instructions() {
+        let classfile = build(&[], &Java::Void, |m| {
+            m.println_string("hello").do_return();
+        });
+        let code = code_of(&classfile);
+        assert_eq!(code.len(), 4);
+        match (&code[0], &code[1], &code[2]) {
+            (&Instruction::GetStatic(fieldref), &Instruction::LoadConstant(string),
+             &Instruction::InvokeVirtual(methodref)) => {
+                assert_eq!(member_name(&classfile, fieldref), "out");
+                match *classfile.lookup_constant(string as u16) {
+                    Constant::String(utf8) => assert_eq!(classfile.lookup_string(utf8), "hello"),
+                    ref other => panic!("expected a String, found {:?}", other),
+                }
+                assert_eq!(member_name(&classfile, methodref), "println");
+            },
+            other => panic!("expected getstatic, ldc and invokevirtual, found {:?}", other),
+        }
+    }
+
+    #[test]
+    fn locals_past_255_use_wide() {
+        let classfile = build(&[], &Java::Void, |m| {
+            m.iconst0().istore(300).iload(300).pop().do_return();
+        });
+        let code = code_of(&classfile);
+        assert_eq!(code[1], Instruction::WideIstore(300));
+        assert_eq!(code[2], Instruction::WideIload(300));
+        assert_eq!(code[2].size(0), 4);
+    }
+
+    fn max_locals(classfile: &Classfile) -> u16 {
+        match *code_attribute(classfile) {
+            Attribute::Code(_, _, max_locals, _, _, _) => max_locals,
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn arguments_count_their_slot_widths() {
+        let mut class = ClassBuilder::new(ACC_PUBLIC, "T", "java/lang/Object");
+        {
+            let mut method = class.define_method(ACC_PUBLIC, "m", &[Java::Long, Java::Int], &Java::Void);
+            assert_eq!(method.locals_count(), 4);
+            method.do_return();
+            method.done().unwrap();
+        }
+        assert_eq!(max_locals(&class.done().unwrap()), 4);
+    }
+
+    #[test]
+    fn dotted_super_class_is_stored_with_slashes() {
+        let classfile = ClassBuilder::new(ACC_PUBLIC, "T", "java.lang.Object").done().unwrap();
+        match *classfile.lookup_constant(classfile.super_class) {
+            Constant::Class(name) => assert_eq!(classfile.lookup_string(name), "java/lang/Object"),
+            ref other => panic!("expected a Class, found {:?}", other),
+        }
+    }
+
+    #[test]
+    fn push_picks_const_instructions_or_ldc() {
+        let classfile = build(&[], &Java::Void, |m| {
+            m.push_int(3).push_int(100000).pop2()
+                .push_long(1).push_float(2.0).push_double(0.5).pop2().pop().pop2()
+                .do_return();
+        });
+        let code = code_of(&classfile);
+        assert_eq!(code[0], Instruction::Iconst3);
+        match code[1] {
+            Instruction::LoadConstant(index) =>
+                assert_eq!(*classfile.lookup_constant(index as u16), Constant::Integer(100000)),
+            ref other => panic!("expected ldc, found {:?}", other),
+        }
+        assert_eq!(code[3], Instruction::Lconst1);
+        assert_eq!(code[4], Instruction::Fconst2);
+        match code[5] {
+            Instruction::Ldc2W(index) => assert_eq!(*classfile.lookup_constant(index), Constant::Double(0.5)),
+            ref other => panic!("expected ldc2_w, found {:?}", other),
+        }
+    }
+
+    #[test]
+    fn deprecated_method() {
+        let classfile = build(&[], &Java::Void, |m| {
+            m.mark_deprecated().do_return();
+        });
+        let name_index = classfile.methods[0].attributes.iter().filter_map(|a| match *a {
+            Attribute::Deprecated(name_index) => Some(name_index),
+            _ => None,
+        }).next().unwrap();
+        assert_eq!(classfile.lookup_string(name_index), "Deprecated");
+        // the name, then an attribute_length of 0
+        let expected = [(name_index >> 8) as u8, name_index as u8, 0, 0, 0, 0];
+        assert!(classfile.to_bytes().windows(6).any(|w| w == expected));
+    }
+
+    #[test]
+    fn synthetic_field_has_the_flag_and_the_attribute() {
+        let mut class = ClassBuilder::new(ACC_PUBLIC, "T", "java/lang/Object");
+        class.mark_synthetic_field(ACC_PRIVATE, "this$0", &Java::Class("Outer")).done();
+        let classfile = class.done().unwrap();
+        let field = &classfile.fields[0];
+        assert_eq!(field.access_flags, ACC_PRIVATE | ACC_SYNTHETIC);
+        match field.attributes[..] {
+            [Attribute::Synthetic(name_index)] => assert_eq!(classfile.lookup_string(name_index), "Synthetic"),
+            ref other => panic!("expected a Synthetic attribute, found {:?}", other),
+        }
+    }
+
+    #[test]
+    fn inner_class_entry() {
+        let mut class = ClassBuilder::new(ACC_PUBLIC, "Outer", "java/lang/Object");
+        class.add_inner_class("Outer$Inner", Some("Outer"), Some("Inner"), ACC_PUBLIC | ACC_STATIC);
+        let classfile = class.done().unwrap();
+        let entries = classfile.attributes.iter().filter_map(|a| match *a {
+            Attribute::InnerClasses(_, ref entries) => Some(entries.clone()),
+            _ => None,
+        }).next().unwrap();
+        assert_eq!(entries.len(), 1);
+        let class_name = |index| match *classfile.lookup_constant(index) {
+            Constant::Class(name) => classfile.lookup_string(name),
+            ref other => panic!("expected a Class, found {:?}", other),
+        };
+        assert_eq!(class_name(entries[0].inner_class_info_index), "Outer$Inner");
+        assert_eq!(entries[0].outer_class_info_index, classfile.this_class);
+        assert_eq!(classfile.lookup_string(entries[0].inner_name_index), "Inner");
+        assert_eq!(entries[0].inner_class_access_flags, ACC_PUBLIC | ACC_STATIC);
+    }
+
+    // a class whose constant pool has been filled up to `entries` entries
+    fn class_with_constants(entries: usize) -> ClassBuilder {
+        let mut class = ClassBuilder::new(ACC_PUBLIC, "T", "java/lang/Object");
+        // pushed directly, since defining them one at a time searches the pool each time
+        let filler = (class.constants.len()..entries).map(|i| Constant::Utf8(format!("filler{}", i)));
+        class.constants.extend(filler.collect::<Vec<_>>());
+        class
+    }
+
+    #[test]
+    fn constant_pool_full() {
+        let mut class = class_with_constants(MAX_CONSTANT_POOL_ENTRIES - 1);
+        class.define_utf8("last");
+        assert!(class.done().is_ok());
+
+        let mut class = class_with_constants(MAX_CONSTANT_POOL_ENTRIES - 1);
+        class.define_utf8("last");
+        class.define_utf8("one too many");
+        assert_eq!(class.done().unwrap_err(), AssemblerError::ConstantPoolFull(MAX_CONSTANT_POOL_ENTRIES + 1));
+
+        // a long needs two entries, so it doesn't fit in the last one
+        let mut class = class_with_constants(MAX_CONSTANT_POOL_ENTRIES - 1);
+        class.define_long(1 << 40);
+        assert_eq!(class.done().unwrap_err(), AssemblerError::ConstantPoolFull(MAX_CONSTANT_POOL_ENTRIES + 1));
+    }
+
+    #[test]
+    fn strings_over_65535_bytes_are_reported() {
+        let mut class = ClassBuilder::new(ACC_PUBLIC, "T", "java/lang/Object");
+        class.set_source_file(&"x".repeat(70000));
+        assert_eq!(class.done().unwrap_err(), AssemblerError::StringTooLong(70000));
+
+        // NUL takes two bytes in modified UTF-8
+        let mut class = ClassBuilder::new(ACC_PUBLIC, "T", "java/lang/Object");
+        class.set_source_file(&"\0".repeat(40000));
+        assert_eq!(class.done().unwrap_err(), AssemblerError::StringTooLong(80000));
+    }
+
+    #[test]
+    fn stack_depth_follows_pushes_and_pops() {
+        build(&[], &Java::Void, |m| {
+            assert_eq!(m.stack_depth(), 0);
+            m.iconst1();
+            assert_eq!(m.stack_depth(), 1);
+            m.lconst0();
+            assert_eq!(m.stack_depth(), 3);
+            m.pop2();
+            assert_eq!(m.stack_depth(), 1);
+            m.dup();
+            assert_eq!(m.stack_depth(), 2);
+            m.iadd();
+            assert_eq!(m.stack_depth(), 1);
+            m.pop();
+            assert_eq!(m.stack_depth(), 0);
+            m.do_return();
+        });
+    }
+
+    #[test]
+    fn optimize_drops_a_goto_to_the_next_instruction() {
+        let classfile = build(&[Java::Int], &Java::Int, |m| {
+            m.iload0().ifeq("else").goto("next")
+                .label("next").iconst1().ireturn()
+                .label("else").iconst0().ireturn()
+                .optimize();
+        });
+        // the ifeq now skips just the iconst_1 and ireturn
+        assert_eq!(code_of(&classfile),
+                   vec![Instruction::Iload0, Instruction::IfEq(5), Instruction::Iconst1,
+                        Instruction::IReturn, Instruction::Iconst0, Instruction::IReturn]);
+    }
+
+    #[test]
+    fn optimize_folds_constants_and_drops_popped_pushes() {
+        let classfile = build(&[], &Java::Int, |m| {
+            m.iconst1().pop().iconst2().iconst3().iadd().ireturn().optimize();
+        });
+        assert_eq!(code_of(&classfile), vec![Instruction::Iconst5, Instruction::IReturn]);
+    }
+
+    #[test]
+    fn optimize_leaves_protected_code_alone() {
+        let classfile = build(&[], &Java::Void, |m| {
+            m.label("start").iconst1().pop().label("end").do_return()
+                .try_catch("start", "end", "handler", None)
+                .label("handler").athrow()
+                .optimize();
+        });
+        assert_eq!(code_of(&classfile),
+                   vec![Instruction::Iconst1, Instruction::Pop, Instruction::Return, Instruction::Athrow]);
+        let table = exception_table(&classfile);
+        assert_eq!((table[0].start_pc, table[0].end_pc, table[0].handler_pc), (0, 2, 3));
+    }
+
+    #[test]
+    fn interface_flags_and_super_class() {
+        let classfile = ClassBuilder::new_interface(ACC_PUBLIC, "I").done().unwrap();
+        assert_eq!(classfile.access_flags, ACC_PUBLIC | ACC_INTERFACE | ACC_ABSTRACT);
+        match *classfile.lookup_constant(classfile.super_class) {
+            Constant::Class(name) => assert_eq!(classfile.lookup_string(name), "java/lang/Object"),
+            ref other => panic!("expected a Class, found {:?}", other),
+        }
+    }
+
+    #[test]
+    fn generic_method_signature() {
+        let classfile = build(&[Java::Class("java/util/List")], &Java::Void, |m| {
+            m.set_signature("(Ljava/util/List<Ljava/lang/String;>;)V").do_return();
+        });
+        let signature = classfile.methods[0].attributes.iter().filter_map(|a| match *a {
+            Attribute::Signature(name_index, index) => Some((name_index, index)),
+            _ => None,
+        }).next().unwrap();
+        assert_eq!(classfile.lookup_string(signature.0), "Signature");
+        assert_eq!(classfile.lookup_string(signature.1), "(Ljava/util/List<Ljava/lang/String;>;)V");
+    }
+
+    #[test]
+    fn find_constant_and_constant_count() {
+        let mut class = ClassBuilder::new(ACC_PUBLIC, "T", "java/lang/Object");
+        let count = class.constant_count();
+        let index = class.define_string("hello");
+        assert_eq!(class.constant_count(), count + 2);
+        assert_eq!(class.find_constant(&Constant::String(index - 1)), Some(index));
+        assert_eq!(class.find_constant(&Constant::Utf8("hello".to_owned())), Some(index - 1));
+        assert_eq!(class.find_constant(&Constant::Utf8("goodbye".to_owned())), None);
+        assert_eq!(class.constant_count(), count + 2);
+    }
+
+    #[test]
+    fn duplicate_method_is_reported() {
+        let mut class = ClassBuilder::new(ACC_PUBLIC, "T", "java/lang/Object");
+        for _ in 0..2 {
+            let mut method = class.define_method(ACC_PUBLIC, "foo", &[], &Java::Void);
+            method.do_return();
+            method.done().unwrap();
+        }
+        assert_eq!(class.done().unwrap_err(),
+                   AssemblerError::DuplicateMethod("foo".to_owned(), "()V".to_owned()));
+    }
+
+    #[test]
+    fn falling_off_the_end_is_reported() {
+        let result = try_build(&[], &Java::Void, |m| {
+            m.iconst1().pop();
+        });
+        assert_eq!(result.unwrap_err(), AssemblerError::MissingReturn("m".to_owned()));
+    }
+
+    #[test]
+    fn small_primitive_descriptors() {
+        let mut class = ClassBuilder::new(ACC_PUBLIC, "T", "java/lang/Object");
+        class.define_field(ACC_PUBLIC, "flag", &Java::Boolean).done();
+        {
+            let mut method = class.define_method(ACC_PUBLIC | ACC_STATIC, "m", &[Java::Char], &Java::Byte);
+            method.iload0().i2b().ireturn();
+            method.done().unwrap();
+        }
+        let classfile = class.done().unwrap();
+        assert_eq!(classfile.lookup_string(classfile.fields[0].descriptor_index), "Z");
+        assert_eq!(classfile.lookup_string(classfile.methods[0].descriptor_index), "(C)B");
+    }
+
+    #[test]
+    fn frame_with_a_float_on_the_stack() {
+        let classfile = build(&[Java::Int], &Java::Float, |m| {
+            m.set_strict(true)
+                .fconst1().iload0().ifeq("done")
+                .fconst2().fmul()
+                .label("done").freturn();
+        });
+        assert_eq!(stack_map_frames(&classfile),
+                   vec![StackMapFrame::SameLocals1StackItemFrame(7, VerificationType::Float)]);
+    }
+
+    #[test]
+    fn static_field_by_index_reuses_the_fieldref() {
+        let mut class = ClassBuilder::new(ACC_PUBLIC, "T", "java/lang/Object");
+        class.define_fieldref("T", "count", &Java::Long);
+        let fieldref = class.constants.iter().find(|c| match **c {
+            Constant::Fieldref(..) => true,
+            _ => false,
+        }).cloned().unwrap();
+        let index = class.find_constant(&fieldref).unwrap();
+        {
+            let mut method = class.define_method(ACC_PUBLIC | ACC_STATIC, "m", &[], &Java::Void);
+            method.get_static_index(index);
+            assert_eq!(method.stack_depth(), 2);
+            method.put_static_index(index);
+            assert_eq!(method.stack_depth(), 0);
+            method.do_return();
+            method.done().unwrap();
+        }
+        let classfile = class.done().unwrap();
+        let fieldrefs = classfile.constant_pool.iter().filter(|c| match **c {
+            Constant::Fieldref(..) => true,
+            _ => false,
+        }).count();
+        assert_eq!(fieldrefs, 1);
+        assert_eq!(code_of(&classfile)[..2], [Instruction::GetStatic(index), Instruction::PutStatic(index)]);
+    }
+
+    #[test]
+    fn swap_with_a_double_is_reported() {
+        let result = try_build(&[], &Java::Void, |m| {
+            m.dconst1().swap().pop2().do_return();
+        });
+        assert_eq!(result.unwrap_err(), AssemblerError::SplitsLongOrDouble("swap".to_owned(), 1));
+    }
+
+    #[test]
+    fn loop_with_label_handles() {
+        let classfile = build(&[Java::Int], &Java::Void, |m| {
+            let top = m.new_label();
+            let done = m.new_label();
+            m.label(top).iload0().ifle(done)
+                .iinc(0, -1).goto(top)
+                .label(done).do_return();
+        });
+        assert_eq!(code_of(&classfile),
+                   vec![Instruction::Iload0, Instruction::IfLe(9), Instruction::Iinc(0, -1),
+                        Instruction::Goto(-7i16 as u16), Instruction::Return]);
+    }
+
+    #[test]
+    fn current_pc_advances_by_the_instruction_size() {
+        build(&[], &Java::Void, |m| {
+            m.nop();
+            let before = m.current_pc();
+            m.bipush(100);
+            assert_eq!(m.current_pc(), before + 2);
+            m.pop().do_return();
+        });
+    }
+
+    #[test]
+    fn branch_to_pc() {
+        let classfile = build(&[Java::Int], &Java::Void, |m| {
+            let top = m.current_pc();
+            m.iinc(0, -1).iload0().branch_to_pc(0x9d, top).do_return();
+        });
+        // ifgt at pc 4 back to pc 0
+        assert_eq!(code_of(&classfile)[2], Instruction::IfGt(-4i16 as u16));
+    }
+
+    #[test]
+    fn branch_to_pc_errors() {
+        let result = try_build(&[], &Java::Void, |m| {
+            m.branch_to_pc(0x60, 0).do_return();
+        });
+        assert_eq!(result.unwrap_err(), AssemblerError::NotABranch(0x60));
+        // pc 1 is in the middle of the bipush
+        let result = try_build(&[], &Java::Void, |m| {
+            m.bipush(100).pop().branch_to_pc(0xa7, 1);
+        });
+        assert_eq!(result.unwrap_err(), AssemblerError::InvalidBranchTarget(1));
+    }
+
+    #[test]
+    fn jsr_and_ret() {
+        let mut class = ClassBuilder::new(ACC_PUBLIC, "T", "java/lang/Object");
+        class.set_version(49, 0);
+        {
+            let mut method = class.define_method(ACC_PUBLIC | ACC_STATIC, "m", &[], &Java::Void);
+            method.jsr("subroutine").do_return()
+                .label("subroutine").astore0().ret(0);
+            method.done().unwrap();
+        }
+        let code = code_of(&class.done().unwrap());
+        assert_eq!(code, vec![Instruction::Jsr(4), Instruction::Return, Instruction::Astore0, Instruction::Ret(0)]);
+        assert_eq!((code[0].opcode(), code[3].opcode()), (0xa8, 0xa9));
+    }
+
+    #[test]
+    fn frame_after_storing_a_string() {
+        let mut string = VerificationType::Top;
+        let classfile = build(&[Java::Int], &Java::Void, |m| {
+            string = m.object_verification_type("java/lang/String");
+            m.load_constant("hello").astore1()
+                .iload0().ifeq("end")
+                .aconst_null().astore1()
+                .label("end").do_return();
+        });
+        let index = match string {
+            VerificationType::Object(index) => index,
+            _ => unreachable!(),
+        };
+        match *classfile.lookup_constant(index) {
+            Constant::Class(name) => assert_eq!(classfile.lookup_string(name), "java/lang/String"),
+            ref other => panic!("expected a Class, found {:?}", other),
+        }
+        assert_eq!(stack_map_frames(&classfile), vec![StackMapFrame::AppendFrame(1, 9, vec![string])]);
+    }
+
+    #[test]
+    fn enclosing_method() {
+        let mut class = ClassBuilder::new(ACC_PUBLIC, "Outer$1", "java/lang/Object");
+        class.set_enclosing_method("Outer", Some(("run", &[Java::Int], &Java::Void)));
+        let classfile = class.done().unwrap();
+        match classfile.attributes[..] {
+            [Attribute::EnclosingMethod(name_index, class_index, method_index)] => {
+                assert_eq!(classfile.lookup_string(name_index), "EnclosingMethod");
+                match *classfile.lookup_constant(class_index) {
+                    Constant::Class(name) => assert_eq!(classfile.lookup_string(name), "Outer"),
+                    ref other => panic!("expected a Class, found {:?}", other),
+                }
+                match *classfile.lookup_constant(method_index) {
+                    Constant::NameAndType(name, descriptor) => {
+                        assert_eq!(classfile.lookup_string(name), "run");
+                        assert_eq!(classfile.lookup_string(descriptor), "(I)V");
+                    },
+                    ref other => panic!("expected a NameAndType, found {:?}", other),
+                }
+            },
+            ref other => panic!("expected an EnclosingMethod, found {:?}", other),
+        }
+    }
+
+    #[test]
+    fn clear_methods_keeps_the_pool() {
+        fn define(class: &mut ClassBuilder, text: &str) {
+            let mut method = class.define_method(ACC_PUBLIC | ACC_STATIC, "m", &[], &Java::Void);
+            method.println_string(text).do_return();
+            method.done().unwrap();
+        }
+        let mut class = ClassBuilder::new(ACC_PUBLIC, "T", "java/lang/Object");
+        define(&mut class, "first");
+        let count = class.constant_count();
+        for _ in 0..3 {
+            class.clear_methods();
+            define(&mut class, "first");
+        }
+        assert_eq!(class.constant_count(), count);
+        class.clear_methods();
+        define(&mut class, "second");
+        // just the new string's Utf8 and String
+        assert_eq!(class.constant_count(), count + 2);
+        assert_eq!(class.done().unwrap().methods.len(), 1);
+    }
+
+    #[test]
+    fn getfield_of_a_long() {
+        let mut class = ClassBuilder::new(ACC_PUBLIC, "T", "java/lang/Object");
+        class.define_field(ACC_PRIVATE, "x", &Java::Long).done();
+        {
+            let mut method = class.define_method(ACC_PUBLIC, "get", &[], &Java::Long);
+            method.aload0().get_field("T", "x", &Java::Long);
+            assert_eq!(method.stack_types(), &[VerificationType::Long, VerificationType::Top]);
+            method.lreturn();
+            method.done().unwrap();
+        }
+        assert_eq!(max_stack(&class.done().unwrap()), 2);
+    }
+
+    #[test]
+    fn negative_sipush() {
+        let classfile = build(&[], &Java::Int, |m| {
+            m.sipush(-1000).ireturn();
+        });
+        match code_of(&classfile)[0] {
+            Instruction::Sipush(high, low) => assert_eq!(((high as u16) << 8 | low as u16) as i16, -1000),
+            ref other => panic!("expected sipush, found {:?}", other),
+        }
+    }
+
+    #[test]
+    fn counted_while_loop_verifies() {
+        let mut class = ClassBuilder::new(ACC_PUBLIC, "T", "java/lang/Object");
+        {
+            let mut method = class.define_method(ACC_PUBLIC | ACC_STATIC, "m", &[], &Java::Int);
+            method.set_strict(true).iconst0().istore0().iconst0().istore1()
+                .while_loop(|m| {
+                    m.iload1().bipush(10);
+                    Condition::IcmpLt
+                }, |m| {
+                    m.iload0().iload1().iadd().istore0().iinc(1, 1);
+                })
+                .iload0().ireturn();
+            assert_eq!(method.verify(), Ok(()));
+            method.done().unwrap();
+        }
+        let classfile = class.done().unwrap();
+        let code = code_of(&classfile);
+        // the if_icmpge at pc 7 exits to pc 20, and the goto at pc 17 goes back to pc 4
+        assert_eq!(code[6], Instruction::IfIcmpGe(13));
+        assert_eq!(code[12], Instruction::Goto(-13i16 as u16));
+    }
+
+    #[test]
+    fn areturn_from_an_int_method() {
+        let result = try_build(&[], &Java::Int, |m| {
+            m.aconst_null().areturn();
+        });
+        assert_eq!(result.unwrap_err(),
+                   AssemblerError::WrongReturn("m".to_owned(), "ireturn".to_owned(), "areturn".to_owned()));
+    }
+
+    #[test]
+    fn acc_super_on_classes_but_not_interfaces() {
+        let class = ClassBuilder::new(ACC_PUBLIC, "T", "java/lang/Object").done().unwrap();
+        assert_eq!(class.access_flags & 0x20, 0x20);
+        let interface = ClassBuilder::new_interface(ACC_PUBLIC, "I").done().unwrap();
+        assert_eq!(interface.access_flags & 0x20, 0);
+    }
+
+    #[test]
+    fn code_over_65535_bytes_is_reported() {
+        let result = try_build(&[], &Java::Void, |m| {
+            for _ in 0..70000 {
+                m.nop();
+            }
+            m.do_return();
+        });
+        assert_eq!(result.unwrap_err(), AssemblerError::MethodTooLarge("m".to_owned()));
+    }
+
+    #[test]
+    fn a_switch_too_big_for_a_method_is_reported() {
+        // each pair takes 8 bytes, so this is 80000 bytes of switch
+        let cases = (0..10000).map(|key| (key, "case")).collect::<Vec<_>>();
+        let result = try_build(&[Java::Int], &Java::Void, |m| {
+            m.iload0().lookupswitch("case", &cases).label("case").do_return();
+        });
+        assert_eq!(result.unwrap_err(), AssemblerError::MethodTooLarge("m".to_owned()));
+    }
+
+    #[test]
+    fn emit_raw_advances_the_pc() {
+        let classfile = build(&[], &Java::Void, |m| {
+            let before = m.current_pc();
+            m.emit_raw(&[0x00], 1, 0);
+            assert_eq!(m.current_pc(), before + 1);
+            m.do_return();
+        });
+        assert_eq!(code_of(&classfile)[0], Instruction::Raw(vec![0x00]));
+    }
+
+    #[test]
+    fn emit_raw_errors() {
+        let result = try_build(&[], &Java::Void, |m| {
+            m.emit_raw(&[0x00, 0x00], 1, 0).do_return();
+        });
+        assert_eq!(result.unwrap_err(), AssemblerError::RawSizeMismatch(1, 2));
+        let result = try_build(&[], &Java::Void, |m| {
+            m.emit_raw(&[0x00], 1, 256).do_return();
+        });
+        assert_eq!(result.unwrap_err(), AssemblerError::RawStackDeltaOutOfRange(256));
+    }
+
+    #[test]
+    fn dup_x1_and_dup2_x2_keep_the_types_in_order() {
+        build(&[], &Java::Void, |m| {
+            m.iconst1().iconst2().dup_x1();
+            assert_eq!(m.stack_types(), &[VerificationType::Integer, VerificationType::Integer, VerificationType::Integer]);
+            m.pop().pop().pop();
+
+            m.aconst_null().iconst2().dup_x1();
+            assert_eq!(m.stack_types(),
+                       &[VerificationType::Integer, VerificationType::Null, VerificationType::Integer]);
+            m.pop().pop().pop();
+
+            m.dconst0().fconst1().aconst_null().dup2_x2();
+            assert_eq!(m.stack_types(),
+                       &[VerificationType::Float, VerificationType::Null, VerificationType::Double,
+                         VerificationType::Top, VerificationType::Float, VerificationType::Null]);
+            m.pop2().pop2().pop2().do_return();
+        });
+    }
+
+    #[test]
+    fn class_literal() {
+        let mut class_class = VerificationType::Top;
+        let classfile = build(&[], &Java::Void, |m| {
+            class_class = m.object_verification_type("java/lang/Class");
+            m.load_class_constant("java/lang/String");
+            assert_eq!(m.stack_types(), &[class_class.clone()]);
+            m.pop().do_return();
+        });
+        match code_of(&classfile)[0] {
+            Instruction::LoadConstant(index) => match *classfile.lookup_constant(index as u16) {
+                Constant::Class(name) => assert_eq!(classfile.lookup_string(name), "java/lang/String"),
+                ref other => panic!("expected a Class, found {:?}", other),
+            },
+            ref other => panic!("expected ldc, found {:?}", other),
+        }
+    }
+
+    #[test]
+    fn method_type_constant() {
+        let classfile = build(&[], &Java::Void, |m| {
+            m.load_method_type("()V").pop().do_return();
+        });
+        match code_of(&classfile)[0] {
+            Instruction::LoadConstant(index) => match *classfile.lookup_constant(index as u16) {
+                Constant::MethodType(descriptor) => assert_eq!(classfile.lookup_string(descriptor), "()V"),
+                ref other => panic!("expected a MethodType, found {:?}", other),
+            },
+            ref other => panic!("expected ldc, found {:?}", other),
+        }
+    }
+
+    #[test]
+    fn errors_are_typed() {
+        let errors = vec![
+            try_build(&[], &Java::Void, |m| { m.goto("nowhere").do_return(); }).unwrap_err(),
+            try_build(&[], &Java::Int, |m| { m.do_return(); }).unwrap_err(),
+        ];
+        for error in errors {
+            match error {
+                AssemblerError::UndefinedLabel(ref label, _) => assert_eq!(label, "nowhere"),
+                AssemblerError::WrongReturn(ref name, _, _) => assert_eq!(name, "m"),
+                other => panic!("unexpected error: {}", other),
+            }
+        }
+    }
+
+    #[test]
+    fn misuse_is_reported_by_done() {
+        let mut class = ClassBuilder::new(ACC_PUBLIC, "T", "java/lang/Object");
+        assert_eq!(class.define_method_handle(REF_GET_FIELD, "T", "x", "()I"), 0);
+        assert_eq!(class.done().unwrap_err(),
+                   AssemblerError::InvalidMethodHandle(REF_GET_FIELD, "\"()I\" isn't a field descriptor".to_owned()));
+
+        let mut class = ClassBuilder::new(ACC_PUBLIC, "T", "java/lang/Object");
+        class.define_method_type("(I");
+        assert_eq!(class.done().unwrap_err(), AssemblerError::InvalidDescriptor("(I".to_owned()));
+
+        let result = try_build(&[], &Java::Void, |m| { m.get_static_index(1).do_return(); });
+        assert_eq!(result.unwrap_err(), AssemblerError::NotAFieldref(1));
+
+        let result = try_build(&[], &Java::Void, |m| {
+            m.aconst_null().iconst0().array_load(&Java::Void).do_return();
+        });
+        assert_eq!(result.unwrap_err(), AssemblerError::VoidNotAllowed("an array element".to_owned()));
+    }
+
+    #[test]
+    fn a_later_misuse_doesnt_replace_the_first() {
+        let result = try_build(&[], &Java::Void, |m| {
+            m.get_static_index(1).invoke_static_desc("T", "f", "(J").do_return();
+        });
+        assert_eq!(result.unwrap_err(), AssemblerError::NotAFieldref(1));
+
+        let result = try_build(&[], &Java::Void, |m| {
+            m.invoke_static_desc("T", "f", "(J").get_static_index(1).do_return();
+        });
+        assert_eq!(result.unwrap_err(), AssemblerError::InvalidDescriptor("(J".to_owned()));
+    }
+
+    #[test]
+    fn new_invoke_special_handle_for_a_constructor() {
+        let mut class = ClassBuilder::new(ACC_PUBLIC, "T", "java/lang/Object");
+        assert!(class.define_method_handle(REF_NEW_INVOKE_SPECIAL, "T", "<init>", "()V") != 0);
+        assert!(class.done().is_ok());
+    }
+
+    #[test]
+    fn dotted_class_name_is_stored_with_slashes() {
+        let class = ClassBuilder::new(ACC_PUBLIC, "com.example.Foo", "java/lang/Object");
+        assert_eq!(class.package(), "com/example");
+        let classfile = class.done().unwrap();
+        match *classfile.lookup_constant(classfile.this_class) {
+            Constant::Class(name) => assert_eq!(classfile.lookup_string(name), "com/example/Foo"),
+            ref other => panic!("expected a Class, found {:?}", other),
+        }
+        assert_eq!(ClassBuilder::new(ACC_PUBLIC, "Foo", "java/lang/Object").package(), "");
+    }
+
+    #[test]
+    fn invalid_class_name() {
+        let result = ClassBuilder::new(ACC_PUBLIC, "com/example/", "java/lang/Object").done();
+        assert_eq!(result.unwrap_err(), AssemblerError::InvalidClassName("com/example/".to_owned()));
+        let result = ClassBuilder::new(ACC_PUBLIC, "T", "Ljava/lang/Object;").done();
+        assert_eq!(result.unwrap_err(), AssemblerError::InvalidClassName("Ljava/lang/Object;".to_owned()));
+    }
+
+    #[test]
+    fn invoke_virtual_typed_reports_the_result_type() {
+        build(&[Java::Class("java/lang/Object")], &Java::Void, |m| {
+            let string = m.object_verification_type("java/lang/String");
+            m.aload0();
+            let result = m.invoke_virtual_typed("java/lang/Object", "toString", &[], &Java::Class("java/lang/String"));
+            assert_eq!(result, Some(string));
+            m.aload0();
+            let result = m.invoke_virtual_typed("java/lang/Object", "notify", &[], &Java::Void);
+            assert_eq!(result, None);
+            m.pop().do_return();
+        });
+    }
+
+    #[test]
+    fn if_then_skips_the_body_with_the_inverted_test() {
+        let classfile = build(&[Java::Int], &Java::Void, |m| {
+            m.iload0().if_then(Condition::Eq, |m| {
+                m.iinc(0, 1);
+            }).do_return();
+        });
+        // ifne at pc 1 skips the iinc to the return at pc 7
+        assert_eq!(code_of(&classfile),
+                   vec![Instruction::Iload0, Instruction::IfNe(6), Instruction::Iinc(0, 1), Instruction::Return]);
+    }
+
+    #[test]
+    fn push_int_picks_the_smallest_instruction() {
+        let classfile = build(&[], &Java::Void, |m| {
+            m.push_int(200).push_int(-1000).push_int(100000).pop().pop().pop().do_return();
+        });
+        let code = code_of(&classfile);
+        assert_eq!(code[0], Instruction::Sipush(0, 200));
+        assert_eq!(code[1], Instruction::Sipush(0xfc, 0x18));
+        match code[2] {
+            Instruction::LoadConstant(_) | Instruction::LdcW(_) => {},
+            ref other => panic!("expected ldc, found {:?}", other),
+        }
+    }
+
+    #[test]
+    fn iinc_goes_wide_when_it_has_to() {
+        let classfile = build(&[], &Java::Void, |m| {
+            m.iconst0().istore(1).iconst0().istore(300)
+                .iinc(1, 1).iinc(1, 1000).iinc(300, 1).do_return();
+        });
+        let code = code_of(&classfile);
+        assert!(code.contains(&Instruction::Iinc(1, 1)));
+        assert!(code.contains(&Instruction::WideIinc(1, 1000)));
+        assert!(code.contains(&Instruction::WideIinc(300, 1)));
+    }
+
+    #[test]
+    fn append_frame_has_one_entry_for_a_long() {
+        let classfile = build(&[], &Java::Void, |m| {
+            m.lconst1().lstore0()
+                .iconst1().ifeq("end")
+                .iconst0().pop()
+                .label("end").do_return();
+        });
+        assert_eq!(stack_map_frames(&classfile),
+                   vec![StackMapFrame::AppendFrame(1, 8, vec![VerificationType::Long])]);
     }
 }
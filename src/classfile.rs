@@ -1,6 +1,6 @@
 const CAFEBABE: u32 = 0xCAFEBABE;
-const MAJOR_VERSION: u16 = 52;
-const MINOR_VERSION: u16 = 0;
+pub const MAJOR_VERSION: u16 = 52;
+pub const MINOR_VERSION: u16 = 0;
 
 #[derive(Clone, Debug, PartialEq)]
 pub struct Classfile {
@@ -22,18 +22,32 @@ pub enum Constant {
     Utf8(String),          //  1
     Integer(i32),          //  3
     Float(f32),            //  4
+    Long(i64),             //  5
+    Double(f64),           //  6
     Class(u16),            //  7
     String(u16),           //  8
     Fieldref(u16, u16),    //  9
     Methodref(u16, u16),   // 10
+    InterfaceMethodref(u16, u16), // 11
     NameAndType(u16, u16), // 12
+    MethodHandle(u8, u16), // 15
+    MethodType(u16),       // 16
+    InvokeDynamic(u16, u16), // 18
+    Unusable,              // the slot after a Long or Double
 }
 
 #[derive(Clone, Debug, PartialEq)]
-pub struct Interface;
+pub struct Interface {
+    pub class_index: u16,
+}
 
 #[derive(Clone, Debug, PartialEq)]
-pub struct Field;
+pub struct Field {
+    pub access_flags: u16,
+    pub name_index: u16,
+    pub descriptor_index: u16,
+    pub attributes: Vec<Attribute>,
+}
 
 #[derive(Clone, Debug, PartialEq)]
 pub struct Method {
@@ -47,12 +61,27 @@ pub struct Method {
 pub enum Attribute {
     Code(u16, u16, u16, Vec<Instruction>, Vec<ExceptionTableEntry>, Vec<Attribute>),
     LineNumberTable(u16, Vec<LineNumberTableEntry>),
+    LocalVariableTable(u16, Vec<LocalVariableTableEntry>),
     SourceFile(u16, u16),
+    ConstantValue(u16, u16),
+    BootstrapMethods(u16, Vec<BootstrapMethod>),
+    InnerClasses(u16, Vec<InnerClass>),
+    Exceptions(u16, Vec<u16>),
+    Deprecated(u16),
+    Synthetic(u16),
     StackMapTable(u16, Vec<StackMapFrame>),
+    RuntimeVisibleAnnotations(u16, Vec<Annotation>),
+    Signature(u16, u16),
+    EnclosingMethod(u16, u16, u16), // name index, class index, method name and type index or 0
 }
 
 #[derive(Clone, Debug, PartialEq)]
-pub struct ExceptionTableEntry;
+pub struct ExceptionTableEntry {
+    pub start_pc: u16,
+    pub end_pc: u16,
+    pub handler_pc: u16,
+    pub catch_type: u16,
+}
 
 #[derive(Clone, Debug, PartialEq)]
 pub struct LineNumberTableEntry {
@@ -60,6 +89,58 @@ pub struct LineNumberTableEntry {
     pub line_number: u16,
 }
 
+#[derive(Clone, Debug, PartialEq)]
+pub struct BootstrapMethod {
+    pub method_ref: u16,
+    pub arguments: Vec<u16>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct InnerClass {
+    pub inner_class_info_index: u16,
+    pub outer_class_info_index: u16, // 0 for local and anonymous classes
+    pub inner_name_index: u16,       // 0 for anonymous classes
+    pub inner_class_access_flags: u16,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Annotation {
+    pub type_index: u16, // a field descriptor, e.g. "Ljava/lang/Deprecated;"
+    pub element_value_pairs: Vec<ElementValuePair>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct ElementValuePair {
+    pub element_name_index: u16,
+    pub value: ElementValue,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum ElementValue {
+    Byte(u16),            // B
+    Char(u16),            // C
+    Double(u16),          // D
+    Float(u16),           // F
+    Int(u16),             // I
+    Long(u16),            // J
+    Short(u16),           // S
+    Boolean(u16),         // Z
+    String(u16),          // s, a Utf8 constant rather than a String
+    Enum(u16, u16),       // e, type descriptor and constant name
+    Class(u16),           // c, a return descriptor such as "V" or "Ljava/lang/Object;"
+    Annotation(Annotation), // @
+    Array(Vec<ElementValue>), // [
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct LocalVariableTableEntry {
+    pub start_pc: u16,
+    pub length: u16,
+    pub name_index: u16,
+    pub descriptor_index: u16,
+    pub index: u16,
+}
+
 #[derive(Clone, Debug, PartialEq)]
 pub enum StackMapFrame {
     SameFrame(u8),
@@ -76,8 +157,8 @@ pub enum VerificationType {
     Top,                // 0
     Integer,            // 1
     Float,              // 2
-    Long,               // 3
-    Double,             // 4
+    Long,               // 4
+    Double,             // 3
     Null,               // 5
     UninitializedThis,  // 6
     Object(u16),        // 7
@@ -161,6 +242,8 @@ pub enum Instruction {
     IfIcmpGt(u16),      // 0xA3
     IfIcmpLe(u16),      // 0xA4
     Goto(u16),          // 0xA7
+    Jsr(u16),           // 0xa8
+    Ret(u8),            // 0xa9
     IReturn,            // 0xac
     Return,             // 0xB1
     GetStatic(u16),     // 0xB2
@@ -168,9 +251,153 @@ pub enum Instruction {
     InvokeSpecial(u16), // 0xB7
     InvokeStatic(u16),  // 0xB8
     ArrayLength,        // 0xBE
+    Lload(u8),          // 0x16
+    Lload0,             // 0x1e
+    Lload1,             // 0x1f
+    Lload2,             // 0x20
+    Lload3,             // 0x21
+    Lstore(u8),         // 0x37
+    Lstore0,            // 0x3f
+    Lstore1,            // 0x40
+    Lstore2,            // 0x41
+    Lstore3,            // 0x42
+    Lconst0,            // 0x09
+    Lconst1,            // 0x0a
+    Ladd,               // 0x61
+    Lsub,               // 0x65
+    Lmul,               // 0x69
+    Ldiv,               // 0x6d
+    Lrem,               // 0x71
+    Lreturn,            // 0xad
+    Dload(u8),          // 0x18
+    Dload0,             // 0x26
+    Dload1,             // 0x27
+    Dload2,             // 0x28
+    Dload3,             // 0x29
+    Dstore(u8),         // 0x39
+    Dstore0,            // 0x47
+    Dstore1,            // 0x48
+    Dstore2,            // 0x49
+    Dstore3,            // 0x4a
+    Dconst0,            // 0x0e
+    Dconst1,            // 0x0f
+    Dadd,               // 0x63
+    Dsub,               // 0x67
+    Dmul,               // 0x6b
+    Ddiv,               // 0x6f
+    Drem,               // 0x73
+    Dreturn,            // 0xaf
+    Ldc2W(u16),         // 0x14
+    LdcW(u16),          // 0x13
+    GetField(u16),      // 0xb4
+    PutField(u16),      // 0xb5
+    PutStatic(u16),     // 0xb3
+    InvokeInterface(u16, u8), // 0xb9
+    NewArray(u8),       // 0xbc
+    ANewArray(u16),     // 0xbd
+    MultiANewArray(u16, u8), // 0xc5
+    Pop,                // 0x57
+    Pop2,               // 0x58
+    Swap,               // 0x5f
+    DupX1,              // 0x5a
+    DupX2,              // 0x5b
+    Dup2,               // 0x5c
+    Dup2X1,             // 0x5d
+    Dup2X2,             // 0x5e
+    IfAcmpEq(u16),      // 0xa5
+    IfAcmpNe(u16),      // 0xa6
+    IfNull(u16),        // 0xc6
+    IfNonNull(u16),     // 0xc7
+    Athrow,             // 0xbf
+    CheckCast(u16),     // 0xc0
+    InstanceOf(u16),    // 0xc1
+    MonitorEnter,       // 0xc2
+    MonitorExit,        // 0xc3
+    Nop,                // 0x00
+    Iinc(u8, i8),       // 0x84
+    // the wide (0xc4) forms, for local indices above 255 and larger iinc deltas
+    WideIload(u16),     // 0xc4 0x15
+    WideLload(u16),     // 0xc4 0x16
+    WideFload(u16),     // 0xc4 0x17
+    WideDload(u16),     // 0xc4 0x18
+    WideAload(u16),     // 0xc4 0x19
+    WideIstore(u16),    // 0xc4 0x36
+    WideLstore(u16),    // 0xc4 0x37
+    WideFstore(u16),    // 0xc4 0x38
+    WideDstore(u16),    // 0xc4 0x39
+    WideAstore(u16),    // 0xc4 0x3a
+    WideIinc(u16, i16), // 0xc4 0x84
+    TableSwitch(i32, i32, i32, Vec<i32>), // 0xaa: default, low, high, offsets
+    LookupSwitch(i32, Vec<(i32, i32)>),   // 0xab: default, (match, offset) pairs
+    GotoW(u32),         // 0xc8
+    JsrW(u32),          // 0xc9
+    Ineg,               // 0x74
+    Iand,               // 0x7e
+    Ior,                // 0x80
+    Ixor,               // 0x82
+    Ishl,               // 0x78
+    Ishr,               // 0x7a
+    Iushr,              // 0x7c
+    Lneg,               // 0x75
+    Land,               // 0x7f
+    Lor,                // 0x81
+    Lxor,               // 0x83
+    Lshl,               // 0x79
+    Lshr,               // 0x7b
+    Lushr,              // 0x7d
+    I2L,                // 0x85
+    I2D,                // 0x87
+    I2B,                // 0x91
+    I2S,                // 0x93
+    L2I,                // 0x88
+    L2F,                // 0x89
+    L2D,                // 0x8a
+    F2L,                // 0x8c
+    F2D,                // 0x8d
+    D2I,                // 0x8e
+    D2L,                // 0x8f
+    D2F,                // 0x90
+    Lcmp,               // 0x94
+    Fcmpl,              // 0x95
+    Fcmpg,              // 0x96
+    Dcmpl,              // 0x97
+    Dcmpg,              // 0x98
+    InvokeDynamic(u16), // 0xba
+    Iaload,             // 0x2e
+    Laload,             // 0x2f
+    Faload,             // 0x30
+    Daload,             // 0x31
+    Baload,             // 0x33
+    Caload,             // 0x34
+    Saload,             // 0x35
+    Iastore,            // 0x4f
+    Lastore,            // 0x50
+    Fastore,            // 0x51
+    Dastore,            // 0x52
+    Aastore,            // 0x53
+    Bastore,            // 0x54
+    Castore,            // 0x55
+    Sastore,            // 0x56
+    Fneg,               // 0x76
+    Dneg,               // 0x77
+    Raw(Vec<u8>),       // already encoded bytes, written out as they are
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum PrimitiveType {
+    Boolean, //  4
+    Char,    //  5
+    Float,   //  6
+    Double,  //  7
+    Byte,    //  8
+    Short,   //  9
+    Int,     // 10
+    Long,    // 11
 }
 
 impl Classfile {
+    // The version defaults to MAJOR_VERSION.MINOR_VERSION, with no interfaces,
+    // fields or attributes; set the public fields to change any of them.
     pub fn new(constants: Vec<Constant>, access_flags: u16, this_class: u16, super_class: u16, methods: Vec<Method>) -> Classfile {
         Classfile {
             magic: CAFEBABE,
@@ -187,6 +414,9 @@ impl Classfile {
         }
     }
 
+    // lookup_constant and lookup_string panic on an index that's out of range or
+    // points at the wrong kind of constant. Run validate() first on a class file
+    // that didn't come from a ClassBuilder.
     pub fn lookup_constant(&self, index: u16) -> &Constant {
         &self.constant_pool[index as usize - 1]
     }
@@ -200,6 +430,59 @@ impl Classfile {
     }
 }
 
+impl Constant {
+    // longs and doubles take up two slots in the constant pool; the second
+    // slot is held by a Constant::Unusable, which is never serialized
+    pub fn takes_two_slots(&self) -> bool {
+        match *self {
+            Constant::Long(_) | Constant::Double(_) => true,
+            _ => false,
+        }
+    }
+}
+
+impl PrimitiveType {
+    // the atype operand used by newarray
+    pub fn atype(&self) -> u8 {
+        match *self {
+            PrimitiveType::Boolean => 4,
+            PrimitiveType::Char => 5,
+            PrimitiveType::Float => 6,
+            PrimitiveType::Double => 7,
+            PrimitiveType::Byte => 8,
+            PrimitiveType::Short => 9,
+            PrimitiveType::Int => 10,
+            PrimitiveType::Long => 11,
+        }
+    }
+
+    // the class name of an array of this type, e.g. "[I"
+    pub fn array_descriptor(&self) -> &'static str {
+        match *self {
+            PrimitiveType::Boolean => "[Z",
+            PrimitiveType::Char => "[C",
+            PrimitiveType::Float => "[F",
+            PrimitiveType::Double => "[D",
+            PrimitiveType::Byte => "[B",
+            PrimitiveType::Short => "[S",
+            PrimitiveType::Int => "[I",
+            PrimitiveType::Long => "[J",
+        }
+    }
+}
+
+impl Field {
+    pub fn new(access_flags: u16, name_index: u16, descriptor_index: u16,
+               attributes: Vec<Attribute>) -> Field {
+        Field {
+            access_flags: access_flags,
+            name_index: name_index,
+            descriptor_index: descriptor_index,
+            attributes: attributes,
+        }
+    }
+}
+
 impl Method {
     pub fn new(access_flags: u16, name_index: u16, descriptor_index: u16,
                attributes: Vec<Attribute>) -> Method {
@@ -213,7 +496,385 @@ impl Method {
 }
 
 impl Instruction {
-    pub fn size(&self) -> u8 {
+    // The byte that starts the instruction. The wide forms all start with the
+    // wide prefix, and raw bytes with whatever their first byte is (nop's if
+    // there aren't any).
+    pub fn opcode(&self) -> u8 {
+        match *self {
+            Instruction::New(..) => 0xbb,
+            Instruction::Dup => 0x59,
+            Instruction::Irem => 0x70,
+            Instruction::Frem => 0x72,
+            Instruction::Fmul => 0x6a,
+            Instruction::Fdiv => 0x6e,
+            Instruction::Fadd => 0x62,
+            Instruction::Fsub => 0x66,
+            Instruction::Fload0 => 0x22,
+            Instruction::Fload1 => 0x23,
+            Instruction::Fload2 => 0x24,
+            Instruction::Fload3 => 0x25,
+            Instruction::Fload(..) => 0x17,
+            Instruction::Fstore0 => 0x43,
+            Instruction::Fstore1 => 0x44,
+            Instruction::Fstore2 => 0x45,
+            Instruction::Fstore3 => 0x46,
+            Instruction::Fstore(..) => 0x38,
+            Instruction::Fconst0 => 0x0b,
+            Instruction::Fconst1 => 0x0c,
+            Instruction::Fconst2 => 0x0d,
+            Instruction::FReturn => 0xae,
+            Instruction::I2C => 0x92,
+            Instruction::I2F => 0x86,
+            Instruction::F2I => 0x8b,
+            Instruction::IconstM1 => 0x02,
+            Instruction::Iconst0 => 0x03,
+            Instruction::Iconst1 => 0x04,
+            Instruction::Iconst2 => 0x05,
+            Instruction::Iconst3 => 0x06,
+            Instruction::Iconst4 => 0x07,
+            Instruction::Iconst5 => 0x08,
+            Instruction::Istore0 => 0x3b,
+            Instruction::Istore1 => 0x3c,
+            Instruction::Istore2 => 0x3d,
+            Instruction::Istore3 => 0x3e,
+            Instruction::Istore(..) => 0x36,
+            Instruction::Bipush(..) => 0x10,
+            Instruction::Sipush(..) => 0x11,
+            Instruction::Iload0 => 0x1a,
+            Instruction::Iload1 => 0x1b,
+            Instruction::Iload2 => 0x1c,
+            Instruction::Iload3 => 0x1d,
+            Instruction::Iload(..) => 0x15,
+            Instruction::LoadConstant(..) => 0x12,
+            Instruction::AConstNull => 0x01,
+            Instruction::Areturn => 0xb0,
+            Instruction::Astore0 => 0x4b,
+            Instruction::Astore1 => 0x4c,
+            Instruction::Astore2 => 0x4d,
+            Instruction::Astore3 => 0x4e,
+            Instruction::Astore(..) => 0x3a,
+            Instruction::Aload0 => 0x2a,
+            Instruction::Aload1 => 0x2b,
+            Instruction::Aload2 => 0x2c,
+            Instruction::Aload3 => 0x2d,
+            Instruction::Aload(..) => 0x19,
+            Instruction::Aaload => 0x32,
+            Instruction::Iadd => 0x60,
+            Instruction::Isub => 0x64,
+            Instruction::Imul => 0x68,
+            Instruction::Idiv => 0x6c,
+            Instruction::IfEq(..) => 0x99,
+            Instruction::IfNe(..) => 0x9a,
+            Instruction::IfLt(..) => 0x9b,
+            Instruction::IfGe(..) => 0x9c,
+            Instruction::IfGt(..) => 0x9d,
+            Instruction::IfLe(..) => 0x9e,
+            Instruction::IfIcmpEq(..) => 0x9f,
+            Instruction::IfIcmpNe(..) => 0xa0,
+            Instruction::IfIcmpLt(..) => 0xa1,
+            Instruction::IfIcmpGe(..) => 0xa2,
+            Instruction::IfIcmpGt(..) => 0xa3,
+            Instruction::IfIcmpLe(..) => 0xa4,
+            Instruction::Goto(..) => 0xa7,
+            Instruction::Jsr(..) => 0xa8,
+            Instruction::Ret(..) => 0xa9,
+            Instruction::IReturn => 0xac,
+            Instruction::Return => 0xb1,
+            Instruction::GetStatic(..) => 0xb2,
+            Instruction::InvokeVirtual(..) => 0xb6,
+            Instruction::InvokeSpecial(..) => 0xb7,
+            Instruction::InvokeStatic(..) => 0xb8,
+            Instruction::ArrayLength => 0xbe,
+            Instruction::Lload(..) => 0x16,
+            Instruction::Lload0 => 0x1e,
+            Instruction::Lload1 => 0x1f,
+            Instruction::Lload2 => 0x20,
+            Instruction::Lload3 => 0x21,
+            Instruction::Lstore(..) => 0x37,
+            Instruction::Lstore0 => 0x3f,
+            Instruction::Lstore1 => 0x40,
+            Instruction::Lstore2 => 0x41,
+            Instruction::Lstore3 => 0x42,
+            Instruction::Lconst0 => 0x09,
+            Instruction::Lconst1 => 0x0a,
+            Instruction::Ladd => 0x61,
+            Instruction::Lsub => 0x65,
+            Instruction::Lmul => 0x69,
+            Instruction::Ldiv => 0x6d,
+            Instruction::Lrem => 0x71,
+            Instruction::Lreturn => 0xad,
+            Instruction::Dload(..) => 0x18,
+            Instruction::Dload0 => 0x26,
+            Instruction::Dload1 => 0x27,
+            Instruction::Dload2 => 0x28,
+            Instruction::Dload3 => 0x29,
+            Instruction::Dstore(..) => 0x39,
+            Instruction::Dstore0 => 0x47,
+            Instruction::Dstore1 => 0x48,
+            Instruction::Dstore2 => 0x49,
+            Instruction::Dstore3 => 0x4a,
+            Instruction::Dconst0 => 0x0e,
+            Instruction::Dconst1 => 0x0f,
+            Instruction::Dadd => 0x63,
+            Instruction::Dsub => 0x67,
+            Instruction::Dmul => 0x6b,
+            Instruction::Ddiv => 0x6f,
+            Instruction::Drem => 0x73,
+            Instruction::Dreturn => 0xaf,
+            Instruction::Ldc2W(..) => 0x14,
+            Instruction::LdcW(..) => 0x13,
+            Instruction::GetField(..) => 0xb4,
+            Instruction::PutField(..) => 0xb5,
+            Instruction::PutStatic(..) => 0xb3,
+            Instruction::InvokeInterface(..) => 0xb9,
+            Instruction::NewArray(..) => 0xbc,
+            Instruction::ANewArray(..) => 0xbd,
+            Instruction::MultiANewArray(..) => 0xc5,
+            Instruction::Pop => 0x57,
+            Instruction::Pop2 => 0x58,
+            Instruction::Swap => 0x5f,
+            Instruction::DupX1 => 0x5a,
+            Instruction::DupX2 => 0x5b,
+            Instruction::Dup2 => 0x5c,
+            Instruction::Dup2X1 => 0x5d,
+            Instruction::Dup2X2 => 0x5e,
+            Instruction::IfAcmpEq(..) => 0xa5,
+            Instruction::IfAcmpNe(..) => 0xa6,
+            Instruction::IfNull(..) => 0xc6,
+            Instruction::IfNonNull(..) => 0xc7,
+            Instruction::Athrow => 0xbf,
+            Instruction::CheckCast(..) => 0xc0,
+            Instruction::InstanceOf(..) => 0xc1,
+            Instruction::MonitorEnter => 0xc2,
+            Instruction::MonitorExit => 0xc3,
+            Instruction::Nop => 0x00,
+            Instruction::Iinc(..) => 0x84,
+            Instruction::WideIload(..) => 0xc4,
+            Instruction::WideLload(..) => 0xc4,
+            Instruction::WideFload(..) => 0xc4,
+            Instruction::WideDload(..) => 0xc4,
+            Instruction::WideAload(..) => 0xc4,
+            Instruction::WideIstore(..) => 0xc4,
+            Instruction::WideLstore(..) => 0xc4,
+            Instruction::WideFstore(..) => 0xc4,
+            Instruction::WideDstore(..) => 0xc4,
+            Instruction::WideAstore(..) => 0xc4,
+            Instruction::WideIinc(..) => 0xc4,
+            Instruction::TableSwitch(..) => 0xaa,
+            Instruction::LookupSwitch(..) => 0xab,
+            Instruction::GotoW(..) => 0xc8,
+            Instruction::JsrW(..) => 0xc9,
+            Instruction::Ineg => 0x74,
+            Instruction::Iand => 0x7e,
+            Instruction::Ior => 0x80,
+            Instruction::Ixor => 0x82,
+            Instruction::Ishl => 0x78,
+            Instruction::Ishr => 0x7a,
+            Instruction::Iushr => 0x7c,
+            Instruction::Lneg => 0x75,
+            Instruction::Land => 0x7f,
+            Instruction::Lor => 0x81,
+            Instruction::Lxor => 0x83,
+            Instruction::Lshl => 0x79,
+            Instruction::Lshr => 0x7b,
+            Instruction::Lushr => 0x7d,
+            Instruction::I2L => 0x85,
+            Instruction::I2D => 0x87,
+            Instruction::I2B => 0x91,
+            Instruction::I2S => 0x93,
+            Instruction::L2I => 0x88,
+            Instruction::L2F => 0x89,
+            Instruction::L2D => 0x8a,
+            Instruction::F2L => 0x8c,
+            Instruction::F2D => 0x8d,
+            Instruction::D2I => 0x8e,
+            Instruction::D2L => 0x8f,
+            Instruction::D2F => 0x90,
+            Instruction::Lcmp => 0x94,
+            Instruction::Fcmpl => 0x95,
+            Instruction::Fcmpg => 0x96,
+            Instruction::Dcmpl => 0x97,
+            Instruction::Dcmpg => 0x98,
+            Instruction::InvokeDynamic(..) => 0xba,
+            Instruction::Iaload => 0x2e,
+            Instruction::Laload => 0x2f,
+            Instruction::Faload => 0x30,
+            Instruction::Daload => 0x31,
+            Instruction::Baload => 0x33,
+            Instruction::Caload => 0x34,
+            Instruction::Saload => 0x35,
+            Instruction::Iastore => 0x4f,
+            Instruction::Lastore => 0x50,
+            Instruction::Fastore => 0x51,
+            Instruction::Dastore => 0x52,
+            Instruction::Aastore => 0x53,
+            Instruction::Bastore => 0x54,
+            Instruction::Castore => 0x55,
+            Instruction::Sastore => 0x56,
+            Instruction::Fneg => 0x76,
+            Instruction::Dneg => 0x77,
+            Instruction::Raw(ref bytes) => bytes.first().cloned().unwrap_or(0x00),
+        }
+    }
+
+    // the instruction for an opcode that takes no operands
+    pub fn from_opcode(opcode: u8) -> Option<Instruction> {
+        Some(match opcode {
+            0x00 => Instruction::Nop,
+            0x01 => Instruction::AConstNull,
+            0x02 => Instruction::IconstM1,
+            0x03 => Instruction::Iconst0,
+            0x04 => Instruction::Iconst1,
+            0x05 => Instruction::Iconst2,
+            0x06 => Instruction::Iconst3,
+            0x07 => Instruction::Iconst4,
+            0x08 => Instruction::Iconst5,
+            0x09 => Instruction::Lconst0,
+            0x0a => Instruction::Lconst1,
+            0x0b => Instruction::Fconst0,
+            0x0c => Instruction::Fconst1,
+            0x0d => Instruction::Fconst2,
+            0x0e => Instruction::Dconst0,
+            0x0f => Instruction::Dconst1,
+            0x1a => Instruction::Iload0,
+            0x1b => Instruction::Iload1,
+            0x1c => Instruction::Iload2,
+            0x1d => Instruction::Iload3,
+            0x1e => Instruction::Lload0,
+            0x1f => Instruction::Lload1,
+            0x20 => Instruction::Lload2,
+            0x21 => Instruction::Lload3,
+            0x22 => Instruction::Fload0,
+            0x23 => Instruction::Fload1,
+            0x24 => Instruction::Fload2,
+            0x25 => Instruction::Fload3,
+            0x26 => Instruction::Dload0,
+            0x27 => Instruction::Dload1,
+            0x28 => Instruction::Dload2,
+            0x29 => Instruction::Dload3,
+            0x2a => Instruction::Aload0,
+            0x2b => Instruction::Aload1,
+            0x2c => Instruction::Aload2,
+            0x2d => Instruction::Aload3,
+            0x2e => Instruction::Iaload,
+            0x2f => Instruction::Laload,
+            0x30 => Instruction::Faload,
+            0x31 => Instruction::Daload,
+            0x32 => Instruction::Aaload,
+            0x33 => Instruction::Baload,
+            0x34 => Instruction::Caload,
+            0x35 => Instruction::Saload,
+            0x3b => Instruction::Istore0,
+            0x3c => Instruction::Istore1,
+            0x3d => Instruction::Istore2,
+            0x3e => Instruction::Istore3,
+            0x3f => Instruction::Lstore0,
+            0x40 => Instruction::Lstore1,
+            0x41 => Instruction::Lstore2,
+            0x42 => Instruction::Lstore3,
+            0x43 => Instruction::Fstore0,
+            0x44 => Instruction::Fstore1,
+            0x45 => Instruction::Fstore2,
+            0x46 => Instruction::Fstore3,
+            0x47 => Instruction::Dstore0,
+            0x48 => Instruction::Dstore1,
+            0x49 => Instruction::Dstore2,
+            0x4a => Instruction::Dstore3,
+            0x4b => Instruction::Astore0,
+            0x4c => Instruction::Astore1,
+            0x4d => Instruction::Astore2,
+            0x4e => Instruction::Astore3,
+            0x4f => Instruction::Iastore,
+            0x50 => Instruction::Lastore,
+            0x51 => Instruction::Fastore,
+            0x52 => Instruction::Dastore,
+            0x53 => Instruction::Aastore,
+            0x54 => Instruction::Bastore,
+            0x55 => Instruction::Castore,
+            0x56 => Instruction::Sastore,
+            0x57 => Instruction::Pop,
+            0x58 => Instruction::Pop2,
+            0x59 => Instruction::Dup,
+            0x5a => Instruction::DupX1,
+            0x5b => Instruction::DupX2,
+            0x5c => Instruction::Dup2,
+            0x5d => Instruction::Dup2X1,
+            0x5e => Instruction::Dup2X2,
+            0x5f => Instruction::Swap,
+            0x60 => Instruction::Iadd,
+            0x61 => Instruction::Ladd,
+            0x62 => Instruction::Fadd,
+            0x63 => Instruction::Dadd,
+            0x64 => Instruction::Isub,
+            0x65 => Instruction::Lsub,
+            0x66 => Instruction::Fsub,
+            0x67 => Instruction::Dsub,
+            0x68 => Instruction::Imul,
+            0x69 => Instruction::Lmul,
+            0x6a => Instruction::Fmul,
+            0x6b => Instruction::Dmul,
+            0x6c => Instruction::Idiv,
+            0x6d => Instruction::Ldiv,
+            0x6e => Instruction::Fdiv,
+            0x6f => Instruction::Ddiv,
+            0x70 => Instruction::Irem,
+            0x71 => Instruction::Lrem,
+            0x72 => Instruction::Frem,
+            0x73 => Instruction::Drem,
+            0x74 => Instruction::Ineg,
+            0x75 => Instruction::Lneg,
+            0x76 => Instruction::Fneg,
+            0x77 => Instruction::Dneg,
+            0x78 => Instruction::Ishl,
+            0x79 => Instruction::Lshl,
+            0x7a => Instruction::Ishr,
+            0x7b => Instruction::Lshr,
+            0x7c => Instruction::Iushr,
+            0x7d => Instruction::Lushr,
+            0x7e => Instruction::Iand,
+            0x7f => Instruction::Land,
+            0x80 => Instruction::Ior,
+            0x81 => Instruction::Lor,
+            0x82 => Instruction::Ixor,
+            0x83 => Instruction::Lxor,
+            0x85 => Instruction::I2L,
+            0x86 => Instruction::I2F,
+            0x87 => Instruction::I2D,
+            0x88 => Instruction::L2I,
+            0x89 => Instruction::L2F,
+            0x8a => Instruction::L2D,
+            0x8b => Instruction::F2I,
+            0x8c => Instruction::F2L,
+            0x8d => Instruction::F2D,
+            0x8e => Instruction::D2I,
+            0x8f => Instruction::D2L,
+            0x90 => Instruction::D2F,
+            0x91 => Instruction::I2B,
+            0x92 => Instruction::I2C,
+            0x93 => Instruction::I2S,
+            0x94 => Instruction::Lcmp,
+            0x95 => Instruction::Fcmpl,
+            0x96 => Instruction::Fcmpg,
+            0x97 => Instruction::Dcmpl,
+            0x98 => Instruction::Dcmpg,
+            0xac => Instruction::IReturn,
+            0xad => Instruction::Lreturn,
+            0xae => Instruction::FReturn,
+            0xaf => Instruction::Dreturn,
+            0xb0 => Instruction::Areturn,
+            0xb1 => Instruction::Return,
+            0xbe => Instruction::ArrayLength,
+            0xbf => Instruction::Athrow,
+            0xc2 => Instruction::MonitorEnter,
+            0xc3 => Instruction::MonitorExit,
+            _ => return None,
+        })
+    }
+
+    // `pc` is only needed by the switch instructions, whose operands are
+    // padded out to start on a 4-byte boundary. A switch can be too big for a
+    // u16, so the size is a u32 and callers check it against the code limit.
+    pub fn size(&self, pc: u16) -> u32 {
         match *self {
             Instruction::New(_, _) => 3,
             Instruction::Dup => 1,
@@ -290,6 +951,8 @@ impl Instruction {
             Instruction::IfIcmpGt(_) => 3,
             Instruction::IfIcmpLe(_) => 3,
             Instruction::Goto(_) => 3,
+            Instruction::Jsr(_) => 3,
+            Instruction::Ret(_) => 2,
             Instruction::IReturn => 1,
             Instruction::Return => 1,
             Instruction::GetStatic(_) => 3,
@@ -297,6 +960,171 @@ impl Instruction {
             Instruction::InvokeSpecial(_) => 3,
             Instruction::InvokeStatic(_) => 3,
             Instruction::ArrayLength => 1,
+            Instruction::Lload(_) => 2,
+            Instruction::Lload0 => 1,
+            Instruction::Lload1 => 1,
+            Instruction::Lload2 => 1,
+            Instruction::Lload3 => 1,
+            Instruction::Lstore(_) => 2,
+            Instruction::Lstore0 => 1,
+            Instruction::Lstore1 => 1,
+            Instruction::Lstore2 => 1,
+            Instruction::Lstore3 => 1,
+            Instruction::Lconst0 => 1,
+            Instruction::Lconst1 => 1,
+            Instruction::Ladd => 1,
+            Instruction::Lsub => 1,
+            Instruction::Lmul => 1,
+            Instruction::Ldiv => 1,
+            Instruction::Lrem => 1,
+            Instruction::Lreturn => 1,
+            Instruction::Dload(_) => 2,
+            Instruction::Dload0 => 1,
+            Instruction::Dload1 => 1,
+            Instruction::Dload2 => 1,
+            Instruction::Dload3 => 1,
+            Instruction::Dstore(_) => 2,
+            Instruction::Dstore0 => 1,
+            Instruction::Dstore1 => 1,
+            Instruction::Dstore2 => 1,
+            Instruction::Dstore3 => 1,
+            Instruction::Dconst0 => 1,
+            Instruction::Dconst1 => 1,
+            Instruction::Dadd => 1,
+            Instruction::Dsub => 1,
+            Instruction::Dmul => 1,
+            Instruction::Ddiv => 1,
+            Instruction::Drem => 1,
+            Instruction::Dreturn => 1,
+            Instruction::Ldc2W(_) => 3,
+            Instruction::LdcW(_) => 3,
+            Instruction::GetField(_) => 3,
+            Instruction::PutField(_) => 3,
+            Instruction::PutStatic(_) => 3,
+            Instruction::InvokeInterface(_, _) => 5,
+            Instruction::NewArray(_) => 2,
+            Instruction::ANewArray(_) => 3,
+            Instruction::MultiANewArray(_, _) => 4,
+            Instruction::Pop => 1,
+            Instruction::Pop2 => 1,
+            Instruction::Swap => 1,
+            Instruction::DupX1 => 1,
+            Instruction::DupX2 => 1,
+            Instruction::Dup2 => 1,
+            Instruction::Dup2X1 => 1,
+            Instruction::Dup2X2 => 1,
+            Instruction::IfAcmpEq(_) => 3,
+            Instruction::IfAcmpNe(_) => 3,
+            Instruction::IfNull(_) => 3,
+            Instruction::IfNonNull(_) => 3,
+            Instruction::Athrow => 1,
+            Instruction::CheckCast(_) => 3,
+            Instruction::InstanceOf(_) => 3,
+            Instruction::MonitorEnter => 1,
+            Instruction::MonitorExit => 1,
+            Instruction::Nop => 1,
+            Instruction::Iinc(_, _) => 3,
+            Instruction::WideIload(_) | Instruction::WideLload(_) | Instruction::WideFload(_) |
+            Instruction::WideDload(_) | Instruction::WideAload(_) | Instruction::WideIstore(_) |
+            Instruction::WideLstore(_) | Instruction::WideFstore(_) | Instruction::WideDstore(_) |
+            Instruction::WideAstore(_) => 4,
+            Instruction::WideIinc(_, _) => 6,
+            Instruction::TableSwitch(_, _, _, ref offsets) => {
+                1 + switch_padding(pc) as u32 + 12 + 4 * offsets.len() as u32
+            },
+            Instruction::LookupSwitch(_, ref pairs) => {
+                1 + switch_padding(pc) as u32 + 8 + 8 * pairs.len() as u32
+            },
+            Instruction::GotoW(_) => 5,
+            Instruction::JsrW(_) => 5,
+            Instruction::Ineg => 1,
+            Instruction::Iand => 1,
+            Instruction::Ior => 1,
+            Instruction::Ixor => 1,
+            Instruction::Ishl => 1,
+            Instruction::Ishr => 1,
+            Instruction::Iushr => 1,
+            Instruction::Lneg => 1,
+            Instruction::Land => 1,
+            Instruction::Lor => 1,
+            Instruction::Lxor => 1,
+            Instruction::Lshl => 1,
+            Instruction::Lshr => 1,
+            Instruction::Lushr => 1,
+            Instruction::I2L => 1,
+            Instruction::I2D => 1,
+            Instruction::I2B => 1,
+            Instruction::I2S => 1,
+            Instruction::L2I => 1,
+            Instruction::L2F => 1,
+            Instruction::L2D => 1,
+            Instruction::F2L => 1,
+            Instruction::F2D => 1,
+            Instruction::D2I => 1,
+            Instruction::D2L => 1,
+            Instruction::D2F => 1,
+            Instruction::Lcmp => 1,
+            Instruction::Fcmpl => 1,
+            Instruction::Fcmpg => 1,
+            Instruction::Dcmpl => 1,
+            Instruction::Dcmpg => 1,
+            Instruction::InvokeDynamic(_) => 5,
+            Instruction::Iaload => 1,
+            Instruction::Laload => 1,
+            Instruction::Faload => 1,
+            Instruction::Daload => 1,
+            Instruction::Baload => 1,
+            Instruction::Caload => 1,
+            Instruction::Saload => 1,
+            Instruction::Iastore => 1,
+            Instruction::Lastore => 1,
+            Instruction::Fastore => 1,
+            Instruction::Dastore => 1,
+            Instruction::Aastore => 1,
+            Instruction::Bastore => 1,
+            Instruction::Castore => 1,
+            Instruction::Sastore => 1,
+            Instruction::Fneg => 1,
+            Instruction::Dneg => 1,
+            Instruction::Raw(ref bytes) => bytes.len() as u32,
+        }
+    }
+}
+
+// the number of padding bytes between a switch opcode at `pc` and its operands
+pub fn switch_padding(pc: u16) -> u16 {
+    3 - (pc % 4)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serialization::decode_instructions;
+    use std::collections::HashMap;
+    use std::mem;
+
+    #[test]
+    fn opcodes_round_trip_and_are_unique() {
+        assert_eq!(Instruction::Goto(0).opcode(), 0xa7);
+        // Decode every opcode followed by enough zeros for its operands (a switch
+        // with a single entry at most), and check it encodes back to the same
+        // byte. Every variant but the wide forms and Raw decodes from some byte,
+        // so this also makes sure no two variants share an opcode.
+        let mut variants = HashMap::new();
+        for opcode in 0..=255u8 {
+            if opcode == 0xc4 {
+                continue;
+            }
+            let mut code = vec![opcode];
+            code.extend(vec![0; 24]);
+            if let Ok(instructions) = decode_instructions(&code) {
+                let instruction = &instructions[0].1;
+                assert_eq!(instruction.opcode(), opcode, "{:?}", instruction);
+                if let Some(other) = variants.insert(mem::discriminant(instruction), opcode) {
+                    panic!("{:?} decodes from both 0x{:x} and 0x{:x}", instruction, other, opcode);
+                }
+            }
         }
+        assert_eq!(variants.len(), 201);
     }
 }
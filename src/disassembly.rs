@@ -0,0 +1,445 @@
+// A textual dump of a class in the style of `javap -c`, for debugging.
+
+use access_flags::*;
+use classfile::*;
+
+impl Classfile {
+    pub fn disassemble(&self) -> String {
+        let mut out = String::new();
+        let kind = if self.access_flags & ACC_INTERFACE != 0 { "interface" } else { "class" };
+        out.push_str(&format!("{}{} {}", flag_words(self.access_flags & ACC_PUBLIC),
+                              kind, self.class_name(self.this_class)));
+        // only java/lang/Object (and module-info) have no super class
+        if self.super_class != 0 {
+            out.push_str(&format!(" extends {}", self.class_name(self.super_class)));
+        }
+        out.push_str(" {\n");
+
+        for method in &self.methods {
+            out.push_str(&format!("  {}{}{}\n", flag_words(method.access_flags),
+                                  self.lookup_string(method.name_index),
+                                  self.lookup_string(method.descriptor_index)));
+            for attribute in &method.attributes {
+                if let Attribute::Code(_, _, _, ref code, _, _) = *attribute {
+                    out.push_str("    Code:\n");
+                    let mut pc = 0;
+                    for instruction in code {
+                        out.push_str(&self.disassemble_instruction(instruction, pc));
+                        pc += instruction.size(pc) as u16;
+                    }
+                }
+            }
+            out.push('\n');
+        }
+        out.push_str("}\n");
+        out
+    }
+
+    // one line per instruction, or several for a switch
+    fn disassemble_instruction(&self, instruction: &Instruction, pc: u16) -> String {
+        let line = format!("{:>8}: {:<13}", pc, instruction.mnemonic());
+        let target = |offset: i32| pc as i32 + offset;
+        let (operands, constant) = match *instruction {
+            Instruction::LoadConstant(index) => (format!("#{}", index), Some(index as u16)),
+            Instruction::New(high, low) => {
+                let index = (high as u16) << 8 | low as u16;
+                (format!("#{}", index), Some(index))
+            },
+            Instruction::LdcW(index) | Instruction::Ldc2W(index) |
+            Instruction::GetStatic(index) | Instruction::PutStatic(index) |
+            Instruction::GetField(index) | Instruction::PutField(index) |
+            Instruction::InvokeVirtual(index) | Instruction::InvokeSpecial(index) |
+            Instruction::InvokeStatic(index) | Instruction::InvokeDynamic(index) |
+            Instruction::ANewArray(index) | Instruction::CheckCast(index) |
+            Instruction::InstanceOf(index) => (format!("#{}", index), Some(index)),
+            Instruction::Raw(ref bytes) => {
+                let hex: Vec<String> = bytes.iter().map(|b| format!("{:02x}", b)).collect();
+                (hex.join(" "), None)
+            },
+            Instruction::InvokeInterface(index, count) => (format!("#{},  {}", index, count), Some(index)),
+            Instruction::MultiANewArray(index, dimensions) => {
+                (format!("#{},  {}", index, dimensions), Some(index))
+            },
+            Instruction::Bipush(value) => (format!("{}", value as i8), None),
+            Instruction::Sipush(high, low) => (format!("{}", ((high as u16) << 8 | low as u16) as i16), None),
+            Instruction::Iload(index) | Instruction::Lload(index) | Instruction::Fload(index) |
+            Instruction::Dload(index) | Instruction::Aload(index) | Instruction::Istore(index) |
+            Instruction::Lstore(index) | Instruction::Fstore(index) | Instruction::Dstore(index) |
+            Instruction::Astore(index) | Instruction::Ret(index) => (format!("{}", index), None),
+            Instruction::WideIload(index) | Instruction::WideLload(index) |
+            Instruction::WideFload(index) | Instruction::WideDload(index) |
+            Instruction::WideAload(index) | Instruction::WideIstore(index) |
+            Instruction::WideLstore(index) | Instruction::WideFstore(index) |
+            Instruction::WideDstore(index) | Instruction::WideAstore(index) => (format!("{}", index), None),
+            Instruction::Iinc(index, delta) => (format!("{}, {}", index, delta), None),
+            Instruction::WideIinc(index, delta) => (format!("{}, {}", index, delta), None),
+            Instruction::NewArray(atype) => (array_type_name(atype).to_owned(), None),
+            Instruction::IfEq(offset) | Instruction::IfNe(offset) | Instruction::IfLt(offset) |
+            Instruction::IfGe(offset) | Instruction::IfGt(offset) | Instruction::IfLe(offset) |
+            Instruction::IfIcmpEq(offset) | Instruction::IfIcmpNe(offset) |
+            Instruction::IfIcmpLt(offset) | Instruction::IfIcmpGe(offset) |
+            Instruction::IfIcmpGt(offset) | Instruction::IfIcmpLe(offset) |
+            Instruction::IfAcmpEq(offset) | Instruction::IfAcmpNe(offset) |
+            Instruction::IfNull(offset) | Instruction::IfNonNull(offset) |
+            Instruction::Goto(offset) | Instruction::Jsr(offset) => (format!("{}", target(offset as i16 as i32)), None),
+            Instruction::GotoW(offset) | Instruction::JsrW(offset) => {
+                (format!("{}", target(offset as i32)), None)
+            },
+            Instruction::TableSwitch(default, low, high, ref offsets) => {
+                let cases = offsets.iter().enumerate().map(|(i, &offset)| (low + i as i32, offset)).collect();
+                return switch_lines(line, format!("{} to {}", low, high), cases, default, pc);
+            },
+            Instruction::LookupSwitch(default, ref pairs) => {
+                return switch_lines(line, format!("{}", pairs.len()), pairs.clone(), default, pc);
+            },
+            _ => return format!("{}\n", line.trim_end()),
+        };
+        match constant {
+            Some(index) => format!("{} {:<19} // {}\n", line, operands, self.describe_constant(index)),
+            None => format!("{} {}\n", line, operands),
+        }
+    }
+
+    // roughly how javap describes a constant in its comments
+    fn describe_constant(&self, index: u16) -> String {
+        match *self.lookup_constant(index) {
+            Constant::Utf8(ref s) => s.clone(),
+            Constant::Integer(n) => format!("int {}", n),
+            Constant::Float(n) => format!("float {}f", n),
+            Constant::Long(n) => format!("long {}l", n),
+            Constant::Double(n) => format!("double {}d", n),
+            Constant::Class(name_index) => format!("class {}", self.lookup_string(name_index)),
+            Constant::String(string_index) => format!("String {}", self.lookup_string(string_index)),
+            Constant::Fieldref(class_index, name_and_type_index) => {
+                format!("Field {}", self.describe_member(class_index, name_and_type_index))
+            },
+            Constant::Methodref(class_index, name_and_type_index) => {
+                format!("Method {}", self.describe_member(class_index, name_and_type_index))
+            },
+            Constant::InterfaceMethodref(class_index, name_and_type_index) => {
+                format!("InterfaceMethod {}", self.describe_member(class_index, name_and_type_index))
+            },
+            Constant::NameAndType(name_index, descriptor_index) => {
+                format!("{}:{}", self.lookup_string(name_index), self.lookup_string(descriptor_index))
+            },
+            Constant::MethodHandle(kind, reference_index) => {
+                format!("MethodHandle {}:{}", kind, self.describe_constant(reference_index))
+            },
+            Constant::MethodType(descriptor_index) => {
+                format!("MethodType {}", self.lookup_string(descriptor_index))
+            },
+            Constant::InvokeDynamic(bootstrap_method, name_and_type_index) => {
+                format!("InvokeDynamic #{}:{}", bootstrap_method, self.describe_constant(name_and_type_index))
+            },
+            Constant::Unusable => "(unusable)".to_owned(),
+        }
+    }
+
+    // e.g. "java/io/PrintStream.println:(Ljava/lang/String;)V"
+    fn describe_member(&self, class_index: u16, name_and_type_index: u16) -> String {
+        format!("{}.{}", self.class_name(class_index), self.describe_constant(name_and_type_index))
+    }
+
+    fn class_name(&self, class_index: u16) -> &str {
+        match *self.lookup_constant(class_index) {
+            Constant::Class(name_index) => self.lookup_string(name_index),
+            ref c => panic!("Wanted class, found {:?}", c),
+        }
+    }
+}
+
+impl Instruction {
+    pub fn mnemonic(&self) -> &'static str {
+        match *self {
+            Instruction::New(..) => "new",
+            Instruction::Dup => "dup",
+            Instruction::Irem => "irem",
+            Instruction::Frem => "frem",
+            Instruction::Fmul => "fmul",
+            Instruction::Fdiv => "fdiv",
+            Instruction::Fadd => "fadd",
+            Instruction::Fsub => "fsub",
+            Instruction::Fload0 => "fload_0",
+            Instruction::Fload1 => "fload_1",
+            Instruction::Fload2 => "fload_2",
+            Instruction::Fload3 => "fload_3",
+            Instruction::Fload(..) => "fload",
+            Instruction::Fstore0 => "fstore_0",
+            Instruction::Fstore1 => "fstore_1",
+            Instruction::Fstore2 => "fstore_2",
+            Instruction::Fstore3 => "fstore_3",
+            Instruction::Fstore(..) => "fstore",
+            Instruction::Fconst0 => "fconst_0",
+            Instruction::Fconst1 => "fconst_1",
+            Instruction::Fconst2 => "fconst_2",
+            Instruction::FReturn => "freturn",
+            Instruction::I2C => "i2c",
+            Instruction::I2F => "i2f",
+            Instruction::F2I => "f2i",
+            Instruction::IconstM1 => "iconst_m1",
+            Instruction::Iconst0 => "iconst_0",
+            Instruction::Iconst1 => "iconst_1",
+            Instruction::Iconst2 => "iconst_2",
+            Instruction::Iconst3 => "iconst_3",
+            Instruction::Iconst4 => "iconst_4",
+            Instruction::Iconst5 => "iconst_5",
+            Instruction::Istore0 => "istore_0",
+            Instruction::Istore1 => "istore_1",
+            Instruction::Istore2 => "istore_2",
+            Instruction::Istore3 => "istore_3",
+            Instruction::Istore(..) => "istore",
+            Instruction::Bipush(..) => "bipush",
+            Instruction::Sipush(..) => "sipush",
+            Instruction::Iload0 => "iload_0",
+            Instruction::Iload1 => "iload_1",
+            Instruction::Iload2 => "iload_2",
+            Instruction::Iload3 => "iload_3",
+            Instruction::Iload(..) => "iload",
+            Instruction::LoadConstant(..) => "ldc",
+            Instruction::AConstNull => "aconst_null",
+            Instruction::Areturn => "areturn",
+            Instruction::Astore0 => "astore_0",
+            Instruction::Astore1 => "astore_1",
+            Instruction::Astore2 => "astore_2",
+            Instruction::Astore3 => "astore_3",
+            Instruction::Astore(..) => "astore",
+            Instruction::Aload0 => "aload_0",
+            Instruction::Aload1 => "aload_1",
+            Instruction::Aload2 => "aload_2",
+            Instruction::Aload3 => "aload_3",
+            Instruction::Aload(..) => "aload",
+            Instruction::Aaload => "aaload",
+            Instruction::Iadd => "iadd",
+            Instruction::Isub => "isub",
+            Instruction::Imul => "imul",
+            Instruction::Idiv => "idiv",
+            Instruction::IfEq(..) => "ifeq",
+            Instruction::IfNe(..) => "ifne",
+            Instruction::IfLt(..) => "iflt",
+            Instruction::IfGe(..) => "ifge",
+            Instruction::IfGt(..) => "ifgt",
+            Instruction::IfLe(..) => "ifle",
+            Instruction::IfIcmpEq(..) => "if_icmpeq",
+            Instruction::IfIcmpNe(..) => "if_icmpne",
+            Instruction::IfIcmpLt(..) => "if_icmplt",
+            Instruction::IfIcmpGe(..) => "if_icmpge",
+            Instruction::IfIcmpGt(..) => "if_icmpgt",
+            Instruction::IfIcmpLe(..) => "if_icmple",
+            Instruction::Goto(..) => "goto",
+            Instruction::Jsr(..) => "jsr",
+            Instruction::Ret(..) => "ret",
+            Instruction::IReturn => "ireturn",
+            Instruction::Return => "return",
+            Instruction::GetStatic(..) => "getstatic",
+            Instruction::InvokeVirtual(..) => "invokevirtual",
+            Instruction::InvokeSpecial(..) => "invokespecial",
+            Instruction::InvokeStatic(..) => "invokestatic",
+            Instruction::ArrayLength => "arraylength",
+            Instruction::Lload(..) => "lload",
+            Instruction::Lload0 => "lload_0",
+            Instruction::Lload1 => "lload_1",
+            Instruction::Lload2 => "lload_2",
+            Instruction::Lload3 => "lload_3",
+            Instruction::Lstore(..) => "lstore",
+            Instruction::Lstore0 => "lstore_0",
+            Instruction::Lstore1 => "lstore_1",
+            Instruction::Lstore2 => "lstore_2",
+            Instruction::Lstore3 => "lstore_3",
+            Instruction::Lconst0 => "lconst_0",
+            Instruction::Lconst1 => "lconst_1",
+            Instruction::Ladd => "ladd",
+            Instruction::Lsub => "lsub",
+            Instruction::Lmul => "lmul",
+            Instruction::Ldiv => "ldiv",
+            Instruction::Lrem => "lrem",
+            Instruction::Lreturn => "lreturn",
+            Instruction::Dload(..) => "dload",
+            Instruction::Dload0 => "dload_0",
+            Instruction::Dload1 => "dload_1",
+            Instruction::Dload2 => "dload_2",
+            Instruction::Dload3 => "dload_3",
+            Instruction::Dstore(..) => "dstore",
+            Instruction::Dstore0 => "dstore_0",
+            Instruction::Dstore1 => "dstore_1",
+            Instruction::Dstore2 => "dstore_2",
+            Instruction::Dstore3 => "dstore_3",
+            Instruction::Dconst0 => "dconst_0",
+            Instruction::Dconst1 => "dconst_1",
+            Instruction::Dadd => "dadd",
+            Instruction::Dsub => "dsub",
+            Instruction::Dmul => "dmul",
+            Instruction::Ddiv => "ddiv",
+            Instruction::Drem => "drem",
+            Instruction::Dreturn => "dreturn",
+            Instruction::Ldc2W(..) => "ldc2_w",
+            Instruction::LdcW(..) => "ldc_w",
+            Instruction::GetField(..) => "getfield",
+            Instruction::PutField(..) => "putfield",
+            Instruction::PutStatic(..) => "putstatic",
+            Instruction::InvokeInterface(..) => "invokeinterface",
+            Instruction::NewArray(..) => "newarray",
+            Instruction::ANewArray(..) => "anewarray",
+            Instruction::MultiANewArray(..) => "multianewarray",
+            Instruction::Pop => "pop",
+            Instruction::Pop2 => "pop2",
+            Instruction::Swap => "swap",
+            Instruction::DupX1 => "dup_x1",
+            Instruction::DupX2 => "dup_x2",
+            Instruction::Dup2 => "dup2",
+            Instruction::Dup2X1 => "dup2_x1",
+            Instruction::Dup2X2 => "dup2_x2",
+            Instruction::IfAcmpEq(..) => "if_acmpeq",
+            Instruction::IfAcmpNe(..) => "if_acmpne",
+            Instruction::IfNull(..) => "ifnull",
+            Instruction::IfNonNull(..) => "ifnonnull",
+            Instruction::Athrow => "athrow",
+            Instruction::CheckCast(..) => "checkcast",
+            Instruction::InstanceOf(..) => "instanceof",
+            Instruction::MonitorEnter => "monitorenter",
+            Instruction::MonitorExit => "monitorexit",
+            Instruction::Nop => "nop",
+            Instruction::Iinc(..) => "iinc",
+            Instruction::WideIload(..) => "iload",
+            Instruction::WideLload(..) => "lload",
+            Instruction::WideFload(..) => "fload",
+            Instruction::WideDload(..) => "dload",
+            Instruction::WideAload(..) => "aload",
+            Instruction::WideIstore(..) => "istore",
+            Instruction::WideLstore(..) => "lstore",
+            Instruction::WideFstore(..) => "fstore",
+            Instruction::WideDstore(..) => "dstore",
+            Instruction::WideAstore(..) => "astore",
+            Instruction::WideIinc(..) => "iinc",
+            Instruction::TableSwitch(..) => "tableswitch",
+            Instruction::LookupSwitch(..) => "lookupswitch",
+            Instruction::GotoW(..) => "goto_w",
+            Instruction::JsrW(..) => "jsr_w",
+            Instruction::Ineg => "ineg",
+            Instruction::Iand => "iand",
+            Instruction::Ior => "ior",
+            Instruction::Ixor => "ixor",
+            Instruction::Ishl => "ishl",
+            Instruction::Ishr => "ishr",
+            Instruction::Iushr => "iushr",
+            Instruction::Lneg => "lneg",
+            Instruction::Land => "land",
+            Instruction::Lor => "lor",
+            Instruction::Lxor => "lxor",
+            Instruction::Lshl => "lshl",
+            Instruction::Lshr => "lshr",
+            Instruction::Lushr => "lushr",
+            Instruction::I2L => "i2l",
+            Instruction::I2D => "i2d",
+            Instruction::I2B => "i2b",
+            Instruction::I2S => "i2s",
+            Instruction::L2I => "l2i",
+            Instruction::L2F => "l2f",
+            Instruction::L2D => "l2d",
+            Instruction::F2L => "f2l",
+            Instruction::F2D => "f2d",
+            Instruction::D2I => "d2i",
+            Instruction::D2L => "d2l",
+            Instruction::D2F => "d2f",
+            Instruction::Lcmp => "lcmp",
+            Instruction::Fcmpl => "fcmpl",
+            Instruction::Fcmpg => "fcmpg",
+            Instruction::Dcmpl => "dcmpl",
+            Instruction::Dcmpg => "dcmpg",
+            Instruction::InvokeDynamic(..) => "invokedynamic",
+            Instruction::Iaload => "iaload",
+            Instruction::Laload => "laload",
+            Instruction::Faload => "faload",
+            Instruction::Daload => "daload",
+            Instruction::Baload => "baload",
+            Instruction::Caload => "caload",
+            Instruction::Saload => "saload",
+            Instruction::Iastore => "iastore",
+            Instruction::Lastore => "lastore",
+            Instruction::Fastore => "fastore",
+            Instruction::Dastore => "dastore",
+            Instruction::Aastore => "aastore",
+            Instruction::Bastore => "bastore",
+            Instruction::Castore => "castore",
+            Instruction::Sastore => "sastore",
+            Instruction::Fneg => "fneg",
+            Instruction::Dneg => "dneg",
+            Instruction::Raw(..) => "raw",
+        }
+    }
+}
+
+fn switch_lines(line: String, summary: String, cases: Vec<(i32, i32)>, default: i32, pc: u16) -> String {
+    let mut s = format!("{} {{ // {}\n", line, summary);
+    for (key, offset) in cases {
+        s.push_str(&format!("{:>22}: {}\n", key, pc as i32 + offset));
+    }
+    s.push_str(&format!("{:>22}: {}\n", "default", pc as i32 + default));
+    s.push_str(&format!("{:>11}\n", "}"));
+    s
+}
+
+// the modifiers javap would show, each followed by a space
+fn flag_words(access_flags: u16) -> String {
+    let words = [(ACC_PUBLIC, "public"), (ACC_PRIVATE, "private"), (ACC_PROTECTED, "protected"),
+                 (ACC_STATIC, "static"), (ACC_FINAL, "final"), (ACC_SYNCHRONIZED, "synchronized"),
+                 (ACC_NATIVE, "native"), (ACC_ABSTRACT, "abstract")];
+    words.iter()
+        .filter(|&&(flag, _)| access_flags & flag != 0)
+        .map(|&(_, word)| format!("{} ", word))
+        .collect()
+}
+
+fn array_type_name(atype: u8) -> &'static str {
+    match atype {
+        4 => "boolean",
+        5 => "char",
+        6 => "float",
+        7 => "double",
+        8 => "byte",
+        9 => "short",
+        10 => "int",
+        11 => "long",
+        _ => "?",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use access_flags::*;
+    use class_builder::ClassBuilder;
+    use java_type_signatures::Java;
+
+    #[test]
+    fn disassembles_a_small_class() {
+        let mut class = ClassBuilder::new(ACC_PUBLIC, "T", "java/lang/Object");
+        {
+            let mut method = class.define_method(ACC_PUBLIC | ACC_STATIC, "m", &[Java::Int], &Java::Int);
+            method.iload0().iconst2().imul().ireturn();
+            method.done().unwrap();
+        }
+        let text = class.done().unwrap().disassemble();
+        assert!(text.contains("extends java/lang/Object"));
+        for mnemonic in &["iload_0", "iconst_2", "imul", "ireturn"] {
+            assert!(text.lines().any(|line| line.contains(mnemonic)), "no {} in:\n{}", mnemonic, text);
+        }
+    }
+
+    #[test]
+    fn class_without_a_super_class() {
+        let mut classfile = ClassBuilder::new(ACC_PUBLIC, "java/lang/Object", "java/lang/Object").done().unwrap();
+        classfile.super_class = 0;
+        assert!(!classfile.disassemble().contains("extends"));
+    }
+
+    #[test]
+    fn mnemonics() {
+        use classfile::Instruction;
+
+        assert_eq!(Instruction::Iadd.mnemonic(), "iadd");
+        assert_eq!(Instruction::AConstNull.mnemonic(), "aconst_null");
+        assert_eq!(Instruction::Goto(0).mnemonic(), "goto");
+        assert_eq!(Instruction::InvokeVirtual(1).mnemonic(), "invokevirtual");
+        assert_eq!(Instruction::LdcW(1).mnemonic(), "ldc_w");
+        assert_eq!(Instruction::TableSwitch(0, 0, 0, vec![0]).mnemonic(), "tableswitch");
+    }
+}
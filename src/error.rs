@@ -0,0 +1,93 @@
+use std::error::Error;
+use std::fmt;
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum AssemblerError {
+    UndefinedLabel(String, u16), // label, environment
+    LocalEndsBeforeStart(String), // local variable name
+    BranchOutOfRange(String, i32), // label, offset
+    ConstantPoolFull(usize), // number of entries the class needed
+    DuplicateMethod(String, String), // name, descriptor
+    MissingReturn(String), // method name
+    InvalidClassfile(String), // what was wrong
+    SplitsLongOrDouble(String, u16), // instruction, pc
+    NotABranch(u8), // opcode
+    InvalidBranchTarget(u16), // pc
+    WrongReturn(String, String, String), // method name, expected instruction, found instruction
+    MethodTooLarge(String), // method name
+    RawSizeMismatch(u16, usize), // size given, number of bytes
+    RawStackDeltaOutOfRange(i32), // stack delta
+    StackUnderflow(String, u16), // instruction, pc
+    TypeMismatch(String, u16, String), // instruction, pc, what was wrong
+    InvalidDescriptor(String), // descriptor
+    InvalidMethodHandle(u8, String), // reference kind, what was wrong
+    NotAFieldref(u16), // constant pool index
+    VoidNotAllowed(String), // where void was used
+    InvalidClassName(String), // class name
+    EmptyTableSwitch(i32), // low
+    TableSwitchOutOfRange(i32, usize), // low, number of labels
+    DuplicateSwitchKey(i32), // key
+    StringTooLong(usize), // length in modified UTF-8 bytes
+}
+
+impl fmt::Display for AssemblerError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            AssemblerError::UndefinedLabel(ref label, env) =>
+                write!(f, "label {:?} (environment {}) is used but never defined", label, env),
+            AssemblerError::LocalEndsBeforeStart(ref name) =>
+                write!(f, "local variable {} ends before it starts", name),
+            AssemblerError::BranchOutOfRange(ref label, offset) =>
+                write!(f, "branch to label {:?} is {} bytes away, more than a 16-bit offset can reach",
+                       label, offset),
+            AssemblerError::ConstantPoolFull(entries) =>
+                write!(f, "the class needs {} constant pool entries, but at most 65534 fit", entries),
+            AssemblerError::DuplicateMethod(ref name, ref descriptor) =>
+                write!(f, "method {}{} is defined more than once", name, descriptor),
+            AssemblerError::MissingReturn(ref name) =>
+                write!(f, "method {} can run off the end of its code without returning", name),
+            AssemblerError::InvalidClassfile(ref problem) =>
+                write!(f, "invalid class file: {}", problem),
+            AssemblerError::SplitsLongOrDouble(ref instruction, pc) =>
+                write!(f, "{} at pc {} would split a long or double on the stack", instruction, pc),
+            AssemblerError::NotABranch(opcode) =>
+                write!(f, "opcode 0x{:x} isn't a branch", opcode),
+            AssemblerError::InvalidBranchTarget(pc) =>
+                write!(f, "pc {} isn't the start of an instruction, so it can't be branched to", pc),
+            AssemblerError::WrongReturn(ref name, ref expected, ref found) =>
+                write!(f, "method {} returns with {}, but its descriptor needs {}", name, found, expected),
+            AssemblerError::MethodTooLarge(ref name) =>
+                write!(f, "method {} is too large: its code is longer than 65535 bytes", name),
+            AssemblerError::RawSizeMismatch(size, len) =>
+                write!(f, "raw instructions said to be {} bytes long are {} bytes long", size, len),
+            AssemblerError::RawStackDeltaOutOfRange(delta) =>
+                write!(f, "raw instructions can't change the stack depth by {}; the limit is 255 either way", delta),
+            AssemblerError::StackUnderflow(ref instruction, pc) =>
+                write!(f, "{} at pc {} needs more values than the stack holds", instruction, pc),
+            AssemblerError::TypeMismatch(ref instruction, pc, ref problem) =>
+                write!(f, "{} at pc {}: {}", instruction, pc, problem),
+            AssemblerError::InvalidDescriptor(ref descriptor) =>
+                write!(f, "{:?} isn't a valid descriptor", descriptor),
+            AssemblerError::InvalidMethodHandle(kind, ref problem) =>
+                write!(f, "invalid method handle of kind {}: {}", kind, problem),
+            AssemblerError::NotAFieldref(index) =>
+                write!(f, "constant pool entry {} isn't a Fieldref", index),
+            AssemblerError::VoidNotAllowed(ref place) =>
+                write!(f, "{} can't be void", place),
+            AssemblerError::InvalidClassName(ref name) =>
+                write!(f, "{:?} isn't a valid class name", name),
+            AssemblerError::EmptyTableSwitch(low) =>
+                write!(f, "tableswitch starting at key {} has no labels", low),
+            AssemblerError::TableSwitchOutOfRange(low, count) =>
+                write!(f, "tableswitch starting at key {} can't have {} labels: its keys would pass {}",
+                       low, count, i32::MAX),
+            AssemblerError::DuplicateSwitchKey(key) =>
+                write!(f, "lookupswitch has more than one case for key {}", key),
+            AssemblerError::StringTooLong(length) =>
+                write!(f, "a string is {} bytes long in modified UTF-8, but at most 65535 fit in a constant",
+                       length),
+        }
+    }
+}
+
+impl Error for AssemblerError {}
@@ -0,0 +1,201 @@
+// Writes several classes out together as a jar. A jar is just a zip file with a
+// manifest, and the entries here are stored uncompressed, so no compression
+// library is needed.
+
+use std::io;
+use std::io::Write;
+
+use class_builder::*;
+use classfile::*;
+use error::AssemblerError;
+
+pub struct JarBuilder {
+    main_class: Option<String>,
+    entries: Vec<(String, Vec<u8>)>, // path, contents
+}
+
+impl JarBuilder {
+    pub fn new() -> JarBuilder {
+        JarBuilder { main_class: None, entries: vec![] }
+    }
+
+    // finishes the class and adds it under its package path, e.g. "com/example/Foo.class"
+    pub fn add_class(&mut self, builder: ClassBuilder) -> Result<(), AssemblerError> {
+        let classfile = try!(builder.done());
+        self.add_classfile(&classfile)
+    }
+
+    pub fn add_classfile(&mut self, classfile: &Classfile) -> Result<(), AssemblerError> {
+        let name = match this_class_name(classfile) {
+            Some(name) => name.to_owned(),
+            None => {
+                let problem = format!("this_class ({}) doesn't point at a Class constant", classfile.this_class);
+                return Err(AssemblerError::InvalidClassfile(problem));
+            },
+        };
+        let path = format!("{}.class", name);
+        self.entries.retain(|&(ref p, _)| *p != path);
+        self.entries.push((path, classfile.to_bytes()));
+        Ok(())
+    }
+
+    // the class `java -jar` runs, in either dotted or slashed form
+    pub fn set_main_class(&mut self, class: &str) {
+        self.main_class = Some(class.replace('/', "."));
+    }
+
+    pub fn write_to<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        let mut manifest = "Manifest-Version: 1.0\r\nCreated-By: jvm-assembler\r\n".to_owned();
+        if let Some(ref class) = self.main_class {
+            manifest.push_str(&format!("Main-Class: {}\r\n", class));
+        }
+        manifest.push_str("\r\n");
+
+        let mut entries = vec![("META-INF/MANIFEST.MF", manifest.as_bytes())];
+        entries.extend(self.entries.iter().map(|&(ref path, ref contents)| (&path[..], &contents[..])));
+
+        // each entry is a local header followed by its contents; the central
+        // directory at the end repeats the headers along with where to find them
+        let mut offset = 0;
+        let mut central_directory = vec![];
+        for &(path, contents) in &entries {
+            let crc = crc32(contents);
+            let mut header = vec![];
+            push_u32(&mut header, 0x04034b50);
+            push_entry_fields(&mut header, path, contents.len() as u32, crc);
+            header.extend_from_slice(path.as_bytes());
+            try!(w.write_all(&header));
+            try!(w.write_all(contents));
+
+            push_u32(&mut central_directory, 0x02014b50);
+            push_u16(&mut central_directory, 20); // version made by
+            push_entry_fields(&mut central_directory, path, contents.len() as u32, crc);
+            push_u16(&mut central_directory, 0); // file comment length
+            push_u16(&mut central_directory, 0); // disk number start
+            push_u16(&mut central_directory, 0); // internal attributes
+            push_u32(&mut central_directory, 0); // external attributes
+            push_u32(&mut central_directory, offset);
+            central_directory.extend_from_slice(path.as_bytes());
+
+            offset += (header.len() + contents.len()) as u32;
+        }
+        try!(w.write_all(&central_directory));
+
+        let mut end = vec![];
+        push_u32(&mut end, 0x06054b50);
+        push_u16(&mut end, 0); // this disk
+        push_u16(&mut end, 0); // disk with the central directory
+        push_u16(&mut end, entries.len() as u16);
+        push_u16(&mut end, entries.len() as u16);
+        push_u32(&mut end, central_directory.len() as u32);
+        push_u32(&mut end, offset);
+        push_u16(&mut end, 0); // comment length
+        w.write_all(&end)
+    }
+}
+
+impl Default for JarBuilder {
+    fn default() -> JarBuilder {
+        JarBuilder::new()
+    }
+}
+
+// the name of the class a class file defines, if its this_class is well-formed
+fn this_class_name(classfile: &Classfile) -> Option<&str> {
+    let constant = |index: u16| classfile.constant_pool.get((index as usize).wrapping_sub(1));
+    match constant(classfile.this_class) {
+        Some(&Constant::Class(name_index)) => match constant(name_index) {
+            Some(&Constant::Utf8(ref name)) => Some(name),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+// the fields shared by local headers and central directory entries, from
+// "version needed to extract" through "extra field length"
+fn push_entry_fields(buf: &mut Vec<u8>, path: &str, size: u32, crc: u32) {
+    push_u16(buf, 10); // version needed to extract
+    push_u16(buf, 0); // flags
+    push_u16(buf, 0); // compression method: stored
+    push_u16(buf, 0); // modification time
+    push_u16(buf, 0x21); // modification date: 1980-01-01
+    push_u32(buf, crc);
+    push_u32(buf, size); // compressed size
+    push_u32(buf, size); // uncompressed size
+    push_u16(buf, path.len() as u16);
+    push_u16(buf, 0); // extra field length
+}
+
+// zip files are little-endian, unlike class files
+fn push_u16(buf: &mut Vec<u8>, n: u16) {
+    buf.push(n as u8);
+    buf.push((n >> 8) as u8);
+}
+
+fn push_u32(buf: &mut Vec<u8>, n: u32) {
+    push_u16(buf, n as u16);
+    push_u16(buf, (n >> 16) as u16);
+}
+
+fn crc32(bytes: &[u8]) -> u32 {
+    let mut crc = 0xffffffff;
+    for &byte in bytes {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 { (crc >> 1) ^ 0xedb88320 } else { crc >> 1 };
+        }
+    }
+    !crc
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use access_flags::*;
+
+    // the (path, contents) of each entry, read from the local headers
+    fn read_entries(jar: &[u8]) -> Vec<(String, Vec<u8>)> {
+        let u16_at = |i: usize| jar[i] as usize | (jar[i + 1] as usize) << 8;
+        let u32_at = |i: usize| u16_at(i) | u16_at(i + 2) << 16;
+        let mut entries = vec![];
+        let mut offset = 0;
+        while u32_at(offset) == 0x04034b50 {
+            let size = u32_at(offset + 18);
+            let name_length = u16_at(offset + 26);
+            let start = offset + 30 + name_length + u16_at(offset + 28);
+            let name = String::from_utf8(jar[offset + 30..offset + 30 + name_length].to_vec()).unwrap();
+            entries.push((name, jar[start..start + size].to_vec()));
+            offset = start + size;
+        }
+        entries
+    }
+
+    #[test]
+    fn jar_holds_both_classes() {
+        let mut jar = JarBuilder::new();
+        jar.add_class(ClassBuilder::new(ACC_PUBLIC, "com/example/Foo", "java/lang/Object")).unwrap();
+        jar.add_class(ClassBuilder::new(ACC_PUBLIC, "com.example.Bar", "java/lang/Object")).unwrap();
+        jar.set_main_class("com/example/Foo");
+        let mut buf = vec![];
+        jar.write_to(&mut buf).unwrap();
+
+        let entries = read_entries(&buf);
+        let names: Vec<&str> = entries.iter().map(|&(ref name, _)| &name[..]).collect();
+        assert_eq!(names, ["META-INF/MANIFEST.MF", "com/example/Foo.class", "com/example/Bar.class"]);
+        assert!(String::from_utf8(entries[0].1.clone()).unwrap().contains("Main-Class: com.example.Foo\r\n"));
+        for &(_, ref contents) in &entries[1..] {
+            assert_eq!(&contents[..4], &[0xca, 0xfe, 0xba, 0xbe]);
+        }
+    }
+
+    #[test]
+    fn malformed_this_class_is_an_error() {
+        let mut classfile = ClassBuilder::new(ACC_PUBLIC, "Foo", "java/lang/Object").done().unwrap();
+        classfile.this_class = 500;
+        match JarBuilder::new().add_classfile(&classfile) {
+            Err(AssemblerError::InvalidClassfile(_)) => {},
+            r => panic!("expected InvalidClassfile, got {:?}", r),
+        }
+    }
+}
@@ -27,12 +27,34 @@ impl<'a> fmt::Display for Java<'a> {
             Java::Float        => write!(f, "F"),
             Java::Double       => write!(f, "D"),
             Java::Void         => write!(f, "V"),
-            Java::Class(ref s) => write!(f, "L{};", s),
+            Java::Class(ref s) => write!(f, "L{};", internal_name(s)),
             Java::Array(ref t) => write!(f, "[{}", t),
         }
     }
 }
 
+impl<'a> Java<'a> {
+    // an array with the given number of dimensions, e.g. array(Int, 2) is int[][] ("[[I")
+    pub fn array(element: Java<'a>, dimensions: u8) -> Java<'a> {
+        (0..dimensions).fold(element, |t, _| Java::Array(Box::new(t)))
+    }
+
+    // the number of stack or local variable slots a value of this type occupies
+    pub fn slot_size(&self) -> u8 {
+        match *self {
+            Java::Long | Java::Double => 2,
+            Java::Void => 0,
+            _ => 1,
+        }
+    }
+}
+
+// Class files name classes with slashes ("java/lang/String"), so this converts
+// the dotted form used in Java source. Names already using slashes are unchanged.
+pub fn internal_name(class: &str) -> String {
+    class.replace('.', "/")
+}
+
 pub fn method_signature(argument_types: &[Java], return_type: &Java) -> String {
     let mut args = "".to_owned();
     for t in argument_types {
@@ -40,3 +62,64 @@ pub fn method_signature(argument_types: &[Java], return_type: &Java) -> String {
     }
     format!("({}){}", args, return_type)
 }
+
+// Parses a field descriptor like "[Ljava/lang/String;". Returns None if it isn't
+// exactly one valid type.
+pub fn parse_type_signature<'a>(descriptor: &'a str) -> Option<Java<'a>> {
+    match parse_type(descriptor) {
+        Some((t, "")) => Some(t),
+        _ => None,
+    }
+}
+
+// Parses a method descriptor like "(IJ)V" into its argument and return types,
+// the inverse of `method_signature`.
+pub fn parse_method_signature<'a>(descriptor: &'a str) -> Option<(Vec<Java<'a>>, Java<'a>)> {
+    if !descriptor.starts_with('(') {
+        return None;
+    }
+    let mut rest = &descriptor[1..];
+    let mut argument_types = vec![];
+    while !rest.starts_with(')') {
+        let (t, r) = match parse_type(rest) {
+            Some((Java::Void, _)) | None => return None,
+            Some(parsed) => parsed,
+        };
+        argument_types.push(t);
+        rest = r;
+    }
+    match parse_type(&rest[1..]) {
+        Some((return_type, "")) => Some((argument_types, return_type)),
+        _ => None,
+    }
+}
+
+// parses the type at the start of `descriptor`, returning it and the rest of the string
+fn parse_type<'a>(descriptor: &'a str) -> Option<(Java<'a>, &'a str)> {
+    let mut chars = descriptor.chars();
+    let first = chars.next();
+    let rest = chars.as_str();
+    let t = match first {
+        Some('Z') => Java::Boolean,
+        Some('B') => Java::Byte,
+        Some('C') => Java::Char,
+        Some('S') => Java::Short,
+        Some('I') => Java::Int,
+        Some('J') => Java::Long,
+        Some('F') => Java::Float,
+        Some('D') => Java::Double,
+        Some('V') => Java::Void,
+        Some('L') => {
+            return rest.find(';').map(|end| (Java::Class(&rest[..end]), &rest[end + 1..]));
+        },
+        Some('[') => {
+            return match parse_type(rest) {
+                Some((Java::Void, _)) | None => None,
+                Some((element, r)) => Some((Java::Array(Box::new(element)), r)),
+            };
+        },
+        _ => return None,
+    };
+    Some((t, rest))
+}
+
@@ -1,22 +1,27 @@
+pub mod access_flags;
 mod classfile;
 mod class_builder;
+mod disassembly;
+mod error;
+mod jar;
 mod java_type_signatures;
 mod pretty_printing;
 mod serialization;
+mod validation;
 
 use std::fs::File;
-use std::io::Write;
 
+pub use access_flags::*;
 pub use classfile::*;
 pub use class_builder::*;
+pub use error::AssemblerError;
+pub use jar::JarBuilder;
 pub use java_type_signatures::*;
+pub use serialization::{decode_instructions, DecodeError};
 
 pub fn write_classfile(classfile: Classfile, filename: &str) {
-    let mut bytes = vec![];
-    classfile.serialize(&mut bytes);
-
     let mut f = File::create(filename).unwrap();
-    f.write_all(&bytes).unwrap();
+    classfile.write_to(&mut f).unwrap();
 }
 
 pub fn read_classfile(filename: &str) -> Classfile {
@@ -27,3 +32,7 @@ pub fn read_classfile(filename: &str) -> Classfile {
 pub fn define_class(access_flags: u16, this_class: &str, super_class: &str) -> ClassBuilder {
     ClassBuilder::new(access_flags, this_class, super_class)
 }
+
+pub fn define_interface(access_flags: u16, this_class: &str) -> ClassBuilder {
+    ClassBuilder::new_interface(access_flags, this_class)
+}
@@ -20,6 +20,7 @@ impl fmt::Display for Classfile {
         try!(writeln!(f, "Methods:"));
         try!(self.methods.pretty_println(f, 2));
         try!(writeln!(f, "Attributes:"));
+        try!(self.attributes.pretty_println(f, 2));
         Ok(())
     }
 }
@@ -108,13 +109,16 @@ impl<'a> PrettyPrint for &'a Constant {
 
 impl PrettyPrint for Interface {
     fn pretty_print(&self, f: &mut fmt::Formatter, _indent: usize) -> fmt::Result {
-        write!(f, "{:?}", self)
+        write!(f, "Interface(class_index: {})", self.class_index)
     }
 }
 
 impl PrettyPrint for Field {
-    fn pretty_print(&self, f: &mut fmt::Formatter, _indent: usize) -> fmt::Result {
-        write!(f, "{:?}", self)
+    fn pretty_print(&self, f: &mut fmt::Formatter, indent: usize) -> fmt::Result {
+        try!(write!(f, "Field(access_flags: 0x{:X}, name_index: {}, descriptor_index: {})\n", self.access_flags, self.name_index, self.descriptor_index));
+        try!(write!(f, "{0:1$}Attributes:", "", indent));
+        try!(self.attributes.pretty_print_preln(f, indent + 2));
+        Ok(())
     }
 }
 
@@ -145,8 +149,47 @@ impl PrettyPrint for Attribute {
                 try!(entries.pretty_print_preln(f, indent));
                 Ok(())
             },
+            Attribute::LocalVariableTable(_, ref entries) => {
+                try!(write!(f, "LocalVariableTable:"));
+                try!(entries.pretty_print_preln(f, indent));
+                Ok(())
+            },
             Attribute::SourceFile(_, index) => {
-                try!(write!(f, "SourceFile(index: {}):", index));
+                try!(write!(f, "SourceFile(index: {})", index));
+                Ok(())
+            }
+            Attribute::ConstantValue(_, index) => {
+                try!(write!(f, "ConstantValue(index: {})", index));
+                Ok(())
+            }
+            Attribute::Signature(_, index) => {
+                try!(write!(f, "Signature(index: {})", index));
+                Ok(())
+            }
+            Attribute::EnclosingMethod(_, class_index, method_index) => {
+                try!(write!(f, "EnclosingMethod(class_index: {}, method_index: {})", class_index, method_index));
+                Ok(())
+            }
+            Attribute::Exceptions(_, ref exception_indices) => {
+                try!(write!(f, "Exceptions(indices: {:?})", exception_indices));
+                Ok(())
+            }
+            Attribute::Deprecated(_) => {
+                try!(write!(f, "Deprecated"));
+                Ok(())
+            }
+            Attribute::Synthetic(_) => {
+                try!(write!(f, "Synthetic"));
+                Ok(())
+            }
+            Attribute::BootstrapMethods(_, ref bootstrap_methods) => {
+                try!(write!(f, "BootstrapMethods:"));
+                try!(bootstrap_methods.pretty_print_preln(f, indent));
+                Ok(())
+            }
+            Attribute::InnerClasses(_, ref inner_classes) => {
+                try!(write!(f, "InnerClasses:"));
+                try!(inner_classes.pretty_print_preln(f, indent));
                 Ok(())
             }
             Attribute::StackMapTable(_, ref entries) => {
@@ -154,13 +197,18 @@ impl PrettyPrint for Attribute {
                 try!(entries.pretty_print_preln(f, indent));
                 Ok(())
             },
+            Attribute::RuntimeVisibleAnnotations(_, ref annotations) => {
+                try!(write!(f, "RuntimeVisibleAnnotations:"));
+                try!(annotations.pretty_print_preln(f, indent));
+                Ok(())
+            },
         }
     }
 }
 
 impl PrettyPrint for ExceptionTableEntry {
     fn pretty_print(&self, f: &mut fmt::Formatter, _indent: usize) -> fmt::Result {
-        write!(f, "{:?}", self)
+        write!(f, "start_pc: {:2}, end_pc: {:2}, handler_pc: {:2}, catch_type: {:2}", self.start_pc, self.end_pc, self.handler_pc, self.catch_type)
     }
 }
 
@@ -170,6 +218,30 @@ impl PrettyPrint for LineNumberTableEntry {
     }
 }
 
+impl PrettyPrint for LocalVariableTableEntry {
+    fn pretty_print(&self, f: &mut fmt::Formatter, _indent: usize) -> fmt::Result {
+        write!(f, "start_pc: {:2}, length: {:2}, name_index: {:2}, descriptor_index: {:2}, index: {:2}", self.start_pc, self.length, self.name_index, self.descriptor_index, self.index)
+    }
+}
+
+impl PrettyPrint for BootstrapMethod {
+    fn pretty_print(&self, f: &mut fmt::Formatter, _indent: usize) -> fmt::Result {
+        write!(f, "method_ref: {:2}, arguments: {:?}", self.method_ref, self.arguments)
+    }
+}
+
+impl PrettyPrint for InnerClass {
+    fn pretty_print(&self, f: &mut fmt::Formatter, _indent: usize) -> fmt::Result {
+        write!(f, "inner_class_info_index: {:2}, outer_class_info_index: {:2}, inner_name_index: {:2}, inner_class_access_flags: 0x{:X}", self.inner_class_info_index, self.outer_class_info_index, self.inner_name_index, self.inner_class_access_flags)
+    }
+}
+
+impl PrettyPrint for Annotation {
+    fn pretty_print(&self, f: &mut fmt::Formatter, _indent: usize) -> fmt::Result {
+        write!(f, "type_index: {:2}, element_value_pairs: {:?}", self.type_index, self.element_value_pairs)
+    }
+}
+
 impl PrettyPrint for StackMapFrame {
     fn pretty_print(&self, f: &mut fmt::Formatter, _indent: usize) -> fmt::Result {
         write!(f, "{:?}", self)
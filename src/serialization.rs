@@ -1,4 +1,7 @@
-use std::io::Read;
+use std::fmt;
+use std::io;
+use std::io::{Read, Write};
+use std::iter;
 
 use classfile::*;
 
@@ -17,6 +20,44 @@ impl Classfile {
         self.attributes.serialize(buf);
     }
 
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = vec![];
+        self.clone().serialize(&mut bytes);
+        bytes
+    }
+
+    // Streams the same bytes as `to_bytes`, but one top-level item at a time,
+    // so the whole class never has to be held in memory at once.
+    pub fn write_to<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        try!(write_item(w, self.magic));
+        try!(write_item(w, self.minor_version));
+        try!(write_item(w, self.major_version));
+        try!(write_item(w, (self.constant_pool.len() + 1) as u16)); // constant_pool_length is len + 1
+        for constant in &self.constant_pool {
+            try!(write_item(w, constant.clone()));
+        }
+        try!(write_item(w, self.access_flags));
+        try!(write_item(w, self.this_class));
+        try!(write_item(w, self.super_class));
+        try!(write_item(w, self.interfaces.len() as u16));
+        for interface in &self.interfaces {
+            try!(write_item(w, interface.clone()));
+        }
+        try!(write_item(w, self.fields.len() as u16));
+        for field in &self.fields {
+            try!(write_item(w, field.clone()));
+        }
+        try!(write_item(w, self.methods.len() as u16));
+        for method in &self.methods {
+            try!(write_item(w, method.clone()));
+        }
+        try!(write_item(w, self.attributes.len() as u16));
+        for attribute in &self.attributes {
+            try!(write_item(w, attribute.clone()));
+        }
+        Ok(())
+    }
+
     pub fn deserialize(stream: Box<Read>) -> Classfile {
         let mut buf = &mut Deserializer::new(Box::new(stream.bytes().map(|r| r.unwrap())));
         let mut c = Classfile {
@@ -47,13 +88,13 @@ impl Classfile {
     }
 }
 
-struct Deserializer {
-    stream: Box<Iterator<Item=u8>>,
+struct Deserializer<'a> {
+    stream: Box<Iterator<Item=u8> + 'a>,
     bytes_taken: u32,
 }
 
-impl Deserializer {
-    fn new(stream: Box<Iterator<Item=u8>>) -> Deserializer {
+impl<'a> Deserializer<'a> {
+    fn new(stream: Box<Iterator<Item=u8> + 'a>) -> Deserializer<'a> {
         Deserializer { stream: stream, bytes_taken: 0 }
     }
 
@@ -68,6 +109,12 @@ impl Deserializer {
     }
 }
 
+fn write_item<W: Write, T: Serializable>(w: &mut W, item: T) -> io::Result<()> {
+    let mut buf = vec![];
+    item.serialize(&mut buf);
+    w.write_all(&buf)
+}
+
 trait Serializable {
     fn serialize(self, &mut Vec<u8>);
     fn deserialize(&mut Deserializer, &Classfile) -> Self;
@@ -93,14 +140,34 @@ impl Serializable for i32 {
 }
 
 impl Serializable for f32 {
+    // going through the raw bits keeps NaN payloads, infinities and -0.0 exact
     fn serialize(self, buf: &mut Vec<u8>) {
         let bits: u32 = self.to_bits();
         bits.serialize(buf);
     }
 
-    fn deserialize(buf: &mut Deserializer, _classfile: &Classfile) -> f32 {
-        println!("Deserialization is not yet implemented for floats!!! stop!!!");
-        return 0.0;
+    fn deserialize(buf: &mut Deserializer, classfile: &Classfile) -> f32 {
+        f32::from_bits(u32::deserialize(buf, classfile))
+    }
+}
+
+impl Serializable for i8 {
+    fn serialize(self, buf: &mut Vec<u8>) {
+        (self as u8).serialize(buf);
+    }
+
+    fn deserialize(buf: &mut Deserializer, classfile: &Classfile) -> i8 {
+        u8::deserialize(buf, classfile) as i8
+    }
+}
+
+impl Serializable for i16 {
+    fn serialize(self, buf: &mut Vec<u8>) {
+        (self as u16).serialize(buf);
+    }
+
+    fn deserialize(buf: &mut Deserializer, classfile: &Classfile) -> i16 {
+        u16::deserialize(buf, classfile) as i16
     }
 }
 
@@ -140,10 +207,45 @@ impl Serializable for u32 {
     }
 }
 
+impl Serializable for i64 {
+    fn serialize(self, buf: &mut Vec<u8>) {
+        (self as u64).serialize(buf);
+    }
+
+    fn deserialize(buf: &mut Deserializer, classfile: &Classfile) -> i64 {
+        u64::deserialize(buf, classfile) as i64
+    }
+}
+
+impl Serializable for f64 {
+    fn serialize(self, buf: &mut Vec<u8>) {
+        let bits: u64 = self.to_bits();
+        bits.serialize(buf);
+    }
+
+    fn deserialize(buf: &mut Deserializer, classfile: &Classfile) -> f64 {
+        f64::from_bits(u64::deserialize(buf, classfile))
+    }
+}
+
+impl Serializable for u64 {
+    fn serialize(self, buf: &mut Vec<u8>) {
+        ((self >> 32) as u32).serialize(buf);
+        (self as u32).serialize(buf);
+    }
+
+    fn deserialize(buf: &mut Deserializer, classfile: &Classfile) -> u64 {
+        let high = u32::deserialize(buf, classfile) as u64;
+        let low = u32::deserialize(buf, classfile) as u64;
+        (high << 32) + low
+    }
+}
+
 impl Serializable for String {
     fn serialize(self, buf: &mut Vec<u8>) {
-        (self.len() as u16).serialize(buf);
-        for b in self.as_bytes() {
+        let bytes = encode_modified_utf8(&self);
+        (bytes.len() as u16).serialize(buf);
+        for b in bytes {
             b.serialize(buf);
         }
     }
@@ -151,8 +253,56 @@ impl Serializable for String {
     fn deserialize(buf: &mut Deserializer, classfile: &Classfile) -> String {
         let len = u16::deserialize(buf, classfile);
         let v = buf.take_bytes(len as u32);
-        String::from_utf8(v).unwrap()
+        decode_modified_utf8(&v)
+    }
+}
+
+// the JVM uses "modified" UTF-8: NUL is encoded as two bytes, and characters
+// outside the BMP are encoded as a surrogate pair of three-byte sequences
+pub fn encode_modified_utf8(string: &str) -> Vec<u8> {
+    let mut out = vec![];
+    for c in string.encode_utf16() {
+        match c {
+            0x0001..=0x007f => {
+                out.push(c as u8);
+            },
+            0x0000 | 0x0080..=0x07ff => {
+                out.push((0xc0 | (c >> 6)) as u8);
+                out.push((0x80 | (c & 0x3f)) as u8);
+            },
+            _ => {
+                out.push((0xe0 | (c >> 12)) as u8);
+                out.push((0x80 | ((c >> 6) & 0x3f)) as u8);
+                out.push((0x80 | (c & 0x3f)) as u8);
+            },
+        }
     }
+    out
+}
+
+// The reverse of encode_modified_utf8. Java strings can hold unpaired
+// surrogates, which Rust strings can't, so those become U+FFFD.
+fn decode_modified_utf8(bytes: &[u8]) -> String {
+    let mut units = vec![];
+    let mut i = 0;
+    while i < bytes.len() {
+        let b = bytes[i] as u16;
+        if b & 0x80 == 0 {
+            units.push(b);
+            i += 1;
+        } else if b & 0xe0 == 0xc0 && i + 1 < bytes.len() {
+            units.push((b & 0x1f) << 6 | (bytes[i + 1] as u16 & 0x3f));
+            i += 2;
+        } else if b & 0xf0 == 0xe0 && i + 2 < bytes.len() {
+            units.push((b & 0x0f) << 12 | (bytes[i + 1] as u16 & 0x3f) << 6 |
+                       (bytes[i + 2] as u16 & 0x3f));
+            i += 3;
+        } else {
+            units.push(0xfffd);
+            i += 1;
+        }
+    }
+    String::from_utf16_lossy(&units)
 }
 
 impl Serializable for Vec<u8> {
@@ -179,7 +329,16 @@ impl Serializable for Vec<Constant> {
 
     fn deserialize(buf: &mut Deserializer, classfile: &Classfile) -> Vec<Constant> {
         let len = u16::deserialize(buf, classfile) - 1; // IMPORTANT: constant_pool_length is len + 1
-        (0..len).into_iter().map(|_| Constant::deserialize(buf, classfile)).collect()
+        let mut constants = vec![];
+        while constants.len() < len as usize {
+            let constant = Constant::deserialize(buf, classfile);
+            let two_slots = constant.takes_two_slots();
+            constants.push(constant);
+            if two_slots {
+                constants.push(Constant::Unusable);
+            }
+        }
+        constants
     }
 }
 
@@ -267,6 +426,104 @@ impl Serializable for Vec<LineNumberTableEntry> {
     }
 }
 
+impl Serializable for Vec<LocalVariableTableEntry> {
+    fn serialize(self, buf: &mut Vec<u8>) {
+        (self.len() as u16).serialize(buf);
+        for constant in self.into_iter() {
+            constant.serialize(buf);
+        }
+    }
+
+    fn deserialize(buf: &mut Deserializer, classfile: &Classfile) -> Vec<LocalVariableTableEntry> {
+        let len = u16::deserialize(buf, classfile);
+        (0..len).into_iter().map(|_| LocalVariableTableEntry::deserialize(buf, classfile)).collect()
+    }
+}
+
+impl Serializable for Vec<BootstrapMethod> {
+    fn serialize(self, buf: &mut Vec<u8>) {
+        (self.len() as u16).serialize(buf);
+        for constant in self.into_iter() {
+            constant.serialize(buf);
+        }
+    }
+
+    fn deserialize(buf: &mut Deserializer, classfile: &Classfile) -> Vec<BootstrapMethod> {
+        let len = u16::deserialize(buf, classfile);
+        (0..len).into_iter().map(|_| BootstrapMethod::deserialize(buf, classfile)).collect()
+    }
+}
+
+impl Serializable for Vec<InnerClass> {
+    fn serialize(self, buf: &mut Vec<u8>) {
+        (self.len() as u16).serialize(buf);
+        for inner_class in self.into_iter() {
+            inner_class.serialize(buf);
+        }
+    }
+
+    fn deserialize(buf: &mut Deserializer, classfile: &Classfile) -> Vec<InnerClass> {
+        let len = u16::deserialize(buf, classfile);
+        (0..len).into_iter().map(|_| InnerClass::deserialize(buf, classfile)).collect()
+    }
+}
+
+impl Serializable for Vec<Annotation> {
+    fn serialize(self, buf: &mut Vec<u8>) {
+        (self.len() as u16).serialize(buf);
+        for annotation in self.into_iter() {
+            annotation.serialize(buf);
+        }
+    }
+
+    fn deserialize(buf: &mut Deserializer, classfile: &Classfile) -> Vec<Annotation> {
+        let len = u16::deserialize(buf, classfile);
+        (0..len).into_iter().map(|_| Annotation::deserialize(buf, classfile)).collect()
+    }
+}
+
+impl Serializable for Vec<ElementValuePair> {
+    fn serialize(self, buf: &mut Vec<u8>) {
+        (self.len() as u16).serialize(buf);
+        for pair in self.into_iter() {
+            pair.serialize(buf);
+        }
+    }
+
+    fn deserialize(buf: &mut Deserializer, classfile: &Classfile) -> Vec<ElementValuePair> {
+        let len = u16::deserialize(buf, classfile);
+        (0..len).into_iter().map(|_| ElementValuePair::deserialize(buf, classfile)).collect()
+    }
+}
+
+impl Serializable for Vec<ElementValue> {
+    fn serialize(self, buf: &mut Vec<u8>) {
+        (self.len() as u16).serialize(buf);
+        for value in self.into_iter() {
+            value.serialize(buf);
+        }
+    }
+
+    fn deserialize(buf: &mut Deserializer, classfile: &Classfile) -> Vec<ElementValue> {
+        let len = u16::deserialize(buf, classfile);
+        (0..len).into_iter().map(|_| ElementValue::deserialize(buf, classfile)).collect()
+    }
+}
+
+impl Serializable for Vec<u16> {
+    fn serialize(self, buf: &mut Vec<u8>) {
+        (self.len() as u16).serialize(buf);
+        for index in self.into_iter() {
+            index.serialize(buf);
+        }
+    }
+
+    fn deserialize(buf: &mut Deserializer, classfile: &Classfile) -> Vec<u16> {
+        let len = u16::deserialize(buf, classfile);
+        (0..len).into_iter().map(|_| u16::deserialize(buf, classfile)).collect()
+    }
+}
+
 impl Serializable for Vec<StackMapFrame> {
     fn serialize(self, buf: &mut Vec<u8>) {
         (self.len() as u16).serialize(buf);
@@ -316,6 +573,87 @@ impl Serializable for Vec<Instruction> {
     }
 }
 
+// wide, then the opcode being widened and its two-byte local index
+fn serialize_wide(opcode: u8, index: u16, buf: &mut Vec<u8>) {
+    (0xc4 as u8).serialize(buf);
+    opcode.serialize(buf);
+    index.serialize(buf);
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum DecodeError {
+    UnknownOpcode(u16, u8), // pc, opcode
+    Truncated(u16),         // pc of the instruction that runs past the end of the code
+    InvalidSwitch(u16),     // pc of a tableswitch with low > high, or a lookupswitch with negative npairs
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            DecodeError::UnknownOpcode(pc, opcode) => write!(f, "unknown opcode 0x{:X} at pc {}", opcode, pc),
+            DecodeError::Truncated(pc) => write!(f, "instruction at pc {} runs past the end of the code", pc),
+            DecodeError::InvalidSwitch(pc) => write!(f, "switch at pc {} has low > high or a negative npairs", pc),
+        }
+    }
+}
+
+// Splits the body of a Code attribute back into instructions, each paired with its PC.
+pub fn decode_instructions(code: &[u8]) -> Result<Vec<(u16, Instruction)>, DecodeError> {
+    // instructions don't refer to the constant pool while being read, so an empty class will do
+    let classfile = Classfile::new(vec![], 0, 0, 0, vec![]);
+    // pad the code with zeros so a truncated instruction can be read and then reported
+    let padded = code.iter().cloned().chain(iter::repeat(0));
+    let buf = &mut Deserializer::new(Box::new(padded));
+
+    let mut out = vec![];
+    while (buf.bytes_taken as usize) < code.len() {
+        let pc = buf.bytes_taken as u16;
+        let opcode = u8::deserialize(buf, &classfile);
+        if opcode == 0xaa || opcode == 0xab {
+            try!(check_switch(code, pc));
+        }
+        match Instruction::read_operands(opcode, buf, &classfile) {
+            Some(instruction) => out.push((pc, instruction)),
+            None => return Err(DecodeError::UnknownOpcode(pc, opcode)),
+        }
+        if buf.bytes_taken as usize > code.len() {
+            return Err(DecodeError::Truncated(pc));
+        }
+    }
+    Ok(out)
+}
+
+// Switches say how many entries follow them, so a bad count would have the
+// decoder read the zero padding for ever. This checks the count against the
+// code that's actually there before anything is read.
+fn check_switch(code: &[u8], pc: u16) -> Result<(), DecodeError> {
+    let header_start = pc as usize + 1 + switch_padding(pc) as usize;
+    let read_i32 = |at: usize| -> Result<i64, DecodeError> {
+        match code.get(at..at + 4) {
+            Some(b) => Ok(((b[0] as u32) << 24 | (b[1] as u32) << 16 | (b[2] as u32) << 8 | b[3] as u32) as i32 as i64),
+            None => Err(DecodeError::Truncated(pc)),
+        }
+    };
+    let (header_size, entries, entry_size) = if code[pc as usize] == 0xaa {
+        let low = try!(read_i32(header_start + 4));
+        let high = try!(read_i32(header_start + 8));
+        if low > high {
+            return Err(DecodeError::InvalidSwitch(pc));
+        }
+        (12, high - low + 1, 4)
+    } else {
+        (8, try!(read_i32(header_start + 4)), 8)
+    };
+    if entries < 0 {
+        return Err(DecodeError::InvalidSwitch(pc));
+    }
+    let remaining = (code.len() - header_start - header_size) as i64;
+    if entries * entry_size > remaining {
+        return Err(DecodeError::Truncated(pc));
+    }
+    Ok(())
+}
+
 impl Serializable for Constant {
     fn serialize(self, buf: &mut Vec<u8>) {
         match self {
@@ -331,6 +669,14 @@ impl Serializable for Constant {
                 (4 as u8).serialize(buf);
                 n.serialize(buf);
             },
+            Constant::Long(n) => {
+                (5 as u8).serialize(buf);
+                n.serialize(buf);
+            },
+            Constant::Double(n) => {
+                (6 as u8).serialize(buf);
+                n.serialize(buf);
+            },
             Constant::Class(name_index) => {
                 (7 as u8).serialize(buf);
                 name_index.serialize(buf);
@@ -349,11 +695,31 @@ impl Serializable for Constant {
                 class_index.serialize(buf);
                 name_and_type_index.serialize(buf);
             },
+            Constant::InterfaceMethodref(class_index, name_and_type_index) => {
+                (11 as u8).serialize(buf);
+                class_index.serialize(buf);
+                name_and_type_index.serialize(buf);
+            },
             Constant::NameAndType(name_index, descriptor_index) => {
                 (12 as u8).serialize(buf);
                 name_index.serialize(buf);
                 descriptor_index.serialize(buf);
             },
+            Constant::MethodHandle(reference_kind, reference_index) => {
+                (15 as u8).serialize(buf);
+                reference_kind.serialize(buf);
+                reference_index.serialize(buf);
+            },
+            Constant::MethodType(descriptor_index) => {
+                (16 as u8).serialize(buf);
+                descriptor_index.serialize(buf);
+            },
+            Constant::InvokeDynamic(bootstrap_method_attr_index, name_and_type_index) => {
+                (18 as u8).serialize(buf);
+                bootstrap_method_attr_index.serialize(buf);
+                name_and_type_index.serialize(buf);
+            },
+            Constant::Unusable => {},
         }
     }
 
@@ -362,33 +728,50 @@ impl Serializable for Constant {
         match code {
             1 => Constant::Utf8(String::deserialize(buf, classfile)),
             3 => Constant::Integer(i32::deserialize(buf, classfile)),
+            4 => Constant::Float(f32::deserialize(buf, classfile)),
+            5 => Constant::Long(i64::deserialize(buf, classfile)),
+            6 => Constant::Double(f64::deserialize(buf, classfile)),
             7 => Constant::Class(u16::deserialize(buf, classfile)),
             8 => Constant::String(u16::deserialize(buf, classfile)),
             9 => Constant::Fieldref(u16::deserialize(buf, classfile), u16::deserialize(buf, classfile)),
             10 => Constant::Methodref(u16::deserialize(buf, classfile), u16::deserialize(buf, classfile)),
+            11 => Constant::InterfaceMethodref(u16::deserialize(buf, classfile), u16::deserialize(buf, classfile)),
             12 => Constant::NameAndType(u16::deserialize(buf, classfile), u16::deserialize(buf, classfile)),
+            15 => Constant::MethodHandle(u8::deserialize(buf, classfile), u16::deserialize(buf, classfile)),
+            16 => Constant::MethodType(u16::deserialize(buf, classfile)),
+            18 => Constant::InvokeDynamic(u16::deserialize(buf, classfile), u16::deserialize(buf, classfile)),
             _ => panic!("Don't know how to deserialize Constant of type: {}", code)
         }
     }
 }
 
 impl Serializable for Interface {
-    fn serialize(self, _buf: &mut Vec<u8>) {
-        panic!("TODO implement Interface::serialize")
+    fn serialize(self, buf: &mut Vec<u8>) {
+        self.class_index.serialize(buf);
     }
 
-    fn deserialize(_buf: &mut Deserializer, _classfile: &Classfile) -> Interface {
-        panic!("TODO implement Interface::deserialize")
+    fn deserialize(buf: &mut Deserializer, classfile: &Classfile) -> Interface {
+        Interface {
+            class_index: u16::deserialize(buf, classfile),
+        }
     }
 }
 
 impl Serializable for Field {
-    fn serialize(self, _buf: &mut Vec<u8>) {
-        panic!("TODO implement Field::serialize")
+    fn serialize(self, buf: &mut Vec<u8>) {
+        self.access_flags.serialize(buf);
+        self.name_index.serialize(buf);
+        self.descriptor_index.serialize(buf);
+        self.attributes.serialize(buf);
     }
 
-    fn deserialize(_buf: &mut Deserializer, _classfile: &Classfile) -> Field {
-        panic!("TODO implement Field::deserialize")
+    fn deserialize(buf: &mut Deserializer, classfile: &Classfile) -> Field {
+        Field {
+            access_flags: u16::deserialize(buf, classfile),
+            name_index: u16::deserialize(buf, classfile),
+            descriptor_index: u16::deserialize(buf, classfile),
+            attributes: Vec::deserialize(buf, classfile),
+        }
     }
 }
 
@@ -433,14 +816,51 @@ impl Serializable for Attribute {
                     attribute_name_index = name_index;
                     entries.serialize(body_buf);
                 },
+                Attribute::LocalVariableTable(name_index, entries) => {
+                    attribute_name_index = name_index;
+                    entries.serialize(body_buf);
+                },
                 Attribute::SourceFile(name_index, sourcefile_index) => {
                     attribute_name_index = name_index;
                     sourcefile_index.serialize(body_buf);
                 },
+                Attribute::ConstantValue(name_index, constantvalue_index) => {
+                    attribute_name_index = name_index;
+                    constantvalue_index.serialize(body_buf);
+                },
+                Attribute::BootstrapMethods(name_index, bootstrap_methods) => {
+                    attribute_name_index = name_index;
+                    bootstrap_methods.serialize(body_buf);
+                },
+                Attribute::InnerClasses(name_index, inner_classes) => {
+                    attribute_name_index = name_index;
+                    inner_classes.serialize(body_buf);
+                },
+                Attribute::Exceptions(name_index, exception_indices) => {
+                    attribute_name_index = name_index;
+                    exception_indices.serialize(body_buf);
+                },
+                Attribute::Deprecated(name_index) | Attribute::Synthetic(name_index) => {
+                    // these attributes are just markers, with an empty body
+                    attribute_name_index = name_index;
+                },
                 Attribute::StackMapTable(name_index, entries) => {
                     attribute_name_index = name_index;
                     entries.serialize(body_buf);
                 },
+                Attribute::RuntimeVisibleAnnotations(name_index, annotations) => {
+                    attribute_name_index = name_index;
+                    annotations.serialize(body_buf);
+                },
+                Attribute::Signature(name_index, signature_index) => {
+                    attribute_name_index = name_index;
+                    signature_index.serialize(body_buf);
+                },
+                Attribute::EnclosingMethod(name_index, class_index, method_index) => {
+                    attribute_name_index = name_index;
+                    class_index.serialize(body_buf);
+                    method_index.serialize(body_buf);
+                },
             }
         }
 
@@ -469,14 +889,49 @@ impl Serializable for Attribute {
                 let entries = Vec::deserialize(buf2, classfile);
                 Attribute::LineNumberTable(attribute_name_index, entries)
             },
+            "LocalVariableTable" => {
+                let entries = Vec::deserialize(buf2, classfile);
+                Attribute::LocalVariableTable(attribute_name_index, entries)
+            },
             "SourceFile" => {
                 let sourcefile_index = u16::deserialize(buf2, classfile);
                 Attribute::SourceFile(attribute_name_index, sourcefile_index)
             },
+            "ConstantValue" => {
+                let constantvalue_index = u16::deserialize(buf2, classfile);
+                Attribute::ConstantValue(attribute_name_index, constantvalue_index)
+            },
+            "BootstrapMethods" => {
+                let bootstrap_methods = Vec::deserialize(buf2, classfile);
+                Attribute::BootstrapMethods(attribute_name_index, bootstrap_methods)
+            },
+            "InnerClasses" => {
+                let inner_classes = Vec::deserialize(buf2, classfile);
+                Attribute::InnerClasses(attribute_name_index, inner_classes)
+            },
+            "Exceptions" => {
+                let exception_indices = Vec::deserialize(buf2, classfile);
+                Attribute::Exceptions(attribute_name_index, exception_indices)
+            },
+            "Deprecated" => Attribute::Deprecated(attribute_name_index),
+            "Synthetic" => Attribute::Synthetic(attribute_name_index),
             "StackMapTable" => {
                 let entries = Vec::deserialize(buf2, classfile);
                 Attribute::StackMapTable(attribute_name_index, entries)
             },
+            "RuntimeVisibleAnnotations" => {
+                let annotations = Vec::deserialize(buf2, classfile);
+                Attribute::RuntimeVisibleAnnotations(attribute_name_index, annotations)
+            },
+            "Signature" => {
+                let signature_index = u16::deserialize(buf2, classfile);
+                Attribute::Signature(attribute_name_index, signature_index)
+            },
+            "EnclosingMethod" => {
+                let class_index = u16::deserialize(buf2, classfile);
+                let method_index = u16::deserialize(buf2, classfile);
+                Attribute::EnclosingMethod(attribute_name_index, class_index, method_index)
+            },
             _ => panic!("TODO implement Attribute::deserialize for attribute type: {:?}", attribute_name)
 
         }
@@ -484,12 +939,20 @@ impl Serializable for Attribute {
 }
 
 impl Serializable for ExceptionTableEntry {
-    fn serialize(self, _buf: &mut Vec<u8>) {
-        panic!("TODO implement ExceptionTableEntry::serialize")
+    fn serialize(self, buf: &mut Vec<u8>) {
+        self.start_pc.serialize(buf);
+        self.end_pc.serialize(buf);
+        self.handler_pc.serialize(buf);
+        self.catch_type.serialize(buf);
     }
 
-    fn deserialize(_buf: &mut Deserializer, _classfile: &Classfile) -> ExceptionTableEntry {
-        panic!("TODO implement ExceptionTableEntry::deserialize")
+    fn deserialize(buf: &mut Deserializer, classfile: &Classfile) -> ExceptionTableEntry {
+        ExceptionTableEntry {
+            start_pc: u16::deserialize(buf, classfile),
+            end_pc: u16::deserialize(buf, classfile),
+            handler_pc: u16::deserialize(buf, classfile),
+            catch_type: u16::deserialize(buf, classfile),
+        }
     }
 }
 
@@ -507,6 +970,140 @@ impl Serializable for LineNumberTableEntry {
     }
 }
 
+impl Serializable for LocalVariableTableEntry {
+    fn serialize(self, buf: &mut Vec<u8>) {
+        self.start_pc.serialize(buf);
+        self.length.serialize(buf);
+        self.name_index.serialize(buf);
+        self.descriptor_index.serialize(buf);
+        self.index.serialize(buf);
+    }
+
+    fn deserialize(buf: &mut Deserializer, classfile: &Classfile) -> LocalVariableTableEntry {
+        LocalVariableTableEntry {
+            start_pc: u16::deserialize(buf, classfile),
+            length: u16::deserialize(buf, classfile),
+            name_index: u16::deserialize(buf, classfile),
+            descriptor_index: u16::deserialize(buf, classfile),
+            index: u16::deserialize(buf, classfile),
+        }
+    }
+}
+
+impl Serializable for BootstrapMethod {
+    fn serialize(self, buf: &mut Vec<u8>) {
+        self.method_ref.serialize(buf);
+        self.arguments.serialize(buf);
+    }
+
+    fn deserialize(buf: &mut Deserializer, classfile: &Classfile) -> BootstrapMethod {
+        BootstrapMethod {
+            method_ref: u16::deserialize(buf, classfile),
+            arguments: Vec::deserialize(buf, classfile),
+        }
+    }
+}
+
+impl Serializable for InnerClass {
+    fn serialize(self, buf: &mut Vec<u8>) {
+        self.inner_class_info_index.serialize(buf);
+        self.outer_class_info_index.serialize(buf);
+        self.inner_name_index.serialize(buf);
+        self.inner_class_access_flags.serialize(buf);
+    }
+
+    fn deserialize(buf: &mut Deserializer, classfile: &Classfile) -> InnerClass {
+        InnerClass {
+            inner_class_info_index: u16::deserialize(buf, classfile),
+            outer_class_info_index: u16::deserialize(buf, classfile),
+            inner_name_index: u16::deserialize(buf, classfile),
+            inner_class_access_flags: u16::deserialize(buf, classfile),
+        }
+    }
+}
+
+impl Serializable for Annotation {
+    fn serialize(self, buf: &mut Vec<u8>) {
+        self.type_index.serialize(buf);
+        self.element_value_pairs.serialize(buf);
+    }
+
+    fn deserialize(buf: &mut Deserializer, classfile: &Classfile) -> Annotation {
+        Annotation {
+            type_index: u16::deserialize(buf, classfile),
+            element_value_pairs: Vec::deserialize(buf, classfile),
+        }
+    }
+}
+
+impl Serializable for ElementValuePair {
+    fn serialize(self, buf: &mut Vec<u8>) {
+        self.element_name_index.serialize(buf);
+        self.value.serialize(buf);
+    }
+
+    fn deserialize(buf: &mut Deserializer, classfile: &Classfile) -> ElementValuePair {
+        ElementValuePair {
+            element_name_index: u16::deserialize(buf, classfile),
+            value: ElementValue::deserialize(buf, classfile),
+        }
+    }
+}
+
+impl Serializable for ElementValue {
+    fn serialize(self, buf: &mut Vec<u8>) {
+        match self {
+            ElementValue::Byte(index) => { (b'B').serialize(buf); index.serialize(buf); },
+            ElementValue::Char(index) => { (b'C').serialize(buf); index.serialize(buf); },
+            ElementValue::Double(index) => { (b'D').serialize(buf); index.serialize(buf); },
+            ElementValue::Float(index) => { (b'F').serialize(buf); index.serialize(buf); },
+            ElementValue::Int(index) => { (b'I').serialize(buf); index.serialize(buf); },
+            ElementValue::Long(index) => { (b'J').serialize(buf); index.serialize(buf); },
+            ElementValue::Short(index) => { (b'S').serialize(buf); index.serialize(buf); },
+            ElementValue::Boolean(index) => { (b'Z').serialize(buf); index.serialize(buf); },
+            ElementValue::String(index) => { (b's').serialize(buf); index.serialize(buf); },
+            ElementValue::Enum(type_name_index, const_name_index) => {
+                (b'e').serialize(buf);
+                type_name_index.serialize(buf);
+                const_name_index.serialize(buf);
+            },
+            ElementValue::Class(index) => { (b'c').serialize(buf); index.serialize(buf); },
+            ElementValue::Annotation(annotation) => {
+                (b'@').serialize(buf);
+                annotation.serialize(buf);
+            },
+            ElementValue::Array(values) => {
+                (b'[').serialize(buf);
+                values.serialize(buf);
+            },
+        }
+    }
+
+    fn deserialize(buf: &mut Deserializer, classfile: &Classfile) -> ElementValue {
+        let tag = u8::deserialize(buf, classfile);
+        match tag {
+            b'B' => ElementValue::Byte(u16::deserialize(buf, classfile)),
+            b'C' => ElementValue::Char(u16::deserialize(buf, classfile)),
+            b'D' => ElementValue::Double(u16::deserialize(buf, classfile)),
+            b'F' => ElementValue::Float(u16::deserialize(buf, classfile)),
+            b'I' => ElementValue::Int(u16::deserialize(buf, classfile)),
+            b'J' => ElementValue::Long(u16::deserialize(buf, classfile)),
+            b'S' => ElementValue::Short(u16::deserialize(buf, classfile)),
+            b'Z' => ElementValue::Boolean(u16::deserialize(buf, classfile)),
+            b's' => ElementValue::String(u16::deserialize(buf, classfile)),
+            b'e' => {
+                let type_name_index = u16::deserialize(buf, classfile);
+                let const_name_index = u16::deserialize(buf, classfile);
+                ElementValue::Enum(type_name_index, const_name_index)
+            },
+            b'c' => ElementValue::Class(u16::deserialize(buf, classfile)),
+            b'@' => ElementValue::Annotation(Annotation::deserialize(buf, classfile)),
+            b'[' => ElementValue::Array(Vec::deserialize(buf, classfile)),
+            _ => panic!("Unknown element_value tag: {:?}", tag as char),
+        }
+    }
+}
+
 impl Serializable for StackMapFrame {
     fn serialize(self, buf: &mut Vec<u8>) {
         match self {
@@ -609,10 +1206,10 @@ impl Serializable for VerificationType {
                 (2 as u8).serialize(buf);
             },
             VerificationType::Long => {
-                (3 as u8).serialize(buf);
+                (4 as u8).serialize(buf);
             },
             VerificationType::Double => {
-                (4 as u8).serialize(buf);
+                (3 as u8).serialize(buf);
             },
             VerificationType::Null => {
                 (5 as u8).serialize(buf);
@@ -644,10 +1241,10 @@ impl Serializable for VerificationType {
                 VerificationType::Float
             },
             3 => {
-                VerificationType::Long
+                VerificationType::Double
             },
             4 => {
-                VerificationType::Double
+                VerificationType::Long
             },
             5 => {
                 VerificationType::Null
@@ -670,302 +1267,286 @@ impl Serializable for VerificationType {
 
 impl Serializable for Instruction {
     fn serialize(self, buf: &mut Vec<u8>) {
+        let opcode = self.opcode();
         match self {
             Instruction::New(b1, b2) => {
-                (0xbb as u8).serialize(buf);
+                opcode.serialize(buf);
                 b1.serialize(buf);
                 b2.serialize(buf);
             },
-            Instruction::Dup => {
-                (0x59 as u8).serialize(buf);
-            },
-            Instruction::Irem => {
-                (0x70 as u8).serialize(buf);
-            },
-            Instruction::Frem => {
-                (0x72 as u8).serialize(buf);
-            },
-            Instruction::Fmul => {
-                (0x6a as u8).serialize(buf);
-            },
-            Instruction::Fdiv => {
-                (0x6e as u8).serialize(buf);
-            },
-            Instruction::Fadd => {
-                (0x62 as u8).serialize(buf);
-            },
-            Instruction::Fsub => {
-                (0x66 as u8).serialize(buf);
-            },
-            Instruction::Fload0 => {
-                (0x22 as u8).serialize(buf);
-            },
-            Instruction::Fload1 => {
-                (0x23 as u8).serialize(buf);
-            },
-            Instruction::Fload2 => {
-                (0x24 as u8).serialize(buf);
-            },
-            Instruction::Fload3 => {
-                (0x25 as u8).serialize(buf);
-            },
             Instruction::Fload(val) => {
-                (0x17 as u8).serialize(buf);
+                opcode.serialize(buf);
                 val.serialize(buf);
             },
-            Instruction::Fstore0 => {
-                (0x43 as u8).serialize(buf);
-            },
-            Instruction::Fstore1 => {
-                (0x44 as u8).serialize(buf);
-            },
-            Instruction::Fstore2 => {
-                (0x45 as u8).serialize(buf);
-            },
-            Instruction::Fstore3 => {
-                (0x46 as u8).serialize(buf);
-            },
             Instruction::Fstore(val) => {
-                (0x38 as u8).serialize(buf);
+                opcode.serialize(buf);
                 val.serialize(buf);
             },
-            Instruction::Fconst0 => {
-                (0x0b as u8).serialize(buf);
-            },
-            Instruction::Fconst1 => {
-                (0x0c as u8).serialize(buf);
-            },
-            Instruction::Fconst2 => {
-                (0x0b as u8).serialize(buf);
-            },
-            Instruction::FReturn => {
-                (0xae as u8).serialize(buf);
-            },
-            Instruction::I2C => {
-                (0x92 as u8).serialize(buf);
-            },
-            Instruction::I2F => {
-                (0x86 as u8).serialize(buf);
-            },
-            Instruction::F2I => {
-                (0x8b as u8).serialize(buf);
-            },
-            Instruction::IconstM1 => {
-                (0x2 as u8).serialize(buf);
-            },
-            Instruction::Iconst0 => {
-                (0x3 as u8).serialize(buf);
-            },
-            Instruction::Iconst1 => {
-                (0x4 as u8).serialize(buf);
-            },
-            Instruction::Iconst2 => {
-                (0x5 as u8).serialize(buf);
-            },
-            Instruction::Iconst3 => {
-                (0x6 as u8).serialize(buf);
-            },
-            Instruction::Iconst4 => {
-                (0x7 as u8).serialize(buf);
-            },
-            Instruction::Iconst5 => {
-                (0x8 as u8).serialize(buf);
-            },
-            Instruction::Istore0 => {
-                (0x3b as u8).serialize(buf);
-            },
-            Instruction::Istore1 => {
-                (0x3c as u8).serialize(buf);
-            },
-            Instruction::Istore2 => {
-                (0x3d as u8).serialize(buf);
-            },
-            Instruction::Istore3 => {
-                (0x3e as u8).serialize(buf);
-            },
             Instruction::Istore(idx) => {
-                (0x36 as u8).serialize(buf);
+                opcode.serialize(buf);
                 idx.serialize(buf);
             },
             Instruction::Bipush(val) => {
-                (0x10 as u8).serialize(buf);
+                opcode.serialize(buf);
                 val.serialize(buf);
             },
             Instruction::Sipush(val0, val1) => {
-                (0x11 as u8).serialize(buf);
+                opcode.serialize(buf);
                 val0.serialize(buf);
                 val1.serialize(buf);
             },
-            Instruction::Iload0 => {
-                (0x1a as u8).serialize(buf);
-            },
-            Instruction::Iload1 => {
-                (0x1b as u8).serialize(buf);
-            },
-            Instruction::Iload2 => {
-                (0x1c as u8).serialize(buf);
-            },
-            Instruction::Iload3 => {
-                (0x1d as u8).serialize(buf);
-            },
             Instruction::Iload(val) => {
-                (0x15 as u8).serialize(buf);
+                opcode.serialize(buf);
                 val.serialize(buf);
             },
             Instruction::LoadConstant(index) => {
-                (0x12 as u8).serialize(buf);
+                opcode.serialize(buf);
                 index.serialize(buf);
             },
-            Instruction::AConstNull => {
-                (0x01 as u8).serialize(buf);
-            },
-            Instruction::Areturn => {
-                (0xb0 as u8).serialize(buf);
-            },
-            Instruction::Astore0 => {
-                (0x4b as u8).serialize(buf);
-            },
-            Instruction::Astore1 => {
-                (0x4c as u8).serialize(buf);
-            },
-            Instruction::Astore2 => {
-                (0x4d as u8).serialize(buf);
-            },
-            Instruction::Astore3 => {
-                (0x4e as u8).serialize(buf);
-            },
             Instruction::Astore(n) => {
-                (0x3a as u8).serialize(buf);
+                opcode.serialize(buf);
                 n.serialize(buf);
             },
-            Instruction::Aload0 => {
-                (0x2A as u8).serialize(buf);
-            },
-            Instruction::Aload1 => {
-                (0x2B as u8).serialize(buf);
-            },
-            Instruction::Aload2 => {
-                (0x2C as u8).serialize(buf);
-            },
-            Instruction::Aload3 => {
-                (0x2D as u8).serialize(buf);
-            },
             Instruction::Aload(n) => {
-                (0x19 as u8).serialize(buf);
+                opcode.serialize(buf);
                 n.serialize(buf);
             },
-            Instruction::Aaload => {
-                (0x32 as u8).serialize(buf);
-            },
             Instruction::IfEq(index) => {
-                (0x99 as u8).serialize(buf);
+                opcode.serialize(buf);
                 index.serialize(buf);
             },
             Instruction::IfNe(index) => {
-                (0x9A as u8).serialize(buf);
+                opcode.serialize(buf);
                 index.serialize(buf);
             },
             Instruction::IfLt(index) => {
-                (0x9B as u8).serialize(buf);
+                opcode.serialize(buf);
                 index.serialize(buf);
             },
             Instruction::IfGe(index) => {
-                (0x9C as u8).serialize(buf);
+                opcode.serialize(buf);
                 index.serialize(buf);
             },
             Instruction::IfGt(index) => {
-                (0x9D as u8).serialize(buf);
+                opcode.serialize(buf);
                 index.serialize(buf);
             },
             Instruction::IfLe(index) => {
-                (0x9E as u8).serialize(buf);
+                opcode.serialize(buf);
                 index.serialize(buf);
             },
             Instruction::IfIcmpEq(index) => {
-                (0x9F as u8).serialize(buf);
+                opcode.serialize(buf);
                 index.serialize(buf);
             },
             Instruction::IfIcmpNe(index) => {
-                (0xA0 as u8).serialize(buf);
+                opcode.serialize(buf);
                 index.serialize(buf);
             },
             Instruction::IfIcmpLt(index) => {
-                (0xA1 as u8).serialize(buf);
+                opcode.serialize(buf);
                 index.serialize(buf);
             },
             Instruction::IfIcmpGe(index) => {
-                (0xA2 as u8).serialize(buf);
+                opcode.serialize(buf);
                 index.serialize(buf);
             },
             Instruction::IfIcmpGt(index) => {
-                (0xA3 as u8).serialize(buf);
+                opcode.serialize(buf);
                 index.serialize(buf);
             },
             Instruction::IfIcmpLe(index) => {
-                (0xA4 as u8).serialize(buf);
+                opcode.serialize(buf);
                 index.serialize(buf);
             },
             Instruction::Goto(index) => {
-                (0xA7 as u8).serialize(buf);
+                opcode.serialize(buf);
                 index.serialize(buf);
             },
-            Instruction::Iadd => {
-                (0x60 as u8).serialize(buf);
-            },
-            Instruction::Isub => {
-                (0x64 as u8).serialize(buf);
-            },
-            Instruction::Imul => {
-                (0x68 as u8).serialize(buf);
-            },
-            Instruction::Idiv => {
-                (0x6c as u8).serialize(buf);
-            }, 
-            Instruction::IReturn => {
-                (0xAC as u8).serialize(buf);
+            Instruction::Jsr(index) => {
+                opcode.serialize(buf);
+                index.serialize(buf);
             },
-            Instruction::Return => {
-                (0xB1 as u8).serialize(buf);
+            Instruction::Ret(index) => {
+                opcode.serialize(buf);
+                index.serialize(buf);
             },
             Instruction::GetStatic(index) => {
-                (0xB2 as u8).serialize(buf);
+                opcode.serialize(buf);
                 index.serialize(buf);
             },
             Instruction::InvokeVirtual(index) => {
-                (0xB6 as u8).serialize(buf);
+                opcode.serialize(buf);
                 index.serialize(buf);
             },
             Instruction::InvokeSpecial(index) => {
-                (0xB7 as u8).serialize(buf);
+                opcode.serialize(buf);
                 index.serialize(buf);
             },
             Instruction::InvokeStatic(index) => {
-                (0xB8 as u8).serialize(buf);
+                opcode.serialize(buf);
+                index.serialize(buf);
+            },
+            Instruction::Lload(idx) => {
+                opcode.serialize(buf);
+                idx.serialize(buf);
+            },
+            Instruction::Lstore(idx) => {
+                opcode.serialize(buf);
+                idx.serialize(buf);
+            },
+            Instruction::Dload(idx) => {
+                opcode.serialize(buf);
+                idx.serialize(buf);
+            },
+            Instruction::Dstore(idx) => {
+                opcode.serialize(buf);
+                idx.serialize(buf);
+            },
+            Instruction::Ldc2W(index) => {
+                opcode.serialize(buf);
+                index.serialize(buf);
+            },
+            Instruction::LdcW(index) => {
+                opcode.serialize(buf);
                 index.serialize(buf);
             },
-            Instruction::ArrayLength => {
-                (0xBE as u8).serialize(buf);
+            Instruction::GetField(index) => {
+                opcode.serialize(buf);
+                index.serialize(buf);
+            },
+            Instruction::PutField(index) => {
+                opcode.serialize(buf);
+                index.serialize(buf);
             },
+            Instruction::PutStatic(index) => {
+                opcode.serialize(buf);
+                index.serialize(buf);
+            },
+            Instruction::InvokeInterface(index, count) => {
+                opcode.serialize(buf);
+                index.serialize(buf);
+                count.serialize(buf);
+                (0 as u8).serialize(buf);
+            },
+            Instruction::NewArray(atype) => {
+                opcode.serialize(buf);
+                atype.serialize(buf);
+            },
+            Instruction::ANewArray(index) => {
+                opcode.serialize(buf);
+                index.serialize(buf);
+            },
+            Instruction::MultiANewArray(index, dimensions) => {
+                opcode.serialize(buf);
+                index.serialize(buf);
+                dimensions.serialize(buf);
+            },
+            Instruction::IfAcmpEq(index) => {
+                opcode.serialize(buf);
+                index.serialize(buf);
+            },
+            Instruction::IfAcmpNe(index) => {
+                opcode.serialize(buf);
+                index.serialize(buf);
+            },
+            Instruction::IfNull(index) => {
+                opcode.serialize(buf);
+                index.serialize(buf);
+            },
+            Instruction::IfNonNull(index) => {
+                opcode.serialize(buf);
+                index.serialize(buf);
+            },
+            Instruction::CheckCast(index) => {
+                opcode.serialize(buf);
+                index.serialize(buf);
+            },
+            Instruction::InstanceOf(index) => {
+                opcode.serialize(buf);
+                index.serialize(buf);
+            },
+            Instruction::Iinc(index, delta) => {
+                opcode.serialize(buf);
+                index.serialize(buf);
+                delta.serialize(buf);
+            },
+            Instruction::WideIload(index) => serialize_wide(0x15, index, buf),
+            Instruction::WideLload(index) => serialize_wide(0x16, index, buf),
+            Instruction::WideFload(index) => serialize_wide(0x17, index, buf),
+            Instruction::WideDload(index) => serialize_wide(0x18, index, buf),
+            Instruction::WideAload(index) => serialize_wide(0x19, index, buf),
+            Instruction::WideIstore(index) => serialize_wide(0x36, index, buf),
+            Instruction::WideLstore(index) => serialize_wide(0x37, index, buf),
+            Instruction::WideFstore(index) => serialize_wide(0x38, index, buf),
+            Instruction::WideDstore(index) => serialize_wide(0x39, index, buf),
+            Instruction::WideAstore(index) => serialize_wide(0x3a, index, buf),
+            Instruction::WideIinc(index, delta) => {
+                serialize_wide(0x84, index, buf);
+                delta.serialize(buf);
+            },
+            Instruction::TableSwitch(default, low, high, offsets) => {
+                // when serializing a method's code, buf holds just the code so far
+                let pc = buf.len() as u16;
+                opcode.serialize(buf);
+                for _ in 0..switch_padding(pc) {
+                    (0 as u8).serialize(buf);
+                }
+                default.serialize(buf);
+                low.serialize(buf);
+                high.serialize(buf);
+                for offset in offsets {
+                    offset.serialize(buf);
+                }
+            },
+            Instruction::LookupSwitch(default, pairs) => {
+                // when serializing a method's code, buf holds just the code so far
+                let pc = buf.len() as u16;
+                opcode.serialize(buf);
+                for _ in 0..switch_padding(pc) {
+                    (0 as u8).serialize(buf);
+                }
+                default.serialize(buf);
+                (pairs.len() as i32).serialize(buf);
+                for (key, offset) in pairs {
+                    key.serialize(buf);
+                    offset.serialize(buf);
+                }
+            },
+            Instruction::GotoW(offset) => {
+                opcode.serialize(buf);
+                offset.serialize(buf);
+            },
+            Instruction::JsrW(offset) => {
+                opcode.serialize(buf);
+                offset.serialize(buf);
+            },
+            Instruction::InvokeDynamic(index) => {
+                opcode.serialize(buf);
+                index.serialize(buf);
+                (0 as u16).serialize(buf);
+            },
+            Instruction::Raw(bytes) => buf.extend_from_slice(&bytes),
+            // everything else is just the opcode
+            _ => opcode.serialize(buf),
         }
     }
 
     fn deserialize(buf: &mut Deserializer, classfile: &Classfile) -> Instruction {
         let code = u8::deserialize(buf, classfile);
-        match code {
-            0x02 => Instruction::IconstM1,
-            0x03 => Instruction::Iconst0,
-            0x04 => Instruction::Iconst1,
-            0x05 => Instruction::Iconst2,
-            0x06 => Instruction::Iconst3,
-            0x07 => Instruction::Iconst4,
-            0x08 => Instruction::Iconst5,
+        match Instruction::read_operands(code, buf, classfile) {
+            Some(instruction) => instruction,
+            None => panic!("Don't know how to deserialize Instruction of type: 0x{:X}", code)
+        }
+    }
+}
+
+impl Instruction {
+    // reads the operands that follow the opcode `code`, or returns None for an unknown opcode
+    fn read_operands(code: u8, buf: &mut Deserializer, classfile: &Classfile) -> Option<Instruction> {
+        Some(match code {
             0x10 => Instruction::Bipush(u8::deserialize(buf, classfile)),
             0x12 => Instruction::LoadConstant(u8::deserialize(buf, classfile)),
-            0x2A => Instruction::Aload0,
-            0x2B => Instruction::Aload1,
-            0x2C => Instruction::Aload2,
-            0x2D => Instruction::Aload3,
-            0x32 => Instruction::Aaload,
             0x99 => Instruction::IfEq(u16::deserialize(buf, classfile)),
             0x9A => Instruction::IfNe(u16::deserialize(buf, classfile)),
             0x9B => Instruction::IfLt(u16::deserialize(buf, classfile)),
@@ -979,15 +1560,271 @@ impl Serializable for Instruction {
             0xA3 => Instruction::IfIcmpGt(u16::deserialize(buf, classfile)),
             0xA4 => Instruction::IfIcmpLe(u16::deserialize(buf, classfile)),
             0xA7 => Instruction::Goto(u16::deserialize(buf, classfile)),
-            0x60 => Instruction::Iadd,
-            0xB1 => Instruction::Return,
+            0xa8 => Instruction::Jsr(u16::deserialize(buf, classfile)),
+            0xa9 => Instruction::Ret(u8::deserialize(buf, classfile)),
             0xB2 => Instruction::GetStatic(u16::deserialize(buf, classfile)),
             0xB6 => Instruction::InvokeVirtual(u16::deserialize(buf, classfile)),
             0xB7 => Instruction::InvokeSpecial(u16::deserialize(buf, classfile)),
             0xB8 => Instruction::InvokeStatic(u16::deserialize(buf, classfile)),
-            0xBE => Instruction::ArrayLength,
-            _ => panic!("Don't know how to deserialize Instruction of type: 0x{:X}", code)
+            0x11 => Instruction::Sipush(u8::deserialize(buf, classfile), u8::deserialize(buf, classfile)),
+            0x15 => Instruction::Iload(u8::deserialize(buf, classfile)),
+            0x17 => Instruction::Fload(u8::deserialize(buf, classfile)),
+            0x19 => Instruction::Aload(u8::deserialize(buf, classfile)),
+            0x36 => Instruction::Istore(u8::deserialize(buf, classfile)),
+            0x38 => Instruction::Fstore(u8::deserialize(buf, classfile)),
+            0x3a => Instruction::Astore(u8::deserialize(buf, classfile)),
+            0xbb => Instruction::New(u8::deserialize(buf, classfile), u8::deserialize(buf, classfile)),
+            0x16 => Instruction::Lload(u8::deserialize(buf, classfile)),
+            0x37 => Instruction::Lstore(u8::deserialize(buf, classfile)),
+            0x18 => Instruction::Dload(u8::deserialize(buf, classfile)),
+            0x39 => Instruction::Dstore(u8::deserialize(buf, classfile)),
+            0x14 => Instruction::Ldc2W(u16::deserialize(buf, classfile)),
+            0x13 => Instruction::LdcW(u16::deserialize(buf, classfile)),
+            0xb4 => Instruction::GetField(u16::deserialize(buf, classfile)),
+            0xb5 => Instruction::PutField(u16::deserialize(buf, classfile)),
+            0xb3 => Instruction::PutStatic(u16::deserialize(buf, classfile)),
+            0xb9 => {
+                let index = u16::deserialize(buf, classfile);
+                let count = u8::deserialize(buf, classfile);
+                u8::deserialize(buf, classfile); // always zero
+                Instruction::InvokeInterface(index, count)
+            },
+            0xbc => Instruction::NewArray(u8::deserialize(buf, classfile)),
+            0xbd => Instruction::ANewArray(u16::deserialize(buf, classfile)),
+            0xc5 => Instruction::MultiANewArray(u16::deserialize(buf, classfile), u8::deserialize(buf, classfile)),
+            0xa5 => Instruction::IfAcmpEq(u16::deserialize(buf, classfile)),
+            0xa6 => Instruction::IfAcmpNe(u16::deserialize(buf, classfile)),
+            0xc6 => Instruction::IfNull(u16::deserialize(buf, classfile)),
+            0xc7 => Instruction::IfNonNull(u16::deserialize(buf, classfile)),
+            0xc0 => Instruction::CheckCast(u16::deserialize(buf, classfile)),
+            0xc1 => Instruction::InstanceOf(u16::deserialize(buf, classfile)),
+            0x84 => Instruction::Iinc(u8::deserialize(buf, classfile), i8::deserialize(buf, classfile)),
+            0xc4 => {
+                let opcode = u8::deserialize(buf, classfile);
+                let index = u16::deserialize(buf, classfile);
+                match opcode {
+                    0x15 => Instruction::WideIload(index),
+                    0x16 => Instruction::WideLload(index),
+                    0x17 => Instruction::WideFload(index),
+                    0x18 => Instruction::WideDload(index),
+                    0x19 => Instruction::WideAload(index),
+                    0x36 => Instruction::WideIstore(index),
+                    0x37 => Instruction::WideLstore(index),
+                    0x38 => Instruction::WideFstore(index),
+                    0x39 => Instruction::WideDstore(index),
+                    0x3a => Instruction::WideAstore(index),
+                    0x84 => Instruction::WideIinc(index, i16::deserialize(buf, classfile)),
+                    _ => return None,
+                }
+            },
+            0xaa => {
+                let pc = (buf.bytes_taken - 1) as u16;
+                buf.take_bytes(switch_padding(pc) as u32);
+                let default = i32::deserialize(buf, classfile);
+                let low = i32::deserialize(buf, classfile);
+                let high = i32::deserialize(buf, classfile);
+                let offsets = (low..=high).map(|_| i32::deserialize(buf, classfile)).collect();
+                Instruction::TableSwitch(default, low, high, offsets)
+            },
+            0xab => {
+                let pc = (buf.bytes_taken - 1) as u16;
+                buf.take_bytes(switch_padding(pc) as u32);
+                let default = i32::deserialize(buf, classfile);
+                let npairs = i32::deserialize(buf, classfile);
+                let pairs = (0..npairs).map(|_| {
+                    let key = i32::deserialize(buf, classfile);
+                    let offset = i32::deserialize(buf, classfile);
+                    (key, offset)
+                }).collect();
+                Instruction::LookupSwitch(default, pairs)
+            },
+            0xc8 => Instruction::GotoW(u32::deserialize(buf, classfile)),
+            0xc9 => Instruction::JsrW(u32::deserialize(buf, classfile)),
+            0xba => {
+                let index = u16::deserialize(buf, classfile);
+                u16::deserialize(buf, classfile); // always zero
+                Instruction::InvokeDynamic(index)
+            },
+            _ => return Instruction::from_opcode(code),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use access_flags::*;
+    use class_builder::ClassBuilder;
+
+    #[test]
+    fn to_bytes_starts_with_magic_and_version() {
+        let classfile = ClassBuilder::new(ACC_PUBLIC, "T", "java/lang/Object").done().unwrap();
+        assert_eq!(classfile.to_bytes()[..8], [0xca, 0xfe, 0xba, 0xbe, 0x00, 0x00, 0x00, 0x34]);
+    }
+
+    #[test]
+    fn write_to_agrees_with_to_bytes() {
+        let mut class = ClassBuilder::new(ACC_PUBLIC, "T", "java/lang/Object");
+        class.set_source_file("T.java");
+        let classfile = class.done().unwrap();
+        let mut buf = ::std::io::Cursor::new(vec![]);
+        classfile.write_to(&mut buf).unwrap();
+        assert_eq!(buf.into_inner(), classfile.to_bytes());
+    }
+
+    #[test]
+    fn interfaces_are_written_after_the_super_class() {
+        let mut class = ClassBuilder::new(ACC_PUBLIC, "T", "java/lang/Object");
+        class.add_interface("java/lang/Runnable");
+        class.add_interface("java/lang/Cloneable");
+        let classfile = class.done().unwrap();
+        assert_eq!(classfile.interfaces.len(), 2);
+        let mut expected = vec![];
+        for &index in &[classfile.this_class, classfile.super_class, 2,
+                        classfile.interfaces[0].class_index, classfile.interfaces[1].class_index] {
+            index.serialize(&mut expected);
         }
+        let bytes = classfile.to_bytes();
+        assert!(bytes.windows(expected.len()).any(|w| w == &expected[..]));
+    }
+
+    // the bytes of a method's code, as serializing its Code attribute writes them
+    fn encode(instructions: Vec<Instruction>) -> Vec<u8> {
+        let mut code = vec![];
+        for instruction in instructions {
+            instruction.serialize(&mut code);
+        }
+        code
+    }
+
+    #[test]
+    fn decodes_what_was_encoded() {
+        let instructions = vec![
+            Instruction::Iload0,
+            Instruction::TableSwitch(40, 1, 3, vec![10, 20, 30]),
+            Instruction::Iload0,
+            Instruction::LookupSwitch(-8, vec![(-5, 12), (1000, 24)]),
+            Instruction::Sipush(0xfc, 0x18),
+            Instruction::WideIload(300),
+            Instruction::WideIinc(1, 1000),
+            Instruction::InvokeInterface(0x0102, 2),
+            Instruction::GotoW(-3i32 as u32),
+            Instruction::Return,
+        ];
+        let decoded = decode_instructions(&encode(instructions.clone())).unwrap();
+        assert_eq!(decoded.into_iter().map(|(_, i)| i).collect::<Vec<_>>(), instructions);
+    }
+
+    #[test]
+    fn malformed_switches_are_errors() {
+        // opcode, padding, default, then low 1 and high 0
+        let backwards_table = [0xaa, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1, 0, 0, 0, 0];
+        assert_eq!(decode_instructions(&backwards_table), Err(DecodeError::InvalidSwitch(0)));
+        // a lookupswitch claiming 0x7fffffff pairs, with none there
+        let huge_lookup = [0xab, 0, 0, 0, 0, 0, 0, 0, 0x7f, 0xff, 0xff, 0xff];
+        assert_eq!(decode_instructions(&huge_lookup), Err(DecodeError::Truncated(0)));
+        let negative_lookup = [0xab, 0, 0, 0, 0, 0, 0, 0, 0xff, 0xff, 0xff, 0xff];
+        assert_eq!(decode_instructions(&negative_lookup), Err(DecodeError::InvalidSwitch(0)));
+        assert_eq!(decode_instructions(&[0x00, 0xaa, 0, 0]), Err(DecodeError::Truncated(1)));
+    }
+
+    #[test]
+    fn version_is_written_as_set() {
+        let mut class = ClassBuilder::new(ACC_PUBLIC, "T", "java/lang/Object");
+        class.set_version(55, 0);
+        assert_eq!(class.done().unwrap().to_bytes()[4..8], [0x00, 0x00, 0x00, 0x37]);
+    }
+
+    #[test]
+    fn access_flags_survive_a_round_trip() {
+        assert_eq!((ACC_PUBLIC, ACC_FINAL, ACC_SUPER), (0x0001, 0x0010, 0x0020));
+        let bytes = ClassBuilder::new(ACC_PUBLIC | ACC_FINAL, "T", "java/lang/Object").done().unwrap().to_bytes();
+        let classfile = Classfile::deserialize(Box::new(::std::io::Cursor::new(bytes)));
+        assert_eq!(classfile.access_flags, ACC_PUBLIC | ACC_FINAL | ACC_SUPER);
+    }
 
+    #[test]
+    fn stack_map_frames_use_the_right_tags() {
+        let frames = vec![
+            (StackMapFrame::SameFrame(5), vec![5]),
+            (StackMapFrame::SameLocals1StackItemFrame(3, VerificationType::Integer), vec![67, 1]),
+            (StackMapFrame::SameLocals1StackItemFrameExtended(300, VerificationType::Null), vec![247, 1, 44, 5]),
+            (StackMapFrame::ChopFrame(2, 10), vec![249, 0, 10]),
+            (StackMapFrame::SameFrameExtended(300), vec![251, 1, 44]),
+            (StackMapFrame::AppendFrame(1, 7, vec![VerificationType::Object(9)]), vec![252, 0, 7, 7, 0, 9]),
+            (StackMapFrame::FullFrame(2, vec![VerificationType::Long], vec![VerificationType::Float]),
+             vec![255, 0, 2, 0, 1, 4, 0, 1, 2]),
+        ];
+        let classfile = Classfile::new(vec![], 0, 0, 0, vec![]);
+        for (frame, expected) in frames {
+            let mut bytes = vec![];
+            frame.clone().serialize(&mut bytes);
+            assert_eq!(bytes, expected);
+            let buf = &mut Deserializer::new(Box::new(bytes.into_iter()));
+            assert_eq!(StackMapFrame::deserialize(buf, &classfile), frame);
+        }
+    }
+
+    #[test]
+    fn annotation_without_elements() {
+        let mut class = ClassBuilder::new(ACC_PUBLIC, "T", "java/lang/Object");
+        class.add_annotation("java/lang/Deprecated", &[]);
+        let classfile = class.done().unwrap();
+        let (name_index, type_index) = match classfile.attributes[..] {
+            [Attribute::RuntimeVisibleAnnotations(name_index, ref annotations)] => {
+                assert_eq!(annotations.len(), 1);
+                assert!(annotations[0].element_value_pairs.is_empty());
+                (name_index, annotations[0].type_index)
+            },
+            ref other => panic!("expected RuntimeVisibleAnnotations, found {:?}", other),
+        };
+        assert_eq!(classfile.lookup_string(type_index), "Ljava/lang/Deprecated;");
+        // name, a length of 6, one annotation, its type and no element pairs
+        let mut expected = vec![];
+        name_index.serialize(&mut expected);
+        6u32.serialize(&mut expected);
+        1u16.serialize(&mut expected);
+        type_index.serialize(&mut expected);
+        0u16.serialize(&mut expected);
+        assert!(classfile.to_bytes().ends_with(&expected));
+    }
+
+    #[test]
+    fn float_bit_patterns() {
+        let bytes = |f: f32| {
+            let mut buf = vec![];
+            f.serialize(&mut buf);
+            buf
+        };
+        assert_eq!(bytes(::std::f32::INFINITY), [0x7f, 0x80, 0x00, 0x00]);
+        assert_eq!(bytes(-0.0), [0x80, 0x00, 0x00, 0x00]);
+        assert_eq!(bytes(1.5), [0x3f, 0xc0, 0x00, 0x00]);
+    }
+
+    #[test]
+    fn modified_utf8_round_trip() {
+        let string = "a\0\u{1f600}";
+        let bytes = encode_modified_utf8(string);
+        // one byte for the a, two for the nul and three for each half of the
+        // emoji's surrogate pair
+        assert_eq!(bytes.len(), 9);
+        assert_eq!(bytes[1..3], [0xc0, 0x80]);
+        assert!(!bytes.contains(&0));
+        assert_eq!(decode_modified_utf8(&bytes), string);
+    }
+
+    #[test]
+    fn invokestatic_method_handle() {
+        use class_builder::REF_INVOKE_STATIC;
+        let mut class = ClassBuilder::new(ACC_PUBLIC, "T", "java/lang/Object");
+        let index = class.define_method_handle(REF_INVOKE_STATIC, "T", "run", "()V");
+        let classfile = class.done().unwrap();
+        let reference = match *classfile.lookup_constant(index) {
+            Constant::MethodHandle(6, reference) => reference,
+            ref other => panic!("expected a kind 6 MethodHandle, found {:?}", other),
+        };
+        let mut bytes = vec![];
+        classfile.lookup_constant(index).clone().serialize(&mut bytes);
+        assert_eq!(bytes, [15, 6, (reference >> 8) as u8, reference as u8]);
     }
 }
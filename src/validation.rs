@@ -0,0 +1,427 @@
+// Structural checks on a class file: every constant pool index has to point at
+// the kind of constant the JVM expects there, and descriptors have to parse.
+// The bytecode itself isn't checked; MethodBuilder::verify does that.
+
+use class_builder::*;
+use classfile::*;
+use java_type_signatures::*;
+
+impl Classfile {
+    // reports every problem found, not just the first
+    pub fn validate(&self) -> Result<(), Vec<String>> {
+        let mut validator = Validator { classfile: self, problems: vec![] };
+        validator.check_constant_pool();
+
+        validator.expect_class(self.this_class, "this_class");
+        if self.super_class != 0 {
+            validator.expect_class(self.super_class, "super_class");
+        }
+        for interface in &self.interfaces {
+            validator.expect_class(interface.class_index, "interface");
+        }
+        for field in &self.fields {
+            let context = format!("field {}", validator.describe(field.name_index));
+            validator.expect_utf8(field.name_index, &context);
+            validator.expect_descriptor(field.descriptor_index, &context, false);
+            validator.check_attributes(&field.attributes, &context);
+        }
+        for method in &self.methods {
+            let context = format!("method {}", validator.describe(method.name_index));
+            validator.expect_utf8(method.name_index, &context);
+            validator.expect_descriptor(method.descriptor_index, &context, true);
+            validator.check_attributes(&method.attributes, &context);
+        }
+        validator.check_attributes(&self.attributes, "class");
+
+        if validator.problems.is_empty() {
+            Ok(())
+        } else {
+            Err(validator.problems)
+        }
+    }
+}
+
+struct Validator<'a> {
+    classfile: &'a Classfile,
+    problems: Vec<String>,
+}
+
+impl<'a> Validator<'a> {
+    fn check_constant_pool(&mut self) {
+        let classfile = self.classfile;
+        let bootstrap_method_count = classfile.attributes.iter().filter_map(|a| match *a {
+            Attribute::BootstrapMethods(_, ref methods) => Some(methods.len()),
+            _ => None,
+        }).next().unwrap_or(0);
+
+        for (i, constant) in classfile.constant_pool.iter().enumerate() {
+            let context = format!("constant {}", i + 1);
+            match *constant {
+                Constant::Utf8(_) | Constant::Integer(_) | Constant::Float(_) => {},
+                Constant::Long(_) | Constant::Double(_) => {
+                    if classfile.constant_pool.get(i + 1) != Some(&Constant::Unusable) {
+                        self.problems.push(format!("{}: a long or double must be followed by an unusable slot", context));
+                    }
+                },
+                Constant::Unusable => {
+                    if i == 0 || !classfile.constant_pool[i - 1].takes_two_slots() {
+                        self.problems.push(format!("{}: unusable slot doesn't follow a long or double", context));
+                    }
+                },
+                Constant::Class(name_index) | Constant::String(name_index) => {
+                    self.expect_utf8(name_index, &context);
+                },
+                Constant::Fieldref(class_index, name_and_type_index) => {
+                    self.expect_class(class_index, &context);
+                    self.expect_name_and_type(name_and_type_index, &context, false);
+                },
+                Constant::Methodref(class_index, name_and_type_index) |
+                Constant::InterfaceMethodref(class_index, name_and_type_index) => {
+                    self.expect_class(class_index, &context);
+                    self.expect_name_and_type(name_and_type_index, &context, true);
+                },
+                Constant::NameAndType(name_index, descriptor_index) => {
+                    self.expect_utf8(name_index, &context);
+                    self.expect_utf8(descriptor_index, &context);
+                },
+                Constant::MethodHandle(reference_kind, reference_index) => {
+                    let (wanted, ok): (&str, fn(&Constant) -> bool) = match reference_kind {
+                        REF_GET_FIELD..=REF_PUT_STATIC => ("Fieldref", is_fieldref),
+                        REF_INVOKE_VIRTUAL | REF_NEW_INVOKE_SPECIAL => ("Methodref", is_methodref),
+                        REF_INVOKE_STATIC | REF_INVOKE_SPECIAL => ("Methodref or InterfaceMethodref", is_any_methodref),
+                        REF_INVOKE_INTERFACE => ("InterfaceMethodref", is_interface_methodref),
+                        _ => {
+                            self.problems.push(format!("{}: invalid method handle kind {}", context, reference_kind));
+                            continue;
+                        },
+                    };
+                    self.expect(reference_index, &context, wanted, ok);
+                },
+                Constant::MethodType(descriptor_index) => {
+                    self.expect_descriptor(descriptor_index, &context, true);
+                },
+                Constant::InvokeDynamic(bootstrap_method_index, name_and_type_index) => {
+                    if bootstrap_method_index as usize >= bootstrap_method_count {
+                        self.problems.push(format!("{}: bootstrap method {} doesn't exist (the class has {})",
+                                                   context, bootstrap_method_index, bootstrap_method_count));
+                    }
+                    self.expect_name_and_type(name_and_type_index, &context, true);
+                },
+            }
+        }
+    }
+
+    fn check_attributes(&mut self, attributes: &[Attribute], context: &str) {
+        for attribute in attributes {
+            self.check_attribute(attribute, context);
+        }
+    }
+
+    fn check_attribute(&mut self, attribute: &Attribute, context: &str) {
+        match *attribute {
+            Attribute::Code(name_index, _, _, ref code, ref exception_table, ref attributes) => {
+                self.expect_utf8(name_index, context);
+                let context = format!("{}, Code", context);
+                for (i, instruction) in code.iter().enumerate() {
+                    self.check_instruction(instruction, &format!("{}, instruction {}", context, i));
+                }
+                for entry in exception_table {
+                    if entry.catch_type != 0 {
+                        self.expect_class(entry.catch_type, &context);
+                    }
+                }
+                self.check_attributes(attributes, &context);
+            },
+            Attribute::LineNumberTable(name_index, _) | Attribute::Deprecated(name_index) |
+            Attribute::Synthetic(name_index) => {
+                self.expect_utf8(name_index, context);
+            },
+            Attribute::LocalVariableTable(name_index, ref entries) => {
+                self.expect_utf8(name_index, context);
+                for entry in entries {
+                    self.expect_utf8(entry.name_index, context);
+                    self.expect_descriptor(entry.descriptor_index, context, false);
+                }
+            },
+            Attribute::SourceFile(name_index, index) | Attribute::Signature(name_index, index) => {
+                self.expect_utf8(name_index, context);
+                self.expect_utf8(index, context);
+            },
+            Attribute::ConstantValue(name_index, index) => {
+                self.expect_utf8(name_index, context);
+                self.expect(index, context, "a constant value", |c| match *c {
+                    Constant::Integer(_) | Constant::Float(_) | Constant::Long(_) |
+                    Constant::Double(_) | Constant::String(_) => true,
+                    _ => false,
+                });
+            },
+            Attribute::BootstrapMethods(name_index, ref methods) => {
+                self.expect_utf8(name_index, context);
+                for method in methods {
+                    self.expect(method.method_ref, context, "MethodHandle", |c| match *c {
+                        Constant::MethodHandle(..) => true,
+                        _ => false,
+                    });
+                    for &argument in &method.arguments {
+                        self.expect(argument, context, "a loadable constant", |c| is_loadable(c) || c.takes_two_slots());
+                    }
+                }
+            },
+            Attribute::InnerClasses(name_index, ref classes) => {
+                self.expect_utf8(name_index, context);
+                for class in classes {
+                    self.expect_class(class.inner_class_info_index, context);
+                    if class.outer_class_info_index != 0 {
+                        self.expect_class(class.outer_class_info_index, context);
+                    }
+                    if class.inner_name_index != 0 {
+                        self.expect_utf8(class.inner_name_index, context);
+                    }
+                }
+            },
+            Attribute::Exceptions(name_index, ref exception_indices) => {
+                self.expect_utf8(name_index, context);
+                for &index in exception_indices {
+                    self.expect_class(index, context);
+                }
+            },
+            Attribute::StackMapTable(name_index, ref frames) => {
+                self.expect_utf8(name_index, context);
+                for frame in frames {
+                    let types: Vec<&VerificationType> = match *frame {
+                        StackMapFrame::SameLocals1StackItemFrame(_, ref t) |
+                        StackMapFrame::SameLocals1StackItemFrameExtended(_, ref t) => vec![t],
+                        StackMapFrame::AppendFrame(_, _, ref locals) => locals.iter().collect(),
+                        StackMapFrame::FullFrame(_, ref locals, ref stack) => locals.iter().chain(stack).collect(),
+                        _ => vec![],
+                    };
+                    for t in types {
+                        if let VerificationType::Object(index) = *t {
+                            self.expect_class(index, context);
+                        }
+                    }
+                }
+            },
+            Attribute::RuntimeVisibleAnnotations(name_index, ref annotations) => {
+                self.expect_utf8(name_index, context);
+                for annotation in annotations {
+                    self.check_annotation(annotation, context);
+                }
+            },
+            Attribute::EnclosingMethod(name_index, class_index, method_index) => {
+                self.expect_utf8(name_index, context);
+                self.expect_class(class_index, context);
+                if method_index != 0 {
+                    self.expect_name_and_type(method_index, context, true);
+                }
+            },
+        }
+    }
+
+    fn check_annotation(&mut self, annotation: &Annotation, context: &str) {
+        self.expect_descriptor(annotation.type_index, context, false);
+        for pair in &annotation.element_value_pairs {
+            self.expect_utf8(pair.element_name_index, context);
+            self.check_element_value(&pair.value, context);
+        }
+    }
+
+    fn check_element_value(&mut self, value: &ElementValue, context: &str) {
+        match *value {
+            ElementValue::Byte(index) | ElementValue::Char(index) | ElementValue::Int(index) |
+            ElementValue::Short(index) | ElementValue::Boolean(index) =>
+                self.expect(index, context, "Integer", |c| match *c { Constant::Integer(_) => true, _ => false }),
+            ElementValue::Double(index) =>
+                self.expect(index, context, "Double", |c| match *c { Constant::Double(_) => true, _ => false }),
+            ElementValue::Float(index) =>
+                self.expect(index, context, "Float", |c| match *c { Constant::Float(_) => true, _ => false }),
+            ElementValue::Long(index) =>
+                self.expect(index, context, "Long", |c| match *c { Constant::Long(_) => true, _ => false }),
+            ElementValue::String(index) | ElementValue::Class(index) => {
+                self.expect_utf8(index, context);
+            },
+            ElementValue::Enum(type_index, name_index) => {
+                self.expect_descriptor(type_index, context, false);
+                self.expect_utf8(name_index, context);
+            },
+            ElementValue::Annotation(ref annotation) => self.check_annotation(annotation, context),
+            ElementValue::Array(ref values) => {
+                for value in values {
+                    self.check_element_value(value, context);
+                }
+            },
+        }
+    }
+
+    fn check_instruction(&mut self, instruction: &Instruction, context: &str) {
+        match *instruction {
+            Instruction::LoadConstant(index) =>
+                self.expect(index as u16, context, "a loadable constant", is_loadable),
+            Instruction::LdcW(index) =>
+                self.expect(index, context, "a loadable constant", is_loadable),
+            Instruction::Ldc2W(index) =>
+                self.expect(index, context, "Long or Double", |c| c.takes_two_slots()),
+            Instruction::GetStatic(index) | Instruction::PutStatic(index) |
+            Instruction::GetField(index) | Instruction::PutField(index) =>
+                self.expect(index, context, "Fieldref", is_fieldref),
+            Instruction::InvokeVirtual(index) =>
+                self.expect(index, context, "Methodref", is_methodref),
+            Instruction::InvokeSpecial(index) | Instruction::InvokeStatic(index) =>
+                self.expect(index, context, "Methodref or InterfaceMethodref", is_any_methodref),
+            Instruction::InvokeInterface(index, _) =>
+                self.expect(index, context, "InterfaceMethodref", is_interface_methodref),
+            Instruction::InvokeDynamic(index) =>
+                self.expect(index, context, "InvokeDynamic", |c| match *c {
+                    Constant::InvokeDynamic(..) => true,
+                    _ => false,
+                }),
+            Instruction::New(high, low) => self.expect_class((high as u16) << 8 | low as u16, context),
+            Instruction::ANewArray(index) | Instruction::MultiANewArray(index, _) |
+            Instruction::CheckCast(index) | Instruction::InstanceOf(index) => self.expect_class(index, context),
+            _ => {},
+        }
+    }
+
+    // the constant at `index`, or None if there isn't one there, which is reported
+    fn constant(&mut self, index: u16, context: &str) -> Option<&'a Constant> {
+        let pool = &self.classfile.constant_pool;
+        match pool.get((index as usize).wrapping_sub(1)) {
+            Some(&Constant::Unusable) => {
+                self.problems.push(format!("{}: constant {} is the unusable slot after a long or double",
+                                           context, index));
+                None
+            },
+            Some(constant) => Some(constant),
+            None => {
+                self.problems.push(format!("{}: constant index {} is out of range (the pool has {} entries)",
+                                           context, index, pool.len()));
+                None
+            },
+        }
+    }
+
+    fn expect<F: Fn(&Constant) -> bool>(&mut self, index: u16, context: &str, wanted: &str, ok: F) {
+        match self.constant(index, context) {
+            Some(constant) if !ok(constant) => {
+                self.problems.push(format!("{}: constant {} should be {}, found {:?}",
+                                           context, index, wanted, constant));
+            },
+            _ => {},
+        }
+    }
+
+    fn expect_class(&mut self, index: u16, context: &str) {
+        self.expect(index, context, "Class", |c| match *c { Constant::Class(_) => true, _ => false });
+    }
+
+    fn expect_utf8(&mut self, index: u16, context: &str) -> Option<&'a str> {
+        match self.constant(index, context) {
+            Some(&Constant::Utf8(ref string)) => Some(string),
+            Some(constant) => {
+                self.problems.push(format!("{}: constant {} should be Utf8, found {:?}", context, index, constant));
+                None
+            },
+            None => None,
+        }
+    }
+
+    fn expect_descriptor(&mut self, index: u16, context: &str, method: bool) {
+        if let Some(descriptor) = self.expect_utf8(index, context) {
+            let valid = if method {
+                parse_method_signature(descriptor).is_some()
+            } else {
+                parse_type_signature(descriptor).is_some()
+            };
+            if !valid {
+                let kind = if method { "method" } else { "field" };
+                self.problems.push(format!("{}: {:?} isn't a valid {} descriptor", context, descriptor, kind));
+            }
+        }
+    }
+
+    fn expect_name_and_type(&mut self, index: u16, context: &str, method: bool) {
+        match self.constant(index, context) {
+            Some(&Constant::NameAndType(_, descriptor_index)) => {
+                // the NameAndType's own indices are checked with the rest of the pool
+                if let Some(&Constant::Utf8(_)) = self.classfile.constant_pool.get((descriptor_index as usize).wrapping_sub(1)) {
+                    self.expect_descriptor(descriptor_index, context, method);
+                }
+            },
+            Some(constant) => {
+                self.problems.push(format!("{}: constant {} should be NameAndType, found {:?}",
+                                           context, index, constant));
+            },
+            None => {},
+        }
+    }
+
+    // a name for messages, falling back to the index if it isn't a Utf8
+    fn describe(&self, index: u16) -> String {
+        match self.classfile.constant_pool.get((index as usize).wrapping_sub(1)) {
+            Some(&Constant::Utf8(ref string)) => string.clone(),
+            _ => format!("#{}", index),
+        }
+    }
+}
+
+// the constants ldc and ldc_w can push
+fn is_loadable(constant: &Constant) -> bool {
+    match *constant {
+        Constant::Integer(_) | Constant::Float(_) | Constant::String(_) | Constant::Class(_) |
+        Constant::MethodHandle(..) | Constant::MethodType(_) => true,
+        _ => false,
+    }
+}
+
+fn is_fieldref(constant: &Constant) -> bool {
+    match *constant {
+        Constant::Fieldref(..) => true,
+        _ => false,
+    }
+}
+
+fn is_methodref(constant: &Constant) -> bool {
+    match *constant {
+        Constant::Methodref(..) => true,
+        _ => false,
+    }
+}
+
+fn is_interface_methodref(constant: &Constant) -> bool {
+    match *constant {
+        Constant::InterfaceMethodref(..) => true,
+        _ => false,
+    }
+}
+
+fn is_any_methodref(constant: &Constant) -> bool {
+    is_methodref(constant) || is_interface_methodref(constant)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use access_flags::*;
+
+    #[test]
+    fn dangling_methodref() {
+        let mut class = ClassBuilder::new(ACC_PUBLIC, "T", "java/lang/Object");
+        {
+            let mut method = class.define_method(ACC_PUBLIC | ACC_STATIC, "m", &[], &Java::Void);
+            method.invoke_static("T", "f", &[], &Java::Void).do_return();
+            method.done().unwrap();
+        }
+        let mut classfile = class.done().unwrap();
+        assert_eq!(classfile.validate(), Ok(()));
+
+        let dangling = classfile.constant_pool.len() as u16 + 10;
+        for constant in &mut classfile.constant_pool {
+            if let Constant::Methodref(_, ref mut name_and_type) = *constant {
+                *name_and_type = dangling;
+            }
+        }
+        let problems = classfile.validate().unwrap_err();
+        assert_eq!(problems.len(), 1);
+        assert!(problems[0].contains(&format!("constant index {} is out of range", dangling)), "{:?}", problems);
+    }
+}